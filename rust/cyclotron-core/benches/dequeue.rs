@@ -0,0 +1,125 @@
+use std::time::{Duration, Instant};
+
+use common::{bench_job, job_count, runtime, BenchDb};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use cyclotron_core::{QueueManager, Worker};
+use futures::future::join_all;
+
+mod common;
+
+const QUEUE: &str = "bench";
+const BATCH_SIZE: usize = 100;
+
+async fn seed(db: &BenchDb, manager: &QueueManager, jobs: usize) {
+    db.reset().await;
+    let inits: Vec<_> = (0..jobs).map(|_| bench_job(QUEUE)).collect();
+    for chunk in inits.chunks(1_000) {
+        manager.bulk_create_jobs(chunk.to_vec()).await.unwrap();
+    }
+}
+
+// Dequeues until the queue is empty, with `concurrency` workers dequeuing batches at once
+async fn drain(workers: &[Worker]) {
+    join_all(workers.iter().map(|worker| async move {
+        while !worker
+            .dequeue_jobs(QUEUE, BATCH_SIZE)
+            .await
+            .unwrap()
+            .is_empty()
+        {}
+    }))
+    .await;
+}
+
+fn dequeue_throughput(c: &mut Criterion) {
+    let rt = runtime();
+    let db = rt.block_on(BenchDb::start());
+    let manager = QueueManager::from_pool(db.pool.clone());
+    let jobs = job_count();
+
+    let mut group = c.benchmark_group("dequeue_throughput");
+    group.throughput(Throughput::Elements(jobs as u64));
+    group.sample_size(10);
+    for concurrency in [1, 4, 16] {
+        let workers: Vec<_> = rt.block_on(async {
+            (0..concurrency)
+                .map(|_| Worker::from_pool(db.pool.clone(), Default::default()))
+                .collect()
+        });
+        group.bench_with_input(
+            BenchmarkId::from_parameter(concurrency),
+            &workers,
+            |b, workers| {
+                // Seeding isn't part of the measurement
+                b.to_async(&rt).iter_custom(|iters| {
+                    let (db, manager) = (&db, &manager);
+                    async move {
+                        let mut elapsed = Duration::ZERO;
+                        for _ in 0..iters {
+                            seed(db, manager, jobs).await;
+                            let start = Instant::now();
+                            drain(workers).await;
+                            elapsed += start.elapsed();
+                        }
+                        elapsed
+                    }
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+// How long a single one-job dequeue takes against a deep queue, while `concurrency - 1` other workers are
+// dequeuing from it too
+fn dequeue_latency(c: &mut Criterion) {
+    let rt = runtime();
+    let db = rt.block_on(BenchDb::start());
+    let manager = QueueManager::from_pool(db.pool.clone());
+    let jobs = job_count();
+
+    let mut group = c.benchmark_group("dequeue_latency");
+    for concurrency in [1, 4, 16] {
+        let workers: Vec<_> = rt.block_on(async {
+            (0..concurrency)
+                .map(|_| Worker::from_pool(db.pool.clone(), Default::default()))
+                .collect()
+        });
+        group.bench_with_input(
+            BenchmarkId::from_parameter(concurrency),
+            &workers,
+            |b, workers| {
+                b.to_async(&rt).iter_custom(|iters| {
+                    let (db, manager) = (&db, &manager);
+                    async move {
+                        let mut elapsed = Duration::ZERO;
+                        let mut remaining = 0;
+                        for _ in 0..iters {
+                            if remaining < workers.len() {
+                                seed(db, manager, jobs).await;
+                                remaining = jobs;
+                            }
+                            let (measured, others) = workers.split_first().unwrap();
+                            let start = Instant::now();
+                            let (dequeued, _) = futures::join!(
+                                async {
+                                    let dequeued = measured.dequeue_jobs(QUEUE, 1).await.unwrap();
+                                    elapsed += start.elapsed();
+                                    dequeued
+                                },
+                                join_all(others.iter().map(|w| w.dequeue_jobs(QUEUE, 1)))
+                            );
+                            assert_eq!(dequeued.len(), 1);
+                            remaining -= workers.len();
+                        }
+                        elapsed
+                    }
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, dequeue_throughput, dequeue_latency);
+criterion_main!(benches);
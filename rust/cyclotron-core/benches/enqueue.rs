@@ -0,0 +1,55 @@
+use std::time::{Duration, Instant};
+
+use common::{bench_job, job_count, runtime, BenchDb};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use cyclotron_core::QueueManager;
+
+mod common;
+
+const QUEUE: &str = "bench";
+
+// Enqueueing `job_count` jobs one at a time, and in batches of various sizes
+fn enqueue_throughput(c: &mut Criterion) {
+    let rt = runtime();
+    let db = rt.block_on(BenchDb::start());
+    let manager = QueueManager::from_pool(db.pool.clone());
+    let jobs = job_count();
+
+    let mut group = c.benchmark_group("enqueue_throughput");
+    group.throughput(Throughput::Elements(jobs as u64));
+    group.sample_size(10);
+    for batch_size in [1, 100, 1_000] {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(batch_size),
+            &batch_size,
+            |b, &batch_size| {
+                b.to_async(&rt).iter_custom(|iters| {
+                    let (db, manager) = (&db, &manager);
+                    async move {
+                        let mut elapsed = Duration::ZERO;
+                        for _ in 0..iters {
+                            db.reset().await;
+                            let inits: Vec<_> = (0..jobs).map(|_| bench_job(QUEUE)).collect();
+                            let start = Instant::now();
+                            if batch_size == 1 {
+                                for init in inits {
+                                    manager.create_job(init).await.unwrap();
+                                }
+                            } else {
+                                for chunk in inits.chunks(batch_size) {
+                                    manager.bulk_create_jobs(chunk.to_vec()).await.unwrap();
+                                }
+                            }
+                            elapsed += start.elapsed();
+                        }
+                        elapsed
+                    }
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, enqueue_throughput);
+criterion_main!(benches);
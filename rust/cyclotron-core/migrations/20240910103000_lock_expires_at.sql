@@ -0,0 +1,5 @@
+-- An explicit lock deadline, set by workers that want to hold a job for longer than the janitor's
+-- stall timeout without heartbeating on every tick. When set, the janitor uses this instead of the
+-- last_heartbeat to decide whether a job has stalled.
+ALTER TABLE cyclotron_jobs
+    ADD COLUMN lock_expires_at TIMESTAMPTZ;
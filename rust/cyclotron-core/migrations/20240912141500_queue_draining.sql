@@ -0,0 +1,8 @@
+-- Per-queue state. Right now this is only used to mark queues as draining, which rejects any new jobs
+-- being created in the queue while letting jobs already in it run to completion.
+CREATE TABLE IF NOT EXISTS cyclotron_queues (
+    queue_name TEXT PRIMARY KEY,
+    draining BOOLEAN NOT NULL DEFAULT FALSE,
+    -- When the queue was marked as draining, if it is
+    draining_since TIMESTAMPTZ
+);
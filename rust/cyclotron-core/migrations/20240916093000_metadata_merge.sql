@@ -0,0 +1,20 @@
+-- Merges a JSON object into a job's metadata, server side, so workers can update some keys without
+-- having to read-modify-write the whole thing. Metadata is stored as bytes, so we have to parse it here -
+-- if the existing metadata isn't a JSON object, it's replaced by the patch.
+CREATE OR REPLACE FUNCTION cyclotron_merge_metadata(existing BYTEA, patch JSONB) RETURNS BYTEA AS $$
+DECLARE
+    current JSONB;
+BEGIN
+    BEGIN
+        current := convert_from(existing, 'UTF8')::jsonb;
+    EXCEPTION WHEN OTHERS THEN
+        current := NULL;
+    END;
+
+    IF current IS NULL OR jsonb_typeof(current) <> 'object' THEN
+        current := '{}'::jsonb;
+    END IF;
+
+    RETURN convert_to((current || patch)::text, 'UTF8');
+END;
+$$ LANGUAGE plpgsql IMMUTABLE;
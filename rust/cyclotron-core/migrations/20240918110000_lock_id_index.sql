@@ -0,0 +1,2 @@
+-- Dequeues supplied with a lock id check it isn't already held by another job, so we need to be able to look jobs up by lock
+CREATE INDEX idx_cyclotron_jobs_lock_id ON cyclotron_jobs(lock_id) WHERE lock_id IS NOT NULL;
@@ -0,0 +1,4 @@
+-- 'paused' has been part of the enum since the initial schema, but the Rust enum and the DB type have drifted
+-- before, so make sure every variant the Rust side knows about exists. Note the type was created unquoted, so
+-- it's really called jobstate.
+ALTER TYPE JobState ADD VALUE IF NOT EXISTS 'paused';
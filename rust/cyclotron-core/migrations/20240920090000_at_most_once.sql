@@ -0,0 +1,3 @@
+-- Jobs marked at-most-once are failed, rather than returned to the queue, when the janitor finds them stalled -
+-- for jobs where running twice is worse than not running at all.
+ALTER TABLE cyclotron_jobs ADD COLUMN at_most_once BOOLEAN NOT NULL DEFAULT FALSE;
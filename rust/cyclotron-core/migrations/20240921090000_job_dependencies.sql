@@ -0,0 +1,27 @@
+-- Jobs can be created blocked on another job, via `QueueManager::enqueue_after`. Blocked jobs sit paused, with
+-- the id of the job they're waiting on in blocked_by, until that job completes, at which point they're made
+-- available. Jobs blocked on a job that fails (or is dead-lettered) stay paused.
+ALTER TABLE cyclotron_jobs ADD COLUMN blocked_by UUID;
+
+CREATE INDEX idx_cyclotron_jobs_blocked_by ON cyclotron_jobs(blocked_by) WHERE blocked_by IS NOT NULL;
+
+-- Done in a trigger, rather than by the worker, so every path that completes a job unblocks its dependents,
+-- in the same transaction as the completion.
+CREATE OR REPLACE FUNCTION cyclotron_unblock_dependents() RETURNS TRIGGER AS $$
+BEGIN
+    UPDATE cyclotron_jobs
+    SET
+        state = 'available',
+        blocked_by = NULL,
+        transition_count = transition_count + 1,
+        last_transition = NOW()
+    WHERE blocked_by = NEW.id AND state = 'paused';
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER cyclotron_unblock_dependents
+AFTER UPDATE OF state ON cyclotron_jobs
+FOR EACH ROW
+WHEN (NEW.state = 'completed' AND OLD.state <> 'completed')
+EXECUTE FUNCTION cyclotron_unblock_dependents();
@@ -0,0 +1,10 @@
+-- A single row recording the version of the schema, so code can check it's running against a schema it
+-- understands - see `check_compatibility`. Migrations that change the shape of the schema in a way code has to
+-- know about should bump this (along with SCHEMA_VERSION in the crate) in the same migration.
+CREATE TABLE IF NOT EXISTS cyclotron_meta (
+    -- Always true, so the table can only ever have one row
+    singleton BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (singleton),
+    schema_version INTEGER NOT NULL
+);
+
+INSERT INTO cyclotron_meta (schema_version) VALUES (1) ON CONFLICT (singleton) DO NOTHING;
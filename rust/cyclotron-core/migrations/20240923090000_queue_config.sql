@@ -0,0 +1,10 @@
+-- Per-queue enqueue defaults, used by QueueManager::create_job_with_queue_defaults for jobs that leave their
+-- priority or schedule unset. A queue with no row here, or a null column, has no default for that field.
+CREATE TABLE IF NOT EXISTS cyclotron_queue_config (
+    queue_name TEXT PRIMARY KEY,
+    default_priority SMALLINT,
+    -- How long after being enqueued jobs are scheduled to run
+    default_delay_ms BIGINT CHECK (default_delay_ms >= 0)
+);
+
+UPDATE cyclotron_meta SET schema_version = 2;
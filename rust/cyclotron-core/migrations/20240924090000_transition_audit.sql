@@ -0,0 +1,51 @@
+-- An append-only record of every job state change, for auditing. Recording is off by default - it's turned on and
+-- off by enabling and disabling the trigger, with `QueueManager::set_transition_audit`, so it costs nothing when
+-- it's not wanted. Rows are written by the trigger, so they're always in the same transaction as the change.
+CREATE TABLE IF NOT EXISTS cyclotron_job_transitions (
+    -- Orders transitions, including ones made in the same transaction, which share a timestamp
+    id BIGSERIAL PRIMARY KEY,
+    job_id UUID NOT NULL,
+    -- Null for the job being created
+    from_state JobState,
+    to_state JobState NOT NULL,
+    at TIMESTAMPTZ NOT NULL,
+    -- The lock the job was held under when taken or released, if any
+    lock_id UUID
+);
+
+-- No foreign key, since the record has to outlive the jobs the janitor deletes
+CREATE INDEX idx_cyclotron_job_transitions_job_id ON cyclotron_job_transitions(job_id, id);
+
+CREATE OR REPLACE FUNCTION cyclotron_record_transition() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        INSERT INTO cyclotron_job_transitions (job_id, from_state, to_state, at, lock_id)
+        VALUES (NEW.id, NULL, NEW.state, NOW(), NEW.lock_id);
+    ELSIF NEW.state IS DISTINCT FROM OLD.state THEN
+        INSERT INTO cyclotron_job_transitions (job_id, from_state, to_state, at, lock_id)
+        VALUES (NEW.id, OLD.state, NEW.state, NOW(), COALESCE(NEW.lock_id, OLD.lock_id));
+    END IF;
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER cyclotron_record_transitions
+AFTER INSERT OR UPDATE OF state ON cyclotron_jobs
+FOR EACH ROW
+EXECUTE FUNCTION cyclotron_record_transition();
+
+ALTER TABLE cyclotron_jobs DISABLE TRIGGER cyclotron_record_transitions;
+
+-- The record is immutable once written
+CREATE OR REPLACE FUNCTION cyclotron_reject_transition_changes() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'cyclotron_job_transitions is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER cyclotron_job_transitions_append_only
+BEFORE UPDATE OR DELETE ON cyclotron_job_transitions
+FOR EACH ROW
+EXECUTE FUNCTION cyclotron_reject_transition_changes();
+
+UPDATE cyclotron_meta SET schema_version = 3;
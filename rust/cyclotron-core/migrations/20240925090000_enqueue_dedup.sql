@@ -0,0 +1,8 @@
+-- A hash of a job's team, queue and parameters, only set on jobs enqueued through `create_job_deduplicated`, so an
+-- identical job enqueued again within the dedup window can be found. The index is partial, so jobs enqueued any other
+-- way don't pay for it.
+ALTER TABLE cyclotron_jobs ADD COLUMN dedup_hash BYTEA;
+
+CREATE INDEX idx_cyclotron_jobs_dedup_hash ON cyclotron_jobs(dedup_hash, created) WHERE dedup_hash IS NOT NULL;
+
+UPDATE cyclotron_meta SET schema_version = 4;
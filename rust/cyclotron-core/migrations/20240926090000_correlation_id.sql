@@ -0,0 +1,7 @@
+-- The id of the request a job was enqueued as part of, if the enqueuer passed one, so every job from a request can
+-- be found, e.g. by support. Unlike dedup_hash most jobs are expected to have one, so the index isn't partial.
+ALTER TABLE cyclotron_jobs ADD COLUMN correlation_id UUID;
+
+CREATE INDEX idx_cyclotron_jobs_correlation_id ON cyclotron_jobs(correlation_id);
+
+UPDATE cyclotron_meta SET schema_version = 5;
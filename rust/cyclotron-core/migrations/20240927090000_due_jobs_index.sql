@@ -0,0 +1,21 @@
+-- Serves the dequeue query in the order it hands jobs out. Being partial, it only holds available jobs, but the
+-- predicate can't also be `scheduled <= NOW()` - index predicates have to be immutable - so jobs that aren't due yet
+-- are in it too, and are skipped past during the scan. For postgres to use it, a query has to match this shape:
+--
+--     WHERE state = 'available' AND queue_name = $1 AND scheduled <= NOW()
+--     ORDER BY priority, scheduled, id
+--     LIMIT $2
+--
+-- The literal `state = 'available'` is what lets the planner prove the partial index applies - a bound parameter
+-- for the state doesn't, since the plan might be reused for another state. The queue is matched exactly, so the
+-- scan starts at the queue's first (highest priority) available job, and walks the index in dequeue order until
+-- it has found the limit's worth of due jobs. The id is the last column so that order is the query's whole
+-- ORDER BY, tie break included, and nothing has to be sorted. NOW() is stable rather than immutable, so it can't
+-- be in the predicate, but it can be compared against during the scan.
+CREATE INDEX idx_cyclotron_jobs_due ON cyclotron_jobs (queue_name, priority, scheduled, id)
+WHERE
+    state = 'available';
+
+-- The dequeue index from the initial schema, which this replaces. By putting the state second, it's no use to
+-- queries across states, and the dequeue is better served by the index above, so it's only a cost on every write.
+DROP INDEX idx_cyclotron_jobs_dequeue;
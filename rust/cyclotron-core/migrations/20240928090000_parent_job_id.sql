@@ -0,0 +1,8 @@
+-- The job that spawned a job, if the enqueuer passed one, so fan-outs can be rendered as a tree from their root.
+-- It's purely informational - unlike blocked_by, nothing waits on it - and isn't a foreign key, since the janitor
+-- routinely deletes parents before their children. Most jobs won't have one, so the index is partial.
+ALTER TABLE cyclotron_jobs ADD COLUMN parent_job_id UUID;
+
+CREATE INDEX idx_cyclotron_jobs_parent_job_id ON cyclotron_jobs(parent_job_id) WHERE parent_job_id IS NOT NULL;
+
+UPDATE cyclotron_meta SET schema_version = 6;
@@ -0,0 +1,5 @@
+-- The queue a job's result is enqueued into once it completes, for request/response over the queue. Nothing
+-- looks jobs up by it, so it isn't indexed.
+ALTER TABLE cyclotron_jobs ADD COLUMN reply_to TEXT;
+
+UPDATE cyclotron_meta SET schema_version = 7;
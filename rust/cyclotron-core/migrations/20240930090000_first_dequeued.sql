@@ -0,0 +1,6 @@
+-- When a job was first dequeued, for `time_in_queue`. Set by the first dequeue or claim, and never changed after,
+-- however many times the job is retried, released or reset. Jobs dequeued before this column existed don't have
+-- one, and are left out of the latencies, just as they were before.
+ALTER TABLE cyclotron_jobs ADD COLUMN first_dequeued TIMESTAMPTZ;
+
+UPDATE cyclotron_meta SET schema_version = 8;
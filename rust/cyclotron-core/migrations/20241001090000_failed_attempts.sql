@@ -0,0 +1,6 @@
+-- How many attempts at a job have failed - bumped when a worker fails the job, or returns it to be retried after a
+-- failure, and by nothing else, so it counts attempts however else the job moves between states. Jobs from before
+-- this column existed start from zero.
+ALTER TABLE cyclotron_jobs ADD COLUMN failed_attempts SMALLINT NOT NULL DEFAULT 0;
+
+UPDATE cyclotron_meta SET schema_version = 9;
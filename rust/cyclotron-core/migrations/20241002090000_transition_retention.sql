@@ -0,0 +1,16 @@
+-- The transition record is append-only, but it can't grow forever, so the janitor prunes transitions past their
+-- retention. Rows still can't be updated, and can only be deleted by a transaction that's declared itself to be
+-- pruning, by setting cyclotron.pruning_transitions - see `Janitor::prune_transitions_older_than`.
+CREATE OR REPLACE FUNCTION cyclotron_reject_transition_changes() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'DELETE' AND current_setting('cyclotron.pruning_transitions', true) = 'on' THEN
+        RETURN OLD;
+    END IF;
+    RAISE EXCEPTION 'cyclotron_job_transitions is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+-- For finding the transitions past their retention
+CREATE INDEX idx_cyclotron_job_transitions_at ON cyclotron_job_transitions(at);
+
+UPDATE cyclotron_meta SET schema_version = 10;
@@ -0,0 +1,67 @@
+use std::{future::Future, time::Instant};
+
+use sqlx::{pool::PoolConnection, PgPool, Postgres, Transaction};
+use tracing::warn;
+
+use crate::{config::AcquireRetry, QueueError};
+
+// A connection from the pool, retrying according to `retry` if getting one fails transiently
+pub(crate) async fn acquire(
+    pool: &PgPool,
+    retry: &AcquireRetry,
+) -> Result<PoolConnection<Postgres>, QueueError> {
+    with_retry(retry, || pool.acquire()).await
+}
+
+// A transaction on the pool, retrying according to `retry` if getting one fails transiently
+pub(crate) async fn begin(
+    pool: &PgPool,
+    retry: &AcquireRetry,
+) -> Result<Transaction<'static, Postgres>, QueueError> {
+    with_retry(retry, || pool.begin()).await
+}
+
+async fn with_retry<T, F, Fut>(retry: &AcquireRetry, acquire: F) -> Result<T, QueueError>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<T, sqlx::Error>>,
+{
+    // Waiting on the pool counts against max_wait too, so a pool that keeps timing out doesn't multiply its
+    // acquire timeout by the number of attempts
+    let started = Instant::now();
+    let max_wait = retry.max_wait.to_std().unwrap_or_default();
+    let mut attempt = 1;
+    loop {
+        match acquire().await {
+            Ok(acquired) => return Ok(acquired),
+            Err(e) if attempt < retry.attempts && is_transient(&e) => {
+                let backoff = retry.backoff(attempt).to_std().unwrap_or_default();
+                if started.elapsed() + backoff >= max_wait {
+                    return Err(e.into());
+                }
+                warn!(
+                    attempt,
+                    backoff_ms = backoff.as_millis() as u64,
+                    "failed to get a database connection, retrying: {}",
+                    e
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Whether failing to get a connection is something that might clear up if we try again, rather than e.g. the
+// pool having been closed, or the database rejecting our credentials
+fn is_transient(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::PoolTimedOut | sqlx::Error::Io(_) | sqlx::Error::Tls(_) => true,
+        // connection_exception, and admin_shutdown, crash_shutdown and cannot_connect_now, seen during failovers
+        sqlx::Error::Database(db) => db.code().is_some_and(|code| {
+            code.starts_with("08") || matches!(&*code, "57P01" | "57P02" | "57P03")
+        }),
+        _ => false,
+    }
+}
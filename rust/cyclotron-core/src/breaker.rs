@@ -0,0 +1,108 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+    time::Instant,
+};
+
+use crate::config::BreakerConfig;
+
+// A circuit breaker per queue, tracking how the jobs a worker dequeued from each queue turned out. Once too
+// many of a queue's recent jobs have failed, the breaker trips, and the worker stops dequeuing from that queue
+// for the cooldown - after which a single job is let through to probe whether whatever was failing them has
+// recovered. If the probe succeeds the breaker closes again, and if it fails it's tripped for another cooldown.
+// Like the rate limiter, it's local to this process.
+pub struct CircuitBreaker {
+    config: BreakerConfig,
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,   // Jobs are dequeued as normal
+    Open,     // Nothing is dequeued until the cooldown is over
+    HalfOpen, // One job at a time is dequeued, until one of them succeeds or fails
+}
+
+enum Circuit {
+    Closed(VecDeque<bool>),    // The most recent outcomes, true for a success
+    Open(Instant),             // Until this
+    HalfOpen(Option<Instant>), // When the current probe was let through, if there is one
+}
+
+impl CircuitBreaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            circuits: Default::default(),
+        }
+    }
+
+    pub fn state(&self, queue: &str) -> BreakerState {
+        match self.circuits.lock().unwrap().get(queue) {
+            None | Some(Circuit::Closed(_)) => BreakerState::Closed,
+            Some(Circuit::Open(until)) if Instant::now() < *until => BreakerState::Open,
+            Some(Circuit::Open(_)) | Some(Circuit::HalfOpen(_)) => BreakerState::HalfOpen,
+        }
+    }
+
+    // How many of `limit` jobs can be dequeued from the queue right now. Half open, that's one, if no other
+    // probe is out - a caller let through to probe that then doesn't dequeue anything should `cancel_probe`.
+    pub(crate) fn admit(&self, queue: &str, limit: usize) -> usize {
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(queue) else {
+            return limit;
+        };
+        match circuit {
+            Circuit::Closed(_) => limit,
+            Circuit::Open(until) if Instant::now() < *until => 0,
+            // A probe that hasn't been heard back from within a cooldown, e.g. because the rate limiter held it
+            // back, or its lock was lost, is given up on, and another is let through
+            Circuit::HalfOpen(Some(since)) if since.elapsed() < self.cooldown() => 0,
+            Circuit::Open(_) | Circuit::HalfOpen(_) => {
+                *circuit = Circuit::HalfOpen(Some(Instant::now()));
+                limit.min(1)
+            }
+        }
+    }
+
+    pub(crate) fn cancel_probe(&self, queue: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(Circuit::HalfOpen(probe)) = circuits.get_mut(queue) {
+            *probe = None;
+        }
+    }
+
+    // Records how one of the queue's jobs turned out, returning true if that tripped the breaker. Outcomes
+    // for jobs that were already running when the breaker tripped don't count for anything.
+    pub(crate) fn record(&self, queue: &str, success: bool) -> bool {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(queue.to_string())
+            .or_insert_with(|| Circuit::Closed(VecDeque::with_capacity(self.config.window)));
+        let trip = match circuit {
+            Circuit::Closed(outcomes) => {
+                outcomes.push_back(success);
+                if outcomes.len() > self.config.window {
+                    outcomes.pop_front();
+                }
+                let failures = outcomes.iter().filter(|ok| !**ok).count();
+                outcomes.len() >= self.config.window
+                    && failures as f64 >= self.config.failure_ratio * outcomes.len() as f64
+            }
+            Circuit::Open(_) => false,
+            Circuit::HalfOpen(_) if success => {
+                *circuit = Circuit::Closed(VecDeque::with_capacity(self.config.window));
+                false
+            }
+            Circuit::HalfOpen(_) => true,
+        };
+        if trip {
+            *circuit = Circuit::Open(Instant::now() + self.cooldown());
+        }
+        trip
+    }
+
+    fn cooldown(&self) -> std::time::Duration {
+        self.config.cooldown.to_std().unwrap_or_default()
+    }
+}
@@ -1,7 +1,10 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
 use serde::{Deserialize, Serialize};
-use sqlx::{pool::PoolOptions, PgPool};
+use sqlx::{pool::PoolOptions, postgres::PgConnectOptions, PgPool};
+use uuid::Uuid;
+
+use crate::types::RetryMode;
 
 // A pool config object, designed to be passable across API boundaries
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -12,6 +15,10 @@ pub struct PoolConfig {
     pub acquire_timeout_seconds: Option<u64>, // Default to 30
     pub max_lifetime_seconds: Option<u64>,    // Default to 300
     pub idle_timeout_seconds: Option<u64>,    // Default to 60
+    // Statements running longer than this are aborted, failing with a StatementTimeout error. Zero means no timeout.
+    // Defaults to a timeout suited to what the pool is for - see the DEFAULT_*_STATEMENT_TIMEOUT_MS constants - or,
+    // for pools connected directly with `connect`, to no timeout
+    pub statement_timeout_ms: Option<u64>,
 }
 
 impl PoolConfig {
@@ -27,18 +34,119 @@ impl PoolConfig {
                 self.acquire_timeout_seconds.unwrap_or(30),
             ));
 
-        builder.connect(&self.db_url).await
+        let mut options: PgConnectOptions = self.db_url.parse()?;
+        if let Some(timeout) = self.statement_timeout_ms {
+            // Set on every connection as it's opened, so there's no per-statement cost
+            options = options.options([("statement_timeout", timeout.to_string())]);
+        }
+
+        builder.connect_with(options).await
+    }
+
+    // Connects with `timeout_ms` as the statement timeout, unless the config sets its own
+    pub(crate) async fn connect_with_default_timeout(
+        &self,
+        timeout_ms: u64,
+    ) -> Result<PgPool, sqlx::Error> {
+        let config = Self {
+            statement_timeout_ms: Some(self.statement_timeout_ms.unwrap_or(timeout_ms)),
+            ..self.clone()
+        };
+        config.connect().await
     }
 }
 
+// The statement timeouts pools are given if their config doesn't set one. Dequeues and flushes should take
+// milliseconds, while the janitor's resets and deletes can touch a large part of the table in one statement.
+pub const DEFAULT_WORKER_STATEMENT_TIMEOUT_MS: u64 = 10_000;
+pub const DEFAULT_MANAGER_STATEMENT_TIMEOUT_MS: u64 = 30_000;
+pub const DEFAULT_JANITOR_STATEMENT_TIMEOUT_MS: u64 = 300_000;
+
 pub const DEFAULT_QUEUE_DEPTH_LIMIT: u64 = 1_000_000;
 pub const DEFAULT_SHARD_HEALTH_CHECK_INTERVAL: u64 = 10;
+pub const DEFAULT_QUERY_LIMIT: u64 = 100; // Jobs returned by a `JobQuery` that doesn't set a limit
+pub const WAIT_POLL_INTERVAL_MS: u64 = 100; // How often `QueueManager::wait_for_completion` checks on the job
+pub const DEQUEUE_POLL_INTERVAL_MS: u64 = 100; // How often `Worker::dequeue_batch_wait` tries again, by default
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ManagerConfig {
     pub shards: Vec<PoolConfig>,
     pub shard_depth_limit: Option<u64>, // Defaults to 10_000 available jobs per shard
     pub shard_depth_check_interval_seconds: Option<u64>, // Defaults to 10 seconds - checking shard capacity
+    // The band of priorities jobs created through this manager can have - anything outside it is clamped into it. Lets us
+    // reserve e.g. very low (so very urgent) priorities for system tasks. Both default to no limit
+    pub min_priority: Option<i16>,
+    pub max_priority: Option<i16>,
+    pub search_enabled: Option<bool>, // Defaults to false - job search is a table scan, so we only allow it where that's acceptable
+    // How far in the future jobs can be scheduled, and what happens to jobs scheduled beyond that. Guards against producer
+    // bugs leaving jobs in the table that will never run. Defaults to no limit, and to rejecting jobs past it
+    pub max_schedule_ahead_seconds: Option<u64>,
+    pub schedule_ahead_policy: Option<ScheduleAheadPolicy>,
+    pub acquire_retry_attempts: Option<u32>, // Defaults to 5 - see `AcquireRetry`. 1 disables retrying
+    pub acquire_retry_max_backoff_ms: Option<u64>, // Defaults to 2 seconds
+    pub acquire_retry_max_wait_ms: Option<u64>, // Defaults to 5 seconds
+}
+
+// How a `QueueManager` or `Worker` retries getting a database connection, when that fails in a way that might clear up
+// by itself - a pool timeout, a dropped or refused connection, or postgres turning connections away mid-failover -
+// so a brief outage is ridden out rather than failing whatever was being done. Only getting the connection is
+// retried, never the operation run on it, so errors from the queries themselves are returned as they were.
+#[derive(Debug, Clone, Copy)]
+pub struct AcquireRetry {
+    pub attempts: u32, // Including the first, so 1 never retries. Defaults to 5
+    pub base_backoff: chrono::Duration, // Retries back off exponentially from this. Defaults to 50ms
+    pub max_backoff: chrono::Duration,  // But never for longer than this. Defaults to 2 seconds
+    // Retrying stops once this long has gone on getting the connection, counting time spent waiting on the pool
+    // as well as backing off, so a pool that keeps timing out fails within this, rather than after its acquire
+    // timeout times `attempts`. Defaults to 5 seconds
+    pub max_wait: chrono::Duration,
+}
+
+impl Default for AcquireRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            base_backoff: chrono::Duration::milliseconds(50),
+            max_backoff: chrono::Duration::seconds(2),
+            max_wait: chrono::Duration::seconds(5),
+        }
+    }
+}
+
+impl AcquireRetry {
+    // The defaults, with whichever of the manager or worker config options for them are set
+    pub fn from_options(
+        attempts: Option<u32>,
+        max_backoff_ms: Option<u64>,
+        max_wait_ms: Option<u64>,
+    ) -> Self {
+        let defaults = Self::default();
+        let millis = |ms: u64| chrono::Duration::milliseconds(ms as i64);
+        Self {
+            attempts: attempts.unwrap_or(defaults.attempts),
+            max_backoff: max_backoff_ms.map_or(defaults.max_backoff, millis),
+            max_wait: max_wait_ms.map_or(defaults.max_wait, millis),
+            ..defaults
+        }
+    }
+
+    // The backoff before retrying after the nth failed attempt (1-indexed)
+    pub fn backoff(&self, attempt: u32) -> chrono::Duration {
+        let factor = 2i32.saturating_pow(attempt.saturating_sub(1).min(30));
+        self.base_backoff
+            .checked_mul(factor)
+            .unwrap_or(self.max_backoff)
+            .min(self.max_backoff)
+    }
+}
+
+// What a manager with a `max_schedule_ahead` does with jobs scheduled beyond it
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleAheadPolicy {
+    #[default]
+    Reject, // Fail the insert with a ScheduledTooFarAhead error
+    Clamp, // Schedule the job for the furthest time allowed instead
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
@@ -53,6 +161,14 @@ pub struct WorkerConfig {
     pub max_bytes_buffered: Option<usize>, // Defaults to 10MB
     #[serde(alias = "flushLoopIntervalMs")]
     pub flush_loop_interval_ms: Option<u64>, // Defaults to 10
+    #[serde(alias = "servedWindowSeconds")]
+    pub served_window_seconds: Option<u64>, // Defaults to 60
+    #[serde(alias = "acquireRetryAttempts")]
+    pub acquire_retry_attempts: Option<u32>, // Defaults to 5 - see `AcquireRetry`. 1 disables retrying
+    #[serde(alias = "acquireRetryMaxBackoffMs")]
+    pub acquire_retry_max_backoff_ms: Option<u64>, // Defaults to 2 seconds
+    #[serde(alias = "acquireRetryMaxWaitMs")]
+    pub acquire_retry_max_wait_ms: Option<u64>, // Defaults to 5 seconds
 }
 
 impl WorkerConfig {
@@ -75,4 +191,139 @@ impl WorkerConfig {
     pub fn max_bytes_buffered(&self) -> usize {
         self.max_bytes_buffered.unwrap_or(10_000_000)
     }
+
+    pub fn served_window(&self) -> chrono::Duration {
+        chrono::Duration::seconds(self.served_window_seconds.unwrap_or(60) as i64)
+    }
+
+    pub fn acquire_retry(&self) -> AcquireRetry {
+        AcquireRetry::from_options(
+            self.acquire_retry_attempts,
+            self.acquire_retry_max_backoff_ms,
+            self.acquire_retry_max_wait_ms,
+        )
+    }
+}
+
+// Options for `Worker::run`
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    pub concurrency: usize, // The most jobs the loop will have in flight at once. Defaults to 10
+    pub poll_interval: chrono::Duration, // How long to wait between dequeues when the queue is empty. Defaults to 100ms
+    pub heartbeat_interval: chrono::Duration, // How often running jobs are heartbeated. Defaults to 5 seconds
+    pub max_attempts: u32, // Jobs whose handler has failed this many times are failed for good. Defaults to 3
+    pub base_backoff: chrono::Duration, // Retries back off exponentially from this. Defaults to 1 second
+    pub max_backoff: chrono::Duration,  // But never for longer than this. Defaults to 5 minutes
+    pub max_panics: u32, // Jobs whose handler has panicked this many times are dead-lettered. Defaults to 3
+    pub shutdown_timeout: Option<chrono::Duration>, // How long to wait for in-flight jobs once shutting down. Defaults to None, waiting for as long as they take
+    pub slow_job_factor: Option<f64>, // Jobs taking longer than this multiple of their function's median are logged. Defaults to 5, None disables
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: 10,
+            poll_interval: chrono::Duration::milliseconds(100),
+            heartbeat_interval: chrono::Duration::seconds(5),
+            max_attempts: 3,
+            base_backoff: chrono::Duration::seconds(1),
+            max_backoff: chrono::Duration::minutes(5),
+            max_panics: 3,
+            shutdown_timeout: None,
+            slow_job_factor: Some(5.0),
+        }
+    }
+}
+
+impl RunConfig {
+    // The backoff before retrying a job that has just failed its nth attempt (1-indexed), under the default policy
+    pub fn backoff(&self, attempt: u32) -> chrono::Duration {
+        self.default_retry_policy().backoff(attempt)
+    }
+
+    // The policy jobs whose function has none of its own in `Worker::retry_policies` are retried under - backing off
+    // from base_backoff, doubling up to max_backoff, without jitter, and resuming
+    pub fn default_retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            base: self.base_backoff,
+            factor: 2.0,
+            max: self.max_backoff,
+            jitter: 0.0,
+            mode: RetryMode::Resume,
+        }
+    }
+}
+
+// How long to wait before retrying a failed job - exponential backoff, from `base` after the first attempt, growing
+// by `factor` with each attempt after that, up to `max`. With jitter, each delay is shortened by up to that fraction
+// of itself, so jobs that failed together don't all retry together. The jitter is derived from the job's id and
+// attempt, rather than being random, so `Job::next_retry_at` previews exactly the delay the worker will apply.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub base: chrono::Duration,
+    pub factor: f64,
+    pub max: chrono::Duration,
+    pub jitter: f64,     // Between 0 and 1
+    pub mode: RetryMode, // Whether retries keep the job's VM state, see `Worker::retry_with_mode`
+}
+
+impl RetryPolicy {
+    // The delay after the nth failed attempt (1-indexed), before jitter
+    pub fn backoff(&self, attempt: u32) -> chrono::Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let millis = self.base.num_milliseconds() as f64 * self.factor.powi(exponent);
+        if millis.is_nan() || millis >= self.max.num_milliseconds() as f64 {
+            return self.max;
+        }
+        chrono::Duration::milliseconds(millis.max(0.0) as i64)
+    }
+
+    // The delay after the nth failed attempt of a particular job, with its jitter applied
+    pub(crate) fn delay(&self, job_id: Uuid, attempt: u32) -> chrono::Duration {
+        let backoff = self.backoff(attempt);
+        let jitter = self.jitter.clamp(0.0, 1.0) * unit_hash(job_id, attempt);
+        chrono::Duration::milliseconds((backoff.num_milliseconds() as f64 * (1.0 - jitter)) as i64)
+    }
+}
+
+// A number in [0, 1), the same every time for a given job and attempt, but spread evenly across jobs. The low bits
+// of a v7 uuid are random, so they're mixed with the attempt by splitmix64's finalizer
+fn unit_hash(job_id: Uuid, attempt: u32) -> f64 {
+    let mut x = (job_id.as_u128() as u64) ^ (attempt as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^= x >> 31;
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Per-team limits on how fast a worker hands out jobs, applied by a `TeamRateLimiter`
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TeamRateLimits {
+    pub default: Option<RateLimit>, // The limit for teams without one of their own. Defaults to no limit
+    #[serde(default)]
+    pub teams: HashMap<i32, RateLimit>,
+}
+
+// When a `CircuitBreaker` trips, and for how long
+#[derive(Debug, Clone, Copy)]
+pub struct BreakerConfig {
+    pub failure_ratio: f64, // The breaker trips once at least this fraction of a queue's recent jobs have failed. Defaults to 0.5
+    pub window: usize, // How many of a queue's most recent jobs count towards that. It can't trip before this many have finished. Defaults to 20
+    pub cooldown: chrono::Duration, // How long a tripped breaker stops dequeuing for, before probing. Defaults to 30 seconds
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_ratio: 0.5,
+            window: 20,
+            cooldown: chrono::Duration::seconds(30),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: Option<f64>, // The most jobs that can be handed out at once after a quiet spell. Defaults to 1
 }
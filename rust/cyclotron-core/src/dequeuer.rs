@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+
+use chrono::{Duration, Utc};
+use tracing::error;
+
+use crate::{Job, JobState, QueueError, Worker};
+
+// The shortest `next_waiting` sleeps between dequeues. The next job can be due and still not be handed out - e.g.
+// if it's held back by a rate limit, or taken by another worker first - so without this it could spin
+const MIN_WAIT_MS: i64 = 50;
+
+// Dequeues jobs in batches, handing them out one at a time, to save a round trip per job for workers that
+// process jobs one by one. Jobs waiting in the buffer are locked to the worker, but aren't heartbeated, so
+// the prefetch size should be small enough they're handed out well within the janitor's stall timeout.
+// Jobs still in the buffer when this is dropped are released back to the queue.
+pub struct BufferedDequeuer<'a> {
+    worker: &'a Worker,
+    queue: String,
+    buffer: VecDeque<Job>,
+    pub prefetch_size: usize, // The most jobs to hold in the buffer at once
+    pub refill_below: usize, // The buffer is topped up once it holds fewer than this many jobs. Defaults to 1, so only once it's empty
+}
+
+impl<'a> BufferedDequeuer<'a> {
+    pub fn new(worker: &'a Worker, queue: &str, prefetch_size: usize) -> Self {
+        Self {
+            worker,
+            queue: queue.to_string(),
+            buffer: VecDeque::with_capacity(prefetch_size),
+            prefetch_size,
+            refill_below: 1,
+        }
+    }
+
+    /// Returns the next job, dequeuing more first if the buffer is running low. Returns None if
+    /// both the buffer and the queue are empty.
+    pub async fn next(&mut self) -> Result<Option<Job>, QueueError> {
+        if self.buffer.len() < self.refill_below {
+            let limit = self.prefetch_size.saturating_sub(self.buffer.len());
+            if limit > 0 {
+                let jobs = self.worker.dequeue_jobs(&self.queue, limit).await?;
+                self.buffer.extend(jobs);
+            }
+        }
+
+        Ok(self.buffer.pop_front())
+    }
+
+    /// As `next`, but if nothing in the queue is due, waits for the next job that's scheduled to
+    /// become due and dequeues that, rather than returning None straight away. Only due jobs are ever
+    /// prefetched, so the buffer never holds jobs locked before they're meant to run. Returns None if
+    /// the queue has no available jobs, or none that are due within `max_wait`.
+    pub async fn next_waiting(&mut self, max_wait: Duration) -> Result<Option<Job>, QueueError> {
+        let deadline = Utc::now() + max_wait;
+        loop {
+            if let Some(job) = self.next().await? {
+                return Ok(Some(job));
+            }
+            let Some(wait) = self.until_next_due().await? else {
+                return Ok(None);
+            };
+            let wait = wait.max(Duration::milliseconds(MIN_WAIT_MS));
+            if Utc::now() + wait > deadline {
+                return Ok(None);
+            }
+            tokio::time::sleep(wait.to_std().unwrap_or_default()).await;
+        }
+    }
+
+    /// How long until the next available job in the queue is due - zero if one already is - or None
+    /// if the queue has no available jobs. This is what `next_waiting` sleeps for.
+    pub async fn until_next_due(&self) -> Result<Option<Duration>, QueueError> {
+        let next = self.worker.next_scheduled(&self.queue).await?;
+        Ok(next.map(|at| (at - Utc::now()).max(Duration::zero())))
+    }
+
+    /// The number of jobs dequeued but not yet handed out.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+impl Drop for BufferedDequeuer<'_> {
+    fn drop(&mut self) {
+        for job in self.buffer.drain(..) {
+            // Nothing else about the job has changed, so this just unlocks it. We can't await the flush
+            // here, but the worker will do it in the background, and if it never does, the janitor
+            // will return the job to the queue once its lock goes stale.
+            if let Err(e) = self.worker.set_state(job.id, JobState::Available) {
+                error!(
+                    "Error returning prefetched job {} to the queue: {:?}",
+                    job.id, e
+                );
+                continue;
+            }
+            drop(self.worker.release_job(job.id, None));
+        }
+    }
+}
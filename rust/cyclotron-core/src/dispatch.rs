@@ -0,0 +1,69 @@
+use std::{collections::HashMap, future::Future};
+
+use futures::{future::BoxFuture, FutureExt};
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+use crate::{DispatchError, Job};
+
+type Handler<R> = Box<dyn Fn(Job) -> Result<BoxFuture<'static, R>, DispatchError> + Send + Sync>;
+
+// Routes jobs to handlers by their function id, for workers that run more than one kind of function. Each
+// handler is registered with the type its function's parameters are in, and is passed the job along with its
+// parameters decoded from JSON into that type. Jobs with no parameters decode from JSON null, so handlers for
+// functions that don't take any can use `()` or an `Option`. Every handler returns the same type, `R` - usually
+// a Result, for passing `dispatch` straight to `Worker::run`. Parameters are decoded as they're handed over, so
+// jobs have to have been decrypted first, as every dequeue does.
+pub struct JobDispatcher<R> {
+    handlers: HashMap<Uuid, Handler<R>>,
+}
+
+impl<R> Default for JobDispatcher<R> {
+    fn default() -> Self {
+        Self {
+            handlers: HashMap::new(),
+        }
+    }
+}
+
+impl<R: 'static> JobDispatcher<R> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Registers the handler for a function, replacing any that was registered for it before
+    pub fn register<T, F, Fut>(&mut self, function_id: Uuid, handler: F)
+    where
+        T: DeserializeOwned,
+        F: Fn(Job, T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+    {
+        let erased = move |job: Job| {
+            let parameters = match job.parameters.as_deref() {
+                Some(parameters) => serde_json::from_slice(parameters),
+                None => serde_json::from_value(serde_json::Value::Null),
+            }
+            .map_err(|e| DispatchError::InvalidParameters(job.id, e))?;
+            Ok(handler(job, parameters).boxed())
+        };
+        self.handlers.insert(function_id, Box::new(erased));
+    }
+
+    pub fn handles(&self, function_id: Uuid) -> bool {
+        self.handlers.contains_key(&function_id)
+    }
+
+    // Decodes the job's parameters and runs the handler registered for its function. Fails without running
+    // anything if the job has no function id, no handler is registered for it, or the parameters can't be
+    // decoded into the handler's type.
+    pub async fn dispatch(&self, job: Job) -> Result<R, DispatchError> {
+        let function_id = job
+            .function_id
+            .ok_or(DispatchError::MissingFunctionId(job.id))?;
+        let handler = self
+            .handlers
+            .get(&function_id)
+            .ok_or(DispatchError::UnknownFunction(job.id, function_id))?;
+        Ok(handler(job)?.await)
+    }
+}
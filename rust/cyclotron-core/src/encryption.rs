@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+
+use ring::{
+    aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN},
+    rand::{SecureRandom, SystemRandom},
+};
+use uuid::Uuid;
+
+use crate::{Bytes, Job, JobError, JobInit};
+
+// Encrypted payloads start with this, followed by the version of the key used, then the nonce, then the
+// ciphertext (with the GCM tag on the end). Payloads without the prefix fail to decrypt, unless the cipher was
+// told to pass them through, see `PayloadCipher::allow_plaintext`.
+const ENCRYPTED_PREFIX: &[u8] = b"cyc_enc";
+const HEADER_LEN: usize = ENCRYPTED_PREFIX.len() + 1 + NONCE_LEN;
+
+// Envelope encryption for the payload fields of a job (its parameters and blob), with AES-256-GCM. Payloads
+// are always encrypted with the current key, and can be decrypted with it or any previous key that's been
+// added, so keys can be rotated without re-encrypting jobs already in the queue. Each payload is bound to the job
+// and field it was encrypted for, so a ciphertext copied into another job, or into the job's other field, fails
+// to decrypt rather than being accepted.
+pub struct PayloadCipher {
+    current_version: u8,
+    keys: HashMap<u8, LessSafeKey>,
+    rng: SystemRandom,
+    allow_plaintext: bool,
+}
+
+// The payload fields a `PayloadCipher` encrypts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PayloadField {
+    Parameters,
+    Blob,
+}
+
+impl PayloadField {
+    pub fn column(&self) -> &'static str {
+        match self {
+            PayloadField::Parameters => "parameters",
+            PayloadField::Blob => "blob",
+        }
+    }
+
+    // The additional authenticated data for this field of a job - its id, then the field's column name
+    fn aad(&self, job_id: Uuid) -> Vec<u8> {
+        let mut aad = job_id.as_bytes().to_vec();
+        aad.extend_from_slice(self.column().as_bytes());
+        aad
+    }
+}
+
+impl PayloadCipher {
+    pub fn new(key_version: u8, key: &[u8; 32]) -> Self {
+        let mut cipher = Self {
+            current_version: key_version,
+            keys: HashMap::new(),
+            rng: SystemRandom::new(),
+            allow_plaintext: false,
+        };
+        cipher.add_decryption_key(key_version, key);
+        cipher
+    }
+
+    // Adds a key that's only used to decrypt payloads encrypted with that version, e.g. the key
+    // being rotated away from
+    pub fn add_decryption_key(&mut self, key_version: u8, key: &[u8; 32]) {
+        let key = UnboundKey::new(&AES_256_GCM, key).expect("AES-256 keys are 32 bytes");
+        self.keys.insert(key_version, LessSafeKey::new(key));
+    }
+
+    // Passes payloads that were never encrypted through decryption as they are, rather than failing them. For
+    // turning encryption on with jobs already in the queue - once they've drained, turn this back off, since
+    // while it's on, anyone who can write a payload can write one that's accepted without a key.
+    pub fn allow_plaintext(&mut self, allow: bool) {
+        self.allow_plaintext = allow;
+    }
+
+    pub fn encrypt(&self, job_id: Uuid, field: PayloadField, plaintext: &[u8]) -> Bytes {
+        let mut nonce = [0u8; NONCE_LEN];
+        // This only fails if the OS can't give us randomness, in which case there's nothing sensible we can do
+        self.rng
+            .fill(&mut nonce)
+            .expect("failed to generate an encryption nonce");
+
+        let mut out = Vec::with_capacity(HEADER_LEN + plaintext.len() + AES_256_GCM.tag_len());
+        out.extend_from_slice(ENCRYPTED_PREFIX);
+        out.push(self.current_version);
+        out.extend_from_slice(&nonce);
+
+        let mut body = plaintext.to_vec();
+        self.keys[&self.current_version]
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(field.aad(job_id)),
+                &mut body,
+            )
+            .expect("payload too large to encrypt");
+        out.extend_from_slice(&body);
+        out
+    }
+
+    // Returns None if the payload was encrypted with a key we don't have, for a different job or field, or has
+    // been tampered with - or wasn't encrypted at all, unless plaintext is allowed
+    pub fn decrypt(&self, job_id: Uuid, field: PayloadField, payload: &[u8]) -> Option<Bytes> {
+        let Some(rest) = payload.strip_prefix(ENCRYPTED_PREFIX) else {
+            return self.allow_plaintext.then(|| payload.to_vec());
+        };
+        if rest.len() < 1 + NONCE_LEN {
+            return None;
+        }
+
+        let key = self.keys.get(&rest[0])?;
+        let nonce = Nonce::try_assume_unique_for_key(&rest[1..1 + NONCE_LEN]).ok()?;
+        let mut body = rest[1 + NONCE_LEN..].to_vec();
+        let plaintext_len = key
+            .open_in_place(nonce, Aad::from(field.aad(job_id)), &mut body)
+            .ok()?
+            .len();
+        body.truncate(plaintext_len);
+        Some(body)
+    }
+
+    // Payloads are bound to their job's id, so the job is given one here if it doesn't have one yet
+    pub(crate) fn encrypt_init(&self, mut init: JobInit) -> JobInit {
+        let id = *init.id.get_or_insert_with(Uuid::now_v7);
+        init.parameters = init
+            .parameters
+            .map(|p| self.encrypt(id, PayloadField::Parameters, &p));
+        init.blob = init.blob.map(|b| self.encrypt(id, PayloadField::Blob, &b));
+        init
+    }
+
+    pub(crate) fn decrypt_job(&self, mut job: Job) -> Result<Job, JobError> {
+        job.parameters = self.decrypt_field(job.id, PayloadField::Parameters, job.parameters)?;
+        job.blob = self.decrypt_field(job.id, PayloadField::Blob, job.blob)?;
+        Ok(job)
+    }
+
+    pub(crate) fn decrypt_field(
+        &self,
+        job_id: Uuid,
+        field: PayloadField,
+        payload: Option<Bytes>,
+    ) -> Result<Option<Bytes>, JobError> {
+        payload
+            .map(|p| {
+                self.decrypt(job_id, field, &p)
+                    .ok_or(JobError::DecryptionFailed(job_id))
+            })
+            .transpose()
+    }
+}
@@ -1,17 +1,74 @@
+use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
 #[derive(Debug, thiserror::Error)]
 pub enum QueueError {
     #[error("sqlx error: {0}")]
-    SqlxError(#[from] sqlx::Error),
+    SqlxError(#[source] sqlx::Error),
+    #[error("Statement timed out, and was aborted")]
+    StatementTimeout,
     #[error("Shard over capacity {0} for this manager, insert aborted")]
     ShardFull(u64),
     #[error("Timed waiting for shard to have capacity")]
     TimedOutWaitingForCapacity,
+    #[error("Queue {0} is draining, insert aborted")]
+    QueueDraining(String),
+    #[error("Queue {0} is at its depth limit of {1} available jobs, insert aborted")]
+    QueueFull(String, u64),
+    #[error("A job with id {0} already exists")]
+    DuplicateJobId(Uuid),
+    #[error("Cannot dequeue with a nil lock id")]
+    NilLockId,
+    #[error("Lock id {0} is already held by another job")]
+    LockIdInUse(Uuid),
+    #[error("Job scheduled for {0}, but jobs can't be scheduled past {1}")]
+    ScheduledTooFarAhead(DateTime<Utc>, DateTime<Utc>),
+    #[error("Queue manager is closed")]
+    ManagerClosed,
+    #[error("Timed out waiting for job {0} to finish")]
+    WaitTimedOut(Uuid),
+    #[error("Job search is not enabled for this manager")]
+    SearchNotEnabled,
+    #[error("Database schema is version {0}, but this code expects version {1} - are migrations out of date?")]
+    IncompatibleSchema(i32, i32),
+    #[error("Object store error: {0}")]
+    ObjectStoreError(String),
     #[error(transparent)]
     JobError(#[from] JobError),
 }
 
+// Statement timeouts are reported as query_canceled. Nothing here cancels statements any other way, so every
+// cancellation is treated as a timeout - matching on the message would break under a localised lc_messages
+impl From<sqlx::Error> for QueueError {
+    fn from(error: sqlx::Error) -> Self {
+        match error.as_database_error() {
+            Some(db) if db.code().as_deref() == Some("57014") => QueueError::StatementTimeout,
+            _ => QueueError::SqlxError(error),
+        }
+    }
+}
+
+// Returned by all-or-nothing batch inserts. When a batch is rejected, none of it was inserted.
+#[derive(Debug, thiserror::Error)]
+#[error("Batch rolled back, no jobs were inserted: {error}")]
+pub struct BatchRejected {
+    pub index: Option<usize>, // The job in the batch that caused the rollback, if the failure was specific to one
+    #[source]
+    pub error: QueueError,
+}
+
+impl From<QueueError> for BatchRejected {
+    fn from(error: QueueError) -> Self {
+        Self { index: None, error }
+    }
+}
+
+impl From<sqlx::Error> for BatchRejected {
+    fn from(error: sqlx::Error) -> Self {
+        QueueError::from(error).into()
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum JobError {
     #[error("Unknown job id: {0}")]
@@ -24,4 +81,40 @@ pub enum JobError {
     DeadlineExceeded(Uuid),
     #[error("Update dropped before being flushed.")]
     UpdateDropped,
+    #[error("Cannot record a failure for job {0}, its metadata is not a JSON object")]
+    InvalidMetadata(Uuid),
+    #[error("Cannot merge a metadata patch into job {0}, the patch is not a JSON object")]
+    InvalidMetadataPatch(Uuid),
+    #[error("Job {0} has no function id")]
+    MissingFunctionId(Uuid),
+    #[error("Job {0} is corrupt: {1}")]
+    InvariantViolated(Uuid, &'static str),
+    #[error("Failed to decrypt the payload of job {0}")]
+    DecryptionFailed(Uuid),
+}
+
+// Returned by `JobDispatcher::dispatch` when a job can't be handed to a handler
+#[derive(Debug, thiserror::Error)]
+pub enum DispatchError {
+    #[error("Job {0} has no function id")]
+    MissingFunctionId(Uuid),
+    #[error("No handler is registered for function {1}, the function of job {0}")]
+    UnknownFunction(Uuid, Uuid),
+    #[error("Failed to decode the parameters of job {0}: {1}")]
+    InvalidParameters(Uuid, #[source] serde_json::Error),
+}
+
+// Returned, as the source of a column decode error, when a job's state isn't one this code knows about
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+#[error("Unknown job state {0:?}, the database may have a newer schema than this code expects")]
+pub struct UnknownJobState(pub String);
+
+#[derive(Debug, thiserror::Error)]
+pub enum CodecError {
+    #[error("Not a compact encoded job")]
+    MissingMagic,
+    #[error("Unsupported compact encoding version {0}")]
+    UnsupportedVersion(u8),
+    #[error("Failed to encode or decode job: {0}")]
+    Bincode(#[from] bincode::Error),
 }
@@ -0,0 +1,36 @@
+use chrono::Duration;
+use uuid::Uuid;
+
+use crate::{Job, QueueError, QueueManager};
+
+// A job created with `QueueManager::enqueue`, tying together what a caller waiting on its result needs
+pub struct JobHandle<'a> {
+    manager: &'a QueueManager,
+    job: Job,
+}
+
+impl<'a> JobHandle<'a> {
+    pub(crate) fn new(manager: &'a QueueManager, job: Job) -> Self {
+        Self { manager, job }
+    }
+
+    pub fn id(&self) -> Uuid {
+        self.job.id
+    }
+
+    // The job as it was created
+    pub fn job(&self) -> &Job {
+        &self.job
+    }
+
+    /// Wait for the job to complete or fail, returning it in its final state. See
+    /// `QueueManager::wait_for_completion`.
+    pub async fn await_completion(&self, timeout: Duration) -> Result<Job, QueueError> {
+        self.manager.wait_for_completion(self.job.id, timeout).await
+    }
+
+    /// Cancel the job, if it hasn't started running yet. See `QueueManager::cancel_job`.
+    pub async fn cancel(&self) -> Result<bool, QueueError> {
+        self.manager.cancel_job(self.job.id).await
+    }
+}
@@ -1,13 +1,18 @@
 use crate::DEAD_LETTER_QUEUE;
-use chrono::Duration;
+use chrono::{DateTime, Duration, Utc};
 use sqlx::PgPool;
 
 use crate::{
+    config::DEFAULT_JANITOR_STATEMENT_TIMEOUT_MS,
     ops::{
-        janitor::{delete_completed_and_failed_jobs, detect_poison_pills, reset_stalled_jobs},
-        meta::{count_total_waiting_jobs, dead_letter, run_migrations},
+        janitor::{
+            allow_transition_pruning, delete_completed_and_failed_jobs, detect_poison_pills,
+            preview_completed_and_failed_jobs, prune_transitions_batch, purge_completed_jobs_batch,
+            reset_stalled_jobs, try_lock_delete_pass,
+        },
+        meta::{count_total_waiting_jobs, dead_letter, run_migrations, time_in_queue},
     },
-    types::AggregatedDelete,
+    types::{AggregatedDelete, QueueLatency},
     PoolConfig, QueueError,
 };
 
@@ -18,7 +23,9 @@ pub struct Janitor {
 
 impl Janitor {
     pub async fn new(config: PoolConfig) -> Result<Self, QueueError> {
-        let pool = config.connect().await?;
+        let pool = config
+            .connect_with_default_timeout(DEFAULT_JANITOR_STATEMENT_TIMEOUT_MS)
+            .await?;
         Ok(Self { pool })
     }
 
@@ -36,6 +43,69 @@ impl Janitor {
         delete_completed_and_failed_jobs(&self.pool).await
     }
 
+    // Returns what `delete_completed_and_failed_jobs` would delete, without deleting anything, for seeing the
+    // impact of a retention change before making it. It's a read-only select, sharing its predicate and
+    // aggregation with the delete, so it doesn't lock the jobs it reports, or hold up a delete pass.
+    pub async fn preview_delete(&self) -> Result<Vec<AggregatedDelete>, QueueError> {
+        preview_completed_and_failed_jobs(&self.pool).await
+    }
+
+    // As above, but only if no other janitor is currently running a delete pass against this database - if one
+    // is, this returns None without deleting anything. This avoids many janitors contending on the same rows.
+    pub async fn try_delete_completed_and_failed_jobs(
+        &self,
+    ) -> Result<Option<Vec<AggregatedDelete>>, QueueError> {
+        let mut txn = self.pool.begin().await?;
+        if !try_lock_delete_pass(&mut *txn).await? {
+            return Ok(None);
+        }
+        let deleted = delete_completed_and_failed_jobs(&mut *txn).await?;
+        txn.commit().await?;
+        Ok(Some(deleted))
+    }
+
+    // Deletes completed and failed jobs that last transitioned more than `age` ago, in batches of `batch_size`,
+    // returning the total deleted. For deployments that don't consume the delete aggregates, this is a cheaper
+    // way to reclaim space than `delete_completed_and_failed_jobs`, and doesn't hold one big delete open.
+    pub async fn purge_completed_older_than(
+        &self,
+        age: Duration,
+        batch_size: u64,
+    ) -> Result<u64, QueueError> {
+        // Fixed up front, so jobs finishing while we purge don't keep us going
+        let older_than = Utc::now() - age;
+        let mut total = 0;
+        loop {
+            let deleted = purge_completed_jobs_batch(&self.pool, older_than, batch_size).await?;
+            total += deleted;
+            if deleted == 0 || deleted < batch_size {
+                return Ok(total);
+            }
+        }
+    }
+
+    // Deletes recorded transitions (see `QueueManager::set_transition_audit`) from more than `age` ago, in batches of
+    // `batch_size`, returning the total deleted. This is the only way transitions are ever removed - each batch runs
+    // in its own transaction, which is let past the guard keeping the record append-only.
+    pub async fn prune_transitions_older_than(
+        &self,
+        age: Duration,
+        batch_size: u64,
+    ) -> Result<u64, QueueError> {
+        let older_than = Utc::now() - age;
+        let mut total = 0;
+        loop {
+            let mut txn = self.pool.begin().await?;
+            allow_transition_pruning(&mut *txn).await?;
+            let deleted = prune_transitions_batch(&mut *txn, older_than, batch_size).await?;
+            txn.commit().await?;
+            total += deleted;
+            if deleted == 0 || deleted < batch_size {
+                return Ok(total);
+            }
+        }
+    }
+
     pub async fn reset_stalled_jobs(&self, timeout: Duration) -> Result<u64, QueueError> {
         reset_stalled_jobs(&self.pool, timeout).await
     }
@@ -49,7 +119,7 @@ impl Janitor {
 
         for job in &poison {
             dead_letter(
-                &self.pool,
+                &mut *self.pool.acquire().await?,
                 *job,
                 &format!("poison pill detected based on a timeout of {}", timeout),
             )
@@ -63,6 +133,15 @@ impl Janitor {
         count_total_waiting_jobs(&self.pool).await
     }
 
+    // p50/p95 of the time jobs created in [from, to) spent waiting to be run for the first time, per function
+    pub async fn time_in_queue(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Result<Vec<QueueLatency>, QueueError> {
+        time_in_queue(&self.pool, from, to).await
+    }
+
     pub async fn count_dlq_depth(&self) -> Result<u64, QueueError> {
         let result = sqlx::query_scalar!(
             "SELECT COUNT(*) FROM cyclotron_jobs WHERE queue_name = $1",
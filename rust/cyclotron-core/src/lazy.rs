@@ -0,0 +1,70 @@
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+use crate::{Bytes, Job, JobHeader, QueueError, Worker};
+
+// A job dequeued with `Worker::dequeue_lazy`. Everything but the payloads is fetched up front, as with any
+// dequeue, and each payload is fetched the first time it's asked for.
+pub struct LazyJob<'a> {
+    pub header: JobHeader,
+    pub vm_state: LazyPayload<'a>,
+    pub metadata: LazyPayload<'a>,
+    pub parameters: LazyPayload<'a>,
+    pub blob: LazyPayload<'a>,
+}
+
+// One of a lazily dequeued job's payload fields. Fetching it checks the job is still held under the lock it was
+// dequeued with, failing with InvalidLock if it isn't. Once fetched, it's kept, so later gets don't go back to
+// the database - and so don't see changes made since, including ones this worker has staged.
+pub struct LazyPayload<'a> {
+    worker: &'a Worker,
+    job_id: Uuid,
+    lock_id: Uuid,
+    column: &'static str,
+    value: OnceCell<Option<Bytes>>,
+}
+
+impl<'a> LazyJob<'a> {
+    // The job has to have been dequeued with none of its payloads, which are dropped here regardless
+    pub(crate) fn new(worker: &'a Worker, job: Job) -> Self {
+        let (header, _) = job.into_parts();
+        let lock_id = header
+            .lock_id
+            .expect("dequeued jobs are always returned with their lock");
+        let job_id = header.id;
+        let payload = |column| LazyPayload {
+            worker,
+            job_id,
+            lock_id,
+            column,
+            value: OnceCell::new(),
+        };
+        Self {
+            vm_state: payload("vm_state"),
+            metadata: payload("metadata"),
+            parameters: payload("parameters"),
+            blob: payload("blob"),
+            header,
+        }
+    }
+}
+
+impl LazyPayload<'_> {
+    /// The payload, fetching it if it hasn't been already. Encrypted payloads are decrypted, as with
+    /// any dequeue.
+    pub async fn get(&self) -> Result<Option<&[u8]>, QueueError> {
+        let value = self
+            .value
+            .get_or_try_init(|| {
+                self.worker
+                    .fetch_payload(self.job_id, self.lock_id, self.column)
+            })
+            .await?;
+        Ok(value.as_deref())
+    }
+
+    /// Whether the payload has been fetched yet.
+    pub fn is_loaded(&self) -> bool {
+        self.value.initialized()
+    }
+}
@@ -1,3 +1,4 @@
+mod acquire;
 mod ops;
 
 // We do this pattern (privately use a module, then re-export parts of it) so we can refactor/rename or generally futz around with the internals without breaking the public API
@@ -6,27 +7,122 @@ mod ops;
 mod types;
 pub use types::AggregatedDelete;
 pub use types::Bytes;
+pub use types::DeleteSet;
+pub use types::EnqueueNotification;
+pub use types::Enqueued;
+pub use types::Filter;
 pub use types::Job;
+pub use types::JobHeader;
 pub use types::JobInit;
+pub use types::JobPayloads;
+pub use types::JobProjection;
+pub use types::JobQuery;
 pub use types::JobState;
+pub use types::JobStateCode;
+pub use types::JobSummary;
+pub use types::JobTransition;
 pub use types::JobUpdate;
+pub use types::OnConflict;
+pub use types::QueueConfig;
+pub use types::QueueConfigFilter;
+pub use types::QueueInfo;
+pub use types::QueueLatency;
+pub use types::RetryEntry;
+pub use types::RetryMode;
+pub use types::StateCount;
+pub use types::StateMetadata;
+pub use types::StateSeverity;
+pub use types::UniqueBatch;
+pub use types::ENQUEUE_CHANNEL;
+pub use types::PANIC_COUNT_KEY;
+pub use types::RETRY_HISTORY_KEY;
 
 // Errors
 mod error;
 // Errors about some job operation - locks being lost, invalid states, flush deadlines exceeded etc
+pub use error::BatchRejected;
+// Errors decoding compact encoded jobs
+pub use error::CodecError;
+// Jobs that couldn't be routed to a handler
+pub use error::DispatchError;
 pub use error::JobError;
 // Errors about the queue itself - full shards, timeouts, postgres/network errors
 pub use error::QueueError;
+// A job state the code doesn't know about, e.g. one added by a newer migration
+pub use error::UnknownJobState;
 
 // Manager
 mod manager;
 pub use manager::QueueManager;
+// A created job, for waiting on or cancelling
+mod handle;
+pub use handle::JobHandle;
+
+// The core queue operations as a trait, implemented by the manager and by an in-memory store for tests
+mod store;
+pub use store::JobStore;
+#[cfg(feature = "test-util")]
+pub use store::MemoryStore;
 
 // Worker
 mod worker;
 // A handle to a released job update, that can be awaited to block waiting for the flush to complete
 pub use worker::FlushHandle;
 pub use worker::Worker;
+// A snapshot of a worker's in-memory state, for debugging
+pub use worker::WorkerSnapshot;
+// Worker::run, a dequeue/process/release loop for workers that don't need anything fancier
+mod runner;
+// What a run did, returned once it's shut down
+pub use runner::ShutdownReport;
+// Passed to `Worker::run` handlers, and cancelled when they should stop early
+pub use tokio_util::sync::CancellationToken;
+// Routes jobs to typed handlers by function id
+mod dispatch;
+pub use dispatch::JobDispatcher;
+// Per-team limits on how fast jobs are dequeued
+mod ratelimit;
+pub use ratelimit::TeamRateLimiter;
+// Per-queue circuit breakers, stopping dequeues from queues whose jobs keep failing
+mod breaker;
+pub use breaker::BreakerState;
+pub use breaker::CircuitBreaker;
+// Jobs whose payloads are only fetched when they're needed
+mod lazy;
+pub use lazy::LazyJob;
+pub use lazy::LazyPayload;
+// Hands out jobs one at a time from batched dequeues
+mod dequeuer;
+pub use dequeuer::BufferedDequeuer;
+// How long handlers take to process each function's jobs, reported in worker snapshots
+mod timings;
+pub use timings::ProcessingHistogram;
+pub use timings::PROCESSING_TIME_BUCKETS_MS;
+
+// Encryption of job payloads, for jobs carrying data that has to be encrypted at rest
+mod encryption;
+pub use encryption::PayloadCipher;
+pub use encryption::PayloadField;
+
+// Moving large vm_states out of postgres, into an object store
+mod offload;
+pub use offload::ObjectStore;
+#[cfg(feature = "s3")]
+pub use offload::S3ObjectStore;
+pub use offload::VmStateOffload;
+
+// Compact binary encoding of jobs, for service-to-service transport. JSON is still used everywhere else
+mod codec;
+
+// Aligned plain text tables of jobs, for CLI tools
+#[cfg(feature = "table")]
+mod table;
+#[cfg(feature = "table")]
+pub use table::format_jobs_table;
+
+// Serde helpers for job timestamps, written as RFC3339 or, inside an `EpochMillis`, as epoch millis
+mod timestamps;
+pub use timestamps::EpochMillis;
 
 // Janitor
 mod janitor;
@@ -34,9 +130,22 @@ pub use janitor::Janitor;
 
 // Config
 mod config;
+pub use config::AcquireRetry;
+pub use config::BreakerConfig;
 pub use config::ManagerConfig;
 pub use config::PoolConfig;
+pub use config::RateLimit;
+pub use config::RetryPolicy;
+pub use config::RunConfig;
+pub use config::ScheduleAheadPolicy;
+pub use config::TeamRateLimits;
 pub use config::WorkerConfig;
+pub use config::DEFAULT_JANITOR_STATEMENT_TIMEOUT_MS;
+pub use config::DEFAULT_MANAGER_STATEMENT_TIMEOUT_MS;
+pub use config::DEFAULT_QUERY_LIMIT;
+pub use config::DEFAULT_WORKER_STATEMENT_TIMEOUT_MS;
+pub use config::DEQUEUE_POLL_INTERVAL_MS;
+pub use config::WAIT_POLL_INTERVAL_MS;
 
 // The shard id is a fixed value that is set by the janitor when it starts up.
 // Workers may use this value when reporting metrics. The `Worker` struct provides
@@ -47,7 +156,12 @@ pub const SHARD_ID_KEY: &str = "shard_id";
 // This isn't pub because, ideally, nothing using the core will ever need to know it.
 const DEAD_LETTER_QUEUE: &str = "_cyclotron_dead_letter";
 
+// Checking the database schema is one this code understands, e.g. on startup
+pub use ops::meta::check_compatibility;
+pub use ops::meta::SCHEMA_VERSION;
+
 #[doc(hidden)]
 pub mod test_support {
     pub use crate::manager::Shard;
+    pub use crate::ops::janitor::DELETE_PASS_LOCK_KEY;
 }
@@ -1,17 +1,38 @@
-use std::sync::atomic::AtomicUsize;
+use std::{
+    collections::{HashMap, HashSet},
+    ops::RangeInclusive,
+    sync::atomic::{AtomicBool, AtomicUsize},
+};
 
 use chrono::{DateTime, Duration, Utc};
-use sqlx::PgPool;
-use tokio::sync::RwLock;
+use sqlx::{pool::PoolConnection, PgPool, Postgres, Transaction};
+use tokio::sync::{RwLock, RwLockReadGuard};
+use tracing::warn;
 use uuid::Uuid;
 
 use crate::{
-    config::{DEFAULT_QUEUE_DEPTH_LIMIT, DEFAULT_SHARD_HEALTH_CHECK_INTERVAL},
+    acquire,
+    config::{
+        AcquireRetry, DEFAULT_MANAGER_STATEMENT_TIMEOUT_MS, DEFAULT_QUEUE_DEPTH_LIMIT,
+        DEFAULT_SHARD_HEALTH_CHECK_INTERVAL, WAIT_POLL_INTERVAL_MS,
+    },
     ops::{
-        manager::{bulk_create_jobs, create_job},
+        janitor::{delete_completed_and_failed_jobs, reset_stalled_jobs},
+        manager::{
+            block_job_on, bulk_create_jobs, cancel_job, count_available_jobs, create_job,
+            create_job_with_dedup_hash, drain_queue, find_duplicate, first_draining_queue,
+            get_jobs, get_queue_config, jitter_schedule, job_transitions, list_queues,
+            lock_dedup_hash, lock_job_state, lock_queue_for_bounded_insert, notify_enqueued,
+            query_job_summaries, query_jobs, queue_is_draining, raise_prerequisite_priority,
+            remap_queue, set_queue_config, set_transition_audit, state_counts, upsert_job,
+        },
         meta::count_total_waiting_jobs,
+        worker::{dequeue_jobs, flush_job, set_heartbeat},
     },
-    JobInit, ManagerConfig, QueueError,
+    BatchRejected, Bytes, Enqueued, Job, JobError, JobHandle, JobInit, JobQuery, JobState,
+    JobStore, JobSummary, JobTransition, JobUpdate, ManagerConfig, OnConflict, PayloadCipher,
+    PayloadField, QueueConfig, QueueError, QueueInfo, ScheduleAheadPolicy, StateCount, UniqueBatch,
+    VmStateOffload,
 };
 
 pub struct Shard {
@@ -19,11 +40,20 @@ pub struct Shard {
     pub last_healthy: RwLock<DateTime<Utc>>,
     pub check_interval: Duration,
     pub depth_limit: u64,
+    pub acquire_retry: AcquireRetry, // Defaults to `AcquireRetry::default()`
 }
 
 pub struct QueueManager {
     shards: RwLock<Vec<Shard>>,
     next_shard: AtomicUsize,
+    closed: AtomicBool,
+    pub priority_band: RangeInclusive<i16>, // Job priorities outside this band are clamped into it on creation
+    pub search_enabled: bool, // Whether `query_jobs` accepts queries with `search` set, which are unindexed scans
+    pub payload_cipher: Option<PayloadCipher>, // If set, job parameters and blobs are encrypted before they're written
+    pub max_schedule_ahead: Option<Duration>, // If set, jobs scheduled further ahead than this are handled according to the policy below
+    pub schedule_ahead_policy: ScheduleAheadPolicy,
+    // If set, jobs created with a vm_state over its threshold have it uploaded to the object store, as workers do
+    pub vm_state_offload: Option<VmStateOffload>,
 }
 
 impl QueueManager {
@@ -37,14 +67,34 @@ impl QueueManager {
                 .shard_depth_check_interval_seconds
                 .unwrap_or(DEFAULT_SHARD_HEALTH_CHECK_INTERVAL) as i64,
         );
+        let acquire_retry = AcquireRetry::from_options(
+            config.acquire_retry_attempts,
+            config.acquire_retry_max_backoff_ms,
+            config.acquire_retry_max_wait_ms,
+        );
         for shard in config.shards {
-            let pool = shard.connect().await.unwrap();
-            let shard = Shard::new(pool, depth_limit, check_interval);
+            let pool = shard
+                .connect_with_default_timeout(DEFAULT_MANAGER_STATEMENT_TIMEOUT_MS)
+                .await
+                .unwrap();
+            let mut shard = Shard::new(pool, depth_limit, check_interval);
+            shard.acquire_retry = acquire_retry;
             shards.push(shard);
         }
+        let priority_band =
+            config.min_priority.unwrap_or(i16::MIN)..=config.max_priority.unwrap_or(i16::MAX);
         Ok(Self {
             shards: RwLock::new(shards),
             next_shard: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            priority_band,
+            search_enabled: config.search_enabled.unwrap_or(false),
+            payload_cipher: None,
+            max_schedule_ahead: config
+                .max_schedule_ahead_seconds
+                .map(|s| Duration::seconds(s as i64)),
+            schedule_ahead_policy: config.schedule_ahead_policy.unwrap_or_default(),
+            vm_state_offload: None,
         })
     }
 
@@ -57,52 +107,797 @@ impl QueueManager {
                 Duration::seconds(DEFAULT_SHARD_HEALTH_CHECK_INTERVAL as i64),
             )]),
             next_shard: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            priority_band: i16::MIN..=i16::MAX,
+            search_enabled: false,
+            payload_cipher: None,
+            max_schedule_ahead: None,
+            schedule_ahead_policy: ScheduleAheadPolicy::Reject,
+            vm_state_offload: None,
         }
     }
 
-    pub async fn create_job(&self, init: JobInit) -> Result<Uuid, QueueError> {
+    pub async fn create_job(&self, init: JobInit) -> Result<Job, QueueError> {
         // TODO - here is where a lot of shard health and failover logic will go, eventually.
         let next = self
             .next_shard
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        let shards = self.shards.read().await;
+        let shards = self.shards().await?;
+        let shard = &shards[next % shards.len()];
+        let (init, vm_state) = self.prepare(init).await?;
+        let job = shard.create_job(init).await?;
+        self.created(job, vm_state)
+    }
+
+    /// As `create_job`, but also notifies listeners on ENQUEUE_CHANNEL of the new job, with an
+    /// `EnqueueNotification`. The notification is sent in the same transaction as the insert, so it's
+    /// delivered if and only if the job is committed, and never before the job is visible.
+    pub async fn enqueue_and_notify(&self, init: JobInit) -> Result<Job, QueueError> {
+        let next = self
+            .next_shard
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let shards = self.shards().await?;
+        let shard = &shards[next % shards.len()];
+        let (init, vm_state) = self.prepare(init).await?;
+        let job = shard.create_job_and_notify(init).await?;
+        self.created(job, vm_state)
+    }
+
+    /// As `create_job`, but the job's priority and schedule come from its queue's defaults (see
+    /// `set_queue_config`) where `priority` and `scheduled` are None. For each, the explicit value wins if
+    /// there is one, then the queue's default, and the init's own field is only used when neither is set.
+    /// The init's fields always have a value, so they can't say whether they were chosen or just left
+    /// at their defaults - pass the ones that were chosen explicitly.
+    pub async fn create_job_with_queue_defaults(
+        &self,
+        mut init: JobInit,
+        priority: Option<i16>,
+        scheduled: Option<DateTime<Utc>>,
+    ) -> Result<Job, QueueError> {
+        let next = self
+            .next_shard
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let shards = self.shards().await?;
+        let shard = &shards[next % shards.len()];
+
+        let defaults = if priority.is_none() || scheduled.is_none() {
+            get_queue_config(&mut *shard.acquire().await?, &init.queue_name).await?
+        } else {
+            QueueConfig::default()
+        };
+        init.priority = priority
+            .or(defaults.default_priority)
+            .unwrap_or(init.priority);
+        init.scheduled = scheduled
+            .or_else(|| defaults.default_delay.map(|delay| Utc::now() + delay))
+            .unwrap_or(init.scheduled);
+
+        let (init, vm_state) = self.prepare(init).await?;
+        let job = shard.create_job(init).await?;
+        self.created(job, vm_state)
+    }
+
+    /// As `create_job`, but if `max_queue_depth` is set, the job is rejected with a QueueFull error
+    /// if its queue already has that many available jobs in it.
+    pub async fn create_job_with_max_depth(
+        &self,
+        init: JobInit,
+        max_queue_depth: Option<u64>,
+    ) -> Result<Job, QueueError> {
+        let next = self
+            .next_shard
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let shards = self.shards().await?;
         let shard = &shards[next % shards.len()];
-        shard.create_job(init).await
+        let (init, vm_state) = self.prepare(init).await?;
+        let job = shard
+            .create_job_with_max_depth(init, max_queue_depth)
+            .await?;
+        self.created(job, vm_state)
+    }
+
+    /// As `create_job`, but if an identical job - one with the same team, queue and parameters - was created
+    /// through here within the last `window`, nothing is inserted, and the existing job's id is returned
+    /// instead. Jobs are placed on a shard picked by that identity, rather than round-robin, so duplicates are
+    /// caught whichever shard they'd otherwise have landed on.
+    pub async fn create_job_deduplicated(
+        &self,
+        init: JobInit,
+        window: Duration,
+    ) -> Result<Enqueued, QueueError> {
+        let dedup_hash = dedup_hash(&init);
+        let since = Utc::now() - window;
+        let shards = self.shards().await?;
+        let mut index = [0; 8];
+        index.copy_from_slice(&dedup_hash[..8]);
+        let shard = &shards[(u64::from_be_bytes(index) % shards.len() as u64) as usize];
+        // Checked before preparing the job too, so a duplicate's vm_state isn't uploaded only to be thrown away.
+        // This check doesn't lock anything, so the shard checks again before inserting.
+        if let Some(id) = find_duplicate(&mut *shard.acquire().await?, &dedup_hash, since).await? {
+            return Ok(Enqueued::Deduplicated(id));
+        }
+        let (init, vm_state) = self.prepare(init).await?;
+        match shard
+            .create_job_deduplicated(init, &dedup_hash, since)
+            .await?
+        {
+            Enqueued::Created(job) => Ok(Enqueued::Created(self.created(job, vm_state)?)),
+            deduplicated => Ok(deduplicated),
+        }
+    }
+
+    /// Creates a job that won't run until the job with id `dependency_id` has completed. Until then, the job
+    /// is paused. If the dependency has already completed, the job is available immediately. Errors with
+    /// UnknownJobId if there's no job with that id, e.g. because it's completed and been cleaned up already.
+    /// If the job is higher priority than its dependency, the dependency - and anything the dependency is
+    /// itself waiting on, whatever queue it's in - is raised to the job's priority, so the job isn't left
+    /// waiting behind lower priority work. Raised priorities stay raised once the job has run.
+    pub async fn enqueue_after(
+        &self,
+        init: JobInit,
+        dependency_id: Uuid,
+    ) -> Result<Job, QueueError> {
+        let (init, vm_state) = self.prepare(init).await?;
+        let shards = self.shards().await?;
+        // The job has to go on the same shard as its dependency, and we don't know which one that is
+        for shard in shards.iter() {
+            match shard.create_job_after(init.clone(), dependency_id).await {
+                Err(QueueError::JobError(JobError::UnknownJobId(_))) => continue,
+                res => return self.created(res?, vm_state),
+            }
+        }
+        Err(JobError::UnknownJobId(dependency_id).into())
+    }
+
+    /// Creates a job that may have a client supplied id, with `on_conflict` deciding what happens if that id
+    /// is already taken. Returns the job as inserted (or updated), or None if nothing was written because of
+    /// the conflict. Ids are only unique per shard, so jobs with client ids are placed on a shard picked by
+    /// their id, rather than round-robin - for conflicts to be caught, create them all through here.
+    pub async fn create_job_on_conflict(
+        &self,
+        init: JobInit,
+        on_conflict: OnConflict,
+    ) -> Result<Option<Job>, QueueError> {
+        let shards = self.shards().await?;
+        let index = match init.id {
+            Some(id) => (id.as_u128() % shards.len() as u128) as usize,
+            None => self
+                .next_shard
+                .fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        };
+        let shard = &shards[index % shards.len()];
+        let (init, vm_state) = self.prepare(init).await?;
+        let job = match on_conflict {
+            OnConflict::Error => Some(shard.create_job(init).await?),
+            OnConflict::Ignore => shard.upsert_job(init, false).await?,
+            OnConflict::Update => shard.upsert_job(init, true).await?,
+        };
+        job.map(|job| self.created(job, vm_state)).transpose()
     }
 
     pub async fn create_job_blocking(
         &self,
         init: JobInit,
         timeout: Option<Duration>,
-    ) -> Result<Uuid, QueueError> {
+    ) -> Result<Job, QueueError> {
         let next = self
             .next_shard
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        let shards = self.shards.read().await;
+        let shards = self.shards().await?;
         let shard = &shards[next % shards.len()];
-        shard.create_job_blocking(init, timeout).await
+        let (init, vm_state) = self.prepare(init).await?;
+        let job = shard.create_job_blocking(init, timeout).await?;
+        self.created(job, vm_state)
     }
 
-    pub async fn bulk_create_jobs(&self, inits: Vec<JobInit>) -> Result<Vec<Uuid>, QueueError> {
+    pub async fn bulk_create_jobs(&self, inits: Vec<JobInit>) -> Result<Vec<Job>, QueueError> {
+        let (inits, vm_states) = self.prepare_all(inits).await?;
         let next = self
             .next_shard
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        let shards = self.shards.read().await;
-        shards[next % shards.len()].bulk_create_jobs(&inits).await
+        let shards = self.shards().await?;
+        let jobs = shards[next % shards.len()].bulk_create_jobs(&inits).await?;
+        self.created_all(jobs, vm_states)
+    }
+
+    /// As `bulk_create_jobs`, but first collapses jobs in the batch that are identical - same team, queue and
+    /// parameters, the identity `create_job_deduplicated` uses - down to the first of each, so only unique
+    /// jobs are inserted. Only the batch itself is checked: unlike `create_job_deduplicated`, jobs already in
+    /// the queue aren't looked at, so this costs nothing extra in the database.
+    pub async fn bulk_create_unique_jobs(
+        &self,
+        inits: Vec<JobInit>,
+    ) -> Result<UniqueBatch, QueueError> {
+        let total = inits.len();
+        let mut seen = HashSet::with_capacity(total);
+        let unique: Vec<JobInit> = inits
+            .into_iter()
+            .filter(|init| seen.insert(dedup_hash(init)))
+            .collect();
+        let collapsed = total - unique.len();
+        let jobs = self.bulk_create_jobs(unique).await?;
+        Ok(UniqueBatch { jobs, collapsed })
     }
 
     pub async fn bulk_create_jobs_blocking(
         &self,
         inits: Vec<JobInit>,
         timeout: Option<Duration>,
-    ) -> Result<Vec<Uuid>, QueueError> {
+    ) -> Result<Vec<Job>, QueueError> {
+        let (inits, vm_states) = self.prepare_all(inits).await?;
         let next = self
             .next_shard
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-        let shards = self.shards.read().await;
-        shards[next % shards.len()]
+        let shards = self.shards().await?;
+        let jobs = shards[next % shards.len()]
             .bulk_create_jobs_blocking(&inits, timeout)
-            .await
+            .await?;
+        self.created_all(jobs, vm_states)
+    }
+
+    /// Inserts every job in the batch, or none of them. Where `bulk_create_jobs` is a single insert,
+    /// this inserts each job in turn inside one transaction, so any one job failing to insert rolls
+    /// back the whole batch, and the error says which job it was.
+    pub async fn bulk_create_jobs_transactional(
+        &self,
+        inits: Vec<JobInit>,
+    ) -> Result<Vec<Job>, BatchRejected> {
+        let mut prepared = Vec::with_capacity(inits.len());
+        let mut plaintexts = Vec::with_capacity(inits.len());
+        for (index, init) in inits.into_iter().enumerate() {
+            // Encryption replaces the payloads, so only then are the plaintext ones kept to hand back
+            let payloads = self
+                .payload_cipher
+                .is_some()
+                .then(|| (init.parameters.clone(), init.blob.clone()));
+            let (init, vm_state) = self.prepare(init).await.map_err(|error| BatchRejected {
+                index: Some(index),
+                error,
+            })?;
+            prepared.push(init);
+            plaintexts.push((payloads, vm_state));
+        }
+        let next = self
+            .next_shard
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        let shards = self.shards().await?;
+        let mut jobs = shards[next % shards.len()]
+            .bulk_create_jobs_transactional(&prepared)
+            .await?;
+        // The batch is committed at this point, so rather than decrypting (and being able to fail after
+        // the fact), we hand back the plaintext payloads and vm_states the caller gave us
+        for (job, (payloads, vm_state)) in jobs.iter_mut().zip(plaintexts) {
+            if let Some((parameters, blob)) = payloads {
+                job.parameters = parameters;
+                job.blob = blob;
+            }
+            if vm_state.is_some() {
+                job.vm_state = vm_state;
+            }
+        }
+        Ok(jobs)
+    }
+
+    /// Closes the manager's connection pools, after waiting for any operations already underway to finish.
+    /// From then on, every operation fails with a ManagerClosed error. Closing a closed manager does nothing.
+    /// The manager runs nothing in the background, and its shard pools are the only ones it holds, so once
+    /// this returns, it's left nothing running. Workers have background tasks of their own, see `Worker::close`.
+    pub async fn close(&self) {
+        // Operations hold the shards for as long as they're running, so taking them here waits for those
+        let shards = self.shards.write().await;
+        if self.closed.swap(true, std::sync::atomic::Ordering::AcqRel) {
+            return;
+        }
+        for shard in shards.iter() {
+            shard.pool.close().await;
+        }
+    }
+
+    // The shards, held for the duration of an operation, or a ManagerClosed error if the manager's been closed
+    async fn shards(&self) -> Result<RwLockReadGuard<'_, Vec<Shard>>, QueueError> {
+        let shards = self.shards.read().await;
+        if self.closed.load(std::sync::atomic::Ordering::Acquire) {
+            return Err(QueueError::ManagerClosed);
+        }
+        Ok(shards)
+    }
+
+    // Everything done to a job on its way into the queue. Along with the job to insert, returns its vm_state,
+    // if it was offloaded - see `created`.
+    async fn prepare(&self, init: JobInit) -> Result<(JobInit, Option<Bytes>), QueueError> {
+        let init = self.limit_schedule(self.clamp_priority(init))?;
+        let mut init = match &self.payload_cipher {
+            Some(cipher) => cipher.encrypt_init(init),
+            None => init,
+        };
+        let Some(offload) = &self.vm_state_offload else {
+            return Ok((init, None));
+        };
+        // Offloaded objects are keyed by job id, so the job needs one before it's inserted
+        let id = *init.id.get_or_insert_with(Uuid::now_v7);
+        let vm_state = init.vm_state.clone();
+        let offloaded = offload.offload_init(id, &mut init).await?;
+        Ok((init, offloaded.then_some(vm_state).flatten()))
+    }
+
+    // As `prepare`, for a batch. Offloaded vm_states are returned by the id of the job they belong to.
+    async fn prepare_all(
+        &self,
+        inits: Vec<JobInit>,
+    ) -> Result<(Vec<JobInit>, HashMap<Uuid, Bytes>), QueueError> {
+        let mut prepared = Vec::with_capacity(inits.len());
+        let mut vm_states = HashMap::new();
+        for init in inits {
+            let (init, vm_state) = self.prepare(init).await?;
+            if let (Some(id), Some(vm_state)) = (init.id, vm_state) {
+                vm_states.insert(id, vm_state);
+            }
+            prepared.push(init);
+        }
+        Ok((prepared, vm_states))
+    }
+
+    // Created jobs are handed back with the vm_state they were created with, rather than the reference to it
+    // that was inserted, so creating a job never has to fetch its state back from the object store
+    fn created(&self, job: Job, vm_state: Option<Bytes>) -> Result<Job, QueueError> {
+        let mut job = self.decrypt(job)?;
+        if vm_state.is_some() {
+            job.vm_state = vm_state;
+        }
+        Ok(job)
+    }
+
+    fn created_all(
+        &self,
+        jobs: Vec<Job>,
+        mut vm_states: HashMap<Uuid, Bytes>,
+    ) -> Result<Vec<Job>, QueueError> {
+        jobs.into_iter()
+            .map(|job| {
+                let vm_state = vm_states.remove(&job.id);
+                self.created(job, vm_state)
+            })
+            .collect()
+    }
+
+    // Jobs handed back to callers have their payloads decrypted, so callers never see ciphertext
+    fn decrypt(&self, job: Job) -> Result<Job, QueueError> {
+        match &self.payload_cipher {
+            Some(cipher) => Ok(cipher.decrypt_job(job)?),
+            None => Ok(job),
+        }
+    }
+
+    fn decrypt_all(&self, jobs: Vec<Job>) -> Result<Vec<Job>, QueueError> {
+        jobs.into_iter().map(|job| self.decrypt(job)).collect()
+    }
+
+    fn clamp_priority(&self, mut init: JobInit) -> JobInit {
+        // Not using i16::clamp, since it panics if the band is inverted
+        let clamped = init
+            .priority
+            .max(*self.priority_band.start())
+            .min(*self.priority_band.end());
+        if clamped != init.priority {
+            warn!(
+                team_id = init.team_id,
+                queue_name = %init.queue_name,
+                requested = init.priority,
+                clamped,
+                "job priority outside the allowed band, clamping"
+            );
+            init.priority = clamped;
+        }
+        init
+    }
+
+    fn limit_schedule(&self, mut init: JobInit) -> Result<JobInit, QueueError> {
+        let Some(max_ahead) = self.max_schedule_ahead else {
+            return Ok(init);
+        };
+        let latest = Utc::now() + max_ahead;
+        if init.scheduled <= latest {
+            return Ok(init);
+        }
+        match self.schedule_ahead_policy {
+            ScheduleAheadPolicy::Reject => {
+                Err(QueueError::ScheduledTooFarAhead(init.scheduled, latest))
+            }
+            ScheduleAheadPolicy::Clamp => {
+                warn!(
+                    team_id = init.team_id,
+                    queue_name = %init.queue_name,
+                    requested = %init.scheduled,
+                    clamped = %latest,
+                    "job scheduled too far ahead, clamping"
+                );
+                init.scheduled = latest;
+                Ok(init)
+            }
+        }
+    }
+
+    /// Stop accepting new jobs for a queue, on every shard. Attempts to create jobs in the queue will
+    /// fail with a QueueDraining error, but jobs already in the queue can still be dequeued and run
+    /// to completion.
+    pub async fn drain_queue(&self, queue_name: &str) -> Result<(), QueueError> {
+        let shards = self.shards().await?;
+        for shard in shards.iter() {
+            drain_queue(&mut *shard.acquire().await?, queue_name).await?;
+        }
+        Ok(())
+    }
+
+    /// Set a queue's enqueue defaults, on every shard, replacing any it already had. These are only used by
+    /// `create_job_with_queue_defaults` - every other way of creating jobs ignores them.
+    pub async fn set_queue_config(
+        &self,
+        queue_name: &str,
+        config: &QueueConfig,
+    ) -> Result<(), QueueError> {
+        let shards = self.shards().await?;
+        for shard in shards.iter() {
+            set_queue_config(&mut *shard.acquire().await?, queue_name, config).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns true if the queue is draining on any shard.
+    pub async fn queue_is_draining(&self, queue_name: &str) -> Result<bool, QueueError> {
+        let shards = self.shards().await?;
+        for shard in shards.iter() {
+            if queue_is_draining(&mut *shard.acquire().await?, queue_name).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Count the jobs matching a query by state, summed across every shard, ignoring the query's
+    /// limit. If `split_delayed` is set, available jobs scheduled in the future are counted under a
+    /// separate "delayed" state, so what's runnable now can be told apart from what's waiting.
+    pub async fn state_counts(
+        &self,
+        query: &JobQuery,
+        split_delayed: bool,
+    ) -> Result<Vec<StateCount>, QueueError> {
+        if query.uses_search() && !self.search_enabled {
+            return Err(QueueError::SearchNotEnabled);
+        }
+
+        let shards = self.shards().await?;
+        let mut totals: Vec<StateCount> = Vec::new();
+        for shard in shards.iter() {
+            for count in state_counts(&mut *shard.acquire().await?, query, split_delayed).await? {
+                match totals.iter_mut().find(|t| t.state == count.state) {
+                    Some(total) => total.count += count.count,
+                    None => totals.push(count),
+                }
+            }
+        }
+        totals.sort_by(|a, b| a.state.cmp(&b.state));
+        Ok(totals)
+    }
+
+    /// Every queue with jobs in it, across all shards, with how many jobs it has in each state. Queues are
+    /// ordered by name. Like `state_counts`, this counts every job in the table, so it's not cheap.
+    pub async fn list_queues(&self) -> Result<Vec<QueueInfo>, QueueError> {
+        let shards = self.shards().await?;
+        let mut totals: Vec<QueueInfo> = Vec::new();
+        for shard in shards.iter() {
+            for queue in list_queues(&mut *shard.acquire().await?).await? {
+                let Some(total) = totals.iter_mut().find(|t| t.queue_name == queue.queue_name)
+                else {
+                    totals.push(queue);
+                    continue;
+                };
+                total.total += queue.total;
+                for count in queue.states {
+                    match total.states.iter_mut().find(|s| s.state == count.state) {
+                        Some(state) => state.count += count.count,
+                        None => total.states.push(count),
+                    }
+                }
+                total.states.sort_by(|a, b| a.state.cmp(&b.state));
+            }
+        }
+        totals.sort_by(|a, b| a.queue_name.cmp(&b.queue_name));
+        Ok(totals)
+    }
+
+    /// Move the pending (available or paused) jobs in queue `from` to queue `to`, on every shard, returning
+    /// how many were moved. If a filter is passed, only jobs matching it are moved - e.g. to split a hot queue
+    /// by team. Running jobs are never moved, and the filter's limit is ignored. As with `query_jobs`, filters
+    /// with `search` set are an error unless search is enabled.
+    pub async fn remap_queue(
+        &self,
+        from: &str,
+        to: &str,
+        filter: Option<&JobQuery>,
+    ) -> Result<u64, QueueError> {
+        if filter.is_some_and(JobQuery::uses_search) && !self.search_enabled {
+            return Err(QueueError::SearchNotEnabled);
+        }
+
+        let shards = self.shards().await?;
+        let mut moved = 0;
+        for shard in shards.iter() {
+            moved += remap_queue(&mut *shard.acquire().await?, from, to, filter).await?;
+        }
+        Ok(moved)
+    }
+
+    /// Spread the available jobs matching a query out over the next `spread`, on every shard, returning how
+    /// many were rescheduled - e.g. so a backlog that all came due during a maintenance window doesn't hit
+    /// workers at once. Each job is given its own random time between now and `spread` from now, in place of
+    /// whatever it was scheduled for, so jobs scheduled further out than that are brought forward. Only
+    /// available jobs are touched, and the query's limit is ignored. As with `query_jobs`, queries with
+    /// `search` set are an error unless search is enabled.
+    pub async fn jitter_schedule(
+        &self,
+        query: &JobQuery,
+        spread: Duration,
+    ) -> Result<u64, QueueError> {
+        if query.uses_search() && !self.search_enabled {
+            return Err(QueueError::SearchNotEnabled);
+        }
+
+        let shards = self.shards().await?;
+        let mut rescheduled = 0;
+        for shard in shards.iter() {
+            rescheduled += jitter_schedule(&mut *shard.acquire().await?, query, spread).await?;
+        }
+        Ok(rescheduled)
+    }
+
+    /// Turn the transition audit on or off, on every shard. While it's on, every job state change - including
+    /// creation - is recorded in `cyclotron_job_transitions`, in the same transaction as the change, where
+    /// `job_transitions` can read it back. The record is append-only, and outlives the jobs themselves.
+    pub async fn set_transition_audit(&self, enabled: bool) -> Result<(), QueueError> {
+        let shards = self.shards().await?;
+        for shard in shards.iter() {
+            set_transition_audit(&mut *shard.acquire().await?, enabled).await?;
+        }
+        Ok(())
+    }
+
+    /// The recorded state changes of a job, oldest first. Changes made while the audit was off aren't there.
+    pub async fn job_transitions(&self, job_id: Uuid) -> Result<Vec<JobTransition>, QueueError> {
+        let shards = self.shards().await?;
+        let mut transitions = Vec::new();
+        for shard in shards.iter() {
+            transitions.extend(job_transitions(&mut *shard.acquire().await?, job_id).await?);
+        }
+        Ok(transitions)
+    }
+
+    /// Fetch a single job by id, from whichever shard it's on. Like `query_jobs`, the VM state
+    /// isn't returned.
+    pub async fn get_job(&self, id: Uuid) -> Result<Option<Job>, QueueError> {
+        Ok(self.get_jobs(&[id]).await?.pop())
+    }
+
+    /// Create a job, returning a handle to it, for callers that want to wait for the job's result or
+    /// cancel it. Otherwise the same as `create_job`.
+    pub async fn enqueue(&self, init: JobInit) -> Result<JobHandle<'_>, QueueError> {
+        let job = self.create_job(init).await?;
+        Ok(JobHandle::new(self, job))
+    }
+
+    /// Cancel a job that's waiting to run, failing it, from whichever shard it's on. Returns whether it
+    /// was cancelled - jobs that a worker has already picked up, or that have finished, can't be.
+    pub async fn cancel_job(&self, id: Uuid) -> Result<bool, QueueError> {
+        let shards = self.shards().await?;
+        for shard in shards.iter() {
+            if cancel_job(&mut *shard.acquire().await?, id).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Wait for a job to complete or fail, returning it in its final state. Errors with WaitTimedOut if
+    /// it's still going after `timeout`, and UnknownJobId if there's no such job - which includes jobs
+    /// that finished and were cleaned up by the janitor before this noticed. This polls, every
+    /// WAIT_POLL_INTERVAL, so it's for the odd request/response workflow, not for waiting on jobs en masse.
+    pub async fn wait_for_completion(
+        &self,
+        id: Uuid,
+        timeout: Duration,
+    ) -> Result<Job, QueueError> {
+        let deadline = Utc::now() + timeout;
+        loop {
+            let job = self.get_job(id).await?.ok_or(JobError::UnknownJobId(id))?;
+            if matches!(job.state, JobState::Completed | JobState::Failed) {
+                return Ok(job);
+            }
+            let remaining = deadline - Utc::now();
+            if remaining <= Duration::zero() {
+                return Err(QueueError::WaitTimedOut(id));
+            }
+            let poll = remaining.min(Duration::milliseconds(WAIT_POLL_INTERVAL_MS as i64));
+            tokio::time::sleep(poll.to_std().unwrap_or_default()).await;
+        }
+    }
+
+    /// Fetch a batch of jobs by id, across every shard. Ids that don't match a job are skipped,
+    /// and the jobs are returned in no particular order.
+    pub async fn get_jobs(&self, ids: &[Uuid]) -> Result<Vec<Job>, QueueError> {
+        let shards = self.shards().await?;
+        let mut jobs = Vec::with_capacity(ids.len());
+        for shard in shards.iter() {
+            jobs.extend(get_jobs(&mut *shard.acquire().await?, ids).await?);
+            if jobs.len() == ids.len() {
+                break;
+            }
+        }
+        self.decrypt_all(jobs)
+    }
+
+    /// Look up the jobs matching a query, across every shard. Queries with `search` set return an
+    /// error unless search is enabled for this manager. Results are ordered oldest first within
+    /// each shard, but not across shards. The limit applies to the total returned, and defaults to
+    /// DEFAULT_QUERY_LIMIT - see `JobQuery::unlimited` for returning everything. Search
+    /// runs against the stored payloads, so won't find anything in encrypted parameters or blobs.
+    pub async fn query_jobs(&self, query: &JobQuery) -> Result<Vec<Job>, QueueError> {
+        if query.uses_search() && !self.search_enabled {
+            return Err(QueueError::SearchNotEnabled);
+        }
+
+        let limit = query.effective_limit();
+        let shards = self.shards().await?;
+        let mut jobs = Vec::new();
+        for shard in shards.iter() {
+            if limit.is_some_and(|limit| jobs.len() as u64 >= limit) {
+                break;
+            }
+            jobs.extend(query_jobs(&mut *shard.acquire().await?, query).await?);
+        }
+        if let Some(limit) = limit {
+            jobs.truncate(limit as usize);
+        }
+        self.decrypt_all(jobs)
+    }
+
+    /// Jobs with a function that isn't one of `valid_function_ids` - e.g. jobs left behind by deleted hog
+    /// functions - oldest first, up to DEFAULT_QUERY_LIMIT of them, for a cleanup job to work through. Jobs
+    /// with no function aren't orphans. With no valid functions, every job with a function is one.
+    pub async fn find_orphans(&self, valid_function_ids: &[Uuid]) -> Result<Vec<Job>, QueueError> {
+        let query = JobQuery {
+            function_id_is_null: Some(false),
+            exclude_function_ids: valid_function_ids.to_vec(),
+            ..Default::default()
+        };
+        self.query_jobs(&query).await
+    }
+
+    /// As `query_jobs`, but returning job summaries, without any of the jobs' payloads, for listing jobs
+    /// cheaply. The same limits and search restrictions apply.
+    pub async fn query_job_summaries(
+        &self,
+        query: &JobQuery,
+    ) -> Result<Vec<JobSummary>, QueueError> {
+        if query.uses_search() && !self.search_enabled {
+            return Err(QueueError::SearchNotEnabled);
+        }
+
+        let limit = query.effective_limit();
+        let shards = self.shards().await?;
+        let mut summaries = Vec::new();
+        for shard in shards.iter() {
+            if limit.is_some_and(|limit| summaries.len() as u64 >= limit) {
+                break;
+            }
+            summaries.extend(query_job_summaries(&mut *shard.acquire().await?, query).await?);
+        }
+        if let Some(limit) = limit {
+            summaries.truncate(limit as usize);
+        }
+        Ok(summaries)
+    }
+}
+
+// What makes two jobs duplicates for `create_job_deduplicated` - their team, queue and parameters, hashed. This
+// has to be computed before the parameters are encrypted, since encrypting the same plaintext twice doesn't give
+// the same ciphertext.
+fn dedup_hash(init: &JobInit) -> Vec<u8> {
+    let mut hash = ring::digest::Context::new(&ring::digest::SHA256);
+    hash.update(&init.team_id.to_be_bytes());
+    hash.update(&(init.queue_name.len() as u64).to_be_bytes());
+    hash.update(init.queue_name.as_bytes());
+    match &init.parameters {
+        Some(parameters) => {
+            hash.update(&[1]);
+            hash.update(parameters);
+        }
+        None => hash.update(&[0]),
+    }
+    hash.finish().as_ref().to_vec()
+}
+
+// Jobs are spread across shards, so operations on existing jobs try each shard in turn, and the janitor's
+// operations run against all of them
+#[async_trait::async_trait]
+impl JobStore for QueueManager {
+    async fn create_job(&self, init: JobInit) -> Result<Job, QueueError> {
+        QueueManager::create_job(self, init).await
+    }
+
+    async fn dequeue_jobs(&self, queue: &str, limit: usize) -> Result<Vec<Job>, QueueError> {
+        let lock_id = Uuid::now_v7();
+        let shards = self.shards().await?;
+        let mut jobs = Vec::new();
+        for shard in shards.iter() {
+            if jobs.len() >= limit {
+                break;
+            }
+            jobs.extend(
+                dequeue_jobs(
+                    &mut *shard.acquire().await?,
+                    queue,
+                    limit - jobs.len(),
+                    lock_id,
+                )
+                .await?,
+            );
+        }
+        self.decrypt_all(jobs)
+    }
+
+    async fn flush_job(&self, job_id: Uuid, mut update: JobUpdate) -> Result<(), QueueError> {
+        if let Some(cipher) = &self.payload_cipher {
+            for (field, payload) in [
+                (PayloadField::Parameters, &mut update.parameters),
+                (PayloadField::Blob, &mut update.blob),
+            ] {
+                if let Some(Some(p)) = payload {
+                    *p = cipher.encrypt(job_id, field, p);
+                }
+            }
+        }
+
+        let shards = self.shards().await?;
+        for shard in shards.iter() {
+            match flush_job(&mut *shard.acquire().await?, job_id, &update).await {
+                Err(QueueError::JobError(JobError::InvalidLock(..))) => continue,
+                res => return res,
+            }
+        }
+        Err(JobError::InvalidLock(update.lock_id, job_id).into())
+    }
+
+    async fn heartbeat(&self, job_id: Uuid, lock_id: Uuid) -> Result<(), QueueError> {
+        let shards = self.shards().await?;
+        for shard in shards.iter() {
+            match set_heartbeat(&mut *shard.acquire().await?, job_id, lock_id).await {
+                Err(QueueError::JobError(JobError::InvalidLock(..))) => continue,
+                res => return res,
+            }
+        }
+        Err(JobError::InvalidLock(lock_id, job_id).into())
+    }
+
+    async fn get_job(&self, id: Uuid) -> Result<Option<Job>, QueueError> {
+        QueueManager::get_job(self, id).await
+    }
+
+    async fn reset_stalled_jobs(&self, timeout: Duration) -> Result<u64, QueueError> {
+        let shards = self.shards().await?;
+        let mut reset = 0;
+        for shard in shards.iter() {
+            reset += reset_stalled_jobs(&mut *shard.acquire().await?, timeout).await?;
+        }
+        Ok(reset)
+    }
+
+    async fn delete_completed_and_failed_jobs(&self) -> Result<u64, QueueError> {
+        let shards = self.shards().await?;
+        let mut deleted = 0;
+        for shard in shards.iter() {
+            let aggregates = delete_completed_and_failed_jobs(&mut *shard.acquire().await?).await?;
+            deleted += aggregates.iter().map(|a| a.count as u64).sum::<u64>();
+        }
+        Ok(deleted)
     }
 }
 
@@ -113,21 +908,146 @@ impl Shard {
             last_healthy: RwLock::new(Utc::now() - check_interval),
             check_interval,
             depth_limit,
+            acquire_retry: AcquireRetry::default(),
         }
     }
 
+    // A connection to the shard, retrying according to `acquire_retry` if getting one fails transiently
+    pub async fn acquire(&self) -> Result<PoolConnection<Postgres>, QueueError> {
+        acquire::acquire(&self.pool, &self.acquire_retry).await
+    }
+
+    // A transaction on the shard, retrying according to `acquire_retry` if getting one fails transiently
+    pub async fn begin(&self) -> Result<Transaction<'static, Postgres>, QueueError> {
+        acquire::begin(&self.pool, &self.acquire_retry).await
+    }
+
     // Inserts a job, failing if the shard is at capacity
-    pub async fn create_job(&self, init: JobInit) -> Result<Uuid, QueueError> {
+    pub async fn create_job(&self, init: JobInit) -> Result<Job, QueueError> {
+        self.insert_guard().await?;
+        create_job(&mut *self.acquire().await?, init).await
+    }
+
+    // Inserts a job and notifies listeners of it in one transaction, failing if the shard is at capacity
+    pub async fn create_job_and_notify(&self, init: JobInit) -> Result<Job, QueueError> {
+        self.insert_guard().await?;
+        let mut txn = self.begin().await?;
+        let job = create_job(&mut *txn, init).await?;
+        notify_enqueued(&mut *txn, &job).await?;
+        txn.commit().await?;
+        Ok(job)
+    }
+
+    // Inserts a job, failing if the shard is at capacity, or if a max depth is passed and the job's queue
+    // already has that many available jobs in it. The depth check and the insert happen in one transaction.
+    pub async fn create_job_with_max_depth(
+        &self,
+        init: JobInit,
+        max_queue_depth: Option<u64>,
+    ) -> Result<Job, QueueError> {
+        let Some(max_queue_depth) = max_queue_depth else {
+            return self.create_job(init).await;
+        };
+
+        self.insert_guard().await?;
+        let mut txn = self.begin().await?;
+        lock_queue_for_bounded_insert(&mut *txn, &init.queue_name).await?;
+        if count_available_jobs(&mut *txn, &init.queue_name).await? >= max_queue_depth {
+            return Err(QueueError::QueueFull(init.queue_name, max_queue_depth));
+        }
+        let job = create_job(&mut *txn, init).await?;
+        txn.commit().await?;
+        Ok(job)
+    }
+
+    // Inserts a job, or if its id is taken, optionally overwrites the existing job - see the `upsert_job` op.
+    // Returns None if the conflicting job was left as it was.
+    pub async fn upsert_job(
+        &self,
+        init: JobInit,
+        update_existing: bool,
+    ) -> Result<Option<Job>, QueueError> {
         self.insert_guard().await?;
-        create_job(&self.pool, init).await
+        let queue_name = init.queue_name.clone();
+        let mut conn = self.acquire().await?;
+        let job = upsert_job(&mut *conn, init, update_existing).await?;
+        // Nothing is written to a draining queue either, so tell the two apart
+        if job.is_none() && queue_is_draining(&mut *conn, &queue_name).await? {
+            return Err(QueueError::QueueDraining(queue_name));
+        }
+        Ok(job)
+    }
+
+    // Inserts a job, unless a job with the same dedup hash has been created since `since`, in which case that job's
+    // id is returned instead. Fails if the shard is at capacity.
+    pub async fn create_job_deduplicated(
+        &self,
+        init: JobInit,
+        dedup_hash: &[u8],
+        since: DateTime<Utc>,
+    ) -> Result<Enqueued, QueueError> {
+        self.insert_guard().await?;
+        let mut txn = self.begin().await?;
+        lock_dedup_hash(&mut *txn, dedup_hash).await?;
+        if let Some(id) = find_duplicate(&mut *txn, dedup_hash, since).await? {
+            return Ok(Enqueued::Deduplicated(id));
+        }
+        let job = create_job_with_dedup_hash(&mut *txn, init, Some(dedup_hash)).await?;
+        txn.commit().await?;
+        Ok(Enqueued::Created(job))
+    }
+
+    // Inserts a job blocked on another job on this shard, failing if the shard is at capacity, or if the other
+    // job isn't on this shard.
+    pub async fn create_job_after(
+        &self,
+        init: JobInit,
+        dependency_id: Uuid,
+    ) -> Result<Job, QueueError> {
+        self.insert_guard().await?;
+        let mut txn = self.begin().await?;
+        let Some(dependency_state) = lock_job_state(&mut *txn, dependency_id).await? else {
+            return Err(JobError::UnknownJobId(dependency_id).into());
+        };
+        let mut job = create_job(&mut *txn, init).await?;
+        if dependency_state != JobState::Completed {
+            block_job_on(&mut *txn, job.id, dependency_id).await?;
+            raise_prerequisite_priority(&mut *txn, dependency_id, job.priority).await?;
+            job.state = JobState::Paused;
+        }
+        txn.commit().await?;
+        Ok(job)
     }
 
     // Inserts a vec of jobs, failing if the shard is at capacity. Note "capacity" here just
     // means "it isn't totally full" - if there's "capacity" for 1 job, and this is a vec of
     // 1000, we still insert all 1000.
-    pub async fn bulk_create_jobs(&self, inits: &[JobInit]) -> Result<Vec<Uuid>, QueueError> {
+    pub async fn bulk_create_jobs(&self, inits: &[JobInit]) -> Result<Vec<Job>, QueueError> {
         self.insert_guard().await?;
-        bulk_create_jobs(&self.pool, inits).await
+        self.insert_jobs(inits).await
+    }
+
+    // Inserts each job in turn in one transaction, rolling back on the first failure. As with `bulk_create_jobs`,
+    // the capacity check is only done once for the whole batch.
+    pub async fn bulk_create_jobs_transactional(
+        &self,
+        inits: &[JobInit],
+    ) -> Result<Vec<Job>, BatchRejected> {
+        self.insert_guard().await?;
+        let mut txn = self.begin().await?;
+        let mut jobs = Vec::with_capacity(inits.len());
+        for (index, init) in inits.iter().enumerate() {
+            // Dropping the transaction on the way out rolls it back
+            let job = create_job(&mut *txn, init.clone())
+                .await
+                .map_err(|error| BatchRejected {
+                    index: Some(index),
+                    error,
+                })?;
+            jobs.push(job);
+        }
+        txn.commit().await?;
+        Ok(jobs)
     }
 
     // Inserts a job, blocking until there's capacity (or until the timeout is reached)
@@ -135,7 +1055,7 @@ impl Shard {
         &self,
         init: JobInit,
         timeout: Option<Duration>,
-    ) -> Result<Uuid, QueueError> {
+    ) -> Result<Job, QueueError> {
         let start = Utc::now();
         while self.is_full().await? {
             tokio::time::sleep(Duration::milliseconds(100).to_std().unwrap()).await;
@@ -146,7 +1066,7 @@ impl Shard {
             }
         }
 
-        create_job(&self.pool, init).await
+        create_job(&mut *self.acquire().await?, init).await
     }
 
     // As above, with the same caveats about what "capacity" means
@@ -154,7 +1074,7 @@ impl Shard {
         &self,
         inits: &[JobInit],
         timeout: Option<Duration>,
-    ) -> Result<Vec<Uuid>, QueueError> {
+    ) -> Result<Vec<Job>, QueueError> {
         let start = Utc::now();
         while self.is_full().await? {
             tokio::time::sleep(Duration::milliseconds(100).to_std().unwrap()).await;
@@ -165,7 +1085,25 @@ impl Shard {
             }
         }
 
-        bulk_create_jobs(&self.pool, inits).await
+        self.insert_jobs(inits).await
+    }
+
+    // The bulk insert op rejects the whole batch if any job is for a draining queue, so if nothing
+    // was inserted, we look up which queue it was to tell the caller
+    async fn insert_jobs(&self, inits: &[JobInit]) -> Result<Vec<Job>, QueueError> {
+        let mut conn = self.acquire().await?;
+        let jobs = bulk_create_jobs(&mut *conn, inits).await?;
+        if jobs.len() == inits.len() {
+            return Ok(jobs);
+        }
+
+        let queue_names: Vec<String> = inits.iter().map(|i| i.queue_name.clone()).collect();
+        let draining = first_draining_queue(&mut *conn, &queue_names).await?;
+        // If the queue stopped draining between the insert and the lookup, we fall back to
+        // reporting the first queue in the batch
+        Err(QueueError::QueueDraining(
+            draining.unwrap_or_else(|| queue_names[0].clone()),
+        ))
     }
 
     pub async fn insert_guard(&self) -> Result<(), QueueError> {
@@ -194,7 +1132,7 @@ impl Shard {
             return Ok(false);
         }
 
-        let pending = count_total_waiting_jobs(&self.pool).await?;
+        let pending = count_total_waiting_jobs(&mut *self.acquire().await?).await?;
         let total_pending = pending.iter().map(|(count, _)| count).sum::<u64>();
         let is_full = total_pending >= self.depth_limit;
         if !is_full {
@@ -0,0 +1,171 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use uuid::Uuid;
+
+use crate::{Bytes, JobInit, JobUpdate, QueueError};
+
+// Offloaded vm_states are stored in the column as this, followed by the object's key. It starts with a NUL byte
+// so it can't be mistaken for the text and JSON states workers write. States without it are inline, so offloading
+// can be turned on without breaking jobs whose states were written before it was.
+const REFERENCE_PREFIX: &[u8] = b"\0cyc_obj:";
+
+// Somewhere to put vm_states too large to keep in postgres - in production an S3-compatible bucket, see
+// `S3ObjectStore`, behind the "s3" feature
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<(), QueueError>;
+
+    // None if there's no object with the key
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, QueueError>;
+}
+
+// Moves vm_states larger than `threshold` bytes out to an object store, leaving a reference to them in the job,
+// and fetches them back when they're read. Workers offload the states they write, and the manager the states jobs
+// are created with. Smaller states are kept inline. Every write of a large state is a new object, so a retried
+// flush never overwrites a state a reader might be fetching. Objects aren't deleted along with their jobs - the
+// bucket should have a lifecycle rule expiring them once jobs can't still be around. The manager uploads a new job's
+// state before inserting it, so a create that ends up writing nothing - `OnConflict::Ignore`, or `Update` against
+// a job it can't overwrite - leaves an object no job refers to, for that rule to clean up too.
+#[derive(Clone)]
+pub struct VmStateOffload {
+    pub store: Arc<dyn ObjectStore>,
+    pub threshold: usize,
+    pub key_prefix: String, // Objects are keyed `{key_prefix}{job_id}/{uuid}`. Defaults to "vm_state/"
+}
+
+impl VmStateOffload {
+    pub fn new(store: Arc<dyn ObjectStore>, threshold: usize) -> Self {
+        Self {
+            store,
+            threshold,
+            key_prefix: "vm_state/".to_string(),
+        }
+    }
+
+    // If the update sets a vm_state over the threshold, uploads it, and swaps it for its reference in the update
+    pub(crate) async fn offload_update(
+        &self,
+        job_id: Uuid,
+        update: &mut JobUpdate,
+    ) -> Result<(), QueueError> {
+        match update.vm_state.as_mut() {
+            Some(Some(vm_state)) => self.offload(job_id, vm_state).await.map(|_| ()),
+            _ => Ok(()),
+        }
+    }
+
+    // As `offload_update`, for a job being created. Returns whether the state was offloaded
+    pub(crate) async fn offload_init(
+        &self,
+        job_id: Uuid,
+        init: &mut JobInit,
+    ) -> Result<bool, QueueError> {
+        match init.vm_state.as_mut() {
+            Some(vm_state) => self.offload(job_id, vm_state).await,
+            None => Ok(false),
+        }
+    }
+
+    async fn offload(&self, job_id: Uuid, vm_state: &mut Bytes) -> Result<bool, QueueError> {
+        if vm_state.len() <= self.threshold || is_reference(vm_state) {
+            return Ok(false);
+        }
+
+        // The state is only swapped for its reference once it's uploaded - if the upload fails, the update
+        // is retried with the state it had
+        let key = format!("{}{}/{}", self.key_prefix, job_id, Uuid::now_v7());
+        self.store.put(&key, vm_state.clone()).await?;
+        *vm_state = [REFERENCE_PREFIX, key.as_bytes()].concat();
+        Ok(true)
+    }
+}
+
+fn is_reference(vm_state: &[u8]) -> bool {
+    vm_state.starts_with(REFERENCE_PREFIX)
+}
+
+// Swaps a vm_state read from a job for the offloaded state it refers to, if it's a reference. Fails if it is, and
+// there's no store to fetch it from, rather than handing the reference to a worker as if it were the state.
+pub(crate) async fn resolve_vm_state(
+    offload: Option<&VmStateOffload>,
+    vm_state: Option<Bytes>,
+) -> Result<Option<Bytes>, QueueError> {
+    let Some(key) = vm_state
+        .as_deref()
+        .and_then(|s| s.strip_prefix(REFERENCE_PREFIX))
+    else {
+        return Ok(vm_state);
+    };
+    let key = String::from_utf8_lossy(key);
+    let Some(offload) = offload else {
+        return Err(QueueError::ObjectStoreError(format!(
+            "vm_state is offloaded to {}, but no object store is configured",
+            key
+        )));
+    };
+    match offload.store.get(&key).await? {
+        Some(state) => Ok(Some(state)),
+        None => Err(QueueError::ObjectStoreError(format!(
+            "offloaded vm_state {} is missing",
+            key
+        ))),
+    }
+}
+
+// An `ObjectStore` backed by an S3 (or S3-compatible, like MinIO) bucket
+#[cfg(feature = "s3")]
+pub struct S3ObjectStore {
+    client: aws_sdk_s3::Client,
+    bucket: String,
+}
+
+#[cfg(feature = "s3")]
+impl S3ObjectStore {
+    pub fn new(client: aws_sdk_s3::Client, bucket: impl Into<String>) -> Self {
+        Self {
+            client,
+            bucket: bucket.into(),
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+#[async_trait]
+impl ObjectStore for S3ObjectStore {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<(), QueueError> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .body(aws_sdk_s3::primitives::ByteStream::from(bytes))
+            .send()
+            .await
+            .map_err(|e| QueueError::ObjectStoreError(aws_sdk_s3::Error::from(e).to_string()))?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, QueueError> {
+        let res = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .send()
+            .await;
+        match res {
+            Ok(res) => {
+                let data = res
+                    .body
+                    .collect()
+                    .await
+                    .map_err(|e| QueueError::ObjectStoreError(e.to_string()))?;
+                Ok(Some(data.to_vec()))
+            }
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => Ok(None),
+            Err(e) => Err(QueueError::ObjectStoreError(
+                aws_sdk_s3::Error::from(e).to_string(),
+            )),
+        }
+    }
+}
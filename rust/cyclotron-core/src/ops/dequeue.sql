@@ -0,0 +1,56 @@
+-- Every dequeue in ops/worker.rs runs this. It's kept in a file of its own so tests/indexes.rs can EXPLAIN the
+-- query exactly as it's run - its selection of available jobs has to keep to the shape the due jobs index needs
+-- (see its migration).
+WITH available AS (
+    SELECT
+        id,
+        state
+    FROM cyclotron_jobs
+    WHERE
+        state = 'available'::JobState
+        AND queue_name = $1
+        AND scheduled <= NOW()
+        AND NOT EXISTS (SELECT 1 FROM cyclotron_jobs held WHERE held.lock_id = $3)
+    ORDER BY
+        priority ASC,
+        scheduled ASC,
+        id ASC
+    LIMIT $2
+    FOR UPDATE SKIP LOCKED
+)
+UPDATE cyclotron_jobs
+SET
+    state = 'running'::JobState,
+    lock_id = $3,
+    last_heartbeat = NOW(),
+    lock_expires_at = NULL,
+    last_transition = NOW(),
+    transition_count = transition_count + 1,
+    first_dequeued = COALESCE(first_dequeued, NOW())
+FROM available
+WHERE
+    cyclotron_jobs.id = available.id
+RETURNING
+    cyclotron_jobs.id,
+    team_id,
+    available.state as "state: JobState",
+    queue_name,
+    priority,
+    function_id,
+    created,
+    last_transition,
+    scheduled,
+    transition_count,
+    failed_attempts,
+    CASE WHEN $4 THEN vm_state END as vm_state,
+    CASE WHEN $5 THEN metadata END as metadata,
+    CASE WHEN $6 THEN parameters END as parameters,
+    CASE WHEN $7 THEN blob END as blob,
+    lock_id,
+    last_heartbeat,
+    lock_expires_at,
+    janitor_touch_count,
+    at_most_once,
+    correlation_id,
+    parent_job_id,
+    reply_to
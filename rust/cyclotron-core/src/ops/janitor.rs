@@ -1,4 +1,4 @@
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use uuid::Uuid;
 
 use crate::error::QueueError;
@@ -8,20 +8,34 @@ use crate::types::AggregatedDelete;
 // queue name). We can revisit this later, if we decide we need the ability to do janitor operations
 // on a per-queue basis.
 
-pub async fn delete_completed_and_failed_jobs<'c, E>(
-    executor: E,
-) -> Result<Vec<AggregatedDelete>, QueueError>
-where
-    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
-{
-    let result: Vec<AggregatedDelete> = sqlx::query_as!(
-        AggregatedDelete,
-        r#"
-WITH to_delete AS (
-    DELETE FROM cyclotron_jobs
-    WHERE state IN ('failed', 'completed')
-    RETURNING last_transition, team_id, function_id::text, state::text
-),
+// The delete pass and its preview report the same aggregates over the same jobs, so both queries are built here,
+// from one predicate, column list and aggregation - they differ only in whether the jobs are deleted, or just
+// selected. The pieces are literals, rather than consts, so the queries can still be checked at compile time.
+macro_rules! aggregate_finished_jobs {
+    ($mode:ident, $executor:expr) => {
+        aggregate_finished_jobs!(
+            @finished $mode,
+            $executor,
+            "state IN ('failed', 'completed')",
+            "last_transition, team_id, function_id::text, state::text"
+        )
+    };
+    (@finished delete, $executor:expr, $predicate:tt, $columns:tt) => {
+        aggregate_finished_jobs!(
+            @query $executor,
+            "DELETE FROM cyclotron_jobs WHERE " + $predicate + " RETURNING " + $columns
+        )
+    };
+    (@finished select, $executor:expr, $predicate:tt, $columns:tt) => {
+        aggregate_finished_jobs!(
+            @query $executor,
+            "SELECT " + $columns + " FROM cyclotron_jobs WHERE " + $predicate
+        )
+    };
+    (@query $executor:expr, $($finished:tt)+) => {
+        sqlx::query_as!(
+            AggregatedDelete,
+            "WITH finished AS (" + $($finished)+ + r#"),
 aggregated_data AS (
     SELECT
         date_trunc('hour', last_transition) AS hour,
@@ -29,7 +43,7 @@ aggregated_data AS (
         function_id,
         state,
         COUNT(*) AS count
-    FROM to_delete
+    FROM finished
     GROUP BY hour, team_id, function_id, state
 )
 SELECT
@@ -39,15 +53,127 @@ SELECT
     state as "state!",
     count as "count!"
 FROM aggregated_data"#
+        )
+        .fetch_all($executor)
+        .await
+        .map_err(QueueError::from)
+    };
+}
+
+pub async fn delete_completed_and_failed_jobs<'c, E>(
+    executor: E,
+) -> Result<Vec<AggregatedDelete>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    aggregate_finished_jobs!(delete, executor)
+}
+
+// What `delete_completed_and_failed_jobs` would delete, aggregated the same way, from a plain select - so it takes
+// no row locks, and doesn't contend with a delete pass running at the same time
+pub async fn preview_completed_and_failed_jobs<'c, E>(
+    executor: E,
+) -> Result<Vec<AggregatedDelete>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    aggregate_finished_jobs!(select, executor)
+}
+
+// Deletes up to `batch_size` completed and failed jobs that last transitioned before `older_than`, returning
+// how many were deleted. Unlike the above, this doesn't aggregate what it deletes, so it's cheaper when the
+// aggregates aren't needed. Rows locked by anything else are skipped, and picked up by a later batch.
+pub async fn purge_completed_jobs_batch<'c, E>(
+    executor: E,
+    older_than: DateTime<Utc>,
+    batch_size: u64,
+) -> Result<u64, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let result = sqlx::query!(
+        r#"
+WITH batch AS (
+    SELECT id
+    FROM cyclotron_jobs
+    WHERE state IN ('failed', 'completed') AND last_transition < $1
+    LIMIT $2
+    FOR UPDATE SKIP LOCKED
+)
+DELETE FROM cyclotron_jobs
+WHERE id IN (SELECT id FROM batch)
+    "#,
+        older_than,
+        batch_size as i64
     )
-    .fetch_all(executor)
-    .await
-    .map_err(QueueError::from)?;
+    .execute(executor)
+    .await?;
 
-    Ok(result)
+    Ok(result.rows_affected())
+}
+
+// Declares the transaction the executor is part of to be pruning the transition record, which is otherwise
+// append-only, letting its deletes past the trigger guarding it. Like the delete pass lock, this ends with the
+// transaction, so it's only useful inside one.
+pub async fn allow_transition_pruning<'c, E>(executor: E) -> Result<(), QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    sqlx::query!("SELECT set_config('cyclotron.pruning_transitions', 'on', true)")
+        .fetch_one(executor)
+        .await?;
+    Ok(())
+}
+
+// Deletes up to `batch_size` recorded transitions from before `older_than`, returning how many were deleted. Only
+// works in a transaction that's called `allow_transition_pruning` first.
+pub async fn prune_transitions_batch<'c, E>(
+    executor: E,
+    older_than: DateTime<Utc>,
+    batch_size: u64,
+) -> Result<u64, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let result = sqlx::query!(
+        r#"
+DELETE FROM cyclotron_job_transitions
+WHERE id IN (
+    SELECT id FROM cyclotron_job_transitions
+    WHERE at < $1
+    LIMIT $2
+)
+    "#,
+        older_than,
+        batch_size as i64
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected())
 }
 
-// Jobs are considered stalled if their lock is held and their last_heartbeat is older than `timeout`.
+// The key of the transaction-scoped advisory lock held while deleting completed and failed jobs. Arbitrary,
+// it just needs to not collide with any other advisory lock taken against the same database.
+pub const DELETE_PASS_LOCK_KEY: i64 = 0x6379_636c_6f74_726e; // "cyclotrn"
+
+// Try to take the delete pass lock, returning whether we got it. The lock is released when the transaction
+// the executor is part of ends, so this is only useful inside a transaction.
+pub async fn try_lock_delete_pass<'c, E>(executor: E) -> Result<bool, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let locked = sqlx::query_scalar!("SELECT pg_try_advisory_xact_lock($1)", DELETE_PASS_LOCK_KEY)
+        .fetch_one(executor)
+        .await?;
+
+    Ok(locked.unwrap_or(false))
+}
+
+// Jobs are considered stalled if their lock is held and their last_heartbeat is older than `timeout`, or,
+// if the worker holding them set an explicit lock deadline, if that deadline has passed. Stalled jobs are
+// returned to the queue, unless they're at-most-once, in which case they're failed (the worker may have
+// done some or all of the work before it stalled, so running the job again might do it twice).
 //
 // TODO - this /could/ return the lock_id's held, which might help with debugging (if workers reported
 // the lock_id's they dequeue'd), but lets not do that right now.
@@ -58,10 +184,16 @@ where
     let oldest_valid_heartbeat = Utc::now() - timeout;
     let result = sqlx::query!(r#"
 WITH stalled AS (
-    SELECT id FROM cyclotron_jobs WHERE state = 'running' AND COALESCE(last_heartbeat, $1) <= $1 FOR UPDATE SKIP LOCKED
+    SELECT id FROM cyclotron_jobs
+    WHERE state = 'running'
+        AND (
+            (lock_expires_at IS NOT NULL AND lock_expires_at <= NOW())
+            OR (lock_expires_at IS NULL AND COALESCE(last_heartbeat, $1) <= $1)
+        )
+    FOR UPDATE SKIP LOCKED
 )
 UPDATE cyclotron_jobs
-SET state = 'available', lock_id = NULL, last_heartbeat = NULL, janitor_touch_count = janitor_touch_count + 1
+SET state = CASE WHEN at_most_once THEN 'failed'::JobState ELSE 'available'::JobState END, lock_id = NULL, last_heartbeat = NULL, lock_expires_at = NULL, janitor_touch_count = janitor_touch_count + 1, transition_count = transition_count + 1, last_transition = NOW()
 FROM stalled
 WHERE cyclotron_jobs.id = stalled.id
     "#,
@@ -88,13 +220,20 @@ where
     // and would be useful to report.
     let result = sqlx::query_scalar!(
         r#"
-SELECT id FROM cyclotron_jobs WHERE state = 'running' AND COALESCE(last_heartbeat, $1) <= $1 AND janitor_touch_count >= $2
+SELECT id FROM cyclotron_jobs
+WHERE state = 'running'
+    AND (
+        (lock_expires_at IS NOT NULL AND lock_expires_at <= NOW())
+        OR (lock_expires_at IS NULL AND COALESCE(last_heartbeat, $1) <= $1)
+    )
+    AND janitor_touch_count >= $2
         "#,
         oldest_valid_heartbeat,
         max_janitor_touched
-    ).fetch_all(executor)
-        .await
-        .map_err(QueueError::from)?;
+    )
+    .fetch_all(executor)
+    .await
+    .map_err(QueueError::from)?;
 
     Ok(result)
 }
@@ -1,17 +1,40 @@
-use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{Postgres, QueryBuilder};
 use uuid::Uuid;
 
 use crate::{
     error::QueueError,
-    types::{JobInit, JobState},
+    types::{
+        truncate_to_db_precision, Filter, Job, JobInit, JobQuery, JobState, JobSummary,
+        JobTransition, QueueConfig, QueueInfo, StateCount,
+    },
+    ENQUEUE_CHANNEL,
 };
 
-pub async fn create_job<'c, E>(executor: E, data: JobInit) -> Result<Uuid, QueueError>
+// Inserts a job, returning it as it was inserted (so with server-assigned fields like `created` filled in),
+// unless its queue is draining, in which case a QueueDraining error is returned. If the job has an id that's
+// already taken, a DuplicateJobId error is returned.
+pub async fn create_job<'c, E>(executor: E, data: JobInit) -> Result<Job, QueueError>
 where
     E: sqlx::Executor<'c, Database = sqlx::Postgres>,
 {
-    let id = Uuid::now_v7();
-    sqlx::query!(
+    create_job_with_dedup_hash(executor, data, None).await
+}
+
+// As `create_job`, also storing the job's dedup hash, for `find_duplicate` to find it by
+pub async fn create_job_with_dedup_hash<'c, E>(
+    executor: E,
+    data: JobInit,
+    dedup_hash: Option<&[u8]>,
+) -> Result<Job, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let id = data.id.unwrap_or_else(Uuid::now_v7);
+    let job = sqlx::query_as!(
+        Job,
         r#"
 INSERT INTO cyclotron_jobs
     (
@@ -31,34 +54,191 @@ INSERT INTO cyclotron_jobs
         vm_state,
         metadata,
         parameters,
-        blob
+        blob,
+        at_most_once,
+        correlation_id,
+        parent_job_id,
+        reply_to,
+        dedup_hash
     )
-VALUES
-    ($1, $2, $3, NOW(), NULL, NULL, 0, 0, NOW(), $4, $5, $6, $7, $8, $9, $10, $11)
+SELECT
+    $1, $2, $3, NOW(), NULL, NULL, 0, 0, NOW(), $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16
+WHERE NOT EXISTS (
+    SELECT 1 FROM cyclotron_queues WHERE queue_name = $4 AND draining
+)
+RETURNING
+    id,
+    team_id,
+    state as "state: JobState",
+    queue_name,
+    priority,
+    function_id,
+    created,
+    last_transition,
+    scheduled,
+    transition_count,
+    failed_attempts,
+    vm_state,
+    metadata,
+    parameters,
+    blob,
+    lock_id,
+    last_heartbeat,
+    lock_expires_at,
+    janitor_touch_count,
+    at_most_once,
+    correlation_id,
+    parent_job_id,
+    reply_to
     "#,
         id,
         data.team_id,
         data.function_id,
         data.queue_name,
         JobState::Available as _,
-        data.scheduled,
+        truncate_to_db_precision(data.scheduled),
         data.priority,
         data.vm_state,
         data.metadata,
         data.parameters,
-        data.blob
+        data.blob,
+        data.at_most_once,
+        data.correlation_id,
+        data.parent_job_id,
+        data.reply_to,
+        dedup_hash
     )
-    .execute(executor)
+    .fetch_optional(executor)
+    .await
+    .map_err(|e| duplicate_id_or(e, id))?;
+
+    job.ok_or(QueueError::QueueDraining(data.queue_name))
+}
+
+// Inserts a job, or if its id is already taken, either leaves the existing job alone (`update_existing` false), or
+// overwrites it with the new one if it's still available, and belongs to the same team. Returns the job inserted
+// or updated, or None if nothing was written - either because of the conflict, or because the queue is draining.
+pub async fn upsert_job<'c, E>(
+    executor: E,
+    data: JobInit,
+    update_existing: bool,
+) -> Result<Option<Job>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let id = data.id.unwrap_or_else(Uuid::now_v7);
+    // A DO UPDATE whose WHERE doesn't match behaves just like DO NOTHING, so one query covers both modes
+    let job = sqlx::query_as!(
+        Job,
+        r#"
+INSERT INTO cyclotron_jobs
+    (
+        id,
+        team_id,
+        function_id,
+        created,
+        lock_id,
+        last_heartbeat,
+        janitor_touch_count,
+        transition_count,
+        last_transition,
+        queue_name,
+        state,
+        scheduled,
+        priority,
+        vm_state,
+        metadata,
+        parameters,
+        blob,
+        at_most_once,
+        correlation_id,
+        parent_job_id,
+        reply_to
+    )
+SELECT
+    $1, $2, $3, NOW(), NULL, NULL, 0, 0, NOW(), $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15
+WHERE NOT EXISTS (
+    SELECT 1 FROM cyclotron_queues WHERE queue_name = $4 AND draining
+)
+ON CONFLICT (id) DO UPDATE SET
+    function_id = EXCLUDED.function_id,
+    queue_name = EXCLUDED.queue_name,
+    scheduled = EXCLUDED.scheduled,
+    priority = EXCLUDED.priority,
+    vm_state = EXCLUDED.vm_state,
+    metadata = EXCLUDED.metadata,
+    parameters = EXCLUDED.parameters,
+    blob = EXCLUDED.blob,
+    at_most_once = EXCLUDED.at_most_once,
+    correlation_id = EXCLUDED.correlation_id,
+    parent_job_id = EXCLUDED.parent_job_id,
+    reply_to = EXCLUDED.reply_to
+WHERE $16 AND cyclotron_jobs.state = 'available' AND cyclotron_jobs.team_id = EXCLUDED.team_id
+RETURNING
+    id,
+    team_id,
+    state as "state: JobState",
+    queue_name,
+    priority,
+    function_id,
+    created,
+    last_transition,
+    scheduled,
+    transition_count,
+    failed_attempts,
+    vm_state,
+    metadata,
+    parameters,
+    blob,
+    lock_id,
+    last_heartbeat,
+    lock_expires_at,
+    janitor_touch_count,
+    at_most_once,
+    correlation_id,
+    parent_job_id,
+    reply_to
+    "#,
+        id,
+        data.team_id,
+        data.function_id,
+        data.queue_name,
+        JobState::Available as _,
+        truncate_to_db_precision(data.scheduled),
+        data.priority,
+        data.vm_state,
+        data.metadata,
+        data.parameters,
+        data.blob,
+        data.at_most_once,
+        data.correlation_id,
+        data.parent_job_id,
+        data.reply_to,
+        update_existing
+    )
+    .fetch_optional(executor)
     .await?;
 
-    Ok(id)
+    Ok(job)
 }
 
-pub async fn bulk_create_jobs<'c, E>(executor: E, jobs: &[JobInit]) -> Result<Vec<Uuid>, QueueError>
+// Inserting a job with a client supplied id that's already taken violates the primary key
+fn duplicate_id_or(e: sqlx::Error, id: Uuid) -> QueueError {
+    match &e {
+        sqlx::Error::Database(db) if db.is_unique_violation() => QueueError::DuplicateJobId(id),
+        _ => e.into(),
+    }
+}
+
+// Inserts a vec of jobs, returning them as they were inserted, in the order they were passed in. If any of
+// the jobs are for a queue that is draining, none of them are inserted, and an empty vec is returned - use
+// `first_draining_queue` to find out which queue blocked the insert. Jobs with client supplied ids keep them,
+// but unlike `create_job`, a taken id fails the batch with a plain sqlx error, since we can't tell which one it was.
+pub async fn bulk_create_jobs<'c, E>(executor: E, jobs: &[JobInit]) -> Result<Vec<Job>, QueueError>
 where
     E: sqlx::Executor<'c, Database = sqlx::Postgres>,
 {
-    let now = Utc::now();
+    let now = truncate_to_db_precision(Utc::now());
     // Flatten these jobs into a series of vecs of arguments PG can unnest
     let mut ids = Vec::with_capacity(jobs.len());
     let mut team_ids = Vec::with_capacity(jobs.len());
@@ -77,9 +257,13 @@ where
     let mut metadatas = Vec::with_capacity(jobs.len());
     let mut parameters = Vec::with_capacity(jobs.len());
     let mut blob = Vec::with_capacity(jobs.len());
+    let mut at_most_once = Vec::with_capacity(jobs.len());
+    let mut correlation_ids = Vec::with_capacity(jobs.len());
+    let mut parent_job_ids = Vec::with_capacity(jobs.len());
+    let mut reply_tos = Vec::with_capacity(jobs.len());
 
     for d in jobs {
-        ids.push(Uuid::now_v7());
+        ids.push(d.id.unwrap_or_else(Uuid::now_v7));
         team_ids.push(d.team_id);
         function_ids.push(d.function_id);
         created_at.push(now);
@@ -90,16 +274,21 @@ where
         last_transitions.push(now);
         queue_names.push(d.queue_name.clone());
         states.push(JobState::Available);
-        scheduleds.push(d.scheduled);
+        scheduleds.push(truncate_to_db_precision(d.scheduled));
         priorities.push(d.priority);
         vm_states.push(d.vm_state.clone());
         metadatas.push(d.metadata.clone());
         parameters.push(d.parameters.clone());
         blob.push(d.blob.clone());
+        at_most_once.push(d.at_most_once);
+        correlation_ids.push(d.correlation_id);
+        parent_job_ids.push(d.parent_job_id);
+        reply_tos.push(d.reply_to.clone());
     }
 
-    // Using the "unnest" function to turn an array of rows into a set of rows
-    sqlx::query(
+    // Using the "unnest" function to turn an array of rows into a set of rows. We do the draining check
+    // in the same statement as the insert, so a queue can't start draining halfway through a batch.
+    let mut inserted: Vec<Job> = sqlx::query_as(
         r#"
 INSERT INTO cyclotron_jobs
     (
@@ -119,7 +308,11 @@ INSERT INTO cyclotron_jobs
         vm_state,
         metadata,
         parameters,
-        blob
+        blob,
+        at_most_once,
+        correlation_id,
+        parent_job_id,
+        reply_to
     )
 SELECT *
 FROM UNNEST(
@@ -139,8 +332,39 @@ FROM UNNEST(
         $14,
         $15,
         $16,
-        $17
+        $17,
+        $18,
+        $19,
+        $20,
+        $21
     )
+WHERE NOT EXISTS (
+    SELECT 1 FROM cyclotron_queues WHERE draining AND queue_name = ANY($10)
+)
+RETURNING
+    id,
+    team_id,
+    state,
+    queue_name,
+    priority,
+    function_id,
+    created,
+    last_transition,
+    scheduled,
+    transition_count,
+    failed_attempts,
+    vm_state,
+    metadata,
+    parameters,
+    blob,
+    lock_id,
+    last_heartbeat,
+    lock_expires_at,
+    janitor_touch_count,
+    at_most_once,
+    correlation_id,
+    parent_job_id,
+    reply_to
 "#,
     )
     .bind(&ids)
@@ -160,8 +384,751 @@ FROM UNNEST(
     .bind(metadatas)
     .bind(parameters)
     .bind(blob)
+    .bind(at_most_once)
+    .bind(correlation_ids)
+    .bind(parent_job_ids)
+    .bind(reply_tos)
+    .fetch_all(executor)
+    .await?;
+
+    // RETURNING makes no promises about ordering, so put the jobs back in the order they were passed in
+    let positions: HashMap<Uuid, usize> = ids.iter().enumerate().map(|(i, id)| (*id, i)).collect();
+    inserted.sort_by_key(|job| positions[&job.id]);
+
+    Ok(inserted)
+}
+
+// Returns the jobs matching the query, oldest first. Like dequeue, this skips the VM state, since it can be large.
+pub async fn query_jobs<'c, E>(executor: E, query: &JobQuery) -> Result<Vec<Job>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let mut builder = QueryBuilder::new(
+        r#"
+SELECT
+    id,
+    team_id,
+    state,
+    queue_name,
+    priority,
+    function_id,
+    created,
+    last_transition,
+    scheduled,
+    transition_count,
+    failed_attempts,
+    NULL::bytea as vm_state,
+    metadata,
+    parameters,
+    blob,
+    lock_id,
+    last_heartbeat,
+    lock_expires_at,
+    janitor_touch_count,
+    at_most_once,
+    correlation_id,
+    parent_job_id,
+    reply_to
+FROM cyclotron_jobs
+WHERE TRUE"#,
+    );
+
+    push_query_filters(&mut builder, query);
+
+    builder.push(" ORDER BY created ASC, id ASC");
+
+    if let Some(limit) = query.effective_limit() {
+        builder.push(" LIMIT ");
+        builder.push_bind(limit as i64);
+    }
+
+    Ok(builder.build_query_as().fetch_all(executor).await?)
+}
+
+// As `query_jobs`, but returning only the summary columns - none of the payloads are selected
+pub async fn query_job_summaries<'c, E>(
+    executor: E,
+    query: &JobQuery,
+) -> Result<Vec<JobSummary>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    Ok(job_summaries_query(query)
+        .build_query_as()
+        .fetch_all(executor)
+        .await?)
+}
+
+// The query `query_job_summaries` runs
+pub fn job_summaries_query(query: &JobQuery) -> QueryBuilder<'_, Postgres> {
+    let mut builder = QueryBuilder::new(
+        r#"
+SELECT
+    id,
+    team_id,
+    function_id,
+    queue_name,
+    state,
+    priority,
+    scheduled,
+    created,
+    transition_count
+FROM cyclotron_jobs
+WHERE TRUE"#,
+    );
+
+    push_query_filters(&mut builder, query);
+
+    builder.push(" ORDER BY created ASC, id ASC");
+
+    if let Some(limit) = query.effective_limit() {
+        builder.push(" LIMIT ");
+        builder.push_bind(limit as i64);
+    }
+
+    builder
+}
+
+// Appends the conditions for all the filters set on a query, to a builder whose query ends in a WHERE clause
+fn push_query_filters<'q>(builder: &mut QueryBuilder<'q, Postgres>, query: &'q JobQuery) {
+    if let Some(queue_name) = &query.queue_name {
+        builder.push(" AND queue_name = ");
+        builder.push_bind(queue_name);
+    }
+
+    if let Some(team_id) = query.team_id {
+        builder.push(" AND team_id = ");
+        builder.push_bind(team_id);
+    }
+
+    if let Some(function_id) = query.function_id {
+        builder.push(" AND function_id = ");
+        builder.push_bind(function_id);
+    }
+
+    if !query.function_ids.is_empty() {
+        let ids: Vec<Uuid> = query.function_ids.iter().flatten().copied().collect();
+        builder.push(" AND (function_id = ANY(");
+        builder.push_bind(ids);
+        builder.push(")");
+        if query.function_ids.contains(&None) {
+            builder.push(" OR function_id IS NULL");
+        }
+        builder.push(")");
+    }
+
+    if let Some(is_null) = query.function_id_is_null {
+        builder.push(if is_null {
+            " AND function_id IS NULL"
+        } else {
+            " AND function_id IS NOT NULL"
+        });
+    }
+
+    if !query.exclude_function_ids.is_empty() {
+        builder.push(" AND (function_id IS NULL OR function_id <> ALL(");
+        builder.push_bind(&query.exclude_function_ids);
+        builder.push("))");
+    }
+
+    if !query.states.is_empty() {
+        builder.push(" AND state = ANY(");
+        builder.push_bind(&query.states);
+        builder.push(")");
+    }
+
+    if !query.exclude_states.is_empty() {
+        builder.push(" AND state <> ALL(");
+        builder.push_bind(&query.exclude_states);
+        builder.push(")");
+    }
+
+    if let Some(lock_id) = query.lock_id {
+        builder.push(" AND lock_id = ");
+        builder.push_bind(lock_id);
+    }
+
+    if let Some(locked) = query.locked {
+        builder.push(if locked {
+            " AND lock_id IS NOT NULL"
+        } else {
+            " AND lock_id IS NULL"
+        });
+    }
+
+    for (column, present) in [
+        ("vm_state", query.has_vm_state),
+        ("blob", query.has_blob),
+        ("parameters", query.has_parameters),
+    ] {
+        if let Some(present) = present {
+            builder.push(format!(
+                " AND {column} IS {}NULL",
+                if present { "NOT " } else { "" }
+            ));
+        }
+    }
+
+    if let Some(min_touches) = query.min_janitor_touches {
+        builder.push(" AND janitor_touch_count >= ");
+        builder.push_bind(min_touches);
+    }
+
+    // These are plain bounds on the column, unrelated to whether a job is due yet
+    if let Some(after) = query.scheduled_after {
+        builder.push(" AND scheduled >= ");
+        builder.push_bind(after);
+    }
+
+    if let Some(before) = query.scheduled_before {
+        builder.push(" AND scheduled < ");
+        builder.push_bind(before);
+    }
+
+    if let Some(correlation_id) = query.correlation_id {
+        builder.push(" AND correlation_id = ");
+        builder.push_bind(correlation_id);
+    }
+
+    if let Some(parent_job_id) = query.parent_job_id {
+        builder.push(" AND parent_job_id = ");
+        builder.push_bind(parent_job_id);
+    }
+
+    if let Some(config) = &query.queue_config {
+        // A semi-join, rather than a join, so it can be used by queries that aren't SELECTs, like `remap_queue`
+        builder
+            .push(" AND queue_name IN (SELECT queue_name FROM cyclotron_queue_config WHERE TRUE");
+        if let Some(priority) = config.default_priority {
+            builder.push(" AND default_priority = ");
+            builder.push_bind(priority);
+        }
+        if let Some(has_delay) = config.has_default_delay {
+            builder.push(if has_delay {
+                " AND default_delay_ms IS NOT NULL"
+            } else {
+                " AND default_delay_ms IS NULL"
+            });
+        }
+        builder.push(")");
+    }
+
+    if let Some(search) = &query.search {
+        // Metadata and parameters are arbitrary bytes, so this is a byte-wise substring match, which works
+        // without knowing the shape of the data (or whether it's JSON at all)
+        let needle = search.as_bytes().to_vec();
+        builder.push(" AND (position(");
+        builder.push_bind(needle.clone());
+        builder.push(" in metadata) > 0 OR position(");
+        builder.push_bind(needle);
+        builder.push(" in parameters) > 0)");
+    }
+
+    if let Some(filter) = &query.filter {
+        builder.push(" AND ");
+        push_filter(builder, filter);
+    }
+}
+
+// Renders a filter as a single parenthesized condition, with every value bound rather than interpolated
+fn push_filter<'q>(builder: &mut QueryBuilder<'q, Postgres>, filter: &'q Filter) {
+    match filter {
+        Filter::And(filters) | Filter::Or(filters) => {
+            let (empty, joiner) = match filter {
+                Filter::And(_) => ("TRUE", " AND "),
+                _ => ("FALSE", " OR "),
+            };
+            builder.push("(");
+            if filters.is_empty() {
+                builder.push(empty);
+            }
+            for (i, filter) in filters.iter().enumerate() {
+                if i > 0 {
+                    builder.push(joiner);
+                }
+                push_filter(builder, filter);
+            }
+            builder.push(")");
+        }
+        Filter::Matches(query) => {
+            builder.push("(TRUE");
+            push_query_filters(builder, query);
+            builder.push(")");
+        }
+    }
+}
+
+// Moves the available and paused jobs in one queue, that match the filter if one is passed, to another queue,
+// returning how many were moved. Running jobs are left where they are, as are finished ones. The filter's limit
+// is ignored - every matching job is moved.
+pub async fn remap_queue<'c, E>(
+    executor: E,
+    from: &str,
+    to: &str,
+    filter: Option<&JobQuery>,
+) -> Result<u64, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let mut builder = QueryBuilder::new("UPDATE cyclotron_jobs SET queue_name = ");
+    builder.push_bind(to);
+    builder.push(" WHERE queue_name = ");
+    builder.push_bind(from);
+    builder.push(" AND state IN ('available', 'paused')");
+
+    if let Some(filter) = filter {
+        push_query_filters(&mut builder, filter);
+    }
+
+    Ok(builder.build().execute(executor).await?.rows_affected())
+}
+
+// Reschedules every available job matching the query (ignoring its limit) to a random time between now and
+// `spread` from now, each job drawing its own, returning how many were rescheduled. Jobs in any other state are
+// left alone, whatever the query's states say.
+pub async fn jitter_schedule<'c, E>(
+    executor: E,
+    query: &JobQuery,
+    spread: Duration,
+) -> Result<u64, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    // random() is evaluated per row, so every job gets its own offset
+    let mut builder = QueryBuilder::new(
+        "UPDATE cyclotron_jobs SET scheduled = NOW() + random() * INTERVAL '1 millisecond' * ",
+    );
+    builder.push_bind(spread.num_milliseconds().max(0) as f64);
+    builder.push(" WHERE state = 'available'");
+    push_query_filters(&mut builder, query);
+
+    Ok(builder.build().execute(executor).await?.rows_affected())
+}
+
+// Counts the jobs matching the query (ignoring its limit) by state. If `split_delayed` is set, available jobs
+// scheduled in the future are counted separately, under the state "delayed", which isn't a real job state -
+// they're still available as far as the queue is concerned, just not runnable yet.
+pub async fn state_counts<'c, E>(
+    executor: E,
+    query: &JobQuery,
+    split_delayed: bool,
+) -> Result<Vec<StateCount>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let mut builder =
+        QueryBuilder::new("SELECT CASE WHEN state = 'available' AND scheduled > NOW() AND ");
+    builder.push_bind(split_delayed);
+    builder.push(
+        r#" THEN 'delayed' ELSE state::text END AS state, COUNT(*) AS count
+FROM cyclotron_jobs
+WHERE TRUE"#,
+    );
+    push_query_filters(&mut builder, query);
+    builder.push(" GROUP BY 1 ORDER BY 1");
+
+    Ok(builder.build_query_as().fetch_all(executor).await?)
+}
+
+// Every queue with jobs in it, with its per-state counts, ordered by queue name
+pub async fn list_queues<'c, E>(executor: E) -> Result<Vec<QueueInfo>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let rows = sqlx::query!(
+        r#"
+SELECT queue_name, state::text AS "state!", COUNT(*) AS "count!"
+FROM cyclotron_jobs
+GROUP BY 1, 2
+ORDER BY 1, 2
+    "#
+    )
+    .fetch_all(executor)
+    .await?;
+
+    let mut queues: Vec<QueueInfo> = Vec::new();
+    for row in rows {
+        let count = StateCount {
+            state: row.state,
+            count: row.count,
+        };
+        match queues.last_mut() {
+            Some(queue) if queue.queue_name == row.queue_name => {
+                queue.total += count.count;
+                queue.states.push(count);
+            }
+            _ => queues.push(QueueInfo {
+                queue_name: row.queue_name,
+                total: count.count,
+                states: vec![count],
+            }),
+        }
+    }
+    Ok(queues)
+}
+
+// Returns the jobs with the passed ids, in no particular order. Ids that don't match a job are skipped. Like
+// `query_jobs`, this skips the VM state.
+pub async fn get_jobs<'c, E>(executor: E, ids: &[Uuid]) -> Result<Vec<Job>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    Ok(sqlx::query_as!(
+        Job,
+        r#"
+SELECT
+    id,
+    team_id,
+    state as "state: JobState",
+    queue_name,
+    priority,
+    function_id,
+    created,
+    last_transition,
+    scheduled,
+    transition_count,
+    failed_attempts,
+    NULL::bytea as vm_state,
+    metadata,
+    parameters,
+    blob,
+    lock_id,
+    last_heartbeat,
+    lock_expires_at,
+    janitor_touch_count,
+    at_most_once,
+    correlation_id,
+    parent_job_id,
+    reply_to
+FROM cyclotron_jobs
+WHERE id = ANY($1)
+    "#,
+        ids
+    )
+    .fetch_all(executor)
+    .await?)
+}
+
+// Takes a lock, held until the end of the current transaction, on bounded inserts into a queue. Callers checking
+// a queue's depth before inserting into it take this first, so two of them can't both see room for one more job
+// and then both insert. Inserts that don't check the depth don't take it.
+pub async fn lock_queue_for_bounded_insert<'c, E>(
+    executor: E,
+    queue_name: &str,
+) -> Result<(), QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    // pg_advisory_xact_lock returns void, which sqlx can't decode, so it's selected from rather than selected
+    sqlx::query_scalar!(
+        r#"SELECT true AS "locked!" FROM pg_advisory_xact_lock(hashtext($1))"#,
+        queue_name
+    )
+    .fetch_one(executor)
+    .await?;
+
+    Ok(())
+}
+
+// Takes a lock, held until the end of the current transaction, on enqueueing jobs with the given dedup hash, so
+// two enqueues of the same job can't both find no duplicate and then both insert
+pub async fn lock_dedup_hash<'c, E>(executor: E, dedup_hash: &[u8]) -> Result<(), QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let mut key = [0; 8];
+    key.copy_from_slice(&dedup_hash[..8]);
+    // As above, selected from, since the lock function returns void
+    sqlx::query_scalar!(
+        r#"SELECT true AS "locked!" FROM pg_advisory_xact_lock($1)"#,
+        i64::from_be_bytes(key)
+    )
+    .fetch_one(executor)
+    .await?;
+
+    Ok(())
+}
+
+// The most recent job with the given dedup hash created at or after `since`, if there is one
+pub async fn find_duplicate<'c, E>(
+    executor: E,
+    dedup_hash: &[u8],
+    since: DateTime<Utc>,
+) -> Result<Option<Uuid>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    Ok(sqlx::query_scalar!(
+        "SELECT id FROM cyclotron_jobs WHERE dedup_hash = $1 AND created >= $2 ORDER BY created DESC LIMIT 1",
+        dedup_hash,
+        since
+    )
+    .fetch_optional(executor)
+    .await?)
+}
+
+// The number of available jobs in a queue, including ones scheduled in the future. Served by the partial dequeue
+// index, so this doesn't touch the table itself.
+pub async fn count_available_jobs<'c, E>(executor: E, queue_name: &str) -> Result<u64, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let count = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM cyclotron_jobs WHERE queue_name = $1 AND state = 'available'",
+        queue_name
+    )
+    .fetch_one(executor)
+    .await?;
+
+    Ok(count.unwrap_or(0) as u64)
+}
+
+// Returns the state of a job, locking it against changes until the end of the transaction - used when creating a
+// job blocked on it, so it can't complete between us checking it and blocking the new job.
+pub async fn lock_job_state<'c, E>(executor: E, id: Uuid) -> Result<Option<JobState>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    Ok(sqlx::query_scalar!(
+        r#"SELECT state as "state: JobState" FROM cyclotron_jobs WHERE id = $1 FOR SHARE"#,
+        id
+    )
+    .fetch_optional(executor)
+    .await?)
+}
+
+// Pauses a newly created job until the job it depends on completes. See the job dependencies migration for how
+// it's unblocked.
+pub async fn block_job_on<'c, E>(
+    executor: E,
+    job_id: Uuid,
+    dependency_id: Uuid,
+) -> Result<(), QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    sqlx::query!(
+        "UPDATE cyclotron_jobs SET state = 'paused', blocked_by = $2 WHERE id = $1",
+        job_id,
+        dependency_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+// Raises the priority of a job, and of everything it's (transitively) waiting on, to at least `priority`, so a
+// prerequisite in a lower priority queue doesn't hold up an urgent job waiting on it. Priorities are only ever
+// raised here, never lowered, and finished jobs are left alone. Returns how many jobs were raised.
+pub async fn raise_prerequisite_priority<'c, E>(
+    executor: E,
+    job_id: Uuid,
+    priority: i16,
+) -> Result<u64, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let result = sqlx::query!(
+        r#"
+WITH RECURSIVE prerequisites AS (
+    SELECT id, blocked_by FROM cyclotron_jobs WHERE id = $1
+    UNION
+    SELECT j.id, j.blocked_by FROM cyclotron_jobs j JOIN prerequisites p ON j.id = p.blocked_by
+)
+UPDATE cyclotron_jobs
+SET priority = $2
+FROM prerequisites
+WHERE cyclotron_jobs.id = prerequisites.id
+    AND cyclotron_jobs.priority > $2
+    AND cyclotron_jobs.state IN ('available', 'paused', 'running')
+    "#,
+        job_id,
+        priority
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected())
+}
+
+// Fails a job that hasn't started running, returning whether it was cancelled. Running jobs are held by a worker,
+// which would go on to release them, so they can't be cancelled here - nor can finished ones.
+pub async fn cancel_job<'c, E>(executor: E, job_id: Uuid) -> Result<bool, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let result = sqlx::query!(
+        r#"
+UPDATE cyclotron_jobs
+SET state = 'failed', transition_count = transition_count + 1, last_transition = NOW()
+WHERE id = $1 AND state IN ('available', 'paused')
+    "#,
+        job_id
+    )
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+// Sends an `EnqueueNotification` for a job on ENQUEUE_CHANNEL. Postgres holds notifications back until the
+// transaction they were sent in commits, and drops them if it rolls back, so run in the same transaction as the
+// job's insert, listeners hear about exactly the jobs that were committed, and only once they can see them.
+pub async fn notify_enqueued<'c, E>(executor: E, job: &Job) -> Result<(), QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    // Not a macro query, since pg_notify returns void, which sqlx can't decode
+    sqlx::query(
+        "SELECT pg_notify($1, json_build_object('id', $2::uuid, 'queue_name', $3::text)::text)",
+    )
+    .bind(ENQUEUE_CHANNEL)
+    .bind(job.id)
+    .bind(&job.queue_name)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+// Returns the name of one of the passed queues that is draining, if any are.
+pub async fn first_draining_queue<'c, E>(
+    executor: E,
+    queue_names: &[String],
+) -> Result<Option<String>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    Ok(sqlx::query_scalar!(
+        "SELECT queue_name FROM cyclotron_queues WHERE draining AND queue_name = ANY($1) LIMIT 1",
+        queue_names
+    )
+    .fetch_optional(executor)
+    .await?)
+}
+
+// Mark a queue as draining, after which any attempt to create jobs in it will fail. Jobs already in the queue
+// are unaffected, and can still be dequeued and run to completion.
+pub async fn drain_queue<'c, E>(executor: E, queue_name: &str) -> Result<(), QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    sqlx::query!(
+        r#"
+INSERT INTO cyclotron_queues (queue_name, draining, draining_since)
+VALUES ($1, TRUE, NOW())
+ON CONFLICT (queue_name) DO UPDATE
+SET draining = TRUE, draining_since = COALESCE(cyclotron_queues.draining_since, NOW())
+    "#,
+        queue_name
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn queue_is_draining<'c, E>(executor: E, queue_name: &str) -> Result<bool, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let draining = sqlx::query_scalar!(
+        "SELECT draining FROM cyclotron_queues WHERE queue_name = $1",
+        queue_name
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(draining.unwrap_or(false))
+}
+
+// Set a queue's enqueue defaults, replacing any it already had
+pub async fn set_queue_config<'c, E>(
+    executor: E,
+    queue_name: &str,
+    config: &QueueConfig,
+) -> Result<(), QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    sqlx::query!(
+        r#"
+INSERT INTO cyclotron_queue_config (queue_name, default_priority, default_delay_ms)
+VALUES ($1, $2, $3)
+ON CONFLICT (queue_name) DO UPDATE
+SET default_priority = EXCLUDED.default_priority, default_delay_ms = EXCLUDED.default_delay_ms
+    "#,
+        queue_name,
+        config.default_priority,
+        config.default_delay.map(|d| d.num_milliseconds())
+    )
     .execute(executor)
     .await?;
 
-    Ok(ids)
+    Ok(())
+}
+
+// A queue's enqueue defaults. A queue that's never been configured has none.
+pub async fn get_queue_config<'c, E>(
+    executor: E,
+    queue_name: &str,
+) -> Result<QueueConfig, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let row = sqlx::query!(
+        "SELECT default_priority, default_delay_ms FROM cyclotron_queue_config WHERE queue_name = $1",
+        queue_name
+    )
+    .fetch_optional(executor)
+    .await?;
+
+    Ok(row
+        .map(|r| QueueConfig {
+            default_priority: r.default_priority,
+            default_delay: r.default_delay_ms.map(Duration::milliseconds),
+        })
+        .unwrap_or_default())
+}
+
+// Turn recording of job state changes on or off, by enabling or disabling the trigger that does it. This takes a
+// brief lock on the jobs table, so it's for flipping occasionally, not per operation.
+pub async fn set_transition_audit<'c, E>(executor: E, enabled: bool) -> Result<(), QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    if enabled {
+        sqlx::query!("ALTER TABLE cyclotron_jobs ENABLE TRIGGER cyclotron_record_transitions")
+            .execute(executor)
+            .await?;
+    } else {
+        sqlx::query!("ALTER TABLE cyclotron_jobs DISABLE TRIGGER cyclotron_record_transitions")
+            .execute(executor)
+            .await?;
+    }
+    Ok(())
+}
+
+// Every recorded state change of a job, oldest first
+pub async fn job_transitions<'c, E>(
+    executor: E,
+    job_id: Uuid,
+) -> Result<Vec<JobTransition>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    Ok(sqlx::query_as!(
+        JobTransition,
+        r#"
+SELECT
+    job_id,
+    from_state as "from_state: JobState",
+    to_state as "to_state: JobState",
+    at,
+    lock_id
+FROM cyclotron_job_transitions
+WHERE job_id = $1
+ORDER BY id ASC
+    "#,
+        job_id
+    )
+    .fetch_all(executor)
+    .await?)
 }
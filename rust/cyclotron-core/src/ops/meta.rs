@@ -1,8 +1,10 @@
+use chrono::{DateTime, Utc};
 use sqlx::{postgres::PgQueryResult, PgPool};
 use uuid::Uuid;
 
 use crate::{
     error::{JobError, QueueError},
+    types::QueueLatency,
     DEAD_LETTER_QUEUE,
 };
 
@@ -28,6 +30,39 @@ where
         .collect())
 }
 
+// The time between creation and first dequeue, for jobs created in [from, to), grouped by function. Jobs that
+// have never been dequeued aren't counted.
+pub async fn time_in_queue<'c, E>(
+    executor: E,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<QueueLatency>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let res = sqlx::query_as!(
+        QueueLatency,
+        r#"
+SELECT
+    function_id,
+    COUNT(*) as "jobs!",
+    percentile_cont(0.5) WITHIN GROUP (ORDER BY EXTRACT(EPOCH FROM first_dequeued - created)::float8) as "p50_seconds!",
+    percentile_cont(0.95) WITHIN GROUP (ORDER BY EXTRACT(EPOCH FROM first_dequeued - created)::float8) as "p95_seconds!"
+FROM cyclotron_jobs
+WHERE first_dequeued IS NOT NULL
+    AND created >= $1
+    AND created < $2
+GROUP BY function_id
+    "#,
+        from,
+        to
+    )
+    .fetch_all(executor)
+    .await?;
+
+    Ok(res)
+}
+
 // Returns an InvalidLock error if the query run did not affect any rows.
 pub fn throw_if_no_rows(res: PgQueryResult, job: Uuid, lock: Uuid) -> Result<(), JobError> {
     if res.rows_affected() == 0 {
@@ -39,29 +74,68 @@ pub fn throw_if_no_rows(res: PgQueryResult, job: Uuid, lock: Uuid) -> Result<(),
 
 /// Run the latest cyclotron migrations. Panics if the migrations can't be run - failure to run migrations is purposefully fatal.
 pub async fn run_migrations(pool: &PgPool) {
+    // Migrations can rewrite or index the whole table, so they run without the pool's statement timeout. The
+    // timeout is only lifted for this connection, and RESET puts it back to what the pool connected with
+    let mut conn = pool
+        .acquire()
+        .await
+        .expect("Failed to acquire a connection to run migrations");
+    sqlx::query("SET statement_timeout = 0")
+        .execute(&mut *conn)
+        .await
+        .expect("Failed to lift the statement timeout for migrations");
     sqlx::migrate!("./migrations")
-        .run(pool)
+        .run(&mut *conn)
         .await
         .expect("Failed to run migrations");
+    sqlx::query("RESET statement_timeout")
+        .execute(&mut *conn)
+        .await
+        .expect("Failed to restore the statement timeout after migrations");
+}
+
+// The version of the schema this code expects, as recorded in `cyclotron_meta`. Bump this whenever a migration
+// bumps the version in the table.
+pub const SCHEMA_VERSION: i32 = 10;
+
+/// Checks the database's schema is the version this code expects, returning an IncompatibleSchema error if it's
+/// older (migrations haven't been run) or newer (something running later code has migrated it). A database
+/// without a recorded version at all predates versioning, and is treated as version 0.
+pub async fn check_compatibility(pool: &PgPool) -> Result<(), QueueError> {
+    let version = match sqlx::query_scalar!("SELECT schema_version FROM cyclotron_meta")
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(version) => version.unwrap_or(0),
+        // undefined_table - the migration adding the meta table hasn't been run
+        Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("42P01") => 0,
+        Err(e) => return Err(e.into()),
+    };
+
+    if version != SCHEMA_VERSION {
+        return Err(QueueError::IncompatibleSchema(version, SCHEMA_VERSION));
+    }
+    Ok(())
 }
 
 /// Move a job into the dead letter queue, also updating the metadata table. Note that this operation does not
 /// require a lock on the job. This is because the janitor needs to DLQ jobs that are stalled. The worker wrapper
 /// around this operation should check that the job is "known" (owned by it) before calling this function.
-pub async fn dead_letter<'c, E>(executor: E, job: Uuid, reason: &str) -> Result<(), QueueError>
-where
-    E: sqlx::Executor<'c, Database = sqlx::Postgres> + Clone,
-{
+pub async fn dead_letter(
+    connection: &mut sqlx::PgConnection,
+    job: Uuid,
+    reason: &str,
+) -> Result<(), QueueError> {
     // The first thing we do here is forcefully take the lock on this job, ensuring any subsequent worker
     // operations will fail - we do this because the janitor can move jobs out from under workers. We mark
     // the job as "running" and heartbeat so nothing else messes with it.
     let lock = Uuid::now_v7();
     let original_queue_name = sqlx::query_scalar!(
-        "UPDATE cyclotron_jobs SET state = 'running', lock_id = $1, last_heartbeat=NOW() WHERE id = $2 returning queue_name",
+        "UPDATE cyclotron_jobs SET state = 'running', lock_id = $1, last_heartbeat=NOW(), lock_expires_at = NULL WHERE id = $2 returning queue_name",
         lock,
         job
     )
-    .fetch_optional(executor.clone())
+    .fetch_optional(&mut *connection)
     .await?;
 
     let Some(original_queue_name) = original_queue_name else {
@@ -74,16 +148,17 @@ where
         job,
         original_queue_name,
         reason
-    ).execute(executor.clone()).await?;
+    ).execute(&mut *connection).await?;
 
     // And finally, we move the job to the dead letter queue. Jobs in the DLQ are "available", because if they ever
-    // get moved back to a queue, they should be re-run.
+    // get moved back to a queue, they should be re-run. Taking the lock above was bookkeeping, not a real transition,
+    // so this is the only one we count.
     sqlx::query!(
-        "UPDATE cyclotron_jobs SET state = 'available', lock_id = NULL, queue_name = $1 WHERE id = $2",
+        "UPDATE cyclotron_jobs SET state = 'available', lock_id = NULL, queue_name = $1, transition_count = transition_count + 1, last_transition = NOW() WHERE id = $2",
         DEAD_LETTER_QUEUE,
         job
     )
-    .execute(executor)
+    .execute(&mut *connection)
     .await?;
 
     Ok(())
@@ -1,122 +1,107 @@
 use chrono::{DateTime, Utc};
-use sqlx::{postgres::PgArguments, query::Query, Encode, QueryBuilder, Type};
+use sqlx::{postgres::PgArguments, query::Query, Encode, Postgres, QueryBuilder, Type};
 use uuid::Uuid;
 
 use crate::{
-    error::QueueError,
-    types::{Bytes, Job, JobState, JobUpdate},
+    error::{JobError, QueueError},
+    types::{Bytes, Job, JobProjection, JobState, JobUpdate},
 };
 
 use super::meta::throw_if_no_rows;
 
-// Dequeue the next job batch from the queue, skipping VM state since it can be large
+// Dequeue the next job batch from the queue, skipping VM state since it can be large. Every job in the
+// batch is locked with the passed lock id. If that lock id is already held by some other job, nothing is
+// dequeued - use `lock_id_in_use` to tell that apart from there being no jobs available.
 pub async fn dequeue_jobs<'c, E>(
     executor: E,
     queue: &str,
     max: usize,
+    lock_id: Uuid,
 ) -> Result<Vec<Job>, QueueError>
 where
     E: sqlx::Executor<'c, Database = sqlx::Postgres>,
 {
-    // Transient lock id. This could be a worker ID, or something, but for now it's totally random (per-batch)
-    let lock_id = Uuid::now_v7();
-    Ok(sqlx::query_as!(
+    let projection = JobProjection {
+        vm_state: false,
+        ..JobProjection::all()
+    };
+    dequeue_projected(executor, queue, max, lock_id, &projection).await
+}
+
+// Dequeue a batch of jobs, with their VM state. As above, nothing is dequeued if the lock id is already held.
+pub async fn dequeue_with_vm_state<'c, E>(
+    executor: E,
+    queue: &str,
+    max: usize,
+    lock_id: Uuid,
+) -> Result<Vec<Job>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    dequeue_projected(executor, queue, max, lock_id, &JobProjection::all()).await
+}
+
+// Dequeue a batch of jobs, fetching only the payload fields in the projection - the rest are returned as NULL.
+// As above, nothing is dequeued if the lock id is already held. Every dequeue above is this one query, so they
+// all lock jobs the same way, and only differ in what they fetch. It's in its own file, so tests/indexes.rs can
+// check postgres runs it off the due jobs index.
+pub async fn dequeue_projected<'c, E>(
+    executor: E,
+    queue: &str,
+    max: usize,
+    lock_id: Uuid,
+    projection: &JobProjection,
+) -> Result<Vec<Job>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    Ok(sqlx::query_file_as!(
         Job,
-        r#"
-WITH available AS (
-    SELECT
-        id,
-        state
-    FROM cyclotron_jobs
-    WHERE
-        state = 'available'::JobState
-        AND queue_name = $1
-        AND scheduled <= NOW()
-    ORDER BY
-        priority ASC,
-        scheduled ASC
-    LIMIT $2
-    FOR UPDATE SKIP LOCKED
-)
-UPDATE cyclotron_jobs
-SET
-    state = 'running'::JobState,
-    lock_id = $3,
-    last_heartbeat = NOW(),
-    last_transition = NOW(),
-    transition_count = transition_count + 1
-FROM available
-WHERE
-    cyclotron_jobs.id = available.id
-RETURNING
-    cyclotron_jobs.id,
-    team_id,
-    available.state as "state: JobState",
-    queue_name,
-    priority,
-    function_id,
-    created,
-    last_transition,
-    scheduled,
-    transition_count,
-    NULL::bytea as vm_state,
-    metadata,
-    parameters,
-    blob,
-    lock_id,
-    last_heartbeat,
-    janitor_touch_count
-    "#,
+        "src/ops/dequeue.sql",
         queue,
         max as i64,
-        lock_id
+        lock_id,
+        projection.vm_state,
+        projection.metadata,
+        projection.parameters,
+        projection.blob
     )
     .fetch_all(executor)
     .await?)
 }
 
-// Dequeue a batch of jobs, with their VM state.
-pub async fn dequeue_with_vm_state<'c, E>(
+// Locks one particular job, if it's available, returning it without its VM state. Returns None if the job isn't
+// available - it doesn't exist, is already running, or has finished - or if the lock id is already held, as with
+// the dequeues above. The state check and the lock are a single update, so of any number of concurrent claims
+// and dequeues, only one gets the job.
+pub async fn claim_job<'c, E>(
     executor: E,
-    queue: &str,
-    max: usize,
-) -> Result<Vec<Job>, QueueError>
+    job_id: Uuid,
+    lock_id: Uuid,
+) -> Result<Option<Job>, QueueError>
 where
     E: sqlx::Executor<'c, Database = sqlx::Postgres>,
 {
-    let lock_id = Uuid::now_v7();
     Ok(sqlx::query_as!(
         Job,
         r#"
-WITH available AS (
-    SELECT
-        id,
-        state
-    FROM cyclotron_jobs
-    WHERE
-        state = 'available'::JobState
-        AND queue_name = $1
-        AND scheduled <= NOW()
-    ORDER BY
-        priority ASC,
-        scheduled ASC
-    LIMIT $2
-    FOR UPDATE SKIP LOCKED
-)
 UPDATE cyclotron_jobs
 SET
     state = 'running'::JobState,
-    lock_id = $3,
+    lock_id = $2,
     last_heartbeat = NOW(),
+    lock_expires_at = NULL,
     last_transition = NOW(),
-    transition_count = transition_count + 1
-FROM available
+    transition_count = transition_count + 1,
+    first_dequeued = COALESCE(first_dequeued, NOW())
 WHERE
-    cyclotron_jobs.id = available.id
+    id = $1
+    AND state = 'available'::JobState
+    AND NOT EXISTS (SELECT 1 FROM cyclotron_jobs held WHERE held.lock_id = $2)
 RETURNING
-    cyclotron_jobs.id,
+    id,
     team_id,
-    available.state as "state: JobState",
+    state as "state: JobState",
     queue_name,
     priority,
     function_id,
@@ -124,19 +109,56 @@ RETURNING
     last_transition,
     scheduled,
     transition_count,
-    vm_state,
+    failed_attempts,
+    NULL::bytea as vm_state,
     metadata,
     parameters,
     blob,
     lock_id,
     last_heartbeat,
-    janitor_touch_count
+    lock_expires_at,
+    janitor_touch_count,
+    at_most_once,
+    correlation_id,
+    parent_job_id,
+    reply_to
     "#,
-        queue,
-        max as i64,
+        job_id,
         lock_id
     )
-    .fetch_all(executor)
+    .fetch_optional(executor)
+    .await?)
+}
+
+// Returns true if some job is currently locked with the passed lock id.
+pub async fn lock_id_in_use<'c, E>(executor: E, lock_id: Uuid) -> Result<bool, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let in_use = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM cyclotron_jobs WHERE lock_id = $1)",
+        lock_id
+    )
+    .fetch_one(executor)
+    .await?;
+
+    Ok(in_use.unwrap_or(false))
+}
+
+// The earliest scheduled time of any available job in the queue, whether or not it's due yet. None if the
+// queue has no available jobs. This is a single probe of the dequeue index.
+pub async fn next_scheduled<'c, E>(
+    executor: E,
+    queue_name: &str,
+) -> Result<Option<DateTime<Utc>>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    Ok(sqlx::query_scalar!(
+        "SELECT MIN(scheduled) FROM cyclotron_jobs WHERE queue_name = $1 AND state = 'available'",
+        queue_name
+    )
+    .fetch_one(executor)
     .await?)
 }
 
@@ -164,8 +186,58 @@ where
     Ok(res.vm_state)
 }
 
+// One payload column of a job, checking it's still held under the lock. Column names are pushed into the query
+// as-is, as with `set_helper`.
+pub async fn get_payload<'c, E>(
+    executor: E,
+    job_id: Uuid,
+    lock_id: Uuid,
+    column: &'static str,
+) -> Result<Option<Bytes>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let mut query = QueryBuilder::new("SELECT ");
+    query.push(column);
+    query.push(" FROM cyclotron_jobs WHERE id = ");
+    query.push_bind(job_id);
+    query.push(" AND lock_id = ");
+    query.push_bind(lock_id);
+
+    query
+        .build_query_scalar()
+        .fetch_optional(executor)
+        .await?
+        .ok_or(JobError::InvalidLock(lock_id, job_id).into())
+}
+
+pub async fn get_metadata<'c, E>(
+    executor: E,
+    job_id: Uuid,
+    lock_id: Uuid,
+) -> Result<Option<Bytes>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    struct Metadata {
+        metadata: Option<Bytes>,
+    }
+
+    let res = sqlx::query_as!(
+        Metadata,
+        "SELECT metadata FROM cyclotron_jobs WHERE id = $1 AND lock_id = $2",
+        job_id,
+        lock_id
+    )
+    .fetch_one(executor)
+    .await?;
+
+    Ok(res.metadata)
+}
+
 // NOTE - this clears the lock_id when the job state is set to anything other than "running", since that indicates
-// the worker is finished with the job. This means subsequent flushes with the same lock_id will fail.
+// the worker is finished with the job. This means subsequent flushes with the same lock_id will fail. The one
+// exception is a touch-only update (see `JobUpdate::touch_only`), which only moves the heartbeat.
 pub async fn flush_job<'c, E>(
     executor: E,
     job_id: Uuid,
@@ -174,7 +246,56 @@ pub async fn flush_job<'c, E>(
 where
     E: sqlx::Executor<'c, Database = sqlx::Postgres>,
 {
-    let job_returned = !matches!(updates.state, Some(JobState::Running));
+    let mut query = flush_query(job_id, updates);
+    assert_does_update(executor, job_id, updates.lock_id, query.build()).await?;
+    Ok(())
+}
+
+// As `flush_job`, but returning the job as the update left it, rather than failing if the lock doesn't match -
+// that returns None instead. Like dequeue, this skips the VM state.
+pub async fn flush_job_returning<'c, E>(
+    executor: E,
+    job_id: Uuid,
+    updates: &JobUpdate,
+) -> Result<Option<Job>, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let mut query = flush_query(job_id, updates);
+    query.push(
+        r#"
+RETURNING
+    id,
+    team_id,
+    state,
+    queue_name,
+    priority,
+    function_id,
+    created,
+    last_transition,
+    scheduled,
+    transition_count,
+    failed_attempts,
+    NULL::bytea as vm_state,
+    metadata,
+    parameters,
+    blob,
+    lock_id,
+    last_heartbeat,
+    lock_expires_at,
+    janitor_touch_count,
+    at_most_once,
+    correlation_id,
+    parent_job_id,
+    reply_to"#,
+    );
+    Ok(query.build_query_as().fetch_optional(executor).await?)
+}
+
+// The UPDATE `flush_job` runs for a set of updates
+fn flush_query(job_id: Uuid, updates: &JobUpdate) -> QueryBuilder<'_, Postgres> {
+    let job_returned =
+        !updates.is_touch_only() && !matches!(updates.state, Some(JobState::Running));
     let lock_id = updates.lock_id;
 
     let mut query = QueryBuilder::new("UPDATE cyclotron_jobs SET ");
@@ -183,6 +304,18 @@ where
     if let Some(state) = &updates.state {
         set_helper(&mut query, "state", state, needs_comma);
         needs_comma = true;
+        // Every state change is a transition - but the job is already running, so keeping it running isn't one
+        if *state != JobState::Running {
+            query.push(", transition_count = transition_count + 1, last_transition = NOW()");
+        }
+    }
+
+    if updates.failed_attempt {
+        if needs_comma {
+            query.push(",");
+        }
+        query.push("failed_attempts = failed_attempts + 1");
+        needs_comma = true;
     }
 
     if let Some(queue_name) = &updates.queue_name {
@@ -208,6 +341,15 @@ where
     if let Some(metadata) = &updates.metadata {
         set_helper(&mut query, "metadata", metadata, needs_comma);
         needs_comma = true;
+    } else if let Some(patch) = &updates.metadata_patch {
+        // Merged server side, so keys written by anyone else since we dequeued the job aren't clobbered
+        if needs_comma {
+            query.push(",");
+        }
+        query.push("metadata = cyclotron_merge_metadata(metadata, ");
+        query.push_bind(patch);
+        query.push(")");
+        needs_comma = true;
     }
 
     if let Some(parameters) = &updates.parameters {
@@ -221,7 +363,7 @@ where
     }
 
     if job_returned {
-        // If we're returning this job, clear the lock id and the heartbeat
+        // If we're returning this job, clear the lock id, the heartbeat and any lock deadline
         set_helper(&mut query, "lock_id", Option::<Uuid>::None, needs_comma);
         set_helper(
             &mut query,
@@ -229,6 +371,12 @@ where
             Option::<DateTime<Utc>>::None,
             true,
         );
+        set_helper(
+            &mut query,
+            "lock_expires_at",
+            Option::<DateTime<Utc>>::None,
+            true,
+        );
     } else {
         // Otherwise, flushing a job update indicates forward progress, so we update the heartbeat
         set_helper(&mut query, "last_heartbeat", Utc::now(), needs_comma);
@@ -238,14 +386,14 @@ where
     query.push_bind(job_id);
     query.push(" AND lock_id = ");
     query.push_bind(lock_id);
-
-    assert_does_update(executor, job_id, lock_id, query.build()).await?;
-    Ok(())
+    query
 }
 
+// Values are always bound as parameters, never interpolated, since things like queue names can come from users.
+// Column names are pushed into the query as-is, so they have to be static strings - never take one from input.
 fn set_helper<'args, T, DB>(
     query: &mut QueryBuilder<'args, DB>,
-    column_name: &str,
+    column_name: &'static str,
     value: T,
     needs_comma: bool,
 ) where
@@ -276,6 +424,81 @@ where
     assert_does_update(executor, job_id, lock_id, q).await
 }
 
+// Heartbeats a set of jobs, and pushes their lock deadline out to `new_deadline`, in a single query.
+// Returns an InvalidLock error for the first job whose lock didn't match, if any. Note that the
+// jobs whose locks did match are still updated in that case.
+pub async fn extend_locks<'c, E>(
+    executor: E,
+    jobs: &[(Uuid, Uuid)],
+    new_deadline: DateTime<Utc>,
+) -> Result<(), QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let (job_ids, lock_ids): (Vec<Uuid>, Vec<Uuid>) = jobs.iter().copied().unzip();
+
+    let updated = sqlx::query_scalar!(
+        r#"
+UPDATE cyclotron_jobs
+SET
+    last_heartbeat = NOW(),
+    lock_expires_at = $3
+FROM UNNEST($1::uuid[], $2::uuid[]) AS held(id, lock_id)
+WHERE
+    cyclotron_jobs.id = held.id
+    AND cyclotron_jobs.lock_id = held.lock_id
+RETURNING cyclotron_jobs.id
+    "#,
+        &job_ids,
+        &lock_ids,
+        new_deadline
+    )
+    .fetch_all(executor)
+    .await?;
+
+    if let Some((job_id, lock_id)) = jobs.iter().find(|(id, _)| !updated.contains(id)) {
+        return Err(JobError::InvalidLock(*lock_id, *job_id).into());
+    }
+
+    Ok(())
+}
+
+// Returns a set of running jobs to the queue in a single query, clearing their locks, as long as they're still
+// held under the given lock. Returns how many were returned.
+pub async fn force_release_jobs<'c, E>(
+    executor: E,
+    jobs: &[(Uuid, Uuid)],
+) -> Result<u64, QueueError>
+where
+    E: sqlx::Executor<'c, Database = sqlx::Postgres>,
+{
+    let (job_ids, lock_ids): (Vec<Uuid>, Vec<Uuid>) = jobs.iter().copied().unzip();
+
+    let result = sqlx::query!(
+        r#"
+UPDATE cyclotron_jobs
+SET
+    state = 'available',
+    lock_id = NULL,
+    last_heartbeat = NULL,
+    lock_expires_at = NULL,
+    transition_count = transition_count + 1,
+    last_transition = NOW()
+FROM UNNEST($1::uuid[], $2::uuid[]) AS held(id, lock_id)
+WHERE
+    cyclotron_jobs.id = held.id
+    AND cyclotron_jobs.lock_id = held.lock_id
+    AND cyclotron_jobs.state = 'running'
+    "#,
+        &job_ids,
+        &lock_ids
+    )
+    .execute(executor)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
 // Simple wrapper, that just executes a query and returns an InvalidLock error if no rows were affected.
 async fn assert_does_update<'c, E>(
     executor: E,
@@ -0,0 +1,76 @@
+use std::{collections::HashMap, sync::Mutex, time::Instant};
+
+use chrono::Duration;
+
+use crate::config::TeamRateLimits;
+
+// A token bucket per team, refilled at the team's rate. Buckets are created full the first time a team's
+// job is seen, and are local to this process - each worker applies its limits independently, so the rate
+// a team is dispatched at overall is its limit times the number of workers pulling from its queues.
+pub struct TeamRateLimiter {
+    limits: TeamRateLimits,
+    buckets: Mutex<HashMap<i32, Bucket>>,
+    clock: Box<dyn Fn() -> Instant + Send + Sync>,
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TeamRateLimiter {
+    pub fn new(limits: TeamRateLimits) -> Self {
+        Self::with_clock(limits, Instant::now)
+    }
+
+    // As `new`, but buckets are refilled according to `clock` rather than the system clock, so tests can
+    // control how much time passes
+    pub fn with_clock(
+        limits: TeamRateLimits,
+        clock: impl Fn() -> Instant + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            limits,
+            buckets: Default::default(),
+            clock: Box::new(clock),
+        }
+    }
+
+    // Takes a token from the team's bucket if there is one, or returns how long until there will be
+    pub fn try_acquire(&self, team_id: i32) -> Result<(), Duration> {
+        let Some(limit) = self
+            .limits
+            .teams
+            .get(&team_id)
+            .or(self.limits.default.as_ref())
+        else {
+            return Ok(());
+        };
+        let burst = limit.burst.unwrap_or(1.0).max(1.0);
+
+        let now = (self.clock)();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(team_id).or_insert(Bucket {
+            tokens: burst,
+            last_refill: now,
+        });
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.per_second).min(burst);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+
+        // A team limited to nothing gets checked again every second, in case the limit is lifted
+        let wait_seconds = if limit.per_second > 0.0 {
+            (1.0 - bucket.tokens) / limit.per_second
+        } else {
+            1.0
+        };
+        Err(Duration::microseconds(
+            (wait_seconds * 1_000_000.0).ceil() as i64
+        ))
+    }
+}
@@ -0,0 +1,321 @@
+use std::{collections::HashMap, fmt::Display, future::Future, panic::AssertUnwindSafe};
+
+use chrono::{Duration, Utc};
+use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
+use serde_json::json;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, warn};
+use uuid::Uuid;
+
+use crate::{
+    config::{RetryPolicy, RunConfig},
+    types::panic_count,
+    Bytes, Job, JobError, JobState, QueueError, Worker, PANIC_COUNT_KEY,
+};
+
+// What happened over the course of a `Worker::run`, returned once it's shut down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShutdownReport {
+    pub completed: usize, // Jobs whose handler finished, and which were released according to its result
+    pub force_released: usize, // Jobs still running when the shutdown timeout elapsed, returned to the queue unfinished
+    pub timed_out_after: Option<Duration>, // If the shutdown timed out, the timeout that elapsed
+}
+
+impl Worker {
+    /// Process jobs from a queue until `shutdown` resolves. Jobs are dequeued as capacity allows,
+    /// and each is passed to `handler`, being heartbeated while the handler runs. If the handler
+    /// returns Ok, the job is completed, with the handler's output, if it returned one, stored as
+    /// the job's blob - returning None leaves the blob as it was. If it returns an error, the job is
+    /// retried with backoff, or failed once it's used up its attempts, with the error recorded in
+    /// its retry history (see `Job::retry_history`). If the handler panics, the panic is caught,
+    /// and the job retried until it's panicked `max_panics` times, after which it's dead-lettered.
+    ///
+    /// Each handler is also passed a `CancellationToken`, which is cancelled if the job should stop
+    /// early: if a heartbeat finds the worker has lost its lock on the job (e.g. because the
+    /// janitor reclaimed it), or the shutdown times out. Cancellation is cooperative - handlers are
+    /// left to wind down and return, rather than being dropped part way through, and whatever they
+    /// return for a cancelled job is discarded, since the job's no longer ours to release.
+    /// Once `shutdown` resolves, no new jobs are dequeued, and this returns after the in-flight
+    /// ones are finished and flushed. If they're still running once the config's
+    /// `shutdown_timeout` has elapsed, their tokens are cancelled, and the jobs are returned to the
+    /// queue straight away, in one update, so another worker can pick them up without waiting for
+    /// the janitor. This still waits for the cancelled handlers to return before it does.
+    ///
+    /// Errors dequeuing or releasing jobs are logged rather than returned, so a database blip
+    /// doesn't stop the loop - the janitor will pick up any jobs that were lost as a result.
+    pub async fn run<F, Fut, E, S>(
+        &self,
+        queue: &str,
+        config: RunConfig,
+        shutdown: S,
+        handler: F,
+    ) -> Result<ShutdownReport, QueueError>
+    where
+        F: Fn(Job, CancellationToken) -> Fut,
+        Fut: Future<Output = Result<Option<Bytes>, E>>,
+        E: Display,
+        S: Future<Output = ()>,
+    {
+        let poll_interval = config.poll_interval.to_std().unwrap_or_default();
+        let shutdown = shutdown.fuse();
+        tokio::pin!(shutdown);
+
+        let mut in_flight = FuturesUnordered::new();
+        // Cancelling this cancels every in-flight handler's token
+        let cancel = CancellationToken::new();
+        // The lock each in-flight job is held under, so they can be released if the shutdown times out
+        let mut held = HashMap::new();
+        let mut shutting_down = false;
+        let mut deadline = None;
+        let mut report = ShutdownReport {
+            completed: 0,
+            force_released: 0,
+            timed_out_after: None,
+        };
+
+        loop {
+            if !shutting_down && in_flight.len() < config.concurrency {
+                let limit = config.concurrency - in_flight.len();
+                match self.dequeue_jobs(queue, limit).await {
+                    Ok(jobs) => {
+                        for job in jobs {
+                            if let Some(lock_id) = job.lock_id {
+                                held.insert(job.id, lock_id);
+                            }
+                            in_flight.push(self.process(
+                                job,
+                                &config,
+                                &handler,
+                                cancel.child_token(),
+                            ));
+                        }
+                    }
+                    Err(e) => error!("Error dequeuing jobs from {}: {:?}", queue, e),
+                }
+            }
+
+            if shutting_down && in_flight.is_empty() {
+                break;
+            }
+
+            let timed_out = async move {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            // Wait for something to finish, or, if we have room for more work, until it's time to poll again
+            let has_capacity = in_flight.len() < config.concurrency;
+            tokio::select! {
+                Some((job_id, finished)) = in_flight.next(), if !in_flight.is_empty() => {
+                    held.remove(&job_id);
+                    if finished {
+                        report.completed += 1;
+                    }
+                }
+                _ = &mut shutdown, if !shutting_down => {
+                    shutting_down = true;
+                    deadline = config
+                        .shutdown_timeout
+                        .map(|t| tokio::time::Instant::now() + t.to_std().unwrap_or_default());
+                }
+                _ = tokio::time::sleep(poll_interval), if has_capacity && !shutting_down => {}
+                _ = timed_out => {
+                    report.timed_out_after = config.shutdown_timeout;
+                    break;
+                }
+            }
+        }
+
+        if report.timed_out_after.is_none() {
+            return self.force_flush().await.map(|()| report);
+        }
+
+        // Tell the unfinished handlers to stop. Anything they'd already released is flushed first, so only the
+        // jobs we still hold are force-released - once their tokens are cancelled, handlers finishing can't
+        // release them any more
+        cancel.cancel();
+        let flushed = self.force_flush().await;
+        let held: Vec<_> = held.into_iter().collect();
+        let force_released = self.force_release_jobs(&held).await;
+        // The cancelled handlers are waited for even if the force release failed. Any whose release was just
+        // flushed have finished their job, so they count as completed, rather than force-released
+        while let Some((_, finished)) = in_flight.next().await {
+            if finished {
+                report.completed += 1;
+            }
+        }
+        report.force_released = force_released? as usize;
+        warn!(
+            "Shutdown timed out with {} jobs still running, returned them to {}",
+            report.force_released, queue
+        );
+        flushed.map(|()| report)
+    }
+
+    // Runs the handler for a single job, heartbeating it until the handler finishes, and then
+    // releases it according to the result, unless it was cancelled along the way. Returns the
+    // job's id, and whether the handler finished without being cancelled
+    async fn process<F, Fut, E>(
+        &self,
+        job: Job,
+        config: &RunConfig,
+        handler: &F,
+        cancel: CancellationToken,
+    ) -> (Uuid, bool)
+    where
+        F: Fn(Job, CancellationToken) -> Fut,
+        Fut: Future<Output = Result<Option<Bytes>, E>>,
+        E: Display,
+    {
+        let job_id = job.id;
+        let lock_id = job.lock_id;
+        let attempt = job.attempt();
+        let panics = panic_count(job.metadata.as_deref());
+        let function_id = job.function_id;
+
+        // The handler is called inside the future, so a panic in the call itself is caught too
+        let token = cancel.clone();
+        let work = AssertUnwindSafe(async move { handler(job, token).await }).catch_unwind();
+        tokio::pin!(work);
+        let mut heartbeat = tokio::time::interval(
+            config
+                .heartbeat_interval
+                .to_std()
+                .unwrap_or(std::time::Duration::from_secs(5)),
+        );
+        heartbeat.tick().await; // The first tick completes immediately, and dequeueing counts as a heartbeat
+
+        let started = std::time::Instant::now();
+        let result = loop {
+            tokio::select! {
+                result = &mut work => break result,
+                _ = heartbeat.tick(), if !cancel.is_cancelled() => match self.heartbeat(job_id).await {
+                    Ok(()) => {}
+                    // We've lost the job, most likely because the janitor decided it had stalled and returned it
+                    // to the queue, so someone else may already be running it. The handler is told to stop, rather
+                    // than left to finish work we could no longer report, and we wait for it to
+                    Err(QueueError::JobError(
+                        JobError::InvalidLock(..) | JobError::UnknownJobId(_),
+                    )) => {
+                        warn!("Lost the lock on job {}, cancelling its handler", job_id);
+                        self.forget_job(job_id, lock_id);
+                        cancel.cancel();
+                    }
+                    Err(e) => warn!("Error heartbeating job {}: {:?}", job_id, e),
+                },
+            }
+        };
+        // Whoever cancelled the job has already dealt with it - it's been forgotten, or force-released
+        if cancel.is_cancelled() {
+            return (job_id, false);
+        }
+        self.record_processing(
+            job_id,
+            function_id,
+            started.elapsed(),
+            config.slow_job_factor,
+        );
+
+        let released = match result {
+            Ok(result) => {
+                self.finish(job_id, function_id, attempt, result, config)
+                    .await
+            }
+            Err(_) => {
+                error!("Handler panicked on job {}", job_id);
+                self.finish_panicked(job_id, function_id, panics, config)
+                    .await
+            }
+        };
+        if let Err(e) = released {
+            error!("Error releasing job {}: {:?}", job_id, e);
+        }
+        (job_id, true)
+    }
+
+    // The policy a function's jobs are retried under - its own, if the worker has one for it, or the config's default
+    fn retry_policy(&self, function_id: Option<Uuid>, config: &RunConfig) -> RetryPolicy {
+        function_id
+            .and_then(|id| self.retry_policies.get(&id))
+            .copied()
+            .unwrap_or_else(|| config.default_retry_policy())
+    }
+
+    // A job whose handler panics is retried, with the panic counted in its metadata, until it's panicked
+    // `max_panics` times, at which point it's dead-lettered, so one bad job can't take up worker time forever.
+    // If the job's metadata isn't a JSON object, we can't count its panics, so it's dead-lettered straight away.
+    async fn finish_panicked(
+        &self,
+        job_id: Uuid,
+        function_id: Option<Uuid>,
+        previous_panics: Option<u32>,
+        config: &RunConfig,
+    ) -> Result<(), QueueError> {
+        let Some(panics) = previous_panics.map(|p| p + 1) else {
+            return self
+                .dead_letter(
+                    job_id,
+                    "handler panicked, and the job's metadata can't record it",
+                )
+                .await;
+        };
+        if panics >= config.max_panics {
+            return self
+                .dead_letter(job_id, &format!("handler panicked {} times", panics))
+                .await;
+        }
+
+        self.merge_metadata(job_id, json!({ PANIC_COUNT_KEY: panics }))?;
+        self.set_state(job_id, JobState::Available)?;
+        let delay = self.retry_policy(function_id, config).delay(job_id, panics);
+        self.set_scheduled_at(job_id, Utc::now() + delay)?;
+        self.release_job(job_id, None).await?;
+        Ok(())
+    }
+
+    async fn finish<E: Display>(
+        &self,
+        job_id: Uuid,
+        function_id: Option<Uuid>,
+        attempt: u32,
+        result: Result<Option<Bytes>, E>,
+        config: &RunConfig,
+    ) -> Result<(), QueueError> {
+        match result {
+            Ok(output) => {
+                // The handler's output, if it has one, is kept in the job's blob
+                if let Some(output) = output {
+                    self.set_blob(job_id, Some(output))?;
+                }
+                self.set_state(job_id, JobState::Completed)?
+            }
+            Err(e) if attempt < config.max_attempts => {
+                // The same delay `Job::next_retry_at` previews
+                let backoff = self
+                    .retry_policy(function_id, config)
+                    .delay(job_id, attempt);
+                match self
+                    .retry_with_backoff(job_id, &e.to_string(), backoff)
+                    .await
+                {
+                    // If the job's metadata isn't a JSON object, we can't record the failure, but we can still retry it
+                    Err(QueueError::JobError(JobError::InvalidMetadata(_))) => {
+                        self.stage_retry(job_id, backoff, self.retry_mode(job_id)?)?
+                    }
+                    res => res?,
+                }
+            }
+            Err(e) => match self.fail_job(job_id, &e.to_string()).await {
+                Err(QueueError::JobError(JobError::InvalidMetadata(_))) => {
+                    self.set_state(job_id, JobState::Failed)?
+                }
+                res => res?,
+            },
+        }
+
+        self.release_job(job_id, None).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,276 @@
+#[cfg(feature = "test-util")]
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+use chrono::Duration;
+#[cfg(feature = "test-util")]
+use chrono::Utc;
+use uuid::Uuid;
+
+#[cfg(feature = "test-util")]
+use crate::{
+    types::{merge_metadata, truncate_to_db_precision},
+    JobError, JobState,
+};
+use crate::{Job, JobInit, JobUpdate, QueueError};
+
+// The core queue operations - enqueue, dequeue, update and the janitor's cleanup - behind a trait, so code built
+// on them can be tested against `MemoryStore`, behind the "test-util" feature, rather than a real database.
+// `QueueManager` implements this against postgres. Both implementations are held to the same observable behaviour,
+// see tests/store.rs.
+#[async_trait]
+pub trait JobStore: Send + Sync {
+    async fn create_job(&self, init: JobInit) -> Result<Job, QueueError>;
+
+    // Locks and returns up to `limit` runnable jobs from the queue, in dequeue order. As with
+    // `Worker::dequeue_jobs`, the VM state isn't returned, and the returned jobs are in the state they were
+    // in before being dequeued, not running.
+    async fn dequeue_jobs(&self, queue: &str, limit: usize) -> Result<Vec<Job>, QueueError>;
+
+    // Applies an update to a job, failing with InvalidLock if the update's lock isn't the one the job is held
+    // under. Any update that doesn't keep the job running releases the lock, unless it's touch-only.
+    async fn flush_job(&self, job_id: Uuid, update: JobUpdate) -> Result<(), QueueError>;
+
+    async fn heartbeat(&self, job_id: Uuid, lock_id: Uuid) -> Result<(), QueueError>;
+
+    // Without its VM state, as with `QueueManager::get_job`
+    async fn get_job(&self, id: Uuid) -> Result<Option<Job>, QueueError>;
+
+    // Returns stalled jobs to the queue, or fails them if they're at-most-once, returning how many were reset
+    async fn reset_stalled_jobs(&self, timeout: Duration) -> Result<u64, QueueError>;
+
+    // Returns how many jobs were deleted
+    async fn delete_completed_and_failed_jobs(&self) -> Result<u64, QueueError>;
+}
+
+// An in-memory job store, for tests. Bounded like a shard - once `depth_limit` jobs are runnable, creating
+// another fails with ShardFull - but otherwise mirrors the postgres queries in ops/, including their quirks.
+// Timestamps are truncated to the precision postgres stores, so jobs compare the same coming out of either.
+#[cfg(feature = "test-util")]
+pub struct MemoryStore {
+    jobs: Mutex<HashMap<Uuid, Job>>,
+    depth_limit: u64,
+}
+
+#[cfg(feature = "test-util")]
+impl MemoryStore {
+    pub fn new(depth_limit: u64) -> Self {
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            depth_limit,
+        }
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new(crate::config::DEFAULT_QUEUE_DEPTH_LIMIT)
+    }
+}
+
+#[cfg(feature = "test-util")]
+fn now() -> chrono::DateTime<Utc> {
+    truncate_to_db_precision(Utc::now())
+}
+
+// Jobs handed back by the store never have their VM state, matching the postgres queries
+#[cfg(feature = "test-util")]
+fn without_vm_state(job: &Job) -> Job {
+    Job {
+        vm_state: None,
+        ..job.clone()
+    }
+}
+
+#[cfg(feature = "test-util")]
+#[async_trait]
+impl JobStore for MemoryStore {
+    async fn create_job(&self, init: JobInit) -> Result<Job, QueueError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let now = now();
+        let available = jobs
+            .values()
+            .filter(|j| j.state == JobState::Available && j.scheduled <= now)
+            .count() as u64;
+        if available >= self.depth_limit {
+            return Err(QueueError::ShardFull(self.depth_limit));
+        }
+
+        let id = init.id.unwrap_or_else(Uuid::now_v7);
+        if jobs.contains_key(&id) {
+            return Err(QueueError::DuplicateJobId(id));
+        }
+
+        let job = Job {
+            id,
+            team_id: init.team_id,
+            function_id: init.function_id,
+            created: now,
+            lock_id: None,
+            last_heartbeat: None,
+            lock_expires_at: None,
+            janitor_touch_count: 0,
+            transition_count: 0,
+            failed_attempts: 0,
+            last_transition: now,
+            queue_name: init.queue_name,
+            state: JobState::Available,
+            priority: init.priority,
+            scheduled: truncate_to_db_precision(init.scheduled),
+            vm_state: init.vm_state,
+            metadata: init.metadata,
+            parameters: init.parameters,
+            blob: init.blob,
+            at_most_once: init.at_most_once,
+            correlation_id: init.correlation_id,
+            parent_job_id: init.parent_job_id,
+            reply_to: init.reply_to.clone(),
+        };
+        jobs.insert(id, job.clone());
+        // Creating a job is the one place the VM state is handed back
+        Ok(job)
+    }
+
+    async fn dequeue_jobs(&self, queue: &str, limit: usize) -> Result<Vec<Job>, QueueError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let now = now();
+
+        let mut runnable: Vec<_> = jobs
+            .values()
+            .filter(|j| {
+                j.state == JobState::Available && j.queue_name == queue && j.scheduled <= now
+            })
+            .map(|j| (j.ordering_key(), j.id))
+            .collect();
+        runnable.sort_unstable();
+
+        let lock_id = Uuid::now_v7();
+        let mut dequeued = Vec::with_capacity(limit.min(runnable.len()));
+        for (_, id) in runnable.into_iter().take(limit) {
+            let job = jobs.get_mut(&id).expect("job ids come from the map");
+            job.state = JobState::Running;
+            job.lock_id = Some(lock_id);
+            job.last_heartbeat = Some(now);
+            job.lock_expires_at = None;
+            job.last_transition = now;
+            job.transition_count += 1;
+
+            // The dequeue query returns the state the job was selected in, rather than the one it's set to
+            let mut returned = without_vm_state(job);
+            returned.state = JobState::Available;
+            dequeued.push(returned);
+        }
+
+        Ok(dequeued)
+    }
+
+    async fn flush_job(&self, job_id: Uuid, update: JobUpdate) -> Result<(), QueueError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = match jobs.get_mut(&job_id) {
+            Some(job) if job.lock_id == Some(update.lock_id) => job,
+            _ => return Err(JobError::InvalidLock(update.lock_id, job_id).into()),
+        };
+        let now = now();
+
+        let job_returned =
+            !update.is_touch_only() && !matches!(update.state, Some(JobState::Running));
+        if let Some(state) = update.state {
+            if state != JobState::Running {
+                job.transition_count += 1;
+                job.last_transition = now;
+            }
+            job.state = state;
+        }
+        if update.failed_attempt {
+            job.failed_attempts += 1;
+        }
+        if let Some(queue_name) = update.queue_name {
+            job.queue_name = queue_name;
+        }
+        if let Some(priority) = update.priority {
+            job.priority = priority;
+        }
+        if let Some(scheduled) = update.scheduled {
+            job.scheduled = truncate_to_db_precision(scheduled);
+        }
+        if let Some(vm_state) = update.vm_state {
+            job.vm_state = vm_state;
+        }
+        if let Some(metadata) = update.metadata {
+            job.metadata = metadata;
+        } else if let Some(patch) = &update.metadata_patch {
+            if let Some(merged) = merge_metadata(job.metadata.as_deref(), patch) {
+                job.metadata = Some(merged);
+            }
+        }
+        if let Some(parameters) = update.parameters {
+            job.parameters = parameters;
+        }
+        if let Some(blob) = update.blob {
+            job.blob = blob;
+        }
+
+        if job_returned {
+            job.lock_id = None;
+            job.last_heartbeat = None;
+            job.lock_expires_at = None;
+        } else {
+            job.last_heartbeat = Some(now);
+        }
+
+        Ok(())
+    }
+
+    async fn heartbeat(&self, job_id: Uuid, lock_id: Uuid) -> Result<(), QueueError> {
+        match self.jobs.lock().unwrap().get_mut(&job_id) {
+            Some(job) if job.lock_id == Some(lock_id) => {
+                job.last_heartbeat = Some(now());
+                Ok(())
+            }
+            _ => Err(JobError::InvalidLock(lock_id, job_id).into()),
+        }
+    }
+
+    async fn get_job(&self, id: Uuid) -> Result<Option<Job>, QueueError> {
+        Ok(self.jobs.lock().unwrap().get(&id).map(without_vm_state))
+    }
+
+    async fn reset_stalled_jobs(&self, timeout: Duration) -> Result<u64, QueueError> {
+        let now = now();
+        let oldest_valid_heartbeat = Utc::now() - timeout;
+        let mut reset = 0;
+        for job in self.jobs.lock().unwrap().values_mut() {
+            let stalled = match job.lock_expires_at {
+                Some(deadline) => deadline <= now,
+                None => {
+                    job.last_heartbeat.unwrap_or(oldest_valid_heartbeat) <= oldest_valid_heartbeat
+                }
+            };
+            if job.state != JobState::Running || !stalled {
+                continue;
+            }
+
+            job.state = if job.at_most_once {
+                JobState::Failed
+            } else {
+                JobState::Available
+            };
+            job.lock_id = None;
+            job.last_heartbeat = None;
+            job.lock_expires_at = None;
+            job.janitor_touch_count += 1;
+            job.transition_count += 1;
+            job.last_transition = now;
+            reset += 1;
+        }
+        Ok(reset)
+    }
+
+    async fn delete_completed_and_failed_jobs(&self) -> Result<u64, QueueError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let before = jobs.len();
+        jobs.retain(|_, j| !matches!(j.state, JobState::Completed | JobState::Failed));
+        Ok((before - jobs.len()) as u64)
+    }
+}
@@ -0,0 +1,92 @@
+use std::fmt::Write;
+
+use chrono::{DateTime, Duration, Utc};
+
+use crate::{Job, StateSeverity};
+
+const HEADERS: [&str; 6] = ["ID", "QUEUE", "STATE", "PRIORITY", "SCHEDULED", "AGE"];
+
+// Renders jobs as an aligned, plain text table, one row per job under a header, for CLI tools dumping query results.
+// States are coloured by their severity with ANSI escapes, unless NO_COLOR is set - see https://no-color.org.
+// Age is how long ago the job was created, at the time of formatting.
+pub fn format_jobs_table(jobs: &[Job]) -> String {
+    let color = std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty());
+    render(jobs, Utc::now(), color)
+}
+
+fn render(jobs: &[Job], now: DateTime<Utc>, color: bool) -> String {
+    let rows: Vec<[String; 6]> = jobs
+        .iter()
+        .map(|job| {
+            [
+                job.id.to_string(),
+                job.queue_name.clone(),
+                job.state.metadata().display_name.to_string(),
+                job.priority.to_string(),
+                job.scheduled.format("%Y-%m-%d %H:%M:%S").to_string(),
+                format_age(now - job.created),
+            ]
+        })
+        .collect();
+
+    let mut widths = HEADERS.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let mut out = String::new();
+    push_row(&mut out, &HEADERS, &widths, None);
+    for (job, row) in jobs.iter().zip(&rows) {
+        let severity = color.then(|| job.state.metadata().severity);
+        push_row(&mut out, row, &widths, severity);
+    }
+    out
+}
+
+// The state column is coloured after padding, so the escape codes don't throw the alignment off
+fn push_row<S: AsRef<str>>(
+    out: &mut String,
+    cells: &[S; 6],
+    widths: &[usize; 6],
+    severity: Option<StateSeverity>,
+) {
+    let mut line = String::new();
+    for (i, (cell, width)) in cells.iter().zip(widths).enumerate() {
+        if i > 0 {
+            line.push_str("  ");
+        }
+        let padded = format!("{:<width$}", cell.as_ref(), width = width);
+        match severity {
+            Some(severity) if i == 2 => {
+                let _ = write!(line, "\x1b[{}m{}\x1b[0m", ansi_color(severity), padded);
+            }
+            _ => line.push_str(&padded),
+        }
+    }
+    out.push_str(line.trim_end());
+    out.push('\n');
+}
+
+fn ansi_color(severity: StateSeverity) -> u8 {
+    match severity {
+        StateSeverity::Info => 36,    // Cyan
+        StateSeverity::Success => 32, // Green
+        StateSeverity::Warning => 33, // Yellow
+        StateSeverity::Error => 31,   // Red
+    }
+}
+
+// The largest two units, like "3d4h" or "12m5s". Jobs created in the future, e.g. on a host with a skewed clock,
+// show as "0s"
+fn format_age(age: Duration) -> String {
+    let secs = age.num_seconds().max(0);
+    let (days, hours, mins, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    match (days, hours, mins) {
+        (0, 0, 0) => format!("{}s", secs),
+        (0, 0, _) => format!("{}m{}s", mins, secs),
+        (0, _, _) => format!("{}h{}m", hours, mins),
+        _ => format!("{}d{}h", days, hours),
+    }
+}
@@ -0,0 +1,124 @@
+use std::{cell::Cell, fmt};
+
+use chrono::{DateTime, Utc};
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+// Serde helpers for the timestamp fields of jobs, for `#[serde(with = ...)]` - `option` is for the optional ones.
+// Timestamps are written as RFC3339 strings, or as integer epoch milliseconds when serialized inside an
+// `EpochMillis`, and either form is accepted when reading. Epoch millis drop anything finer than a millisecond, so
+// timestamps written that way don't round trip exactly. Both only apply to human-readable formats - binary ones,
+// like the codec's bincode, always keep chrono's own encoding.
+pub(crate) fn serialize<S>(timestamp: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if EPOCH_MILLIS.with(Cell::get) && serializer.is_human_readable() {
+        serializer.serialize_i64(timestamp.timestamp_millis())
+    } else {
+        timestamp.serialize(serializer)
+    }
+}
+
+pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_any(TimestampVisitor)
+    } else {
+        DateTime::deserialize(deserializer)
+    }
+}
+
+pub(crate) mod option {
+    use chrono::{DateTime, Utc};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::Timestamp;
+
+    pub(crate) fn serialize<S>(
+        timestamp: &Option<DateTime<Utc>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        timestamp.map(Timestamp).serialize(serializer)
+    }
+
+    pub(crate) fn deserialize<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<Timestamp>::deserialize(deserializer)?.map(|t| t.0))
+    }
+}
+
+thread_local! {
+    // Set for the duration of serializing an `EpochMillis`. Serializing is synchronous, so nothing else can run
+    // on the thread in the meantime
+    static EPOCH_MILLIS: Cell<bool> = const { Cell::new(false) };
+}
+
+// Serializes whatever it wraps - a job, a job init, or anything holding them - with its job timestamps written
+// as integer epoch milliseconds, rather than RFC3339 strings, for consumers that want them that way. Only the
+// values serialized through the wrapper are affected, so one caller asking for millis doesn't change what anyone
+// else writes.
+pub struct EpochMillis<T>(pub T);
+
+impl<T: Serialize> Serialize for EpochMillis<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Put back whatever was set before, even if serializing fails, so wrappers can nest
+        struct Reset(bool);
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                EPOCH_MILLIS.with(|m| m.set(self.0));
+            }
+        }
+        let _reset = Reset(EPOCH_MILLIS.with(|m| m.replace(true)));
+        self.0.serialize(serializer)
+    }
+}
+
+// So the option helpers can defer to the ones above for the value inside
+struct Timestamp(DateTime<Utc>);
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize(&self.0, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserialize(deserializer).map(Timestamp)
+    }
+}
+
+struct TimestampVisitor;
+
+impl Visitor<'_> for TimestampVisitor {
+    type Value = DateTime<Utc>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("an RFC3339 timestamp or an integer number of milliseconds since the epoch")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        v.parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        DateTime::from_timestamp_millis(v)
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Signed(v), &self))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        let millis =
+            i64::try_from(v).map_err(|_| E::invalid_value(de::Unexpected::Unsigned(v), &self))?;
+        self.visit_i64(millis)
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+// Upper bounds, in milliseconds, of the buckets of a `ProcessingHistogram`. A duration goes in the first bucket
+// it's no longer than, and anything slower than the last goes in a final, unbounded one
+pub const PROCESSING_TIME_BUCKETS_MS: [u64; 12] = [
+    5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000,
+];
+
+// How many of a function's most recent jobs its rolling median is taken over, and how many it needs before
+// there's a median to compare against, so the first few jobs a worker runs aren't all outliers
+const MEDIAN_WINDOW: usize = 100;
+const MEDIAN_MIN_SAMPLES: usize = 10;
+
+// How long a function's jobs have taken to process, for `Worker::metrics_snapshot`
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct ProcessingHistogram {
+    pub buckets: Vec<u64>, // The number of jobs in each of PROCESSING_TIME_BUCKETS_MS, plus one for everything slower
+    pub count: u64,
+    pub sum_ms: u64,
+    pub median_ms: Option<u64>, // Over the function's most recent jobs. None until it's run enough of them
+}
+
+impl Default for ProcessingHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; PROCESSING_TIME_BUCKETS_MS.len() + 1],
+            count: 0,
+            sum_ms: 0,
+            median_ms: None,
+        }
+    }
+}
+
+// Every function's timings, for `Worker::metrics_snapshot`. Holds at most the number of functions `record` is
+// passed - recording a function past that drops the one that least recently had a job recorded, so a worker
+// fed an endless stream of functions doesn't grow without bound
+#[derive(Default)]
+pub(crate) struct ProcessingTimings {
+    by_function: HashMap<Uuid, FunctionTimings>,
+    records: u64, // Counts every record, so each function's last one can be ordered against the others'
+}
+
+impl ProcessingTimings {
+    // Records how long one of a function's jobs took, returning the function's rolling median from before it,
+    // as `FunctionTimings::record` does. A limit of 0 records nothing
+    pub(crate) fn record(
+        &mut self,
+        function_id: Uuid,
+        elapsed: Duration,
+        limit: usize,
+    ) -> Option<u64> {
+        if limit == 0 {
+            return None;
+        }
+        // A scan, but only when a function comes in with the map already full
+        if !self.by_function.contains_key(&function_id) && self.by_function.len() >= limit {
+            let stalest = self
+                .by_function
+                .iter()
+                .min_by_key(|(_, timings)| timings.last_record)
+                .map(|(id, _)| *id);
+            if let Some(stalest) = stalest {
+                self.by_function.remove(&stalest);
+            }
+        }
+
+        self.records += 1;
+        let timings = self.by_function.entry(function_id).or_default();
+        timings.last_record = self.records;
+        timings.record(elapsed)
+    }
+
+    pub(crate) fn histograms(&self) -> HashMap<Uuid, ProcessingHistogram> {
+        self.by_function
+            .iter()
+            .map(|(function_id, timings)| (*function_id, timings.histogram()))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct FunctionTimings {
+    histogram: ProcessingHistogram,
+    recent: VecDeque<u64>, // The most recent durations, oldest first
+    sorted: Vec<u64>,      // The same durations, kept sorted, so the median is a lookup
+    last_record: u64,      // The `ProcessingTimings` record count as of this function's last record
+}
+
+impl FunctionTimings {
+    // Records how long a job took, returning the function's rolling median from before it, if there is one,
+    // so the caller can judge whether it was an outlier
+    fn record(&mut self, elapsed: Duration) -> Option<u64> {
+        let median = self.median();
+        let ms = elapsed.as_millis() as u64;
+        let bucket = PROCESSING_TIME_BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(PROCESSING_TIME_BUCKETS_MS.len());
+        self.histogram.buckets[bucket] += 1;
+        self.histogram.count += 1;
+        self.histogram.sum_ms += ms;
+
+        if self.recent.len() == MEDIAN_WINDOW {
+            let oldest = self.recent.pop_front().expect("the window is full");
+            let at = self
+                .sorted
+                .binary_search(&oldest)
+                .expect("every recent duration is in sorted");
+            self.sorted.remove(at);
+        }
+        self.recent.push_back(ms);
+        let at = self.sorted.partition_point(|d| *d <= ms);
+        self.sorted.insert(at, ms);
+        median
+    }
+
+    fn histogram(&self) -> ProcessingHistogram {
+        ProcessingHistogram {
+            median_ms: self.median(),
+            ..self.histogram.clone()
+        }
+    }
+
+    fn median(&self) -> Option<u64> {
+        (self.sorted.len() >= MEDIAN_MIN_SAMPLES).then(|| self.sorted[self.sorted.len() / 2])
+    }
+}
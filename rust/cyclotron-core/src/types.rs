@@ -1,13 +1,25 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, NaiveTime, Timelike, Utc};
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
+use serde_json::Value;
+use sqlx::{
+    encode::IsNull,
+    error::BoxDynError,
+    postgres::{PgArgumentBuffer, PgHasArrayType, PgTypeInfo, PgValueRef},
+    Decode, Encode, Postgres,
+};
+use std::{collections::BTreeMap, fmt, str::FromStr};
 use uuid::Uuid;
 
+use crate::{
+    config::{RetryPolicy, DEFAULT_QUERY_LIMIT},
+    error::UnknownJobState,
+    timestamps, JobError,
+};
+
 pub type Bytes = Vec<u8>;
 
-#[derive(Debug, Deserialize, Serialize, sqlx::Type)]
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
-#[sqlx(type_name = "JobState", rename_all = "lowercase")]
 pub enum JobState {
     Available,
     Running,
@@ -16,6 +28,160 @@ pub enum JobState {
     Paused,
 }
 
+impl JobState {
+    // The state's name, as stored in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobState::Available => "available",
+            JobState::Running => "running",
+            JobState::Completed => "completed",
+            JobState::Failed => "failed",
+            JobState::Paused => "paused",
+        }
+    }
+
+    // The state's integer code, for consumers that store states as numbers, see `JobStateCode`. The codes are
+    // frozen - a state's code never changes, and a new state gets the next unused code, wherever it's declared:
+    //
+    //     available = 0, running = 1, completed = 2, failed = 3, paused = 4
+    pub fn code(&self) -> u8 {
+        match self {
+            JobState::Available => 0,
+            JobState::Running => 1,
+            JobState::Completed => 2,
+            JobState::Failed => 3,
+            JobState::Paused => 4,
+        }
+    }
+
+    // The state with the given integer code, if there is one, see `code`
+    pub fn from_code(code: u8) -> Option<JobState> {
+        match code {
+            0 => Some(JobState::Available),
+            1 => Some(JobState::Running),
+            2 => Some(JobState::Completed),
+            3 => Some(JobState::Failed),
+            4 => Some(JobState::Paused),
+            _ => None,
+        }
+    }
+
+    // Every state, in the order they're declared
+    pub fn all() -> impl Iterator<Item = JobState> {
+        [
+            JobState::Available,
+            JobState::Running,
+            JobState::Completed,
+            JobState::Failed,
+            JobState::Paused,
+        ]
+        .into_iter()
+    }
+
+    // How the state should be presented, for UIs that want to render states without hardcoding them
+    pub fn metadata(&self) -> StateMetadata {
+        let (display_name, is_terminal, is_active, severity) = match self {
+            JobState::Available => ("Available", false, true, StateSeverity::Info),
+            JobState::Running => ("Running", false, true, StateSeverity::Info),
+            JobState::Completed => ("Completed", true, false, StateSeverity::Success),
+            JobState::Failed => ("Failed", true, false, StateSeverity::Error),
+            JobState::Paused => ("Paused", false, false, StateSeverity::Warning),
+        };
+        StateMetadata {
+            state: *self,
+            display_name,
+            is_terminal,
+            is_active,
+            severity,
+        }
+    }
+}
+
+// Presentation details for a job state, from `JobState::metadata`
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub struct StateMetadata {
+    pub state: JobState,
+    pub display_name: &'static str,
+    pub is_terminal: bool, // Jobs never leave this state, except by being deleted
+    // Jobs in this state are running, or will be once they're due. Paused jobs aren't, until what they're
+    // waiting on finishes
+    pub is_active: bool,
+    pub severity: StateSeverity, // For choosing a colour
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StateSeverity {
+    Info,
+    Success,
+    Warning,
+    Error,
+}
+
+// A job state that (de)serializes as its integer code (see `JobState::code`), rather than its name, for consumers
+// that store states as numbers. Deserializing an unknown code fails, rather than guessing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JobStateCode(pub JobState);
+
+impl From<JobState> for JobStateCode {
+    fn from(state: JobState) -> Self {
+        Self(state)
+    }
+}
+
+impl From<JobStateCode> for JobState {
+    fn from(code: JobStateCode) -> Self {
+        code.0
+    }
+}
+
+impl Serialize for JobStateCode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.0.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for JobStateCode {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = u8::deserialize(deserializer)?;
+        JobState::from_code(code).map(Self).ok_or_else(|| {
+            serde::de::Error::invalid_value(
+                serde::de::Unexpected::Unsigned(u64::from(code)),
+                &"a job state code, 0 to 4",
+            )
+        })
+    }
+}
+
+// The postgres encoding is written out, rather than derived, so that a state this code doesn't know about - e.g.
+// one added by a newer migration, mid-deploy - fails to decode with an UnknownJobState error naming it
+impl sqlx::Type<Postgres> for JobState {
+    fn type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("JobState")
+    }
+}
+
+impl PgHasArrayType for JobState {
+    fn array_type_info() -> PgTypeInfo {
+        PgTypeInfo::with_name("_JobState")
+    }
+}
+
+impl Encode<'_, Postgres> for JobState {
+    fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> Result<IsNull, BoxDynError> {
+        <&str as Encode<Postgres>>::encode_by_ref(&self.as_str(), buf)
+    }
+}
+
+impl<'r> Decode<'r, Postgres> for JobState {
+    fn decode(value: PgValueRef<'r>) -> Result<Self, BoxDynError> {
+        let state = <&str as Decode<Postgres>>::decode(value)?;
+        state
+            .parse()
+            .map_err(|()| UnknownJobState(state.to_string()).into())
+    }
+}
+
 impl FromStr for JobState {
     type Err = ();
 
@@ -25,40 +191,167 @@ impl FromStr for JobState {
             "running" => Ok(JobState::Running),
             "completed" => Ok(JobState::Completed),
             "failed" => Ok(JobState::Failed),
+            "paused" => Ok(JobState::Paused),
             _ => Err(()),
         }
     }
 }
 
-// The chunk of data needed to enqueue a job
-#[derive(Debug, Deserialize, Serialize, Clone, Eq, PartialEq)]
+// The chunk of data needed to enqueue a job. Both snake_case and camelCase field names are accepted when
+// deserializing, since jobs are enqueued from both python and typescript services. Like `Job`, Debug output
+// redacts the payloads to their sizes.
+#[derive(Deserialize, Serialize, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct JobInit {
+    // Normally left unset, in which case the job is given a fresh id. Producers that retry enqueues can set one,
+    // and pick what happens if a job with it already exists with `OnConflict`
+    #[serde(default)]
+    pub id: Option<Uuid>,
+    #[serde(alias = "teamId")]
     pub team_id: i32,
+    #[serde(alias = "queueName")]
     pub queue_name: String,
     pub priority: i16,
+    #[serde(default = "Utc::now", with = "timestamps")] // Payloads that omit this are scheduled to run immediately
+    #[cfg_attr(feature = "schema", schemars(with = "DateTime<Utc>"))]
     pub scheduled: DateTime<Utc>,
+    #[serde(alias = "functionId")]
     pub function_id: Option<Uuid>,
+    #[serde(alias = "vmState")]
+    #[cfg_attr(
+        feature = "schema",
+        schemars(description = "Raw bytes, as an array of integers 0-255")
+    )]
     pub vm_state: Option<Bytes>,
+    #[cfg_attr(
+        feature = "schema",
+        schemars(description = "Raw bytes, as an array of integers 0-255")
+    )]
     pub parameters: Option<Bytes>,
+    #[cfg_attr(
+        feature = "schema",
+        schemars(description = "Raw bytes, as an array of integers 0-255")
+    )]
     pub blob: Option<Bytes>,
+    #[cfg_attr(
+        feature = "schema",
+        schemars(description = "Raw bytes, as an array of integers 0-255")
+    )]
     pub metadata: Option<Bytes>,
+    #[serde(default, alias = "atMostOnce")]
+    // If set, the job is failed rather than retried if it stalls
+    pub at_most_once: bool,
+    #[serde(alias = "correlationId")]
+    // The id of the request this job is part of, for finding every job from it with `JobQuery::correlation_id`
+    pub correlation_id: Option<Uuid>,
+    #[serde(alias = "parentJobId")]
+    // The job that spawned this one, for rendering fan-outs as a tree. Purely informational - unlike
+    // `QueueManager::enqueue_after`, it has no effect on when the job runs
+    pub parent_job_id: Option<Uuid>,
+    #[serde(alias = "replyTo")]
+    // The queue to enqueue the job's result into, for request/response over the queue - see
+    // `Worker::complete_job_with_reply`
+    pub reply_to: Option<String>,
+}
+
+impl fmt::Debug for JobInit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let payload = |bytes| Payload {
+            bytes,
+            redact: true,
+        };
+        f.debug_struct("JobInit")
+            .field("id", &self.id)
+            .field("team_id", &self.team_id)
+            .field("queue_name", &self.queue_name)
+            .field("priority", &self.priority)
+            .field("scheduled", &self.scheduled)
+            .field("function_id", &self.function_id)
+            .field("vm_state", &payload(&self.vm_state))
+            .field("parameters", &payload(&self.parameters))
+            .field("blob", &payload(&self.blob))
+            .field("metadata", &payload(&self.metadata))
+            .field("at_most_once", &self.at_most_once)
+            .field("correlation_id", &self.correlation_id)
+            .field("parent_job_id", &self.parent_job_id)
+            .field("reply_to", &self.reply_to)
+            .finish()
+    }
+}
+
+impl JobInit {
+    /// Sets the job's priority from when it has to run by, see `deadline_priority`
+    pub fn with_deadline(mut self, deadline: DateTime<Utc>) -> Self {
+        self.priority = Self::deadline_priority(Utc::now(), deadline);
+        self
+    }
+
+    /// The priority for a job that has to run by `deadline`, as of `now` - the number of whole minutes left
+    /// until the deadline, rounded up, so the sooner the deadline, the lower the number and the sooner the
+    /// job is dequeued. Deadlines already passed get 0, and deadlines more than i16::MAX minutes (about 22
+    /// days) away all get i16::MAX. A manager's priority band still applies on top of this.
+    pub fn deadline_priority(now: DateTime<Utc>, deadline: DateTime<Utc>) -> i16 {
+        let seconds = (deadline - now).num_seconds().max(0);
+        let minutes = (seconds + 59) / 60;
+        minutes.min(i16::MAX as i64) as i16
+    }
+}
+
+#[cfg(feature = "schema")]
+impl JobInit {
+    /// The JSON Schema of an enqueue payload, for validating jobs before they're handed to us. Payload
+    /// fields are described as they're actually encoded - serde_json reads and writes bytes as arrays of
+    /// numbers, not base64 strings. The camelCase field name aliases `JobInit` accepts aren't in the schema.
+    pub fn json_schema() -> schemars::schema::RootSchema {
+        schemars::schema_for!(JobInit)
+    }
+}
+
+// An immediately-runnable job with no payload, for filling in the fields a caller doesn't care about with
+// struct update syntax. Note the empty queue name - callers are expected to set at least that.
+impl Default for JobInit {
+    fn default() -> Self {
+        Self {
+            id: None,
+            team_id: 0,
+            queue_name: String::new(),
+            priority: 0,
+            scheduled: Utc::now(),
+            function_id: None,
+            vm_state: None,
+            parameters: None,
+            blob: None,
+            metadata: None,
+            at_most_once: false,
+            correlation_id: None,
+            parent_job_id: None,
+            reply_to: None,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// Debug output redacts the payload fields, see `Job::debug_unredacted`
+#[derive(Clone, Deserialize, Serialize, sqlx::FromRow)]
 pub struct Job {
     // Job metadata
     pub id: Uuid,
     pub team_id: i32,
     pub function_id: Option<Uuid>, // Some jobs might not come from hog, and it doesn't /kill/ use to support that
+    #[serde(with = "timestamps")]
     pub created: DateTime<Utc>,
 
     // Queue bookkeeping
     // This will be set for any worker that ever has a job in the "running" state (so any worker that dequeues a job)
     // but I don't want to do the work to encode that in the type system right now - later it should be
     pub lock_id: Option<Uuid>,
+    #[serde(default, with = "timestamps::option")]
     pub last_heartbeat: Option<DateTime<Utc>>,
+    #[serde(default, with = "timestamps::option")]
+    pub lock_expires_at: Option<DateTime<Utc>>, // If set, the janitor considers the job stalled after this time, rather than based on last_heartbeat
     pub janitor_touch_count: i16,
     pub transition_count: i16,
+    pub failed_attempts: i16, // Bumped only when a worker fails the job, or retries it after a failure
+    #[serde(with = "timestamps")]
     pub last_transition: DateTime<Utc>,
 
     // Virtual queue components
@@ -67,6 +360,7 @@ pub struct Job {
     // Job availability
     pub state: JobState,
     pub priority: i16, // For sorting "available" jobs. Lower is higher priority
+    #[serde(with = "timestamps")]
     pub scheduled: DateTime<Utc>,
 
     // Job data
@@ -74,11 +368,412 @@ pub struct Job {
     pub metadata: Option<Bytes>, // Additional fields a worker can tack onto a job, for e.g. tracking some state across retries (or number of retries in general by a given class of worker)
     pub parameters: Option<Bytes>, // The actual parameters of the job (function args for a hog function, http request for a fetch function)
     pub blob: Option<Bytes>, // An additional, binary, parameter field (for things like fetch request body)
+
+    // Delivery semantics
+    pub at_most_once: bool, // If set, the janitor fails this job, rather than returning it to the queue, if it stalls
+
+    // Tracing
+    pub correlation_id: Option<Uuid>, // The id of the request this job was enqueued as part of, if any
+    pub parent_job_id: Option<Uuid>, // The job that spawned this one, if any. Informational only, see `JobInit::parent_job_id`
+    pub reply_to: Option<String>, // The queue the job's result goes to, if any, see `JobInit::reply_to`
+}
+
+// Payloads can be large, and can hold anything a user put in them, so by default they're logged as their size
+struct Payload<'a> {
+    bytes: &'a Option<Bytes>,
+    redact: bool,
+}
+
+impl fmt::Debug for Payload<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bytes {
+            Some(bytes) if self.redact => write!(f, "Some(<{} bytes>)", bytes.len()),
+            bytes => bytes.fmt(f),
+        }
+    }
+}
+
+// A payload field of a `JobUpdate`, where the outer None means the field is left alone
+struct PayloadUpdate<'a>(&'a Option<Option<Bytes>>);
+
+impl fmt::Debug for PayloadUpdate<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(bytes) => f
+                .debug_tuple("Some")
+                .field(&Payload {
+                    bytes,
+                    redact: true,
+                })
+                .finish(),
+            None => f.write_str("None"),
+        }
+    }
+}
+
+// A job's Debug output, with or without its payloads
+struct JobDebug<'a> {
+    job: &'a Job,
+    redact: bool,
+}
+
+impl fmt::Debug for JobDebug<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let job = self.job;
+        let payload = |bytes| Payload {
+            bytes,
+            redact: self.redact,
+        };
+        f.debug_struct("Job")
+            .field("id", &job.id)
+            .field("team_id", &job.team_id)
+            .field("function_id", &job.function_id)
+            .field("created", &job.created)
+            .field("lock_id", &job.lock_id)
+            .field("last_heartbeat", &job.last_heartbeat)
+            .field("lock_expires_at", &job.lock_expires_at)
+            .field("janitor_touch_count", &job.janitor_touch_count)
+            .field("transition_count", &job.transition_count)
+            .field("failed_attempts", &job.failed_attempts)
+            .field("last_transition", &job.last_transition)
+            .field("queue_name", &job.queue_name)
+            .field("state", &job.state)
+            .field("priority", &job.priority)
+            .field("scheduled", &job.scheduled)
+            .field("vm_state", &payload(&job.vm_state))
+            .field("metadata", &payload(&job.metadata))
+            .field("parameters", &payload(&job.parameters))
+            .field("blob", &payload(&job.blob))
+            .field("at_most_once", &job.at_most_once)
+            .field("correlation_id", &job.correlation_id)
+            .field("parent_job_id", &job.parent_job_id)
+            .field("reply_to", &job.reply_to)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Job {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        JobDebug {
+            job: self,
+            redact: true,
+        }
+        .fmt(f)
+    }
+}
+
+// What to do when creating a job whose client supplied id is already taken
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnConflict {
+    // Fail with a DuplicateJobId error
+    #[default]
+    Error,
+    // Leave the existing job as it is
+    Ignore,
+    // Overwrite the existing job with the new one, but only if it's still available, and it's the same team's -
+    // a job that's already running, finished or paused, or belongs to another team, is left alone, as with Ignore
+    Update,
+}
+
+// Result of `QueueManager::bulk_create_unique_jobs`
+#[derive(Debug, Clone)]
+pub struct UniqueBatch {
+    pub jobs: Vec<Job>, // The jobs inserted, in the order the first of each was passed in
+    pub collapsed: usize, // How many jobs were dropped for being identical to one earlier in the batch
+}
+
+// Result of `QueueManager::create_job_deduplicated`. Not boxed, since it's returned one at a time, and most
+// enqueues aren't duplicates
+#[derive(Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum Enqueued {
+    Created(Job),
+    Deduplicated(Uuid), // An identical job was enqueued within the window, this is its id
+}
+
+impl Enqueued {
+    pub fn id(&self) -> Uuid {
+        match self {
+            Enqueued::Created(job) => job.id,
+            Enqueued::Deduplicated(id) => *id,
+        }
+    }
+}
+
+// A queue's enqueue defaults, used by `QueueManager::create_job_with_queue_defaults` for jobs that don't set
+// their own priority or schedule. Unset fields mean the queue has no default for them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueConfig {
+    pub default_priority: Option<i16>,
+    pub default_delay: Option<Duration>, // Jobs are scheduled to run this long after they're enqueued
+}
+
+// Everything about a job except its data, from `Job::into_parts`. Cheap to clone and pass around.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobHeader {
+    pub id: Uuid,
+    pub team_id: i32,
+    pub function_id: Option<Uuid>,
+    #[serde(with = "timestamps")]
+    pub created: DateTime<Utc>,
+    pub lock_id: Option<Uuid>,
+    #[serde(default, with = "timestamps::option")]
+    pub last_heartbeat: Option<DateTime<Utc>>,
+    #[serde(default, with = "timestamps::option")]
+    pub lock_expires_at: Option<DateTime<Utc>>,
+    pub janitor_touch_count: i16,
+    pub transition_count: i16,
+    pub failed_attempts: i16,
+    #[serde(with = "timestamps")]
+    pub last_transition: DateTime<Utc>,
+    pub queue_name: String,
+    pub state: JobState,
+    pub priority: i16,
+    #[serde(with = "timestamps")]
+    pub scheduled: DateTime<Utc>,
+    pub at_most_once: bool,
+    pub correlation_id: Option<Uuid>,
+    pub parent_job_id: Option<Uuid>,
+    pub reply_to: Option<String>,
+}
+
+// A job's data, from `Job::into_parts`. These are the potentially large fields, owned so they can be handed
+// off without a copy. Like `Job`, Debug output redacts them to their sizes.
+#[derive(Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobPayloads {
+    pub vm_state: Option<Bytes>,
+    pub metadata: Option<Bytes>,
+    pub parameters: Option<Bytes>,
+    pub blob: Option<Bytes>,
+}
+
+impl fmt::Debug for JobPayloads {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let payload = |bytes| Payload {
+            bytes,
+            redact: true,
+        };
+        f.debug_struct("JobPayloads")
+            .field("vm_state", &payload(&self.vm_state))
+            .field("metadata", &payload(&self.metadata))
+            .field("parameters", &payload(&self.parameters))
+            .field("blob", &payload(&self.blob))
+            .finish()
+    }
+}
+
+// Which of a job's payload fields `Worker::dequeue_projected` fetches. Fields left out come back as None, so
+// workers that don't need e.g. the blob don't pay to transfer it. The default fetches none of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobProjection {
+    pub vm_state: bool,
+    pub metadata: bool,
+    pub parameters: bool,
+    pub blob: bool,
+}
+
+impl JobProjection {
+    pub fn all() -> Self {
+        Self {
+            vm_state: true,
+            metadata: true,
+            parameters: true,
+            blob: true,
+        }
+    }
+}
+
+// The key in a job's metadata under which `Worker::fail_job` and `Worker::retry_with_backoff` record
+// failures. Metadata is otherwise entirely up to the worker - this is the only structure we impose on it,
+// and only if those functions are used (in which case the metadata must be a JSON object).
+pub const RETRY_HISTORY_KEY: &str = "_cyclotron_retry_history";
+
+// The key in a job's metadata under which `Worker::run` counts the times the job's handler has panicked
+pub const PANIC_COUNT_KEY: &str = "_cyclotron_panic_count";
+
+#[derive(Debug, Deserialize, Serialize, Clone, Eq, PartialEq)]
+pub struct RetryEntry {
+    pub timestamp: DateTime<Utc>,
+    pub error: String,
+    pub attempt: u32, // 1-indexed, the first failure is attempt 1
+}
+
+impl Job {
+    /// The job's Debug output with its payloads in full, rather than redacted to their sizes. For when
+    /// the contents really are needed - payloads can hold anything a user put in them, so this shouldn't
+    /// end up in logs by default.
+    pub fn debug_unredacted(&self) -> impl fmt::Debug + '_ {
+        JobDebug {
+            job: self,
+            redact: false,
+        }
+    }
+
+    /// Splits the job into its header and its data, moving the data buffers out rather than copying them.
+    pub fn into_parts(self) -> (JobHeader, JobPayloads) {
+        let header = JobHeader {
+            id: self.id,
+            team_id: self.team_id,
+            function_id: self.function_id,
+            created: self.created,
+            lock_id: self.lock_id,
+            last_heartbeat: self.last_heartbeat,
+            lock_expires_at: self.lock_expires_at,
+            janitor_touch_count: self.janitor_touch_count,
+            transition_count: self.transition_count,
+            failed_attempts: self.failed_attempts,
+            last_transition: self.last_transition,
+            queue_name: self.queue_name,
+            state: self.state,
+            priority: self.priority,
+            scheduled: self.scheduled,
+            at_most_once: self.at_most_once,
+            correlation_id: self.correlation_id,
+            parent_job_id: self.parent_job_id,
+            reply_to: self.reply_to,
+        };
+        let payloads = JobPayloads {
+            vm_state: self.vm_state,
+            metadata: self.metadata,
+            parameters: self.parameters,
+            blob: self.blob,
+        };
+        (header, payloads)
+    }
+
+    /// The failures recorded against this job, oldest first. Jobs whose metadata isn't a JSON object,
+    /// or which have never failed, have an empty history.
+    pub fn retry_history(&self) -> Vec<RetryEntry> {
+        retry_history(self.metadata.as_deref())
+    }
+
+    /// The key the queue orders available jobs by when dequeuing, lowest first. Sorting jobs by this
+    /// gives the order the queue would serve them in, with ties broken the same way.
+    pub fn ordering_key(&self) -> (i16, DateTime<Utc>, Uuid) {
+        (self.priority, self.scheduled, self.id)
+    }
+
+    /// Which attempt at the job is underway, if it's running, or is next, otherwise - 1 for a job that's
+    /// never failed. Only failures count, so a job deferred, released or reset by the janitor is still on
+    /// the same attempt.
+    pub fn attempt(&self) -> u32 {
+        self.failed_attempts.max(0) as u32 + 1
+    }
+
+    /// When the job would be retried, if the attempt at it that's underway (or next, see `attempt`) fails
+    /// at `now` - the same time `Worker::run` schedules the retry for, given the same policy.
+    pub fn next_retry_at(&self, policy: &RetryPolicy, now: DateTime<Utc>) -> DateTime<Utc> {
+        now + policy.delay(self.id, self.attempt())
+    }
+
+    /// Checks the job against the invariants the type system doesn't enforce, returning an error
+    /// describing the first one it violates. A job that fails this was corrupted somewhere.
+    pub fn check_invariants(&self) -> Result<(), JobError> {
+        let violation = |reason| Err(JobError::InvariantViolated(self.id, reason));
+        match self.state {
+            JobState::Running if self.lock_id.is_none() => violation("running without a lock"),
+            JobState::Completed | JobState::Failed if self.scheduled > Utc::now() => {
+                violation("finished, but scheduled in the future")
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The job's function id, for callers that expect every job to have one. A job without one is
+    /// an error, rather than something to be handled.
+    pub fn require_function_id(&self) -> Result<Uuid, JobError> {
+        self.function_id.ok_or(JobError::MissingFunctionId(self.id))
+    }
+
+    /// Whether this job carries what `init` asked for - the fields enqueueing should preserve, which are
+    /// the team, function, queue, priority and payloads. Fields the queue assigns or changes, like the id,
+    /// state and schedule, aren't compared. Note that jobs dequeued or queried without their VM state only
+    /// match inits without one, and that priorities clamped by the manager on creation won't match.
+    pub fn matches_init(&self, init: &JobInit) -> bool {
+        self.team_id == init.team_id
+            && self.function_id == init.function_id
+            && self.queue_name == init.queue_name
+            && self.priority == init.priority
+            && self.vm_state == init.vm_state
+            && self.metadata == init.metadata
+            && self.parameters == init.parameters
+            && self.blob == init.blob
+    }
+}
+
+pub(crate) fn retry_history(metadata: Option<&[u8]>) -> Vec<RetryEntry> {
+    metadata
+        .and_then(|m| serde_json::from_slice::<Value>(m).ok())
+        .and_then(|mut m| m.get_mut(RETRY_HISTORY_KEY).map(Value::take))
+        .and_then(|h| serde_json::from_value(h).ok())
+        .unwrap_or_default()
+}
+
+// The number of times a job's handler has panicked, or None if its metadata isn't a JSON object, so we
+// can't know (or record) that
+pub(crate) fn panic_count(metadata: Option<&[u8]>) -> Option<u32> {
+    let Some(metadata) = metadata else {
+        return Some(0);
+    };
+    let metadata = serde_json::from_slice::<Value>(metadata).ok()?;
+    let count = metadata.as_object()?.get(PANIC_COUNT_KEY);
+    Some(count.and_then(Value::as_u64).unwrap_or(0) as u32)
+}
+
+// Returns the passed metadata with a new retry entry appended to its history, or None if the metadata
+// isn't a JSON object (and so we can't add to it without clobbering whatever the worker put there).
+pub(crate) fn append_retry_entry(metadata: Option<&[u8]>, error: &str) -> Option<Bytes> {
+    let mut metadata = match metadata {
+        Some(m) => serde_json::from_slice::<Value>(m).ok()?,
+        None => Value::Object(Default::default()),
+    };
+
+    let history = metadata
+        .as_object_mut()?
+        .entry(RETRY_HISTORY_KEY)
+        .or_insert_with(|| Value::Array(vec![]))
+        .as_array_mut()?;
+
+    let entry = RetryEntry {
+        timestamp: Utc::now(),
+        error: error.to_string(),
+        attempt: history.len() as u32 + 1,
+    };
+    history.push(serde_json::to_value(entry).ok()?);
+
+    serde_json::to_vec(&metadata).ok()
+}
+
+// Postgres stores timestamps to the microsecond, so anything finer is lost on the way in. We truncate
+// timestamps ourselves before writing them, rather than leaving it to the encoder, so that what we
+// write is exactly what we'll read back.
+pub(crate) fn truncate_to_db_precision(timestamp: DateTime<Utc>) -> DateTime<Utc> {
+    let micros = timestamp.nanosecond() / 1_000;
+    timestamp
+        .with_nanosecond(micros * 1_000)
+        .unwrap_or(timestamp)
+}
+
+// Merges the keys of `patch` into the passed metadata, the same way the database does when flushing a
+// metadata patch - metadata that isn't a JSON object is replaced by the patch.
+pub(crate) fn merge_metadata(metadata: Option<&[u8]>, patch: &Value) -> Option<Bytes> {
+    let mut merged = match metadata.and_then(|m| serde_json::from_slice::<Value>(m).ok()) {
+        Some(Value::Object(m)) => m,
+        _ => Default::default(),
+    };
+
+    for (key, value) in patch.as_object()? {
+        merged.insert(key.clone(), value.clone());
+    }
+
+    serde_json::to_vec(&Value::Object(merged)).ok()
 }
 
 // A struct representing a set of updates for a job. Outer none values mean "don't update this field",
-// with nested none values meaning "set this field to null" for nullable fields
-#[derive(Debug, Deserialize, Serialize)]
+// with nested none values meaning "set this field to null" for nullable fields. Like `Job`, Debug output redacts
+// the payloads to their sizes.
+#[derive(Deserialize, Serialize)]
 pub struct JobUpdate {
     pub lock_id: Uuid, // The ID of the lock acquired when this worker dequeued the job, required for any update to be valid
     pub state: Option<JobState>,
@@ -87,10 +782,41 @@ pub struct JobUpdate {
     pub scheduled: Option<DateTime<Utc>>,
     pub vm_state: Option<Option<Bytes>>,
     pub metadata: Option<Option<Bytes>>,
+    pub metadata_patch: Option<Value>, // A JSON object merged into the stored metadata at flush time. Ignored if `metadata` is set
     pub parameters: Option<Option<Bytes>>,
     pub blob: Option<Option<Bytes>>,
     #[serde(skip)]
     pub last_heartbeat: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    pub dequeued_from: Option<String>, // The queue the job was in when this worker dequeued it, for the worker's bookkeeping
+    #[serde(skip)]
+    pub failed_attempt: bool, // Set when the job is being failed, or retried after a failure. Bumps the job's failed_attempts, and the worker's circuit breaker counts it as a failure
+    #[serde(skip)]
+    pub function_id: Option<Uuid>, // The function of the job this update is for, for choosing how it's retried
+    #[serde(skip)]
+    pub touch_only: bool, // Set by `touch_only`. Flushing keeps the job locked, rather than releasing it
+}
+
+impl fmt::Debug for JobUpdate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobUpdate")
+            .field("lock_id", &self.lock_id)
+            .field("state", &self.state)
+            .field("queue_name", &self.queue_name)
+            .field("priority", &self.priority)
+            .field("scheduled", &self.scheduled)
+            .field("vm_state", &PayloadUpdate(&self.vm_state))
+            .field("metadata", &PayloadUpdate(&self.metadata))
+            .field("metadata_patch", &self.metadata_patch)
+            .field("parameters", &PayloadUpdate(&self.parameters))
+            .field("blob", &PayloadUpdate(&self.blob))
+            .field("last_heartbeat", &self.last_heartbeat)
+            .field("dequeued_from", &self.dequeued_from)
+            .field("failed_attempt", &self.failed_attempt)
+            .field("function_id", &self.function_id)
+            .field("touch_only", &self.touch_only)
+            .finish()
+    }
 }
 
 impl JobUpdate {
@@ -103,15 +829,180 @@ impl JobUpdate {
             scheduled: None,
             vm_state: None,
             metadata: None,
+            metadata_patch: None,
             parameters: None,
             blob: None,
             last_heartbeat: Some(Utc::now()), // Dequeueing a job always touches the heartbeat
+            dequeued_from: None,
+            failed_attempt: false,
+            function_id: None,
+            touch_only: false,
+        }
+    }
+
+    // An update that only heartbeats the job. Flushing it moves the job's last_heartbeat and nothing else -
+    // the job keeps its state and its lock, where any other update without a state releases the lock, even one
+    // that sets nothing. Anything set on it afterwards is still written, but the lock is kept.
+    pub fn touch_only(lock_id: Uuid) -> Self {
+        Self {
+            touch_only: true,
+            ..Self::new(lock_id)
         }
     }
+
+    // Whether this update was made with `touch_only`
+    pub fn is_touch_only(&self) -> bool {
+        self.touch_only
+    }
+}
+
+// What happens to a job's VM state when it's retried, see `Worker::retry_with_mode`
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RetryMode {
+    // The VM state is kept, so the retry carries on from wherever the failed attempt last saved it
+    #[default]
+    Resume,
+    // The VM state is cleared, so the retry starts from scratch
+    Restart,
+}
+
+// A filter for looking jobs up outside of the dequeue path, e.g. for operators inspecting a queue. Every
+// field is optional, and the set ones are ANDed together. If a state is in both `states` and `exclude_states`,
+// it's excluded - so e.g. `states` can be "every state a job has been in lately", and `exclude_states` can
+// carve the terminal ones back out.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct JobQuery {
+    pub queue_name: Option<String>,
+    pub team_id: Option<i32>,
+    pub function_id: Option<Uuid>,
+    // If non-empty, only jobs for one of these functions are returned, with None matching jobs with no function
+    #[serde(default)]
+    pub function_ids: Vec<Option<Uuid>>,
+    pub function_id_is_null: Option<bool>, // If set, only jobs whose function id is (true) or isn't (false) null are returned
+    #[serde(default)]
+    pub exclude_function_ids: Vec<Uuid>, // Jobs for any of these functions are never returned. Jobs with no function aren't affected
+    #[serde(default)]
+    pub states: Vec<JobState>, // If non-empty, only jobs in one of these states are returned
+    #[serde(default)]
+    pub exclude_states: Vec<JobState>, // Jobs in any of these states are never returned
+    pub lock_id: Option<Uuid>, // If set, only jobs held under this lock, e.g. by one worker's dequeue, are returned
+    pub locked: Option<bool>, // If set, only jobs that are (true) or aren't (false) held under some lock are returned
+    // If set, only jobs that do (true) or don't (false) have the given payload are returned
+    pub has_vm_state: Option<bool>,
+    pub has_blob: Option<bool>,
+    pub has_parameters: Option<bool>,
+    pub min_janitor_touches: Option<i16>, // If set, only jobs the janitor has reclaimed at least this many times are returned
+    pub scheduled_after: Option<DateTime<Utc>>, // If set, only jobs scheduled at or after this time are returned
+    pub scheduled_before: Option<DateTime<Utc>>, // If set, only jobs scheduled strictly before this time are returned
+    pub correlation_id: Option<Uuid>, // If set, only jobs enqueued with this correlation id are returned
+    pub parent_job_id: Option<Uuid>, // If set, only jobs enqueued with this parent job id, i.e. its children, are returned
+    // If set, only jobs in queues with a config (see `QueueManager::set_queue_config`) matching this are returned
+    pub queue_config: Option<QueueConfigFilter>,
+    // If set, only jobs matching this are returned, for conditions the fields above can't express on their own
+    pub filter: Option<Filter>,
+    // A substring to look for in job metadata and parameters. This can't use an index, so it's a scan over
+    // every job matching the other filters, and so is only allowed if the manager has search enabled
+    pub search: Option<String>,
+    // At most this many jobs are returned - if unset, DEFAULT_QUERY_LIMIT are. Zero means zero, not unlimited
+    pub limit: Option<u64>,
+    // If set, and `limit` isn't, every matching job is returned. Never deserialized, so queries built from
+    // requests can't ask for it - set it with `unlimited`
+    #[serde(skip)]
+    pub unlimited: bool,
+}
+
+// Conditions on the config of a job's queue, for `JobQuery::queue_config`. Queues that have never been configured
+// match none, even an empty one. As with `JobQuery`, the set fields are ANDed together.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct QueueConfigFilter {
+    pub default_priority: Option<i16>, // If set, only queues with this default priority match
+    pub has_default_delay: Option<bool>, // If set, only queues that do (true) or don't (false) delay new jobs match
+}
+
+// A tree of conditions on jobs, for `JobQuery::filter`, for when ANDing a query's fields together isn't enough -
+// e.g. "team 1's failed jobs, or team 2's running ones" is an `Or` of two `Matches`. Each `Matches` is its query's
+// fields ANDed together, as `query_jobs` does, with its limit ignored.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum Filter {
+    And(Vec<Filter>), // Jobs matching every one of these. An empty And matches every job
+    Or(Vec<Filter>),  // Jobs matching any of these. An empty Or matches none
+    Matches(Box<JobQuery>),
+}
+
+impl Filter {
+    pub fn matches(query: JobQuery) -> Self {
+        Self::Matches(Box::new(query))
+    }
+
+    // Combines two filters, extending this one if it's already an And, rather than nesting
+    pub fn and(self, other: Filter) -> Self {
+        match self {
+            Self::And(mut filters) => {
+                filters.push(other);
+                Self::And(filters)
+            }
+            filter => Self::And(vec![filter, other]),
+        }
+    }
+
+    // As `and`, for Or
+    pub fn or(self, other: Filter) -> Self {
+        match self {
+            Self::Or(mut filters) => {
+                filters.push(other);
+                Self::Or(filters)
+            }
+            filter => Self::Or(vec![filter, other]),
+        }
+    }
+
+    fn uses_search(&self) -> bool {
+        match self {
+            Self::And(filters) | Self::Or(filters) => filters.iter().any(Filter::uses_search),
+            Self::Matches(query) => query.uses_search(),
+        }
+    }
+}
+
+impl JobQuery {
+    /// Return every matching job, rather than the default limit. This is a table scan waiting to happen, so
+    /// it's only for callers that know the result is small, or really do want all of it - not request paths.
+    pub fn unlimited(mut self) -> Self {
+        self.limit = None;
+        self.unlimited = true;
+        self
+    }
+
+    // Whether the query, or any query in its filter, searches payloads, and so needs search to be enabled
+    pub(crate) fn uses_search(&self) -> bool {
+        self.search.is_some() || self.filter.as_ref().is_some_and(Filter::uses_search)
+    }
+
+    // The LIMIT to apply, if any
+    pub(crate) fn effective_limit(&self) -> Option<u64> {
+        match self.limit {
+            Some(limit) => Some(limit),
+            None if self.unlimited => None,
+            None => Some(DEFAULT_QUERY_LIMIT),
+        }
+    }
+}
+
+// A recorded job state change, from `QueueManager::job_transitions`. Only recorded while the transition audit
+// is enabled, see `QueueManager::set_transition_audit`.
+#[derive(sqlx::FromRow, Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct JobTransition {
+    pub job_id: Uuid,
+    pub from_state: Option<JobState>, // None for the job being created
+    pub to_state: JobState,
+    pub at: DateTime<Utc>,
+    pub lock_id: Option<Uuid>, // The lock the job was taken or released under, if any
 }
 
 // Result of janitor's `delete_completed_and_failed_jobs`
-#[derive(sqlx::FromRow, Debug)]
+#[derive(sqlx::FromRow, Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
 pub struct AggregatedDelete {
     // `last_transition` column truncated to the hour.
     pub hour: DateTime<Utc>,
@@ -120,3 +1011,134 @@ pub struct AggregatedDelete {
     pub state: String,
     pub count: i64,
 }
+
+// A set of janitor delete aggregates, for export. Serializes as a single JSON array, or, via `to_ndjson`, as
+// one JSON object per line, for streaming to object storage and the like. Timestamps are RFC3339 either way.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct DeleteSet(pub Vec<AggregatedDelete>);
+
+impl DeleteSet {
+    // The number of jobs deleted, across every state
+    pub fn total(&self) -> u64 {
+        self.0.iter().map(|d| d.count as u64).sum()
+    }
+
+    // The number of jobs deleted in the given state
+    pub fn total_for(&self, state: JobState) -> u64 {
+        self.0
+            .iter()
+            .filter(|d| d.state.parse::<JobState>() == Ok(state))
+            .map(|d| d.count as u64)
+            .sum()
+    }
+
+    // Collapses the hourly aggregates into daily ones, summing the counts for each team, function and state per
+    // UTC day. `hour` is truncated to the start of the day in the result, which is ordered by day, then team,
+    // function and state.
+    pub fn rollup_daily(&self) -> DeleteSet {
+        let mut days: BTreeMap<_, i64> = BTreeMap::new();
+        for delete in &self.0 {
+            let day = delete.hour.date_naive().and_time(NaiveTime::MIN).and_utc();
+            let key = (
+                day,
+                delete.team_id,
+                delete.function_id.clone(),
+                delete.state.clone(),
+            );
+            *days.entry(key).or_default() += delete.count;
+        }
+        days.into_iter()
+            .map(
+                |((hour, team_id, function_id, state), count)| AggregatedDelete {
+                    hour,
+                    team_id,
+                    function_id,
+                    state,
+                    count,
+                },
+            )
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    pub fn write_ndjson<W: std::io::Write>(&self, mut writer: W) -> std::io::Result<()> {
+        for delete in &self.0 {
+            serde_json::to_writer(&mut writer, delete)?;
+            writer.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    pub fn to_ndjson(&self) -> String {
+        let mut out = Vec::new();
+        self.write_ndjson(&mut out)
+            .expect("writing to a vec can't fail");
+        String::from_utf8(out).expect("serde_json always produces utf8")
+    }
+
+    // Blank lines are skipped, so a trailing newline (or a few) is fine
+    pub fn from_ndjson(ndjson: &str) -> Result<Self, serde_json::Error> {
+        ndjson
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<Result<_, _>>()
+            .map(DeleteSet)
+    }
+}
+
+impl From<Vec<AggregatedDelete>> for DeleteSet {
+    fn from(deletes: Vec<AggregatedDelete>) -> Self {
+        DeleteSet(deletes)
+    }
+}
+
+// Result of `state_counts`. `state` is a job state, or "delayed", for available jobs scheduled in the future,
+// if those were asked to be counted separately
+#[derive(sqlx::FromRow, Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct StateCount {
+    pub state: String,
+    pub count: i64,
+}
+
+// A job without any of its payloads, from `query_job_summaries`, for listing jobs without pulling every job's
+// potentially large data along with it
+#[derive(sqlx::FromRow, Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct JobSummary {
+    pub id: Uuid,
+    pub team_id: i32,
+    pub function_id: Option<Uuid>,
+    pub queue_name: String,
+    pub state: JobState,
+    pub priority: i16,
+    pub scheduled: DateTime<Utc>,
+    pub created: DateTime<Utc>,
+    pub transition_count: i16,
+}
+
+// The channel `QueueManager::enqueue_and_notify` notifies on when it creates a job, for listeners to LISTEN to
+pub const ENQUEUE_CHANNEL: &str = "cyclotron_enqueued";
+
+// The payload of a notification on ENQUEUE_CHANNEL, as JSON
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct EnqueueNotification {
+    pub id: Uuid,
+    pub queue_name: String,
+}
+
+// Result of `list_queues`, a queue with jobs in it, and how many jobs it has in each state
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct QueueInfo {
+    pub queue_name: String,
+    pub total: i64,
+    pub states: Vec<StateCount>, // Only the states the queue has jobs in, ordered by state
+}
+
+// Result of `time_in_queue`, how long jobs for a given function waited between being created and first being run
+#[derive(sqlx::FromRow, Debug)]
+pub struct QueueLatency {
+    pub function_id: Option<Uuid>,
+    pub jobs: i64, // The number of jobs the percentiles were computed over
+    pub p50_seconds: f64,
+    pub p95_seconds: f64,
+}
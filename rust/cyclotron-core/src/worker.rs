@@ -7,21 +7,35 @@ use std::{
 
 use chrono::{DateTime, Duration, Utc};
 use futures::FutureExt;
-use sqlx::PgPool;
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::{pool::PoolConnection, PgPool, Postgres, Transaction};
 use std::sync::Mutex;
-use tokio::sync::oneshot;
-use tracing::error;
+use tokio::{sync::oneshot, task::JoinHandle};
+use tracing::{error, warn};
 use uuid::Uuid;
 
 use crate::{
-    config::WorkerConfig,
+    acquire,
+    config::{
+        AcquireRetry, WorkerConfig, DEFAULT_WORKER_STATEMENT_TIMEOUT_MS, DEQUEUE_POLL_INTERVAL_MS,
+    },
     error::JobError,
+    offload::{resolve_vm_state, VmStateOffload},
     ops::{
-        meta::{dead_letter, run_migrations},
-        worker::{dequeue_jobs, dequeue_with_vm_state, flush_job, get_vm_state, set_heartbeat},
+        manager::create_job,
+        meta::{check_compatibility, dead_letter, run_migrations},
+        worker::{
+            claim_job, dequeue_jobs, dequeue_projected, dequeue_with_vm_state, extend_locks,
+            flush_job, flush_job_returning, force_release_jobs, get_metadata, get_payload,
+            get_vm_state, lock_id_in_use, next_scheduled, set_heartbeat,
+        },
     },
-    types::Bytes,
-    Job, JobState, JobUpdate, PoolConfig, QueueError,
+    timings::ProcessingTimings,
+    types::{append_retry_entry, merge_metadata, Bytes},
+    CircuitBreaker, Job, JobInit, JobProjection, JobState, JobUpdate, LazyJob, PayloadCipher,
+    PayloadField, PoolConfig, ProcessingHistogram, QueueError, RetryMode, RetryPolicy,
+    TeamRateLimiter,
 };
 
 // The worker's interface to the underlying queue system - a worker can do everything except
@@ -35,6 +49,7 @@ use crate::{
 // now (client libraries should wrap this to provide better interfaces).
 pub struct Worker {
     pool: PgPool,
+    acquire_retry: AcquireRetry, // How getting a connection from the pool is retried, from the worker config
     // All the jobs the worker is currently working on, and hasn't released for returning
     // to the queue.
     // TRICKY - this is a sync mutex, because that simplifies using the manager in an FFI
@@ -46,85 +61,549 @@ pub struct Worker {
     // some conditions.
     flush_batch: Arc<Mutex<FlushBatch>>,
 
+    // Bookkeeping for `metrics_snapshot`. Shared with the flush loop, so it can record flush errors
+    stats: Arc<Mutex<WorkerStats>>,
+
+    // The background flush loop, and a sender to tell it to stop, taken by `close`
+    flush_loop: Mutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>>,
+
     pub heartbeat_window: Duration, // The worker will only pass one heartbeat to the DB per job every heartbeat_window
     pub linger: Duration,           // Updates will be held at most this long
     pub max_buffered: usize,        // Updates will be flushed after this many are buffered
     pub max_bytes: usize, // Updates will be flushed after the vm_state and blob sizes combined exceed this
+    pub check_invariants: bool, // If set, dequeued jobs are checked with `Job::check_invariants`. Defaults to false
+    pub team_rate_limiter: Option<TeamRateLimiter>, // If set, limits how fast each team's jobs are handed out. Defaults to None
+    // If set, stops dequeuing from queues whose jobs keep failing. A job's outcome is counted once its release has
+    // been flushed. When a queue's breaker trips, the jobs this worker already holds from it are left to finish, but
+    // their outcomes don't count towards the breaker. Defaults to None
+    pub circuit_breaker: Option<Arc<CircuitBreaker>>,
+    pub payload_cipher: Option<PayloadCipher>, // If set, job parameters and blobs are decrypted on dequeue, and encrypted when set
+    pub served_window: Duration, // The per-team served counts in `metrics_snapshot` are reset this often
+    // How each function's failed jobs are retried, by function id - both how long `run` backs off before retrying
+    // them, and whether `retry_with_backoff` keeps their VM state. Jobs whose function isn't in here (including jobs
+    // with no function) resume, and `run` retries them under its config's `RunConfig::default_retry_policy`.
+    // Defaults to empty
+    pub retry_policies: HashMap<Uuid, RetryPolicy>,
+    pub dequeue_poll_interval: Duration, // How often `dequeue_batch_wait` polls the queue. Defaults to DEQUEUE_POLL_INTERVAL_MS
+    // How many functions `metrics_snapshot` keeps processing times for. Past this, the function that least recently
+    // ran a job is dropped, and starts over if it runs another. Defaults to 1000
+    pub max_timed_functions: usize,
+
+    // Set with `set_vm_state_offload`, since the flush loop needs it too
+    vm_state_offload: Option<VmStateOffload>,
 }
 
 impl Worker {
     pub async fn new(pool: PoolConfig, worker: WorkerConfig) -> Result<Self, QueueError> {
-        let pool = pool.connect().await?;
+        let pool = pool
+            .connect_with_default_timeout(DEFAULT_WORKER_STATEMENT_TIMEOUT_MS)
+            .await?;
         Ok(Self::from_pool(pool, worker))
     }
 
     pub fn from_pool(pool: PgPool, worker_config: WorkerConfig) -> Self {
         let worker = Self {
             pool,
+            acquire_retry: worker_config.acquire_retry(),
             running: Default::default(),
             heartbeat_window: worker_config.heartbeat_window(),
             flush_batch: Default::default(),
+            stats: Default::default(),
+            flush_loop: Default::default(),
             linger: worker_config.linger_time(),
             max_buffered: worker_config.max_updates_buffered(),
             max_bytes: worker_config.max_bytes_buffered(),
+            check_invariants: false,
+            team_rate_limiter: None,
+            circuit_breaker: None,
+            payload_cipher: None,
+            served_window: worker_config.served_window(),
+            retry_policies: HashMap::new(),
+            dequeue_poll_interval: Duration::milliseconds(DEQUEUE_POLL_INTERVAL_MS as i64),
+            max_timed_functions: 1000,
+            vm_state_offload: None,
         };
 
-        tokio::spawn(flush_loop(
+        let (stop, stopped) = oneshot::channel();
+        let handle = tokio::spawn(flush_loop(
             worker.pool.clone(),
+            worker.acquire_retry,
             Arc::downgrade(&worker.flush_batch),
+            worker.stats.clone(),
             worker.max_buffered,
             worker.max_bytes,
             worker_config.flush_loop_interval(),
+            stopped,
         ));
+        *worker.flush_loop.lock().unwrap() = Some((stop, handle));
 
         worker
     }
 
+    /// Flushes any released updates still waiting to be, stops the background flush loop, and closes the
+    /// worker's connection pool, once the connections in use are returned to it. Nothing the worker started is
+    /// left running afterwards, and later operations fail. If the final flush fails, the loop and pool are
+    /// still shut down, and the error is returned. Closing a closed worker does nothing.
+    pub async fn close(&self) -> Result<(), QueueError> {
+        let Some((stop, handle)) = self.flush_loop.lock().unwrap().take() else {
+            return Ok(());
+        };
+        // Let the loop finish any flush it's part way through before it stops, so it can't be holding some of
+        // the batch we're about to flush
+        let _ = stop.send(());
+        if let Err(e) = handle.await {
+            error!("Flush loop failed: {:?}", e);
+        }
+        let flushed = self.force_flush().await;
+        self.pool.close().await;
+        flushed
+    }
+
+    // A connection from the pool, retrying according to the worker config if getting one fails transiently
+    async fn acquire(&self) -> Result<PoolConnection<Postgres>, QueueError> {
+        acquire::acquire(&self.pool, &self.acquire_retry).await
+    }
+
+    // A transaction on the pool, retried the same way
+    async fn begin(&self) -> Result<Transaction<'static, Postgres>, QueueError> {
+        acquire::begin(&self.pool, &self.acquire_retry).await
+    }
+
     /// Run the latest cyclotron migrations. Panics if the migrations can't be run - failure to run migrations is purposefully fatal.
     pub async fn run_migrations(&self) {
         run_migrations(&self.pool).await;
     }
 
+    /// Checks the database schema is the version this code expects. See `check_compatibility`.
+    pub async fn check_compatibility(&self) -> Result<(), QueueError> {
+        check_compatibility(&self.pool).await
+    }
+
+    /// Offload vm_states over a size threshold to an object store, see `VmStateOffload`. Large states set
+    /// with `set_vm_state` are uploaded when their update is flushed, and offloaded states are fetched back
+    /// whenever they're read - by `dequeue_with_vm_state`, `get_vm_state`, and projected or lazy dequeues -
+    /// so callers never see the reference. States jobs are created with are only offloaded if the manager
+    /// creating them has its `vm_state_offload` set too. Workers without offloading configured fail to read
+    /// offloaded states. Defaults to None.
+    pub fn set_vm_state_offload(&mut self, offload: Option<VmStateOffload>) {
+        self.flush_batch.lock().unwrap().offload = offload.clone();
+        self.vm_state_offload = offload;
+    }
+
     /// Dequeues jobs from the queue, and returns them. Job sorting happens at the queue level,
     /// workers can't provide any filtering or sorting criteria - queue managers decide which jobs are run,
     /// workers just run them.
+    /// Every call locks its batch with a freshly generated lock id, returned as the `lock_id` of
+    /// each dequeued job.
     pub async fn dequeue_jobs(&self, queue: &str, limit: usize) -> Result<Vec<Job>, QueueError> {
-        let jobs = dequeue_jobs(&self.pool, queue, limit).await?;
+        let limit = self.breaker_limit(queue, limit);
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+        // Transient lock id. This could be a worker ID, or something, but for now it's totally random (per-batch).
+        // Failing to get a connection fails the dequeue like anything else would, so it's recorded the same way
+        let result =
+            async { dequeue_jobs(&mut *self.acquire().await?, queue, limit, Uuid::now_v7()).await }
+                .await;
+        let jobs = self.record_dequeue(self.settle_probe(queue, result))?;
+        self.check_dequeued(&jobs)?;
 
         let mut running = self.running.lock().unwrap();
         for job in &jobs {
             // We need to hang onto the locks for a job until we flush it, so we can send updates.
-            let update = JobUpdate::new(
+            let mut update = JobUpdate::new(
                 job.lock_id
                     .expect("Yell at oliver that the dequeuing code is broken. He's very sorry that your process just panicked"),
             );
+            update.dequeued_from = Some(job.queue_name.clone());
+            update.function_id = job.function_id;
             running.insert(job.id, update);
         }
+        drop(running);
 
-        Ok(jobs)
+        let jobs = self.apply_team_rate_limits(jobs);
+        self.record_served(&jobs);
+        self.decrypt_dequeued(jobs)
     }
 
-    /// This is the same as dequeue_jobs, but it also returns the vm_state of the job
-    pub async fn dequeue_with_vm_state(
+    /// As `dequeue_jobs`, but if nothing is due, keeps polling the queue for up to `max_wait`, rather than
+    /// returning straight away. Returns as soon as a poll dequeues anything - however many jobs were due at
+    /// the time, up to `max` - or with an empty vec once `max_wait` has passed without any. Polls every
+    /// `dequeue_poll_interval`, so jobs are picked up within that long of becoming due.
+    pub async fn dequeue_batch_wait(
+        &self,
+        queue: &str,
+        max: usize,
+        max_wait: Duration,
+    ) -> Result<Vec<Job>, QueueError> {
+        if max == 0 {
+            return Ok(vec![]);
+        }
+        let deadline = Utc::now() + max_wait;
+        loop {
+            let jobs = self.dequeue_jobs(queue, max).await?;
+            let remaining = deadline - Utc::now();
+            if !jobs.is_empty() || remaining <= Duration::zero() {
+                return Ok(jobs);
+            }
+            let wait = remaining.min(self.dequeue_poll_interval);
+            tokio::time::sleep(wait.to_std().unwrap_or_default()).await;
+        }
+    }
+
+    /// The same as dequeue_jobs, but locking the batch with a caller supplied lock id, for callers that
+    /// want to use e.g. the same lock id across their own bookkeeping. Returns an error if the lock id
+    /// is nil, or if it's already held by some other job - lock ids must be unique to a batch.
+    pub async fn dequeue_jobs_with_lock(
         &self,
         queue: &str,
         limit: usize,
+        lock_id: Uuid,
     ) -> Result<Vec<Job>, QueueError> {
-        let jobs = dequeue_with_vm_state(&self.pool, queue, limit).await?;
+        if lock_id.is_nil() {
+            return Err(QueueError::NilLockId);
+        }
+        let limit = self.breaker_limit(queue, limit);
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+
+        let result =
+            async { dequeue_jobs(&mut *self.acquire().await?, queue, limit, lock_id).await }.await;
+        let jobs = self.record_dequeue(self.settle_probe(queue, result))?;
+        // The dequeue skips everything if the lock is already held, so an empty batch might mean
+        // that, rather than there being nothing to do
+        if jobs.is_empty() && lock_id_in_use(&mut *self.acquire().await?, lock_id).await? {
+            return Err(QueueError::LockIdInUse(lock_id));
+        }
+        self.check_dequeued(&jobs)?;
 
         let mut running = self.running.lock().unwrap();
         for job in &jobs {
-            // We need to hang onto the locks for a job until we flush it, so we can send updates.
-            let update = JobUpdate::new(
-                job.lock_id
-                    .expect("Yell at oliver that the dequeuing (with vm) code is broken. He's very sorry that your process just panicked"),
-            );
+            let mut update = JobUpdate::new(lock_id);
+            update.dequeued_from = Some(job.queue_name.clone());
+            update.function_id = job.function_id;
             running.insert(job.id, update);
         }
+        drop(running);
+
+        let jobs = self.apply_team_rate_limits(jobs);
+        self.record_served(&jobs);
+        self.decrypt_dequeued(jobs)
+    }
+
+    /// Claims one particular job by id, locking it with the passed lock id, rather than taking whatever's
+    /// next in the queue - e.g. to replay or debug a specific job. Returns None if the job isn't available,
+    /// because it doesn't exist, is already running, or has finished. Claims are atomic, so a job can't be
+    /// claimed twice, or claimed and dequeued. As with dequeue_jobs, the vm_state isn't returned, but unlike
+    /// it, the returned job is in the running state. Fails with LockIdInUse if some other job already holds
+    /// the lock id, as with dequeue_jobs_with_lock.
+    pub async fn claim_job(&self, job_id: Uuid, lock_id: Uuid) -> Result<Option<Job>, QueueError> {
+        if lock_id.is_nil() {
+            return Err(QueueError::NilLockId);
+        }
+
+        let jobs = self.record_dequeue(
+            claim_job(&mut *self.acquire().await?, job_id, lock_id)
+                .await
+                .map(|job| job.into_iter().collect()),
+        )?;
+        // As with dequeue_jobs_with_lock, the claim is skipped if the lock is already held
+        if jobs.is_empty() && lock_id_in_use(&mut *self.acquire().await?, lock_id).await? {
+            return Err(QueueError::LockIdInUse(lock_id));
+        }
+        self.check_dequeued(&jobs)?;
+
+        let mut running = self.running.lock().unwrap();
+        for job in &jobs {
+            let mut update = JobUpdate::new(lock_id);
+            update.dequeued_from = Some(job.queue_name.clone());
+            update.function_id = job.function_id;
+            running.insert(job.id, update);
+        }
+        drop(running);
+
+        self.record_served(&jobs);
+        Ok(self.decrypt_dequeued(jobs)?.pop())
+    }
+
+    /// This is the same as dequeue_jobs, but it also returns the vm_state of the job
+    pub async fn dequeue_with_vm_state(
+        &self,
+        queue: &str,
+        limit: usize,
+    ) -> Result<Vec<Job>, QueueError> {
+        let limit = self.breaker_limit(queue, limit);
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+        let result = async {
+            dequeue_with_vm_state(&mut *self.acquire().await?, queue, limit, Uuid::now_v7()).await
+        }
+        .await;
+        let jobs = self.record_dequeue(self.settle_probe(queue, result))?;
+        self.check_dequeued(&jobs)?;
+
+        // Scoped rather than dropped, so the guard isn't held across the await below as far as the compiler's concerned
+        {
+            let mut running = self.running.lock().unwrap();
+            for job in &jobs {
+                // We need to hang onto the locks for a job until we flush it, so we can send updates.
+                let mut update = JobUpdate::new(
+                    job.lock_id
+                        .expect("Yell at oliver that the dequeuing (with vm) code is broken. He's very sorry that your process just panicked"),
+                );
+                update.dequeued_from = Some(job.queue_name.clone());
+                update.function_id = job.function_id;
+                running.insert(job.id, update);
+            }
+        }
+
+        let jobs = self.apply_team_rate_limits(jobs);
+        self.record_served(&jobs);
+        self.fetch_offloaded(self.decrypt_dequeued(jobs)?).await
+    }
+
+    /// The same as dequeue_jobs, but fetching only the payload fields in `projection`, with the rest
+    /// returned as None. Unlike dequeue_jobs, this can fetch the vm_state, if it's projected.
+    pub async fn dequeue_projected(
+        &self,
+        queue: &str,
+        limit: usize,
+        projection: JobProjection,
+    ) -> Result<Vec<Job>, QueueError> {
+        let limit = self.breaker_limit(queue, limit);
+        if limit == 0 {
+            return Ok(vec![]);
+        }
+        let result = async {
+            dequeue_projected(
+                &mut *self.acquire().await?,
+                queue,
+                limit,
+                Uuid::now_v7(),
+                &projection,
+            )
+            .await
+        }
+        .await;
+        let jobs = self.record_dequeue(self.settle_probe(queue, result))?;
+        self.check_dequeued(&jobs)?;
+
+        {
+            let mut running = self.running.lock().unwrap();
+            for job in &jobs {
+                let mut update = JobUpdate::new(
+                    job.lock_id
+                        .expect("dequeued jobs are always returned with their lock"),
+                );
+                update.dequeued_from = Some(job.queue_name.clone());
+                update.function_id = job.function_id;
+                running.insert(job.id, update);
+            }
+        }
+
+        let jobs = self.apply_team_rate_limits(jobs);
+        self.record_served(&jobs);
+        self.fetch_offloaded(self.decrypt_dequeued(jobs)?).await
+    }
+
+    /// A point-in-time view of the worker's in-memory state - the jobs it's holding, the updates it
+    /// has waiting to be flushed, when it last dequeued or hit an error, and how many jobs it has handed
+    /// out per team in the current served window. Doesn't touch the database, so it's cheap enough to
+    /// serve from a debug endpoint.
+    pub fn metrics_snapshot(&self) -> WorkerSnapshot {
+        let mut in_flight_by_queue = HashMap::new();
+        let held_jobs = {
+            let running = self.running.lock().unwrap();
+            for queue in running.values().filter_map(|u| u.dequeued_from.as_ref()) {
+                *in_flight_by_queue.entry(queue.clone()).or_insert(0) += 1;
+            }
+            running.len()
+        };
+        let pending_flush = self.flush_batch.lock().unwrap().pending.len();
+        let mut stats = self.stats.lock().unwrap();
+        stats.roll_served_window(self.served_window);
+
+        WorkerSnapshot {
+            held_jobs,
+            in_flight_by_queue,
+            pending_flush,
+            last_dequeue: stats.last_dequeue,
+            last_error: stats.last_error.clone(),
+            served_by_team: stats.served_by_team.clone(),
+            served_since: stats.served_since,
+            processing_by_function: stats.processing.histograms(),
+        }
+    }
+
+    // Records how long a handler took to process one of a function's jobs, warning if that's more than
+    // `slow_factor` times the function's rolling median. Jobs with no function aren't recorded
+    pub(crate) fn record_processing(
+        &self,
+        job_id: Uuid,
+        function_id: Option<Uuid>,
+        elapsed: std::time::Duration,
+        slow_factor: Option<f64>,
+    ) {
+        let Some(function_id) = function_id else {
+            return;
+        };
+        let median = self.stats.lock().unwrap().processing.record(
+            function_id,
+            elapsed,
+            self.max_timed_functions,
+        );
+
+        if let (Some(median), Some(factor)) = (median, slow_factor) {
+            let elapsed_ms = elapsed.as_millis() as u64;
+            if elapsed_ms as f64 > median as f64 * factor {
+                warn!(
+                    "Job {} of function {} took {}ms to process, over {}x the function's median of {}ms",
+                    job_id, function_id, elapsed_ms, factor, median
+                );
+            }
+        }
+    }
+
+    // Counts the jobs handed out to the caller - so after rate limiting - against their teams
+    fn record_served(&self, jobs: &[Job]) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.roll_served_window(self.served_window);
+        for job in jobs {
+            *stats.served_by_team.entry(job.team_id).or_insert(0) += 1;
+        }
+    }
+
+    fn record_dequeue(&self, result: Result<Vec<Job>, QueueError>) -> Result<Vec<Job>, QueueError> {
+        let mut stats = self.stats.lock().unwrap();
+        match &result {
+            Ok(_) => stats.last_dequeue = Some(Utc::now()),
+            Err(e) => stats.record_error(e),
+        }
+        result
+    }
+
+    // How many jobs can be dequeued from the queue, as far as the circuit breaker is concerned
+    fn breaker_limit(&self, queue: &str, limit: usize) -> usize {
+        match &self.circuit_breaker {
+            Some(breaker) => breaker.admit(queue, limit),
+            None => limit,
+        }
+    }
 
+    // A dequeue let through to probe a half open breaker that didn't turn up a job to probe with hands the
+    // chance back, so the next dequeue can try
+    fn settle_probe(
+        &self,
+        queue: &str,
+        result: Result<Vec<Job>, QueueError>,
+    ) -> Result<Vec<Job>, QueueError> {
+        if let Some(breaker) = &self.circuit_breaker {
+            if !matches!(&result, Ok(jobs) if !jobs.is_empty()) {
+                breaker.cancel_probe(queue);
+            }
+        }
+        result
+    }
+
+    // A released job's outcome, to count against its queue's breaker once the release is flushed - completing is a
+    // success, and failing, or being retried after a failure, is a failure. Anything else, like a job being deferred,
+    // doesn't count.
+    fn outcome(&self, update: &JobUpdate) -> Option<Outcome> {
+        let (Some(breaker), Some(queue)) = (&self.circuit_breaker, &update.dequeued_from) else {
+            return None;
+        };
+        let success = match update.state {
+            Some(JobState::Completed) => true,
+            Some(JobState::Failed) => false,
+            _ if update.failed_attempt => false,
+            _ => return None,
+        };
+        Some(Outcome {
+            breaker: breaker.clone(),
+            queue: queue.clone(),
+            success,
+        })
+    }
+
+    // If invariant checking is on, fails the whole dequeue if any job in it is corrupt. The batch is left
+    // locked, rather than handed to the caller, so the janitor will eventually return it to the queue.
+    fn check_dequeued(&self, jobs: &[Job]) -> Result<(), JobError> {
+        if self.check_invariants {
+            jobs.iter().try_for_each(Job::check_invariants)?;
+        }
+        Ok(())
+    }
+
+    // Returns the jobs the team rate limiter lets through, if there is one. The rest are released back to the
+    // queue, rescheduled for when their team will next have capacity, so they aren't immediately dequeued again.
+    fn apply_team_rate_limits(&self, jobs: Vec<Job>) -> Vec<Job> {
+        let Some(limiter) = &self.team_rate_limiter else {
+            return jobs;
+        };
+
+        let now = Utc::now();
+        let mut admitted = Vec::with_capacity(jobs.len());
+        for job in jobs {
+            let Err(wait) = limiter.try_acquire(job.team_id) else {
+                admitted.push(job);
+                continue;
+            };
+
+            let deferred = self
+                .set_state(job.id, JobState::Available)
+                .and_then(|()| self.set_scheduled_at(job.id, now + wait));
+            if let Err(e) = deferred {
+                error!("Error deferring rate limited job {}: {:?}", job.id, e);
+                continue;
+            }
+            // As with any release, if this never flushes, the janitor returns the job to the queue
+            drop(self.release_job(job.id, None));
+        }
+        admitted
+    }
+
+    // Swaps the references to any offloaded vm_states in the jobs for the states themselves
+    async fn fetch_offloaded(&self, mut jobs: Vec<Job>) -> Result<Vec<Job>, QueueError> {
+        for job in jobs.iter_mut() {
+            job.vm_state =
+                resolve_vm_state(self.vm_state_offload.as_ref(), job.vm_state.take()).await?;
+        }
         Ok(jobs)
     }
 
+    // A job that can't be decrypted fails the whole dequeue. As with an invariant violation, the batch
+    // is left locked, to be returned to the queue by the janitor.
+    fn decrypt_dequeued(&self, jobs: Vec<Job>) -> Result<Vec<Job>, QueueError> {
+        let Some(cipher) = &self.payload_cipher else {
+            return Ok(jobs);
+        };
+        Ok(jobs
+            .into_iter()
+            .map(|job| cipher.decrypt_job(job))
+            .collect::<Result<_, _>>()?)
+    }
+
+    fn encrypt_payload(
+        &self,
+        job_id: Uuid,
+        field: PayloadField,
+        payload: Option<Bytes>,
+    ) -> Option<Bytes> {
+        match &self.payload_cipher {
+            Some(cipher) => payload.map(|p| cipher.encrypt(job_id, field, &p)),
+            None => payload,
+        }
+    }
+
+    /// Returns when the next available job in the queue is scheduled to run, even if that's in the future,
+    /// or None if the queue has no available jobs. Lets a worker with nothing to dequeue sleep until there
+    /// will be, rather than polling.
+    pub async fn next_scheduled(&self, queue: &str) -> Result<Option<DateTime<Utc>>, QueueError> {
+        next_scheduled(&mut *self.acquire().await?, queue).await
+    }
+
     /// Retrieve the VM state for a job, if, for example, you dequeued it and then realised you
     /// need the VM state as well.
     pub async fn get_vm_state(&self, job_id: Uuid) -> Result<Option<Bytes>, QueueError> {
@@ -136,7 +615,47 @@ impl Worker {
                 .lock_id
         };
 
-        get_vm_state(&self.pool, job_id, lock_id).await
+        let vm_state = get_vm_state(&mut *self.acquire().await?, job_id, lock_id).await?;
+        resolve_vm_state(self.vm_state_offload.as_ref(), vm_state).await
+    }
+
+    /// Dequeues jobs without any of their payloads, handing each one back with handles that fetch a
+    /// payload the first time it's asked for - for workers that only sometimes need e.g. the blob, and
+    /// don't want to pay to transfer it otherwise. Otherwise the same as dequeue_jobs.
+    pub async fn dequeue_lazy(
+        &self,
+        queue: &str,
+        limit: usize,
+    ) -> Result<Vec<LazyJob<'_>>, QueueError> {
+        let jobs = self
+            .dequeue_projected(queue, limit, JobProjection::default())
+            .await?;
+        Ok(jobs
+            .into_iter()
+            .map(|job| LazyJob::new(self, job))
+            .collect())
+    }
+
+    // Fetches one of a job's payloads, for a `LazyPayload`
+    pub(crate) async fn fetch_payload(
+        &self,
+        job_id: Uuid,
+        lock_id: Uuid,
+        column: &'static str,
+    ) -> Result<Option<Bytes>, QueueError> {
+        let payload = get_payload(&mut *self.acquire().await?, job_id, lock_id, column).await?;
+        match &self.payload_cipher {
+            Some(cipher) if column == "parameters" => {
+                Ok(cipher.decrypt_field(job_id, PayloadField::Parameters, payload)?)
+            }
+            Some(cipher) if column == "blob" => {
+                Ok(cipher.decrypt_field(job_id, PayloadField::Blob, payload)?)
+            }
+            _ if column == "vm_state" => {
+                resolve_vm_state(self.vm_state_offload.as_ref(), payload).await
+            }
+            _ => Ok(payload),
+        }
     }
 
     /// Release a job back to the queue. Callers are returned a flush handle, which they
@@ -152,20 +671,9 @@ impl Worker {
     /// this workers lock on the job has been lost), or until the deadline is exceeded, if one is
     /// provided. All updates will have at least one flush attempt.
     pub fn release_job(&self, job_id: Uuid, deadline: Option<Duration>) -> FlushHandle {
-        let update = {
-            let mut running = self.running.lock().unwrap();
-            let Some(update) = running.remove(&job_id) else {
-                return FlushHandle::immediate(Err(JobError::UnknownJobId(job_id)));
-            };
-            match update.state {
-                Some(JobState::Running) | None => {
-                    // Keep track of any /other/ updates that might have been stored, so this
-                    // error is recoverable simply by providing an appropriate new state.
-                    running.insert(job_id, update);
-                    return FlushHandle::immediate(Err(JobError::FlushWithoutNextState(job_id)));
-                }
-                _ => update,
-            }
+        let update = match self.take_for_release(job_id) {
+            Ok(update) => update,
+            Err(e) => return FlushHandle::immediate(Err(e)),
         };
 
         // If we were given a deadline, this update should be flushed at least as soon as then,
@@ -174,13 +682,110 @@ impl Worker {
         let flush_by = now + deadline.unwrap_or(self.linger);
         let deadline = deadline.map(|d| now + d);
 
-        let (pending, handle) = PendingUpdate::new(job_id, update, deadline);
+        let outcome = self.outcome(&update);
+        let (pending, handle) = PendingUpdate::new(job_id, update, deadline, outcome);
 
         let mut batch = self.flush_batch.lock().unwrap();
         batch.add(pending, flush_by);
         handle
     }
 
+    /// Release a job, flushing its update straight away rather than batching it, and return the job
+    /// as the update left it - saving a fetch for callers that want to e.g. log the finished job. Returns
+    /// None if this worker's lock on the job has been lost, in which case nothing was updated. Fails just
+    /// as `release_job` does for unknown jobs and jobs without a next state. As with dequeue_jobs, the
+    /// vm_state isn't returned.
+    pub async fn release_job_returning(&self, job_id: Uuid) -> Result<Option<Job>, QueueError> {
+        let mut update = self.take_for_release(job_id)?;
+        self.offload_vm_state(job_id, &mut update).await?;
+        let outcome = self.outcome(&update);
+        let Some(job) = flush_job_returning(&mut *self.acquire().await?, job_id, &update).await?
+        else {
+            return Ok(None);
+        };
+        if let Some(outcome) = outcome {
+            outcome.record();
+        }
+        Ok(self.decrypt_dequeued(vec![job])?.pop())
+    }
+
+    /// Complete a job, and if it was enqueued with a `reply_to` queue, enqueue its result there - a job for
+    /// the same team, with `result` as its parameters and the completed job as its parent, so requesters can
+    /// match replies up by `parent_job_id`. The completion and the reply are written in one transaction,
+    /// so a reply is only ever seen for a completed job, and a completion is never left without its reply.
+    /// If the reply can't be enqueued, e.g. because its queue is draining, neither is written, and the error
+    /// is returned - as with `release_job_returning`, the worker has already let go of the job by then, so
+    /// it's left for the janitor. Returns the reply, or None if the job had no `reply_to`. Fails with an
+    /// InvalidLock error if the worker's lock on the job has been lost.
+    pub async fn complete_job_with_reply(
+        &self,
+        job_id: Uuid,
+        result: Option<Bytes>,
+    ) -> Result<Option<Job>, QueueError> {
+        self.set_state(job_id, JobState::Completed)?;
+        let mut update = self.take_for_release(job_id)?;
+        self.offload_vm_state(job_id, &mut update).await?;
+        let outcome = self.outcome(&update);
+
+        let mut txn = self.begin().await?;
+        let Some(completed) = flush_job_returning(&mut *txn, job_id, &update).await? else {
+            return Err(JobError::InvalidLock(update.lock_id, job_id).into());
+        };
+        let Some(reply_to) = completed.reply_to else {
+            txn.commit().await?;
+            if let Some(outcome) = outcome {
+                outcome.record();
+            }
+            return Ok(None);
+        };
+
+        let reply_id = Uuid::now_v7();
+        let reply = JobInit {
+            id: Some(reply_id),
+            team_id: completed.team_id,
+            queue_name: reply_to,
+            priority: completed.priority,
+            parameters: self.encrypt_payload(reply_id, PayloadField::Parameters, result),
+            correlation_id: completed.correlation_id,
+            parent_job_id: Some(job_id),
+            ..Default::default()
+        };
+        let reply = create_job(&mut *txn, reply).await?;
+        txn.commit().await?;
+        if let Some(outcome) = outcome {
+            outcome.record();
+        }
+        Ok(self.decrypt_dequeued(vec![reply])?.pop())
+    }
+
+    async fn offload_vm_state(
+        &self,
+        job_id: Uuid,
+        update: &mut JobUpdate,
+    ) -> Result<(), QueueError> {
+        match &self.vm_state_offload {
+            Some(offload) => offload.offload_update(job_id, update).await,
+            None => Ok(()),
+        }
+    }
+
+    // Takes a job's staged update out of the worker's bookkeeping, for releasing it
+    fn take_for_release(&self, job_id: Uuid) -> Result<JobUpdate, JobError> {
+        let mut running = self.running.lock().unwrap();
+        let Some(update) = running.remove(&job_id) else {
+            return Err(JobError::UnknownJobId(job_id));
+        };
+        match update.state {
+            Some(JobState::Running) | None => {
+                // Keep track of any /other/ updates that might have been stored, so this
+                // error is recoverable simply by providing an appropriate new state.
+                running.insert(job_id, update);
+                Err(JobError::FlushWithoutNextState(job_id))
+            }
+            _ => Ok(update),
+        }
+    }
+
     /// Force flush all pending updates, regardless of linger time or buffer size.
     /// Transient errors encountered during the flush will cause the operation to
     /// be aborted, and the error to be returned to the caller. If no transient errors
@@ -189,7 +794,7 @@ impl Worker {
     pub async fn force_flush(&self) -> Result<(), QueueError> {
         let mut to_flush = { self.flush_batch.lock().unwrap().take() };
         let res = if !to_flush.pending.is_empty() {
-            to_flush.flush(&self.pool).await
+            to_flush.flush(&self.pool, &self.acquire_retry).await
         } else {
             Ok(())
         };
@@ -223,8 +828,59 @@ impl Worker {
             update.last_heartbeat = Some(Utc::now());
             update.lock_id
         };
-        let mut connection = self.pool.acquire().await?;
-        set_heartbeat(connection.as_mut(), job_id, lock_id).await
+        set_heartbeat(&mut *self.acquire().await?, job_id, lock_id).await
+    }
+
+    // Drops a job from the worker's bookkeeping without touching the database, for jobs whose lock has been
+    // lost. If the job has since been dequeued again under another lock, that entry is left alone.
+    pub(crate) fn forget_job(&self, job_id: Uuid, lock_id: Option<Uuid>) {
+        let mut running = self.running.lock().unwrap();
+        if running.get(&job_id).map(|u| Some(u.lock_id)) == Some(lock_id) {
+            running.remove(&job_id);
+        }
+    }
+
+    // Returns jobs to the queue as they are, unfinished, dropping them from the worker's bookkeeping. Each is given
+    // with the lock it was held under, and jobs no longer held under that lock are left alone. Returns how many
+    // were returned.
+    pub(crate) async fn force_release_jobs(
+        &self,
+        jobs: &[(Uuid, Uuid)],
+    ) -> Result<u64, QueueError> {
+        for (job_id, lock_id) in jobs {
+            self.forget_job(*job_id, Some(*lock_id));
+        }
+        force_release_jobs(&mut *self.acquire().await?, jobs).await
+    }
+
+    /// Heartbeat a batch of jobs in one go, also setting an explicit lock deadline on each of them. Until
+    /// that deadline passes, the janitor won't consider these jobs stalled, even if they aren't heartbeated
+    /// again. Unlike `heartbeat`, this is never skipped due to the heartbeat window, since the caller is
+    /// explicitly asking for the deadline to move. Returns an error if any of the jobs are unknown to this
+    /// worker, or if this worker's lock on any of them has been lost.
+    pub async fn extend_locks(
+        &self,
+        job_ids: &[Uuid],
+        new_deadline: DateTime<Utc>,
+    ) -> Result<(), QueueError> {
+        let locks = {
+            let mut pending = self.running.lock().unwrap();
+            if let Some(unknown) = job_ids.iter().find(|id| !pending.contains_key(id)) {
+                return Err(JobError::UnknownJobId(*unknown).into());
+            }
+
+            let now = Utc::now();
+            job_ids
+                .iter()
+                .map(|id| {
+                    let update = pending.get_mut(id).expect("we just checked it's present");
+                    update.last_heartbeat = Some(now);
+                    (*id, update.lock_id)
+                })
+                .collect::<Vec<_>>()
+        };
+
+        extend_locks(&mut *self.acquire().await?, &locks, new_deadline).await
     }
 
     /// This is how you "return" a job to the queue, by setting the state to "available"
@@ -286,15 +942,48 @@ impl Worker {
     /// Passing None here will clear the metadata
     pub fn set_metadata(&self, job_id: Uuid, metadata: Option<Bytes>) -> Result<(), JobError> {
         let mut pending = self.running.lock().unwrap();
-        pending
+        let update = pending
             .get_mut(&job_id)
-            .ok_or(JobError::UnknownJobId(job_id))?
-            .metadata = Some(metadata);
+            .ok_or(JobError::UnknownJobId(job_id))?;
+        update.metadata = Some(metadata);
+        update.metadata_patch = None; // Overwriting the metadata discards any pending merges
+        Ok(())
+    }
+
+    /// Merge the keys of a JSON object into the job's metadata, leaving any other keys as they are.
+    /// The merge is done by the database when the update is flushed, so the worker doesn't need to
+    /// read the current metadata first. If the stored metadata isn't a JSON object, it's replaced by
+    /// the merged keys. Returns an error if `patch` isn't a JSON object.
+    pub fn merge_metadata(&self, job_id: Uuid, patch: Value) -> Result<(), JobError> {
+        if !patch.is_object() {
+            return Err(JobError::InvalidMetadataPatch(job_id));
+        }
+
+        let mut pending = self.running.lock().unwrap();
+        let update = pending
+            .get_mut(&job_id)
+            .ok_or(JobError::UnknownJobId(job_id))?;
+
+        if let Some(metadata) = update.metadata.take() {
+            // The worker has already replaced the metadata wholesale, so just merge into that
+            update.metadata = Some(merge_metadata(metadata.as_deref(), &patch));
+            return Ok(());
+        }
+
+        match update
+            .metadata_patch
+            .as_mut()
+            .and_then(Value::as_object_mut)
+        {
+            Some(existing) => existing.extend(patch.as_object().cloned().unwrap_or_default()),
+            None => update.metadata_patch = Some(patch),
+        }
         Ok(())
     }
 
     /// Passing None here will clear the parameters
     pub fn set_parameters(&self, job_id: Uuid, parameters: Option<Bytes>) -> Result<(), JobError> {
+        let parameters = self.encrypt_payload(job_id, PayloadField::Parameters, parameters);
         let mut pending = self.running.lock().unwrap();
         pending
             .get_mut(&job_id)
@@ -315,11 +1004,113 @@ impl Worker {
             }
         }
 
-        dead_letter(&self.pool, job_id, reason).await
+        dead_letter(&mut *self.acquire().await?, job_id, reason).await?;
+        // Dead lettering takes the job's lock, so there's nothing more this worker can do with it
+        self.running.lock().unwrap().remove(&job_id);
+        Ok(())
+    }
+
+    /// Mark a job as failed, recording the error in the job's retry history (see `Job::retry_history`).
+    /// Like `set_state`, this only stages the update - callers still need to `release_job`.
+    pub async fn fail_job(&self, job_id: Uuid, error: &str) -> Result<(), QueueError> {
+        self.record_failure(job_id, error).await?;
+        self.set_state(job_id, JobState::Failed)?;
+        self.mark_failed_attempt(job_id);
+        Ok(())
+    }
+
+    /// Return a job to the queue to be retried after `backoff`, recording the error in the job's retry
+    /// history (see `Job::retry_history`). The job's VM state is kept or cleared according to the
+    /// retry mode of its function's policy in `retry_policies`. Like `set_state`, this only stages the
+    /// update - callers still need to `release_job`.
+    pub async fn retry_with_backoff(
+        &self,
+        job_id: Uuid,
+        error: &str,
+        backoff: Duration,
+    ) -> Result<(), QueueError> {
+        let mode = self.retry_mode(job_id)?;
+        self.retry_with_mode(job_id, error, backoff, mode).await
+    }
+
+    /// As `retry_with_backoff`, but with the retry mode chosen for this job, rather than by its
+    /// function. Resuming keeps whatever VM state the job has (including any the worker has set
+    /// since dequeuing it), restarting clears it.
+    pub async fn retry_with_mode(
+        &self,
+        job_id: Uuid,
+        error: &str,
+        backoff: Duration,
+        mode: RetryMode,
+    ) -> Result<(), QueueError> {
+        self.record_failure(job_id, error).await?;
+        self.stage_retry(job_id, backoff, mode)?;
+        Ok(())
+    }
+
+    // The retry mode for a job, going by its function
+    pub(crate) fn retry_mode(&self, job_id: Uuid) -> Result<RetryMode, JobError> {
+        let running = self.running.lock().unwrap();
+        let update = running.get(&job_id).ok_or(JobError::UnknownJobId(job_id))?;
+        Ok(update
+            .function_id
+            .and_then(|f| self.retry_policies.get(&f))
+            .map_or_else(RetryMode::default, |policy| policy.mode))
+    }
+
+    // Stages everything about a retry except the retry history entry
+    pub(crate) fn stage_retry(
+        &self,
+        job_id: Uuid,
+        backoff: Duration,
+        mode: RetryMode,
+    ) -> Result<(), JobError> {
+        self.set_state(job_id, JobState::Available)?;
+        self.set_scheduled_at(job_id, Utc::now() + backoff)?;
+        if mode == RetryMode::Restart {
+            self.set_vm_state(job_id, None)?;
+        }
+        self.mark_failed_attempt(job_id);
+        Ok(())
+    }
+
+    // Marks the job's pending release as ending a failed attempt, so the flush bumps its failed_attempts
+    pub(crate) fn mark_failed_attempt(&self, job_id: Uuid) {
+        if let Some(update) = self.running.lock().unwrap().get_mut(&job_id) {
+            update.failed_attempt = true;
+        }
+    }
+
+    // Appends an entry to the job's retry history. If the worker has already set new metadata for the job,
+    // the entry is added to that, otherwise it's added to the metadata currently stored for the job.
+    async fn record_failure(&self, job_id: Uuid, error: &str) -> Result<(), QueueError> {
+        let (lock_id, pending) = {
+            let running = self.running.lock().unwrap();
+            let update = running.get(&job_id).ok_or(JobError::UnknownJobId(job_id))?;
+            (
+                update.lock_id,
+                (update.metadata.clone(), update.metadata_patch.clone()),
+            )
+        };
+
+        let current = match pending {
+            (Some(metadata), _) => metadata,
+            (None, None) => get_metadata(&mut *self.acquire().await?, job_id, lock_id).await?,
+            (None, Some(patch)) => {
+                let stored = get_metadata(&mut *self.acquire().await?, job_id, lock_id).await?;
+                merge_metadata(stored.as_deref(), &patch)
+            }
+        };
+
+        let metadata = append_retry_entry(current.as_deref(), error)
+            .ok_or(JobError::InvalidMetadata(job_id))?;
+        self.set_metadata(job_id, Some(metadata))?;
+        Ok(())
     }
 
     /// Passing None here will clear the blob
     pub fn set_blob(&self, job_id: Uuid, blob: Option<Bytes>) -> Result<(), JobError> {
+        let blob = self.encrypt_payload(job_id, PayloadField::Blob, blob);
         let mut pending = self.running.lock().unwrap();
         pending
             .get_mut(&job_id)
@@ -331,12 +1122,16 @@ impl Worker {
 
 // Started by each worker on creation, just loops seeing if the passed batch can be flushed, and
 // if it can, flushing it.
+#[allow(clippy::too_many_arguments)]
 async fn flush_loop(
     pool: PgPool,
+    acquire_retry: AcquireRetry,
     batch: Weak<Mutex<FlushBatch>>,
+    stats: Arc<Mutex<WorkerStats>>,
     max_buffered: usize,
     max_bytes: usize,
     interval: Duration,
+    mut stop: oneshot::Receiver<()>,
 ) {
     loop {
         let Some(batch) = batch.upgrade() else {
@@ -346,8 +1141,9 @@ async fn flush_loop(
         // Contemplating sync mutexes on the tree of woe.
         let mut to_flush = { batch.lock().unwrap().take() };
         if to_flush.should_flush(max_buffered, max_bytes) {
-            if let Err(e) = to_flush.flush(&pool).await {
+            if let Err(e) = to_flush.flush(&pool, &acquire_retry).await {
                 error!("Error flushing batch: {:?}", e);
+                stats.lock().unwrap().record_error(&e);
             }
         }
         // We can always merge the taken batch back into the pending batch - on successful
@@ -356,7 +1152,54 @@ async fn flush_loop(
         // because it makes this future !Send, and the tokio::spawn above will fail, but in case
         // we change the looping strategy, I'm calling it out explicitly too.
         batch.lock().unwrap().merge(to_flush);
-        tokio::time::sleep(interval.to_std().unwrap()).await;
+        drop(batch);
+        tokio::select! {
+            _ = tokio::time::sleep(interval.to_std().unwrap()) => {}
+            // Told to stop by `Worker::close`
+            _ = &mut stop => break,
+        }
+    }
+}
+
+// What a worker looked like at the moment `Worker::metrics_snapshot` was called
+#[derive(Debug, Clone, Serialize)]
+pub struct WorkerSnapshot {
+    pub held_jobs: usize, // Jobs dequeued and not yet released
+    pub in_flight_by_queue: HashMap<String, usize>, // The held jobs, by the queue they were dequeued from
+    // Released updates waiting to be flushed. Updates being flushed at the moment of the snapshot aren't counted
+    pub pending_flush: usize,
+    pub last_dequeue: Option<DateTime<Utc>>, // The last successful dequeue, even if it returned no jobs
+    pub last_error: Option<(DateTime<Utc>, String)>, // The last error dequeueing or flushing, and when it happened
+    // The jobs handed out since `served_since`, by team. Jobs held back by the team rate limiter aren't counted
+    pub served_by_team: HashMap<i32, u64>,
+    pub served_since: DateTime<Utc>, // The start of the current served window
+    // How long `Worker::run` handlers have taken, by the function of the job they were processing. Handlers
+    // cancelled part way through, e.g. because the job's lock was lost, aren't counted. Only the
+    // `Worker::max_timed_functions` functions that most recently ran a job are kept
+    pub processing_by_function: HashMap<Uuid, ProcessingHistogram>,
+}
+
+#[derive(Default)]
+struct WorkerStats {
+    last_dequeue: Option<DateTime<Utc>>,
+    last_error: Option<(DateTime<Utc>, String)>,
+    served_by_team: HashMap<i32, u64>,
+    served_since: DateTime<Utc>,
+    processing: ProcessingTimings,
+}
+
+impl WorkerStats {
+    fn record_error(&mut self, error: &QueueError) {
+        self.last_error = Some((Utc::now(), error.to_string()));
+    }
+
+    // Starts a new served window, with every team's count back at zero, if the current one is over
+    fn roll_served_window(&mut self, window: Duration) {
+        let now = Utc::now();
+        if now - self.served_since >= window {
+            self.served_by_team.clear();
+            self.served_since = now;
+        }
     }
 }
 
@@ -370,6 +1213,8 @@ struct FlushBatch {
     pub blobs_size: usize,
     // A running total of all vm_state bytes held in the batch
     pub vm_states_size: usize,
+    // The worker's vm_state offloading, if it has any. Carried over to the batch left behind by `take`
+    pub offload: Option<VmStateOffload>,
 }
 
 impl FlushBatch {
@@ -379,6 +1224,7 @@ impl FlushBatch {
             pending: Default::default(),
             blobs_size: 0,
             vm_states_size: 0,
+            offload: None,
         }
     }
 
@@ -400,7 +1246,7 @@ impl FlushBatch {
         self.pending.push(pending);
     }
 
-    async fn flush(&mut self, pool: &PgPool) -> Result<(), QueueError> {
+    async fn flush(&mut self, pool: &PgPool, retry: &AcquireRetry) -> Result<(), QueueError> {
         let now = Utc::now();
         // First, filter any updates whose deadline is exceeded that we have
         // already tried to flush once, sending a deadline exceeded error to the
@@ -414,7 +1260,17 @@ impl FlushBatch {
             }
         }
 
-        let mut txn = pool.begin().await?;
+        // Large vm_states are uploaded before the transaction is opened, so it isn't held across the uploads.
+        // Each is swapped for its reference in the update, so if the flush fails, the retry doesn't upload it again
+        if let Some(offload) = &self.offload {
+            for to_flush in self.pending.iter_mut() {
+                offload
+                    .offload_update(to_flush.job_id, &mut to_flush.update)
+                    .await?;
+            }
+        }
+
+        let mut txn = acquire::begin(pool, retry).await?;
         let mut results = Vec::new();
         for to_flush in self.pending.iter_mut() {
             to_flush.tries += 1;
@@ -452,7 +1308,9 @@ impl FlushBatch {
     // Take the current batch, replacing it in memory with an empty one. Used along with "merge"
     // to let us flush without holding the batch lock for the duration of the flush
     fn take(&mut self) -> Self {
-        std::mem::take(self)
+        let taken = std::mem::take(self);
+        self.offload = taken.offload.clone();
+        taken
     }
 
     // Combine two batches, setting the next mandatory flush to the earliest of the two
@@ -475,6 +1333,7 @@ struct PendingUpdate {
     update: JobUpdate,
     deadline: Option<DateTime<Utc>>,
     tries: u8,
+    outcome: Option<Outcome>, // Counted against the breaker if the update is flushed
     tx: oneshot::Sender<Result<(), JobError>>,
 }
 
@@ -483,6 +1342,7 @@ impl PendingUpdate {
         job_id: Uuid,
         update: JobUpdate,
         deadline: Option<DateTime<Utc>>,
+        outcome: Option<Outcome>,
     ) -> (Self, FlushHandle) {
         let (tx, rx) = oneshot::channel();
         let update = Self {
@@ -490,6 +1350,7 @@ impl PendingUpdate {
             update,
             deadline,
             tries: 0,
+            outcome,
             tx,
         };
         (update, FlushHandle { inner: rx })
@@ -501,11 +1362,34 @@ impl PendingUpdate {
     }
 
     pub fn resolve(self, result: Result<(), JobError>) {
+        if result.is_ok() {
+            if let Some(outcome) = self.outcome {
+                outcome.record();
+            }
+        }
         // We do not care if someone is waiting for this result or not
         let _ = self.tx.send(result);
     }
 }
 
+// How a released job turned out, for its queue's circuit breaker
+struct Outcome {
+    breaker: Arc<CircuitBreaker>,
+    queue: String,
+    success: bool,
+}
+
+impl Outcome {
+    fn record(self) {
+        if self.breaker.record(&self.queue, self.success) {
+            warn!(
+                "Circuit breaker tripped for {}, no more of its jobs will be dequeued until it cools down",
+                self.queue
+            );
+        }
+    }
+}
+
 pub struct FlushHandle {
     inner: oneshot::Receiver<Result<(), JobError>>,
 }
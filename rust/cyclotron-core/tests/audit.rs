@@ -0,0 +1,121 @@
+use chrono::Duration;
+use common::create_new_job;
+use cyclotron_core::{Janitor, JobState, QueueManager, Worker};
+use sqlx::PgPool;
+
+mod common;
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_transition_audit_records_state_changes(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0;
+
+    // Jobs created before the audit is turned on have no record of it
+    let unaudited = manager.create_job(create_new_job()).await.unwrap();
+
+    manager.set_transition_audit(true).await.unwrap();
+    let job = manager.create_job(create_new_job()).await.unwrap();
+
+    // Run the job, return it to the queue, then run it again to completion. We're after a particular job, so
+    // push the unaudited one to the back of the queue first
+    sqlx::query("UPDATE cyclotron_jobs SET priority = 10 WHERE id = $1")
+        .bind(unaudited.id)
+        .execute(&db)
+        .await
+        .unwrap();
+    let mut locks = vec![];
+    for next in [JobState::Available, JobState::Completed] {
+        let dequeued = worker.dequeue_jobs(&job.queue_name, 1).await.unwrap();
+        assert_eq!(dequeued[0].id, job.id);
+        locks.push(dequeued[0].lock_id);
+        worker.set_state(job.id, next).unwrap();
+        let handle = worker.release_job(job.id, None);
+        worker.force_flush().await.unwrap();
+        handle.await.unwrap();
+    }
+
+    let transitions = manager.job_transitions(job.id).await.unwrap();
+    let steps: Vec<_> = transitions
+        .iter()
+        .map(|t| (t.from_state, t.to_state, t.lock_id))
+        .collect();
+    assert_eq!(
+        steps,
+        [
+            (None, JobState::Available, None),
+            (Some(JobState::Available), JobState::Running, locks[0]),
+            (Some(JobState::Running), JobState::Available, locks[0]),
+            (Some(JobState::Available), JobState::Running, locks[1]),
+            (Some(JobState::Running), JobState::Completed, locks[1]),
+        ]
+    );
+    assert!(transitions.iter().all(|t| t.job_id == job.id));
+    assert!(transitions.windows(2).all(|w| w[0].at <= w[1].at));
+
+    // The other job's only change since the audit was turned on was to its priority, which isn't a state change
+    assert!(manager
+        .job_transitions(unaudited.id)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Once it's off, nothing more is recorded
+    manager.set_transition_audit(false).await.unwrap();
+    let dequeued = worker.dequeue_jobs(&job.queue_name, 1).await.unwrap();
+    assert_eq!(dequeued[0].id, unaudited.id);
+    assert!(manager
+        .job_transitions(unaudited.id)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // And the record can't be rewritten
+    let res = sqlx::query("DELETE FROM cyclotron_job_transitions")
+        .execute(&db)
+        .await;
+    assert!(res.is_err());
+    assert_eq!(manager.job_transitions(job.id).await.unwrap().len(), 5);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_janitor_prunes_old_transitions(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let janitor = Janitor::from_pool(db.clone());
+
+    manager.set_transition_audit(true).await.unwrap();
+    let mut jobs = vec![];
+    for _ in 0..3 {
+        jobs.push(manager.create_job(create_new_job()).await.unwrap());
+    }
+
+    // Transitions inside their retention are kept
+    let pruned = janitor
+        .prune_transitions_older_than(Duration::hours(1), 2)
+        .await
+        .unwrap();
+    assert_eq!(pruned, 0);
+    assert_eq!(manager.job_transitions(jobs[0].id).await.unwrap().len(), 1);
+
+    // And ones past it are deleted, across as many batches as it takes
+    let pruned = janitor
+        .prune_transitions_older_than(Duration::zero(), 2)
+        .await
+        .unwrap();
+    assert_eq!(pruned, 3);
+    for job in &jobs {
+        assert!(manager.job_transitions(job.id).await.unwrap().is_empty());
+    }
+
+    // Pruning only lets deletes through - the record still can't be rewritten
+    manager.create_job(create_new_job()).await.unwrap();
+    let mut txn = db.begin().await.unwrap();
+    sqlx::query("SELECT set_config('cyclotron.pruning_transitions', 'on', true)")
+        .execute(&mut *txn)
+        .await
+        .unwrap();
+    let res = sqlx::query("UPDATE cyclotron_job_transitions SET lock_id = NULL")
+        .execute(&mut *txn)
+        .await;
+    assert!(res.is_err());
+}
@@ -1,8 +1,13 @@
 use std::sync::Arc;
 
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use common::{assert_job_matches_init, create_new_job, dates_match};
-use cyclotron_core::{JobState, QueueManager, Worker};
+use cyclotron_core::{
+    Job, JobError, JobProjection, JobState, JobStore, JobUpdate, QueueError, QueueManager,
+    RetryMode, RetryPolicy, Worker,
+};
+use rand::seq::SliceRandom;
+use serde_json::json;
 use sqlx::PgPool;
 use uuid::Uuid;
 
@@ -249,3 +254,793 @@ pub async fn test_bulk_insert(db: PgPool) {
 
     assert_eq!(dequeue_jobs.len(), 1000);
 }
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_extend_locks(db: PgPool) {
+    let worker = Worker::from_pool(db.clone(), Default::default());
+    let manager = QueueManager::from_pool(db.clone());
+
+    let job_template = create_new_job();
+    for _ in 0..3 {
+        manager
+            .create_job(job_template.clone())
+            .await
+            .expect("failed to create job");
+    }
+
+    let jobs = worker
+        .dequeue_jobs(&job_template.queue_name, 3)
+        .await
+        .expect("failed to dequeue job");
+    assert_eq!(jobs.len(), 3);
+
+    let deadline = Utc::now() + Duration::minutes(5);
+    let ids = jobs.iter().map(|j| j.id).collect::<Vec<_>>();
+    worker
+        .extend_locks(&ids, deadline)
+        .await
+        .expect("failed to extend locks");
+
+    for job in &jobs {
+        let (last_heartbeat, lock_expires_at): (Option<DateTime<Utc>>, Option<DateTime<Utc>>) =
+            sqlx::query_as(
+                "SELECT last_heartbeat, lock_expires_at FROM cyclotron_jobs WHERE id = $1",
+            )
+            .bind(job.id)
+            .fetch_one(&db)
+            .await
+            .expect("failed to fetch job");
+
+        // Both the heartbeat and the lock deadline should have moved
+        assert!(last_heartbeat.unwrap() > job.last_heartbeat.unwrap());
+        assert!(dates_match(&lock_expires_at.unwrap(), &deadline));
+    }
+
+    // Extending the lock on a job this worker doesn't hold is an error
+    assert!(worker
+        .extend_locks(&[Uuid::now_v7()], deadline)
+        .await
+        .is_err());
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_retry_history(db: PgPool) {
+    let worker = Worker::from_pool(db.clone(), Default::default());
+    let manager = QueueManager::from_pool(db.clone());
+
+    let job = create_new_job();
+    manager
+        .create_job(job.clone())
+        .await
+        .expect("failed to create job");
+
+    // Fail the job a few times, retrying it immediately each time
+    for i in 0..3 {
+        let dequeued = worker
+            .dequeue_jobs(&job.queue_name, 1)
+            .await
+            .expect("failed to dequeue job")
+            .pop()
+            .expect("failed to dequeue job");
+        assert_eq!(dequeued.retry_history().len(), i);
+
+        worker
+            .retry_with_backoff(dequeued.id, &format!("error {}", i), Duration::zero())
+            .await
+            .expect("failed to retry job");
+        let handle = worker.release_job(dequeued.id, None);
+        worker.force_flush().await.unwrap();
+        handle.await.unwrap();
+    }
+
+    // Then fail it for good
+    let dequeued = worker
+        .dequeue_jobs(&job.queue_name, 1)
+        .await
+        .expect("failed to dequeue job")
+        .pop()
+        .expect("failed to dequeue job");
+    worker
+        .fail_job(dequeued.id, "final error")
+        .await
+        .expect("failed to fail job");
+    let handle = worker.release_job(dequeued.id, None);
+    worker.force_flush().await.unwrap();
+    handle.await.unwrap();
+
+    let metadata: Option<Vec<u8>> =
+        sqlx::query_scalar("SELECT metadata FROM cyclotron_jobs WHERE id = $1")
+            .bind(dequeued.id)
+            .fetch_one(&db)
+            .await
+            .expect("failed to fetch job");
+    let failed = Job {
+        metadata,
+        ..dequeued
+    };
+
+    let history = failed.retry_history();
+    assert_eq!(history.len(), 4);
+    for (i, entry) in history.iter().take(3).enumerate() {
+        assert_eq!(entry.attempt, i as u32 + 1);
+        assert_eq!(entry.error, format!("error {}", i));
+    }
+    assert_eq!(history[3].attempt, 4);
+    assert_eq!(history[3].error, "final error");
+    assert!(history.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_retry_modes(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+
+    let mut resumed = create_new_job();
+    resumed.vm_state = Some(b"checkpoint".to_vec());
+    let mut restarted = resumed.clone();
+    restarted.function_id = Some(Uuid::now_v7());
+    let mut overridden = resumed.clone();
+    overridden.function_id = restarted.function_id;
+    worker.retry_policies.insert(
+        restarted.function_id.unwrap(),
+        RetryPolicy {
+            base: Duration::seconds(1),
+            factor: 2.0,
+            max: Duration::minutes(1),
+            jitter: 0.0,
+            mode: RetryMode::Restart,
+        },
+    );
+
+    let resumed = manager.create_job(resumed).await.unwrap();
+    let restarted = manager.create_job(restarted).await.unwrap();
+    let overridden = manager.create_job(overridden).await.unwrap();
+
+    let jobs = worker.dequeue_with_vm_state("test", 3).await.unwrap();
+    assert!(jobs
+        .iter()
+        .all(|j| j.vm_state.as_deref() == Some(&b"checkpoint"[..])));
+    for job in [&resumed, &restarted] {
+        worker
+            .retry_with_backoff(job.id, "error", Duration::zero())
+            .await
+            .unwrap();
+    }
+    // A mode passed for the job wins over its function's
+    worker
+        .retry_with_mode(overridden.id, "error", Duration::zero(), RetryMode::Resume)
+        .await
+        .unwrap();
+    let handles: Vec<_> = jobs
+        .iter()
+        .map(|j| worker.release_job(j.id, None))
+        .collect();
+    worker.force_flush().await.unwrap();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+
+    let vm_state = |id| {
+        sqlx::query_scalar::<_, Option<Vec<u8>>>(
+            "SELECT vm_state FROM cyclotron_jobs WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_one(&db)
+    };
+    assert_eq!(
+        vm_state(resumed.id).await.unwrap(),
+        Some(b"checkpoint".to_vec())
+    );
+    assert_eq!(vm_state(restarted.id).await.unwrap(), None);
+    assert_eq!(
+        vm_state(overridden.id).await.unwrap(),
+        Some(b"checkpoint".to_vec())
+    );
+
+    // Either way, the jobs are back in the queue to be retried
+    let jobs = worker.dequeue_with_vm_state("test", 3).await.unwrap();
+    assert_eq!(jobs.len(), 3);
+    assert!(jobs.iter().all(|j| j.retry_history().len() == 1));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_failed_attempts_count_only_failures(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+    let job = manager.create_job(create_new_job()).await.unwrap();
+
+    let release = || async {
+        let handle = worker.release_job(job.id, None);
+        worker.force_flush().await.unwrap();
+        handle.await.unwrap();
+        worker.dequeue_jobs("test", 1).await.unwrap().pop().unwrap()
+    };
+
+    // Being returned to the queue isn't a failure, however many transitions it takes
+    worker.dequeue_jobs("test", 1).await.unwrap();
+    worker.set_state(job.id, JobState::Available).unwrap();
+    let dequeued = release().await;
+    assert_eq!(dequeued.transition_count, 3);
+    assert_eq!(dequeued.failed_attempts, 0);
+    assert_eq!(dequeued.attempt(), 1);
+
+    // Retrying after a failure is
+    worker
+        .retry_with_backoff(job.id, "error", Duration::zero())
+        .await
+        .unwrap();
+    let dequeued = release().await;
+    assert_eq!(dequeued.failed_attempts, 1);
+    assert_eq!(dequeued.attempt(), 2);
+
+    // And so is failing for good
+    worker.fail_job(job.id, "error").await.unwrap();
+    let handle = worker.release_job(job.id, None);
+    worker.force_flush().await.unwrap();
+    handle.await.unwrap();
+    let stored = manager.get_job(job.id).await.unwrap().unwrap();
+    assert_eq!(stored.state, JobState::Failed);
+    assert_eq!(stored.failed_attempts, 2);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_metadata_merge(db: PgPool) {
+    let worker = Arc::new(Worker::from_pool(db.clone(), Default::default()));
+    let manager = QueueManager::from_pool(db.clone());
+
+    let mut job = create_new_job();
+    job.metadata = Some(serde_json::to_vec(&json!({"existing": "value"})).unwrap());
+    manager
+        .create_job(job.clone())
+        .await
+        .expect("failed to create job");
+
+    let dequeued = worker
+        .dequeue_jobs(&job.queue_name, 1)
+        .await
+        .expect("failed to dequeue job")
+        .pop()
+        .expect("failed to dequeue job");
+
+    // Someone else writes to the metadata after we dequeued the job - a merge shouldn't clobber it
+    sqlx::query("UPDATE cyclotron_jobs SET metadata = $1 WHERE id = $2")
+        .bind(serde_json::to_vec(&json!({"existing": "value", "external": true})).unwrap())
+        .bind(dequeued.id)
+        .execute(&db)
+        .await
+        .expect("failed to update metadata");
+
+    // Two tasks merge different keys into the job's metadata concurrently
+    let tasks = [json!({"a": 1}), json!({"b": 2})].map(|patch| {
+        let worker = worker.clone();
+        tokio::spawn(async move { worker.merge_metadata(dequeued.id, patch) })
+    });
+    for task in tasks {
+        task.await.unwrap().expect("failed to merge metadata");
+    }
+
+    worker
+        .set_state(dequeued.id, JobState::Completed)
+        .expect("failed to set state");
+    let handle = worker.release_job(dequeued.id, None);
+    worker.force_flush().await.unwrap();
+    handle.await.unwrap();
+
+    let metadata: Vec<u8> = sqlx::query_scalar("SELECT metadata FROM cyclotron_jobs WHERE id = $1")
+        .bind(dequeued.id)
+        .fetch_one(&db)
+        .await
+        .expect("failed to fetch job");
+    let metadata: serde_json::Value = serde_json::from_slice(&metadata).unwrap();
+
+    assert_eq!(
+        metadata,
+        json!({"existing": "value", "external": true, "a": 1, "b": 2})
+    );
+
+    // Patches must be JSON objects
+    let dequeued = {
+        manager
+            .create_job(create_new_job())
+            .await
+            .expect("failed to create job");
+        worker
+            .dequeue_jobs(&job.queue_name, 1)
+            .await
+            .expect("failed to dequeue job")
+            .pop()
+            .expect("failed to dequeue job")
+    };
+    // A bad patch is told apart from a job whose stored metadata is bad
+    assert!(matches!(
+        worker.merge_metadata(dequeued.id, json!([1, 2])),
+        Err(JobError::InvalidMetadataPatch(id)) if id == dequeued.id
+    ));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_create_returns_inserted_job(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let before = Utc::now();
+    let mut init = create_new_job();
+    init.metadata = Some(b"metadata".to_vec());
+    let job = manager
+        .create_job(init.clone())
+        .await
+        .expect("failed to create job");
+
+    assert!(matches!(job.state, JobState::Available));
+    assert!(job.created >= before - Duration::seconds(1) && job.created <= Utc::now());
+    assert!(job.lock_id.is_none());
+    assert_eq!(job.transition_count, 0);
+    assert_job_matches_init(&job, &init);
+
+    // Bulk inserts return the jobs in the order the inits were passed in
+    let inits: Vec<_> = (0..10).map(|_| create_new_job()).collect();
+    let jobs = manager
+        .bulk_create_jobs(inits.clone())
+        .await
+        .expect("failed to bulk insert jobs");
+
+    assert_eq!(jobs.len(), inits.len());
+    for (job, init) in jobs.iter().zip(inits.iter()) {
+        assert!(matches!(job.state, JobState::Available));
+        assert!(job.created >= before - Duration::seconds(1) && job.created <= Utc::now());
+        assert_job_matches_init(job, init);
+    }
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_dequeue_lock_ids(db: PgPool) {
+    let worker = Worker::from_pool(db.clone(), Default::default());
+    let manager = QueueManager::from_pool(db.clone());
+
+    let job = create_new_job();
+    for _ in 0..6 {
+        manager
+            .create_job(job.clone())
+            .await
+            .expect("failed to create job");
+    }
+
+    // Each dequeue gets its own lock, shared by every job in the batch
+    let first = worker.dequeue_jobs(&job.queue_name, 2).await.unwrap();
+    let second = worker.dequeue_jobs(&job.queue_name, 2).await.unwrap();
+    assert_eq!(first.len(), 2);
+    assert_eq!(second.len(), 2);
+    assert!(first[0].lock_id.is_some());
+    assert_eq!(first[0].lock_id, first[1].lock_id);
+    assert_eq!(second[0].lock_id, second[1].lock_id);
+    assert_ne!(first[0].lock_id, second[0].lock_id);
+
+    // Caller supplied lock ids can't be nil
+    let res = worker
+        .dequeue_jobs_with_lock(&job.queue_name, 1, Uuid::nil())
+        .await;
+    assert!(matches!(res, Err(QueueError::NilLockId)));
+
+    // Or already held by some other job
+    let lock_id = Uuid::now_v7();
+    let held = worker
+        .dequeue_jobs_with_lock(&job.queue_name, 1, lock_id)
+        .await
+        .unwrap();
+    assert_eq!(held.len(), 1);
+    assert_eq!(held[0].lock_id, Some(lock_id));
+
+    let res = worker
+        .dequeue_jobs_with_lock(&job.queue_name, 1, lock_id)
+        .await;
+    assert!(matches!(res, Err(QueueError::LockIdInUse(id)) if id == lock_id));
+
+    // An existing lock can't be reused via dequeue either
+    let res = worker
+        .dequeue_jobs_with_lock(&job.queue_name, 1, first[0].lock_id.unwrap())
+        .await;
+    assert!(matches!(res, Err(QueueError::LockIdInUse(_))));
+
+    // Once the held job is released, its lock id is free again
+    worker
+        .set_state(held[0].id, JobState::Completed)
+        .expect("failed to set state");
+    let handle = worker.release_job(held[0].id, None);
+    worker.force_flush().await.unwrap();
+    handle.await.unwrap();
+
+    let reused = worker
+        .dequeue_jobs_with_lock(&job.queue_name, 1, lock_id)
+        .await
+        .unwrap();
+    assert_eq!(reused.len(), 1);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_ordering_key_matches_dequeue_order(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    // A mix of priorities and scheduled times, with some jobs tied on both, so the id tiebreak matters
+    let now = Utc::now() - Duration::minutes(10);
+    for (priority, minutes) in [
+        (1, 0),
+        (0, 2),
+        (0, 1),
+        (1, 0),
+        (2, 5),
+        (0, 1),
+        (1, 3),
+        (0, 1),
+    ] {
+        let mut init = create_new_job();
+        init.priority = priority;
+        init.scheduled = now + Duration::minutes(minutes);
+        manager.create_job(init).await.unwrap();
+    }
+
+    let mut jobs = manager
+        .query_jobs(&cyclotron_core::JobQuery::default())
+        .await
+        .unwrap();
+    jobs.shuffle(&mut rand::thread_rng());
+    jobs.sort_by_key(Job::ordering_key);
+    let sorted: Vec<Uuid> = jobs.iter().map(|j| j.id).collect();
+
+    // Dequeue one at a time, since the order of jobs within a dequeued batch isn't guaranteed
+    let mut dequeued = vec![];
+    loop {
+        let batch = worker.dequeue_jobs("test", 1).await.unwrap();
+        let Some(job) = batch.into_iter().next() else {
+            break;
+        };
+        dequeued.push(job.id);
+    }
+
+    assert_eq!(sorted.len(), 8);
+    assert_eq!(sorted, dequeued);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_scheduled_is_truncated_to_db_precision(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let scheduled: DateTime<Utc> = "2024-08-01T12:00:00.123456789Z".parse().unwrap();
+    let truncated: DateTime<Utc> = "2024-08-01T12:00:00.123456Z".parse().unwrap();
+
+    let mut init = create_new_job();
+    init.scheduled = scheduled;
+    let created = manager.create_job(init.clone()).await.unwrap();
+    let bulk_created = manager.bulk_create_jobs(vec![init]).await.unwrap();
+
+    // What's handed back on insert, and what's read back later, are exactly the truncated value
+    let read_back = manager
+        .query_jobs(&cyclotron_core::JobQuery::default())
+        .await
+        .unwrap();
+    assert_eq!(read_back.len(), 2);
+    for job in std::iter::once(&created)
+        .chain(&bulk_created)
+        .chain(&read_back)
+    {
+        assert_eq!(job.scheduled, truncated);
+        assert_eq!(job.created.timestamp_subsec_nanos() % 1_000, 0);
+    }
+
+    // Including the client-side created timestamp of bulk inserts
+    let bulk_read_back = read_back
+        .iter()
+        .find(|j| j.id == bulk_created[0].id)
+        .unwrap();
+    assert_eq!(bulk_read_back.created, bulk_created[0].created);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_next_scheduled(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    assert_eq!(worker.next_scheduled("test").await.unwrap(), None);
+
+    // Only future-scheduled jobs, so there's nothing to dequeue, but we still know when there will be
+    let now = Utc::now();
+    let mut earliest = None;
+    for minutes in [30, 5, 60] {
+        let mut job = create_new_job();
+        job.scheduled = now + Duration::minutes(minutes);
+        let job = manager.create_job(job).await.unwrap();
+        if minutes == 5 {
+            earliest = Some(job.scheduled);
+        }
+    }
+    assert!(worker.dequeue_jobs("test", 10).await.unwrap().is_empty());
+
+    let next = worker.next_scheduled("test").await.unwrap().unwrap();
+    assert_eq!(Some(next), earliest);
+
+    // Other queues don't have anything scheduled
+    assert_eq!(worker.next_scheduled("other").await.unwrap(), None);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_metrics_snapshot(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    let snapshot = worker.metrics_snapshot();
+    assert_eq!(snapshot.held_jobs, 0);
+    assert!(snapshot.in_flight_by_queue.is_empty());
+    assert!(snapshot.last_dequeue.is_none());
+    assert!(snapshot.last_error.is_none());
+
+    for _ in 0..3 {
+        manager.create_job(create_new_job()).await.unwrap();
+    }
+    let before = Utc::now();
+    let jobs = worker.dequeue_jobs("test", 2).await.unwrap();
+    assert_eq!(jobs.len(), 2);
+
+    let snapshot = worker.metrics_snapshot();
+    assert_eq!(snapshot.held_jobs, 2);
+    assert_eq!(snapshot.in_flight_by_queue.get("test"), Some(&2));
+    assert!(snapshot.last_dequeue.unwrap() >= before);
+    assert_eq!(snapshot.pending_flush, 0);
+
+    // Releasing a job moves it from held to waiting to be flushed
+    worker.set_state(jobs[0].id, JobState::Completed).unwrap();
+    drop(worker.release_job(jobs[0].id, None));
+    let snapshot = worker.metrics_snapshot();
+    assert_eq!(snapshot.held_jobs, 1);
+    assert_eq!(snapshot.in_flight_by_queue.get("test"), Some(&1));
+    assert_eq!(snapshot.pending_flush, 1);
+
+    // Errors are recorded too
+    db.close().await;
+    assert!(worker.dequeue_jobs("test", 1).await.is_err());
+    let snapshot = worker.metrics_snapshot();
+    assert!(snapshot.last_error.is_some());
+    // And a failed dequeue isn't a dequeue
+    assert!(snapshot.last_dequeue.unwrap() < snapshot.last_error.unwrap().0);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_closed_manager_rejects_operations(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let job = manager.create_job(create_new_job()).await.unwrap();
+
+    manager.close().await;
+    assert!(db.is_closed());
+    // Closing again is fine
+    manager.close().await;
+
+    let res = manager.create_job(create_new_job()).await;
+    assert!(matches!(res, Err(QueueError::ManagerClosed)));
+    let res = manager.get_job(job.id).await;
+    assert!(matches!(res, Err(QueueError::ManagerClosed)));
+    let res = manager.bulk_create_jobs(vec![create_new_job()]).await;
+    assert!(matches!(res, Err(QueueError::ManagerClosed)));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_close_leaves_nothing_running(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let job = manager.create_job(create_new_job()).await.unwrap();
+
+    // A worker on a pool of its own, so we can see what closing it leaves behind
+    let pool = PgPool::connect_with(db.connect_options().as_ref().clone())
+        .await
+        .unwrap();
+    let worker = Worker::from_pool(pool.clone(), Default::default());
+    let jobs = worker.dequeue_jobs(&job.queue_name, 1).await.unwrap();
+    worker.set_state(jobs[0].id, JobState::Completed).unwrap();
+    let handle = worker.release_job(jobs[0].id, None);
+
+    // Closing flushes what was still waiting to be, and stops the flush loop before closing the pool, so no
+    // connections are left open
+    worker.close().await.unwrap();
+    handle.await.unwrap();
+    assert!(pool.is_closed());
+    // The last connection back is closed as it's returned, which the pool finishes just after `close` does
+    tokio::time::timeout(std::time::Duration::from_secs(1), async {
+        while pool.size() > 0 {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the worker left connections open");
+    let stored = manager.get_job(job.id).await.unwrap().unwrap();
+    assert_eq!(stored.state, JobState::Completed);
+    assert_eq!(worker.metrics_snapshot().pending_flush, 0);
+    // Closing again is fine, and later operations fail, rather than hanging
+    worker.close().await.unwrap();
+    assert!(worker.dequeue_jobs(&job.queue_name, 1).await.is_err());
+
+    // The same goes for the manager's pools
+    manager.close().await;
+    assert!(db.is_closed());
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_dequeue_projected(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    let mut init = create_new_job();
+    init.vm_state = Some(b"vm".to_vec());
+    init.metadata = Some(b"{}".to_vec());
+    init.parameters = Some(b"params".to_vec());
+    init.blob = Some(b"blob".to_vec());
+    let created = manager.create_job(init).await.unwrap();
+
+    let projection = JobProjection {
+        parameters: true,
+        ..Default::default()
+    };
+    let jobs = worker
+        .dequeue_projected(&created.queue_name, 1, projection)
+        .await
+        .unwrap();
+    assert_eq!(jobs.len(), 1);
+    let job = &jobs[0];
+    assert_eq!(job.parameters.as_deref(), Some(b"params".as_slice()));
+    assert_eq!(job.vm_state, None);
+    assert_eq!(job.metadata, None);
+    assert_eq!(job.blob, None);
+
+    // The job's locked like any other dequeued job, and the payloads left out are still there
+    let stored = manager.get_job(created.id).await.unwrap().unwrap();
+    assert_eq!(stored.state, JobState::Running);
+    assert_eq!(stored.lock_id, job.lock_id);
+    assert_eq!(stored.blob.as_deref(), Some(b"blob".as_slice()));
+    assert!(worker
+        .dequeue_projected(&created.queue_name, 1, JobProjection::all())
+        .await
+        .unwrap()
+        .is_empty());
+    worker.heartbeat(job.id).await.unwrap();
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_touch_only_update(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    let created = manager.create_job(create_new_job()).await.unwrap();
+    let job = worker
+        .dequeue_jobs(&created.queue_name, 1)
+        .await
+        .unwrap()
+        .pop()
+        .unwrap();
+    let lock_id = job.lock_id.unwrap();
+
+    // Only updates made as touch-only are, not any that happen to set nothing
+    let touch = JobUpdate::touch_only(lock_id);
+    assert!(touch.is_touch_only());
+    assert!(!JobUpdate::new(lock_id).is_touch_only());
+
+    // Flushing one touches the heartbeat, and keeps the job running, under the same lock
+    manager.flush_job(job.id, touch).await.unwrap();
+    let stored = manager.get_job(job.id).await.unwrap().unwrap();
+    assert_eq!(stored.state, JobState::Running);
+    assert_eq!(stored.lock_id, Some(lock_id));
+    assert_eq!(stored.transition_count, job.transition_count);
+    assert!(stored.last_heartbeat >= job.last_heartbeat);
+
+    // Where flushing an update that sets nothing releases the job
+    manager
+        .flush_job(job.id, JobUpdate::new(lock_id))
+        .await
+        .unwrap();
+    let stored = manager.get_job(job.id).await.unwrap().unwrap();
+    assert_eq!(stored.lock_id, None);
+    assert_eq!(stored.last_heartbeat, None);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_claim_job(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let first = Worker::from_pool(db.clone(), Default::default());
+    let second = Worker::from_pool(db.clone(), Default::default());
+
+    let created = manager.create_job(create_new_job()).await.unwrap();
+
+    // Two workers race to claim the same job, and exactly one gets it
+    let (a, b) = tokio::join!(
+        first.claim_job(created.id, Uuid::now_v7()),
+        second.claim_job(created.id, Uuid::now_v7())
+    );
+    let (winner, claimed) = match (a.unwrap(), b.unwrap()) {
+        (Some(job), None) => (&first, job),
+        (None, Some(job)) => (&second, job),
+        res => panic!("expected exactly one claim to win, got {:?}", res),
+    };
+    assert_eq!(claimed.id, created.id);
+    assert_eq!(claimed.state, JobState::Running);
+
+    // The claimed job can't be dequeued either, and the winner can release it like any other
+    assert!(first
+        .dequeue_jobs(&created.queue_name, 1)
+        .await
+        .unwrap()
+        .is_empty());
+    winner.set_state(claimed.id, JobState::Completed).unwrap();
+    let handle = winner.release_job(claimed.id, None);
+    winner.force_flush().await.unwrap();
+    handle.await.unwrap();
+
+    // Finished jobs, and jobs that don't exist, can't be claimed
+    assert!(first
+        .claim_job(created.id, Uuid::now_v7())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(first
+        .claim_job(Uuid::now_v7(), Uuid::now_v7())
+        .await
+        .unwrap()
+        .is_none());
+    assert!(matches!(
+        first.claim_job(created.id, Uuid::nil()).await,
+        Err(QueueError::NilLockId)
+    ));
+
+    // Nor can a job be claimed under a lock id some other job already holds
+    let held = manager.create_job(create_new_job()).await.unwrap();
+    let other = manager.create_job(create_new_job()).await.unwrap();
+    let lock_id = Uuid::now_v7();
+    first.claim_job(held.id, lock_id).await.unwrap().unwrap();
+    let res = first.claim_job(other.id, lock_id).await;
+    assert!(matches!(res, Err(QueueError::LockIdInUse(id)) if id == lock_id));
+    let stored = manager.get_job(other.id).await.unwrap().unwrap();
+    assert_eq!(stored.state, JobState::Available);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_release_job_returning(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+    for _ in 0..2 {
+        manager.create_job(create_new_job()).await.unwrap();
+    }
+    let jobs = worker.dequeue_jobs("test", 2).await.unwrap();
+
+    // Completing a job hands it back finished, with no need to fetch it again
+    worker.set_state(jobs[0].id, JobState::Completed).unwrap();
+    worker.set_priority(jobs[0].id, 3).unwrap();
+    let job = worker
+        .release_job_returning(jobs[0].id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(job.id, jobs[0].id);
+    assert_eq!(job.state, JobState::Completed);
+    assert_eq!(job.priority, 3);
+    assert_eq!(job.transition_count, jobs[0].transition_count + 1);
+    assert_eq!(job.lock_id, None);
+    let stored = manager.get_job(jobs[0].id).await.unwrap().unwrap();
+    assert_eq!(stored.state, JobState::Completed);
+    assert_eq!(stored.transition_count, job.transition_count);
+
+    // If the lock has been lost, nothing is updated, and there's no job to hand back
+    sqlx::query("UPDATE cyclotron_jobs SET lock_id = $1 WHERE id = $2")
+        .bind(Uuid::now_v7())
+        .bind(jobs[1].id)
+        .execute(&db)
+        .await
+        .unwrap();
+    worker.set_state(jobs[1].id, JobState::Completed).unwrap();
+    assert!(worker
+        .release_job_returning(jobs[1].id)
+        .await
+        .unwrap()
+        .is_none());
+    let job = manager.get_job(jobs[1].id).await.unwrap().unwrap();
+    assert_eq!(job.state, JobState::Running);
+
+    // And releasing it again is an error, as with release_job
+    let res = worker.release_job_returning(jobs[1].id).await;
+    assert!(matches!(
+        res,
+        Err(QueueError::JobError(JobError::UnknownJobId(_)))
+    ));
+}
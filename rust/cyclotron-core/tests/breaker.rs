@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use chrono::Duration;
+use common::create_new_job;
+use cyclotron_core::{
+    BreakerConfig, BreakerState, CircuitBreaker, JobError, JobState, QueueManager, Worker,
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+mod common;
+
+async fn release(worker: &Worker, job_id: Uuid, state: JobState) {
+    worker.set_state(job_id, state).unwrap();
+    let handle = worker.release_job(job_id, None);
+    worker.force_flush().await.unwrap();
+    handle.await.unwrap();
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_circuit_breaker_trips_and_recovers(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.circuit_breaker = Some(Arc::new(CircuitBreaker::new(BreakerConfig {
+        failure_ratio: 0.5,
+        window: 4,
+        cooldown: Duration::milliseconds(500),
+    })));
+    let breaker = || worker.circuit_breaker.as_ref().unwrap();
+
+    for _ in 0..10 {
+        manager.create_job(create_new_job()).await.unwrap();
+    }
+
+    // A run of failures trips the breaker, once there have been enough of them to go on
+    let jobs = worker.dequeue_jobs("test", 5).await.unwrap();
+    assert_eq!(jobs.len(), 5);
+    for job in &jobs[..3] {
+        release(&worker, job.id, JobState::Failed).await;
+        assert_eq!(breaker().state("test"), BreakerState::Closed);
+    }
+    release(&worker, jobs[3].id, JobState::Failed).await;
+    assert_eq!(breaker().state("test"), BreakerState::Open);
+
+    // Which stops dequeuing, but leaves the job still in flight to finish. Its outcome doesn't count,
+    // since it was dequeued before the breaker tripped
+    assert!(worker.dequeue_jobs("test", 10).await.unwrap().is_empty());
+    release(&worker, jobs[4].id, JobState::Failed).await;
+    let in_flight = manager.get_job(jobs[4].id).await.unwrap().unwrap();
+    assert_eq!(in_flight.state, JobState::Failed);
+    assert_eq!(breaker().state("test"), BreakerState::Open);
+
+    // After the cooldown, a single job is let through to probe with
+    tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+    assert_eq!(breaker().state("test"), BreakerState::HalfOpen);
+    let probe = worker.dequeue_jobs("test", 10).await.unwrap();
+    assert_eq!(probe.len(), 1);
+    assert!(worker.dequeue_jobs("test", 10).await.unwrap().is_empty());
+
+    // And its success closes the breaker again
+    release(&worker, probe[0].id, JobState::Completed).await;
+    assert_eq!(breaker().state("test"), BreakerState::Closed);
+    let rest = worker.dequeue_jobs("test", 10).await.unwrap();
+    assert_eq!(rest.len(), 4);
+
+    // Other queues were never affected
+    assert!(worker.dequeue_jobs("other", 1).await.unwrap().is_empty());
+    assert_eq!(breaker().state("other"), BreakerState::Closed);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_failed_probe_reopens_breaker(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.circuit_breaker = Some(Arc::new(CircuitBreaker::new(BreakerConfig {
+        failure_ratio: 1.0,
+        window: 1,
+        cooldown: Duration::milliseconds(100),
+    })));
+    let breaker = || worker.circuit_breaker.as_ref().unwrap();
+
+    for _ in 0..3 {
+        manager.create_job(create_new_job()).await.unwrap();
+    }
+
+    let job = worker.dequeue_jobs("test", 1).await.unwrap().pop().unwrap();
+    // Retries count as failures too
+    worker
+        .retry_with_backoff(job.id, "downstream is down", Duration::zero())
+        .await
+        .unwrap();
+    let handle = worker.release_job(job.id, None);
+    worker.force_flush().await.unwrap();
+    handle.await.unwrap();
+    assert_eq!(breaker().state("test"), BreakerState::Open);
+
+    tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+    let probe = worker.dequeue_jobs("test", 10).await.unwrap();
+    assert_eq!(probe.len(), 1);
+    release(&worker, probe[0].id, JobState::Failed).await;
+    assert_eq!(breaker().state("test"), BreakerState::Open);
+    assert!(worker.dequeue_jobs("test", 10).await.unwrap().is_empty());
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_unflushed_outcomes_dont_count(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.circuit_breaker = Some(Arc::new(CircuitBreaker::new(BreakerConfig {
+        failure_ratio: 1.0,
+        window: 1,
+        cooldown: Duration::seconds(60),
+    })));
+    let breaker = || worker.circuit_breaker.as_ref().unwrap();
+
+    let job = manager.create_job(create_new_job()).await.unwrap();
+    worker.dequeue_jobs("test", 1).await.unwrap();
+
+    // The worker loses its lock before the failure is flushed, so the failure never happens
+    sqlx::query("UPDATE cyclotron_jobs SET lock_id = NULL, state = 'available' WHERE id = $1")
+        .bind(job.id)
+        .execute(&db)
+        .await
+        .unwrap();
+    worker.set_state(job.id, JobState::Failed).unwrap();
+    let handle = worker.release_job(job.id, None);
+    worker.force_flush().await.unwrap();
+    assert!(matches!(handle.await, Err(JobError::InvalidLock(..))));
+    assert_eq!(breaker().state("test"), BreakerState::Closed);
+}
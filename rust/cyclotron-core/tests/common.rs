@@ -1,10 +1,17 @@
+use std::{
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
 use chrono::{DateTime, Duration, Utc};
 use cyclotron_core::{Job, JobInit};
+use tracing_subscriber::fmt::MakeWriter;
 use uuid::Uuid;
 
 #[allow(dead_code)]
 pub fn create_new_job() -> JobInit {
     JobInit {
+        id: None,
         team_id: 1,
         function_id: Some(Uuid::now_v7()), // Lets us uniquely identify jobs without having the Uuid
         queue_name: "test".to_string(),
@@ -15,6 +22,10 @@ pub fn create_new_job() -> JobInit {
         parameters: None,
         blob: None,
         metadata: None,
+        at_most_once: false,
+        correlation_id: None,
+        parent_job_id: None,
+        reply_to: None,
     }
 }
 
@@ -40,3 +51,34 @@ pub fn assert_job_matches_init(job: &Job, init: &JobInit) {
     assert_eq!(job.parameters, init.parameters);
     assert_eq!(job.metadata, init.metadata);
 }
+
+// Collects everything logged while it's the default subscriber, so we can assert on warnings
+#[allow(dead_code)]
+#[derive(Clone, Default)]
+pub struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+#[allow(dead_code)]
+impl CapturedLogs {
+    pub fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for CapturedLogs {
+    type Writer = CapturedLogs;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
@@ -0,0 +1,247 @@
+use chrono::Duration;
+use common::create_new_job;
+use cyclotron_core::{Enqueued, JobInit, OnConflict, QueueError, QueueManager, Worker};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+mod common;
+
+fn job_with_id(id: Uuid, priority: i16) -> JobInit {
+    let mut init = create_new_job();
+    init.id = Some(id);
+    init.priority = priority;
+    init
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_client_ids_are_kept(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let id = Uuid::now_v7();
+    let job = manager.create_job(job_with_id(id, 0)).await.unwrap();
+    assert_eq!(job.id, id);
+
+    let other = Uuid::now_v7();
+    let jobs = manager
+        .bulk_create_jobs(vec![job_with_id(other, 0), create_new_job()])
+        .await
+        .unwrap();
+    assert_eq!(jobs[0].id, other);
+    assert_ne!(jobs[1].id, other);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_on_conflict_error(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let id = Uuid::now_v7();
+    manager
+        .create_job_on_conflict(job_with_id(id, 0), OnConflict::Error)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let res = manager
+        .create_job_on_conflict(job_with_id(id, 1), OnConflict::Error)
+        .await;
+    assert!(matches!(res, Err(QueueError::DuplicateJobId(dup)) if dup == id));
+
+    // Plain creates behave the same way
+    let res = manager.create_job(job_with_id(id, 1)).await;
+    assert!(matches!(res, Err(QueueError::DuplicateJobId(dup)) if dup == id));
+
+    assert_eq!(manager.get_job(id).await.unwrap().unwrap().priority, 0);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_on_conflict_ignore(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let id = Uuid::now_v7();
+    let first = manager
+        .create_job_on_conflict(job_with_id(id, 0), OnConflict::Ignore)
+        .await
+        .unwrap();
+    assert_eq!(first.unwrap().id, id);
+
+    let second = manager
+        .create_job_on_conflict(job_with_id(id, 1), OnConflict::Ignore)
+        .await
+        .unwrap();
+    assert!(second.is_none());
+    assert_eq!(manager.get_job(id).await.unwrap().unwrap().priority, 0);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_on_conflict_update(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    let id = Uuid::now_v7();
+    manager
+        .create_job_on_conflict(job_with_id(id, 0), OnConflict::Update)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let updated = manager
+        .create_job_on_conflict(job_with_id(id, 1), OnConflict::Update)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(updated.id, id);
+    assert_eq!(updated.priority, 1);
+    assert_eq!(manager.get_job(id).await.unwrap().unwrap().priority, 1);
+
+    // Once the job's been picked up, it's too late to change it
+    let running = worker.dequeue_jobs("test", 1).await.unwrap();
+    assert_eq!(running[0].id, id);
+    let res = manager
+        .create_job_on_conflict(job_with_id(id, 2), OnConflict::Update)
+        .await
+        .unwrap();
+    assert!(res.is_none());
+    assert_eq!(manager.get_job(id).await.unwrap().unwrap().priority, 1);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_on_conflict_update_keeps_other_teams_jobs(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let id = Uuid::now_v7();
+    let original = manager
+        .create_job_on_conflict(job_with_id(id, 0), OnConflict::Update)
+        .await
+        .unwrap()
+        .unwrap();
+
+    // A job for another team that happens to have the same id can't take over the existing one
+    let mut other_team = job_with_id(id, 1);
+    other_team.team_id = original.team_id + 1;
+    other_team.parameters = Some(b"someone else's".to_vec());
+    let res = manager
+        .create_job_on_conflict(other_team, OnConflict::Update)
+        .await
+        .unwrap();
+    assert!(res.is_none());
+
+    let job = manager.get_job(id).await.unwrap().unwrap();
+    assert_eq!(job.team_id, original.team_id);
+    assert_eq!(job.priority, 0);
+    assert_eq!(job.parameters, original.parameters);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_create_job_deduplicated(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let window = Duration::minutes(5);
+    let job = || {
+        let mut init = create_new_job();
+        init.parameters = Some(b"same".to_vec());
+        init
+    };
+
+    let Enqueued::Created(first) = manager
+        .create_job_deduplicated(job(), window)
+        .await
+        .unwrap()
+    else {
+        panic!("expected the first job to be created");
+    };
+
+    // The same job again, within the window, is deduplicated to the first one
+    let again = manager
+        .create_job_deduplicated(job(), window)
+        .await
+        .unwrap();
+    assert!(matches!(again, Enqueued::Deduplicated(id) if id == first.id));
+
+    // Jobs with different parameters, or for a different team or queue, aren't duplicates
+    let mut different = job();
+    different.parameters = Some(b"different".to_vec());
+    let mut other_team = job();
+    other_team.team_id += 1;
+    let mut other_queue = job();
+    other_queue.queue_name = "other".to_string();
+    for init in [different, other_team, other_queue] {
+        let res = manager.create_job_deduplicated(init, window).await.unwrap();
+        assert!(matches!(res, Enqueued::Created(_)));
+    }
+
+    // Once the first job is older than the window, the same job is created again
+    sqlx::query(
+        "UPDATE cyclotron_jobs SET created = created - interval '10 minutes' WHERE id = $1",
+    )
+    .bind(first.id)
+    .execute(&db)
+    .await
+    .unwrap();
+    let later = manager
+        .create_job_deduplicated(job(), window)
+        .await
+        .unwrap();
+    let Enqueued::Created(second) = later else {
+        panic!("expected a new job once the window had passed");
+    };
+    assert_ne!(second.id, first.id);
+
+    // Which the next duplicate is deduplicated to
+    let again = manager
+        .create_job_deduplicated(job(), window)
+        .await
+        .unwrap();
+    assert_eq!(again.id(), second.id);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_bulk_create_unique_jobs(db: PgPool) {
+    let manager = QueueManager::from_pool(db);
+    let job = |team_id, parameters: &[u8]| {
+        let mut init = create_new_job();
+        init.team_id = team_id;
+        init.parameters = Some(parameters.to_vec());
+        init
+    };
+
+    // Three logical jobs, two of them passed more than once. Fields outside the identity, like the priority,
+    // don't make a job unique - the first one passed is the one kept
+    let mut later_duplicate = job(1, b"a");
+    later_duplicate.priority = 5;
+    let batch = vec![
+        job(1, b"a"),
+        job(1, b"b"),
+        later_duplicate,
+        job(2, b"a"),
+        job(1, b"b"),
+        job(1, b"a"),
+    ];
+    let created = manager.bulk_create_unique_jobs(batch).await.unwrap();
+    assert_eq!(created.collapsed, 3);
+
+    let kept: Vec<_> = created
+        .jobs
+        .iter()
+        .map(|j| (j.team_id, j.parameters.clone().unwrap(), j.priority))
+        .collect();
+    assert_eq!(
+        kept,
+        [
+            (1, b"a".to_vec(), 0),
+            (1, b"b".to_vec(), 0),
+            (2, b"a".to_vec(), 0),
+        ]
+    );
+    let inserted = manager
+        .query_jobs(&cyclotron_core::JobQuery::default())
+        .await
+        .unwrap();
+    assert_eq!(inserted.len(), 3);
+
+    // Only the batch itself is checked, so the same jobs can be enqueued again
+    let again = manager
+        .bulk_create_unique_jobs(vec![job(1, b"a"), job(1, b"a")])
+        .await
+        .unwrap();
+    assert_eq!(again.collapsed, 1);
+    assert_eq!(again.jobs.len(), 1);
+}
@@ -0,0 +1,111 @@
+use common::create_new_job;
+use cyclotron_core::{JobError, JobState, QueueError, QueueManager, Worker};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+mod common;
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_enqueue_after_runs_chain_in_order(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+
+    let first = manager.create_job(create_new_job()).await.unwrap();
+    let second = manager
+        .enqueue_after(create_new_job(), first.id)
+        .await
+        .unwrap();
+    let third = manager
+        .enqueue_after(create_new_job(), second.id)
+        .await
+        .unwrap();
+    assert_eq!(second.state, JobState::Paused);
+    assert_eq!(third.state, JobState::Paused);
+
+    // Each job only becomes available once the one before it completes, so they run in chain order
+    let mut ran = Vec::new();
+    loop {
+        let jobs = worker.dequeue_jobs("test", 10).await.unwrap();
+        if jobs.is_empty() {
+            break;
+        }
+        assert_eq!(
+            jobs.len(),
+            1,
+            "only the head of the chain should be runnable"
+        );
+        ran.push(jobs[0].id);
+        worker.set_state(jobs[0].id, JobState::Completed).unwrap();
+        worker.release_job(jobs[0].id, None).await.unwrap();
+    }
+    assert_eq!(ran, vec![first.id, second.id, third.id]);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_enqueue_after_completed_or_missing_dependency(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0;
+
+    let missing = Uuid::now_v7();
+    let res = manager.enqueue_after(create_new_job(), missing).await;
+    assert!(matches!(
+        res,
+        Err(QueueError::JobError(JobError::UnknownJobId(id))) if id == missing
+    ));
+    // And nothing was inserted
+    assert!(worker.dequeue_jobs("test", 10).await.unwrap().is_empty());
+
+    // A job enqueued after one that's already completed can run straight away
+    let done = manager.create_job(create_new_job()).await.unwrap();
+    worker.dequeue_jobs("test", 1).await.unwrap();
+    worker.set_state(done.id, JobState::Completed).unwrap();
+    worker.release_job(done.id, None).await.unwrap();
+
+    let after = manager
+        .enqueue_after(create_new_job(), done.id)
+        .await
+        .unwrap();
+    assert_eq!(after.state, JobState::Available);
+    let jobs = worker.dequeue_jobs("test", 10).await.unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].id, after.id);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_dependencies_inherit_dependent_priority(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    // A bulk export, itself waiting on an earlier bulk step, with both in a low priority queue
+    let mut bulk = create_new_job();
+    bulk.queue_name = "bulk".to_string();
+    bulk.priority = 10;
+    let step = manager.create_job(bulk.clone()).await.unwrap();
+    let export = manager.enqueue_after(bulk.clone(), step.id).await.unwrap();
+    // Something else in the bulk queue, unrelated to any of it
+    let unrelated = manager.create_job(bulk.clone()).await.unwrap();
+
+    // A lower priority job waiting on the export doesn't change anything
+    let mut background = create_new_job();
+    background.priority = 20;
+    manager.enqueue_after(background, export.id).await.unwrap();
+    let get = |id| manager.get_job(id);
+    assert_eq!(get(export.id).await.unwrap().unwrap().priority, 10);
+
+    // But a critical job, in another queue, waiting on the export, raises it and what it's waiting on
+    let mut critical = create_new_job();
+    critical.queue_name = "critical".to_string();
+    critical.priority = 0;
+    let critical = manager.enqueue_after(critical, export.id).await.unwrap();
+    assert_eq!(critical.state, JobState::Paused);
+    assert_eq!(critical.priority, 0);
+    assert_eq!(get(export.id).await.unwrap().unwrap().priority, 0);
+    assert_eq!(get(step.id).await.unwrap().unwrap().priority, 0);
+    assert_eq!(get(unrelated.id).await.unwrap().unwrap().priority, 10);
+
+    // So the prerequisite chain now runs ahead of the rest of its queue
+    let jobs = worker.dequeue_jobs("bulk", 1).await.unwrap();
+    assert_eq!(jobs[0].id, step.id);
+}
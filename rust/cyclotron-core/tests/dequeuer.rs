@@ -0,0 +1,167 @@
+use std::time::Instant;
+
+use chrono::{Duration, Utc};
+use common::create_new_job;
+use cyclotron_core::{BufferedDequeuer, JobQuery, JobState, QueueManager, Worker};
+use sqlx::PgPool;
+
+mod common;
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_buffered_dequeuer_drains_queue(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    for _ in 0..8 {
+        manager.create_job(create_new_job()).await.unwrap();
+    }
+
+    let mut dequeuer = BufferedDequeuer::new(&worker, "test", 3);
+    let mut seen = vec![];
+    while let Some(job) = dequeuer.next().await.unwrap() {
+        assert!(job.lock_id.is_some()); // Locked to the worker, as with any dequeue
+                                        // Never more than a prefetch's worth held at once
+        assert!(dequeuer.buffered() < 3);
+        seen.push(job.id);
+    }
+
+    seen.sort();
+    seen.dedup();
+    assert_eq!(seen.len(), 8);
+    assert_eq!(dequeuer.buffered(), 0);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_buffered_dequeuer_releases_unconsumed_jobs_on_drop(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    for _ in 0..8 {
+        manager.create_job(create_new_job()).await.unwrap();
+    }
+
+    let mut dequeuer = BufferedDequeuer::new(&worker, "test", 5);
+    let mut consumed = vec![];
+    for _ in 0..3 {
+        consumed.push(dequeuer.next().await.unwrap().unwrap().id);
+    }
+    // The first call prefetched 5, so 2 are still buffered
+    assert_eq!(dequeuer.buffered(), 2);
+
+    drop(dequeuer);
+    worker.force_flush().await.unwrap();
+
+    let jobs = manager.query_jobs(&JobQuery::default()).await.unwrap();
+    for job in &jobs {
+        if consumed.contains(&job.id) {
+            assert_eq!(job.state, JobState::Running);
+        } else {
+            // Both the never-dequeued jobs and the unconsumed prefetched ones are available again
+            assert_eq!(job.state, JobState::Available);
+            assert!(job.lock_id.is_none());
+        }
+    }
+
+    // And so can be picked up by anyone
+    assert_eq!(worker.dequeue_jobs("test", 10).await.unwrap().len(), 5);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_buffered_dequeuer_waits_for_scheduled_jobs(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    // Two jobs that are due, and one that isn't yet
+    let due = [
+        manager.create_job(create_new_job()).await.unwrap().id,
+        manager.create_job(create_new_job()).await.unwrap().id,
+    ];
+    let mut later = create_new_job();
+    later.scheduled = Utc::now() + Duration::milliseconds(800);
+    let later = manager.create_job(later).await.unwrap();
+
+    // Only the due jobs are prefetched, however much room there is
+    let mut dequeuer = BufferedDequeuer::new(&worker, "test", 10);
+    let first = dequeuer.next().await.unwrap().unwrap();
+    assert_eq!(dequeuer.buffered(), 1);
+    let second = dequeuer.next().await.unwrap().unwrap();
+    let mut got = [first.id, second.id];
+    got.sort();
+    assert_eq!(got, due);
+    let held = manager.get_job(later.id).await.unwrap().unwrap();
+    assert_eq!(held.state, JobState::Available);
+    assert!(held.lock_id.is_none());
+
+    // With the buffer empty, the wait is until the future job is due
+    assert!(dequeuer.next().await.unwrap().is_none());
+    let wait = dequeuer.until_next_due().await.unwrap().unwrap();
+    assert!(wait > Duration::zero() && wait <= Duration::milliseconds(800));
+
+    // Not willing to wait that long, there's nothing to hand out
+    let start = Instant::now();
+    let res = dequeuer
+        .next_waiting(Duration::milliseconds(100))
+        .await
+        .unwrap();
+    assert!(res.is_none());
+    assert!(start.elapsed() < std::time::Duration::from_millis(100));
+
+    // But waiting long enough, the dequeuer sleeps until it's due, and no longer
+    let job = dequeuer
+        .next_waiting(Duration::seconds(10))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(job.id, later.id);
+    assert!(Utc::now() >= later.scheduled);
+    assert!(start.elapsed() < std::time::Duration::from_secs(3));
+
+    // And once the queue's empty, there's nothing to wait for at all
+    assert!(dequeuer.until_next_due().await.unwrap().is_none());
+    let start = Instant::now();
+    let res = dequeuer.next_waiting(Duration::seconds(10)).await.unwrap();
+    assert!(res.is_none());
+    assert!(start.elapsed() < std::time::Duration::from_secs(1));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_dequeue_batch_wait_returns_once_jobs_are_due(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db, Default::default());
+
+    // With nothing to dequeue, it waits out the whole window, and comes back empty
+    let start = Instant::now();
+    let jobs = worker
+        .dequeue_batch_wait("test", 5, Duration::milliseconds(300))
+        .await
+        .unwrap();
+    assert!(jobs.is_empty());
+    assert!(start.elapsed() >= std::time::Duration::from_millis(300));
+
+    // Two jobs becoming due early in the window, and one only long after it
+    let now = Utc::now();
+    let mut early = vec![];
+    for offset in [300, 400] {
+        let mut init = create_new_job();
+        init.scheduled = now + Duration::milliseconds(offset);
+        early.push(manager.create_job(init).await.unwrap().id);
+    }
+    let mut late = create_new_job();
+    late.scheduled = now + Duration::hours(1);
+    let late = manager.create_job(late).await.unwrap();
+
+    // It comes back as soon as the first is due, rather than at the end of the window, with whichever of the
+    // early jobs were due by then
+    let start = Instant::now();
+    let jobs = worker
+        .dequeue_batch_wait("test", 5, Duration::seconds(10))
+        .await
+        .unwrap();
+    assert!(start.elapsed() < std::time::Duration::from_secs(2));
+    assert!(!jobs.is_empty());
+    assert!(jobs.iter().all(|j| early.contains(&j.id)));
+
+    // The job that isn't due is left where it was
+    let held = manager.get_job(late.id).await.unwrap().unwrap();
+    assert_eq!(held.state, JobState::Available);
+}
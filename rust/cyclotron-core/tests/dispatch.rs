@@ -0,0 +1,110 @@
+use common::create_new_job;
+use cyclotron_core::{DispatchError, JobDispatcher, QueueManager, Worker};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+mod common;
+
+#[derive(Deserialize)]
+struct Email {
+    to: String,
+}
+
+#[derive(Deserialize)]
+struct Webhook {
+    url: String,
+    retries: u32,
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_jobs_are_dispatched_by_function_id(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    let email_fn = Uuid::now_v7();
+    let webhook_fn = Uuid::now_v7();
+    let mut dispatcher = JobDispatcher::new();
+    dispatcher.register(email_fn, |_, email: Email| async move {
+        format!("email to {}", email.to)
+    });
+    dispatcher.register(webhook_fn, |_, hook: Webhook| async move {
+        format!("webhook to {}, {} retries", hook.url, hook.retries)
+    });
+    assert!(dispatcher.handles(email_fn));
+    assert!(!dispatcher.handles(Uuid::now_v7()));
+
+    let enqueue = |function_id, parameters: serde_json::Value| {
+        let mut init = create_new_job();
+        init.function_id = Some(function_id);
+        init.parameters = Some(serde_json::to_vec(&parameters).unwrap());
+        init
+    };
+    let email = manager
+        .create_job(enqueue(email_fn, json!({"to": "a@b.c"})))
+        .await
+        .unwrap();
+    let hook = manager
+        .create_job(enqueue(
+            webhook_fn,
+            json!({"url": "https://x.y", "retries": 3}),
+        ))
+        .await
+        .unwrap();
+
+    let jobs = worker.dequeue_jobs("test", 2).await.unwrap();
+    assert_eq!(jobs.len(), 2);
+    for job in jobs {
+        let id = job.id;
+        let result = dispatcher.dispatch(job).await.unwrap();
+        if id == email.id {
+            assert_eq!(result, "email to a@b.c");
+        } else {
+            assert_eq!(id, hook.id);
+            assert_eq!(result, "webhook to https://x.y, 3 retries");
+        }
+    }
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_undispatchable_jobs_are_errors(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    let known = Uuid::now_v7();
+    let mut dispatcher = JobDispatcher::new();
+    dispatcher.register(known, |_, email: Email| async move { email.to });
+    // Handlers whose functions take no parameters can decode nothing as ()
+    let no_params = Uuid::now_v7();
+    dispatcher.register(no_params, |_, (): ()| async move { "ran".to_string() });
+
+    let mut unknown = create_new_job();
+    unknown.priority = 0;
+    let mut missing = create_new_job();
+    missing.function_id = None;
+    missing.priority = 1;
+    let mut malformed = create_new_job();
+    malformed.function_id = Some(known);
+    malformed.parameters = Some(b"{\"from\": 1}".to_vec());
+    malformed.priority = 2;
+    let mut empty = create_new_job();
+    empty.function_id = Some(no_params);
+    empty.priority = 3;
+    for init in [unknown.clone(), missing, malformed, empty] {
+        manager.create_job(init).await.unwrap();
+    }
+
+    let mut jobs = worker.dequeue_jobs("test", 4).await.unwrap().into_iter();
+    let res = dispatcher.dispatch(jobs.next().unwrap()).await;
+    let Err(DispatchError::UnknownFunction(_, function_id)) = res else {
+        panic!("expected UnknownFunction, got {:?}", res);
+    };
+    assert_eq!(Some(function_id), unknown.function_id);
+    let res = dispatcher.dispatch(jobs.next().unwrap()).await;
+    assert!(matches!(res, Err(DispatchError::MissingFunctionId(_))));
+    let res = dispatcher.dispatch(jobs.next().unwrap()).await;
+    assert!(matches!(res, Err(DispatchError::InvalidParameters(..))));
+    let res = dispatcher.dispatch(jobs.next().unwrap()).await;
+    assert_eq!(res.unwrap(), "ran");
+}
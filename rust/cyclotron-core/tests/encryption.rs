@@ -0,0 +1,165 @@
+use common::create_new_job;
+use cyclotron_core::{
+    JobError, JobState, PayloadCipher, PayloadField, QueueError, QueueManager, Worker,
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+mod common;
+
+const KEY: [u8; 32] = [7; 32];
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_encrypted_payload_round_trip(db: PgPool) {
+    let mut manager = QueueManager::from_pool(db.clone());
+    manager.payload_cipher = Some(PayloadCipher::new(1, &KEY));
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.payload_cipher = Some(PayloadCipher::new(1, &KEY));
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+
+    let parameters = br#"{"email": "someone@example.com"}"#.to_vec();
+    let blob = b"some sensitive request body".to_vec();
+    let mut init = create_new_job();
+    init.parameters = Some(parameters.clone());
+    init.blob = Some(blob.clone());
+    let job = manager.create_job(init).await.unwrap();
+    // Callers never see the ciphertext
+    assert_eq!(job.parameters, Some(parameters.clone()));
+
+    // But that's what's stored
+    let (stored_parameters, stored_blob): (Vec<u8>, Vec<u8>) =
+        sqlx::query_as("SELECT parameters, blob FROM cyclotron_jobs WHERE id = $1")
+            .bind(job.id)
+            .fetch_one(&db)
+            .await
+            .unwrap();
+    assert_ne!(stored_parameters, parameters);
+    assert_ne!(stored_blob, blob);
+    assert!(!stored_parameters
+        .windows(b"someone".len())
+        .any(|w| w == b"someone"));
+
+    // The worker decrypts on dequeue
+    let dequeued = worker.dequeue_jobs("test", 1).await.unwrap();
+    assert_eq!(dequeued[0].parameters, Some(parameters.clone()));
+    assert_eq!(dequeued[0].blob, Some(blob));
+
+    // And encrypts what it writes back
+    let new_blob = b"a sensitive response".to_vec();
+    worker.set_blob(job.id, Some(new_blob.clone())).unwrap();
+    worker.set_state(job.id, JobState::Available).unwrap();
+    worker.release_job(job.id, None).await.unwrap();
+    let stored_blob: Vec<u8> = sqlx::query_scalar("SELECT blob FROM cyclotron_jobs WHERE id = $1")
+        .bind(job.id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_ne!(stored_blob, new_blob);
+    let dequeued = worker.dequeue_jobs("test", 1).await.unwrap();
+    assert_eq!(dequeued[0].blob, Some(new_blob));
+}
+
+#[test]
+pub fn test_payload_key_rotation() {
+    let old_key = [1; 32];
+    let new_key = [2; 32];
+    let job_id = Uuid::now_v7();
+    let params = PayloadField::Parameters;
+
+    let old = PayloadCipher::new(1, &old_key);
+    let encrypted = old.encrypt(job_id, params, b"secret");
+
+    // Once rotated, the old key can still decrypt what was written with it
+    let mut rotated = PayloadCipher::new(2, &new_key);
+    rotated.add_decryption_key(1, &old_key);
+    assert_eq!(
+        rotated.decrypt(job_id, params, &encrypted).unwrap(),
+        b"secret"
+    );
+    let reencrypted = rotated.encrypt(job_id, params, b"secret");
+    assert_eq!(
+        rotated.decrypt(job_id, params, &reencrypted).unwrap(),
+        b"secret"
+    );
+
+    // But without it, or with the payload tampered with, decryption fails
+    let new_only = PayloadCipher::new(2, &new_key);
+    assert!(new_only.decrypt(job_id, params, &encrypted).is_none());
+    let mut tampered = reencrypted.clone();
+    *tampered.last_mut().unwrap() ^= 1;
+    assert!(rotated.decrypt(job_id, params, &tampered).is_none());
+}
+
+#[test]
+pub fn test_payloads_are_bound_to_their_job_and_field() {
+    let cipher = PayloadCipher::new(1, &KEY);
+    let job_id = Uuid::now_v7();
+    let encrypted = cipher.encrypt(job_id, PayloadField::Parameters, b"secret");
+
+    // A payload moved to another job, or to the job's other field, doesn't decrypt
+    assert!(cipher
+        .decrypt(Uuid::now_v7(), PayloadField::Parameters, &encrypted)
+        .is_none());
+    assert!(cipher
+        .decrypt(job_id, PayloadField::Blob, &encrypted)
+        .is_none());
+}
+
+#[test]
+pub fn test_plaintext_passthrough_is_opt_in() {
+    let mut cipher = PayloadCipher::new(1, &KEY);
+    let job_id = Uuid::now_v7();
+    let params = PayloadField::Parameters;
+
+    assert!(cipher.decrypt(job_id, params, b"plaintext").is_none());
+
+    // Payloads written before encryption was turned on only pass through while migrating
+    cipher.allow_plaintext(true);
+    assert_eq!(
+        cipher.decrypt(job_id, params, b"plaintext").unwrap(),
+        b"plaintext"
+    );
+    cipher.allow_plaintext(false);
+    assert!(cipher.decrypt(job_id, params, b"plaintext").is_none());
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_dequeue_with_wrong_key_fails(db: PgPool) {
+    let mut manager = QueueManager::from_pool(db.clone());
+    manager.payload_cipher = Some(PayloadCipher::new(1, &KEY));
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.payload_cipher = Some(PayloadCipher::new(1, &[8; 32]));
+
+    let mut init = create_new_job();
+    init.parameters = Some(b"secret".to_vec());
+    let job = manager.create_job(init).await.unwrap();
+
+    let res = worker.dequeue_jobs("test", 1).await;
+    assert!(matches!(
+        res,
+        Err(QueueError::JobError(JobError::DecryptionFailed(id))) if id == job.id
+    ));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_transactional_batch_hands_back_plaintext(db: PgPool) {
+    let mut manager = QueueManager::from_pool(db.clone());
+    manager.payload_cipher = Some(PayloadCipher::new(1, &KEY));
+
+    let parameters = br#"{"email": "someone@example.com"}"#.to_vec();
+    let mut init = create_new_job();
+    init.parameters = Some(parameters.clone());
+    let jobs = manager
+        .bulk_create_jobs_transactional(vec![init, create_new_job()])
+        .await
+        .unwrap();
+    assert_eq!(jobs[0].parameters, Some(parameters.clone()));
+    assert_eq!(jobs[1].parameters, None);
+
+    let stored: Vec<u8> = sqlx::query_scalar("SELECT parameters FROM cyclotron_jobs WHERE id = $1")
+        .bind(jobs[0].id)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_ne!(stored, parameters);
+}
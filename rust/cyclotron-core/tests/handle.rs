@@ -0,0 +1,71 @@
+use chrono::Duration;
+use common::create_new_job;
+use cyclotron_core::{JobError, JobState, QueueError, QueueManager, Worker};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+mod common;
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_job_handle_awaits_completion(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let handle = manager.enqueue(create_new_job()).await.unwrap();
+    assert_eq!(handle.job().state, JobState::Available);
+
+    // Another task picks the job up and completes it, a little while after we start waiting
+    let id = handle.id();
+    let completer = tokio::spawn(async move {
+        let worker = Worker::from_pool(db, Default::default());
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        let jobs = worker.dequeue_jobs("test", 1).await.unwrap();
+        assert_eq!(jobs[0].id, id);
+        worker.set_state(id, JobState::Completed).unwrap();
+        let flushed = worker.release_job(id, None);
+        worker.force_flush().await.unwrap();
+        flushed.await.unwrap();
+    });
+
+    let finished = handle
+        .await_completion(Duration::seconds(10))
+        .await
+        .unwrap();
+    assert_eq!(finished.id, id);
+    assert_eq!(finished.state, JobState::Completed);
+    completer.await.unwrap();
+
+    // A finished job can't be cancelled
+    assert!(!handle.cancel().await.unwrap());
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_job_handle_cancel_and_timeout(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    // Nothing's running the job, so waiting on it times out
+    let handle = manager.enqueue(create_new_job()).await.unwrap();
+    let res = handle.await_completion(Duration::milliseconds(250)).await;
+    assert!(matches!(res, Err(QueueError::WaitTimedOut(id)) if id == handle.id()));
+
+    // Cancelling it fails it, which ends the wait
+    assert!(handle.cancel().await.unwrap());
+    let finished = handle.await_completion(Duration::seconds(1)).await.unwrap();
+    assert_eq!(finished.state, JobState::Failed);
+    assert!(worker.dequeue_jobs("test", 1).await.unwrap().is_empty());
+
+    // Jobs a worker is already running can't be cancelled
+    let running = manager.enqueue(create_new_job()).await.unwrap();
+    worker.dequeue_jobs("test", 1).await.unwrap();
+    assert!(!running.cancel().await.unwrap());
+
+    // And there's nothing to wait for on a job that doesn't exist
+    let missing = Uuid::now_v7();
+    let res = manager
+        .wait_for_completion(missing, Duration::seconds(1))
+        .await;
+    assert!(matches!(
+        res,
+        Err(QueueError::JobError(JobError::UnknownJobId(id))) if id == missing
+    ));
+}
@@ -0,0 +1,82 @@
+use serde_json::Value;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+// The query every dequeue runs, as ops/worker.rs runs it
+const DEQUEUE_QUERY: &str = include_str!("../src/ops/dequeue.sql");
+
+// Every node in a JSON query plan, depth first
+fn plan_nodes(plan: &Value) -> Vec<&Value> {
+    let mut nodes = vec![plan];
+    if let Some(children) = plan["Plans"].as_array() {
+        nodes.extend(children.iter().flat_map(plan_nodes));
+    }
+    nodes
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_dequeue_uses_due_jobs_index(db: PgPool) {
+    // A table big enough that a sequential scan would be a real choice - jobs spread over twenty queues,
+    // most of them not available, and of the available ones, half not due yet
+    sqlx::query(
+        r#"
+INSERT INTO cyclotron_jobs
+    (id, team_id, created, janitor_touch_count, transition_count, last_transition, queue_name, state, scheduled, priority)
+SELECT
+    gen_random_uuid(),
+    i % 100,
+    NOW(),
+    0,
+    0,
+    NOW(),
+    'queue_' || (i % 20),
+    (ARRAY['available', 'running', 'completed', 'failed'])[1 + i % 4]::JobState,
+    NOW() + ((i % 120) - 60) * INTERVAL '1 minute',
+    (i % 5)::smallint
+FROM generate_series(1, 100000) i
+"#,
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    // So the planner knows what it's dealing with
+    sqlx::query("ANALYZE cyclotron_jobs")
+        .execute(&db)
+        .await
+        .unwrap();
+
+    let explained: Value = sqlx::query_scalar(&format!("EXPLAIN (FORMAT JSON) {}", DEQUEUE_QUERY))
+        .bind("queue_4")
+        .bind(100_i64)
+        .bind(Uuid::now_v7())
+        .bind(true)
+        .bind(true)
+        .bind(true)
+        .bind(true)
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    let nodes = plan_nodes(&explained[0]["Plan"]);
+
+    let node_types: Vec<_> = nodes
+        .iter()
+        .filter_map(|n| n["Node Type"].as_str())
+        .collect();
+    assert!(!node_types.contains(&"Seq Scan"), "{}", explained);
+    // The index hands jobs out in dequeue order, so they don't need sorting
+    assert!(
+        !node_types.iter().any(|t| t.ends_with("Sort")),
+        "{}",
+        explained
+    );
+    assert!(
+        nodes.iter().any(|n| {
+            n["Node Type"]
+                .as_str()
+                .is_some_and(|t| t.starts_with("Index"))
+                && n["Index Name"] == "idx_cyclotron_jobs_due"
+        }),
+        "{}",
+        explained
+    );
+}
@@ -0,0 +1,244 @@
+use chrono::Duration;
+use common::create_new_job;
+use cyclotron_core::{
+    test_support::DELETE_PASS_LOCK_KEY, AggregatedDelete, DeleteSet, Janitor, JobQuery, JobState,
+    QueueManager, Worker,
+};
+use sqlx::PgPool;
+
+mod common;
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_stalled_at_most_once_jobs_are_failed(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+    let janitor = Janitor::from_pool(db.clone());
+
+    let at_least_once = manager.create_job(create_new_job()).await.unwrap();
+    let mut init = create_new_job();
+    init.at_most_once = true;
+    let at_most_once = manager.create_job(init).await.unwrap();
+    assert!(at_most_once.at_most_once);
+    assert!(!at_least_once.at_most_once);
+
+    // Pick both jobs up, then "crash" - never heartbeating or releasing them
+    let jobs = worker.dequeue_jobs("test", 2).await.unwrap();
+    assert_eq!(jobs.len(), 2);
+
+    // Once they're past the stall timeout, the janitor deals with both of them
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    let reset = janitor
+        .reset_stalled_jobs(Duration::milliseconds(1))
+        .await
+        .unwrap();
+    assert_eq!(reset, 2);
+
+    let jobs = manager.query_jobs(&JobQuery::default()).await.unwrap();
+    let find_job = |id| jobs.iter().find(|j| j.id == id).unwrap();
+
+    // The at-least-once job goes back in the queue, to be run again
+    let job = find_job(at_least_once.id);
+    assert_eq!(job.state, JobState::Available);
+    assert!(job.lock_id.is_none());
+
+    // The at-most-once job doesn't
+    let job = find_job(at_most_once.id);
+    assert_eq!(job.state, JobState::Failed);
+    assert!(job.lock_id.is_none());
+    assert_eq!(worker.dequeue_jobs("test", 2).await.unwrap().len(), 1);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_only_one_janitor_runs_delete_pass(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+
+    let job = manager.create_job(create_new_job()).await.unwrap();
+    worker.dequeue_jobs("test", 1).await.unwrap();
+    worker.set_state(job.id, JobState::Completed).unwrap();
+    worker.release_job(job.id, None).await.unwrap();
+
+    let first = Janitor::from_pool(db.clone());
+    let second = Janitor::from_pool(db.clone());
+
+    // The first janitor is mid-way through its delete pass, holding the lock
+    let mut first_pass = db.begin().await.unwrap();
+    let locked: bool = sqlx::query_scalar("SELECT pg_try_advisory_xact_lock($1)")
+        .bind(DELETE_PASS_LOCK_KEY)
+        .fetch_one(&mut *first_pass)
+        .await
+        .unwrap();
+    assert!(locked);
+
+    // So the second skips its pass, leaving the completed job alone
+    let deleted = second.try_delete_completed_and_failed_jobs().await.unwrap();
+    assert!(deleted.is_none());
+    assert_eq!(
+        manager
+            .query_jobs(&JobQuery::default())
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+
+    // Once the first janitor's pass is done, the next one goes ahead
+    first_pass.commit().await.unwrap();
+    let deleted = first
+        .try_delete_completed_and_failed_jobs()
+        .await
+        .unwrap()
+        .expect("lock should be free");
+    assert_eq!(deleted.len(), 1);
+    assert_eq!(deleted[0].count, 1);
+    assert!(manager
+        .query_jobs(&JobQuery::default())
+        .await
+        .unwrap()
+        .is_empty());
+
+    // And the lock is released when the pass is done, so running again is fine too
+    assert!(second
+        .try_delete_completed_and_failed_jobs()
+        .await
+        .unwrap()
+        .is_some());
+}
+
+// Creates 7 jobs across two teams, finishing 5 of them (3 completed, 2 failed) and leaving 2 available
+async fn setup_finished_jobs(manager: &QueueManager, worker: &Worker) {
+    for team_id in [1, 1, 1, 2, 2, 2, 2] {
+        let mut init = create_new_job();
+        init.team_id = team_id;
+        manager.create_job(init).await.unwrap();
+    }
+
+    let jobs = worker.dequeue_jobs("test", 5).await.unwrap();
+    assert_eq!(jobs.len(), 5);
+    let mut handles = vec![];
+    for (i, job) in jobs.iter().enumerate() {
+        let state = if i % 2 == 0 {
+            JobState::Completed
+        } else {
+            JobState::Failed
+        };
+        worker.set_state(job.id, state).unwrap();
+        handles.push(worker.release_job(job.id, None));
+    }
+    worker.force_flush().await.unwrap();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_purge_matches_aggregated_delete(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+    let janitor = Janitor::from_pool(db.clone());
+
+    // Purging in batches smaller than the number of finished jobs still gets all of them
+    setup_finished_jobs(&manager, &worker).await;
+    let purged = janitor
+        .purge_completed_older_than(Duration::zero(), 2)
+        .await
+        .unwrap();
+    let remaining = manager.query_jobs(&JobQuery::default()).await.unwrap();
+    assert_eq!(remaining.len(), 2);
+    assert!(remaining.iter().all(|j| j.state == JobState::Available));
+
+    // The same fixture, cleaned up by the aggregating path, deletes the same number of jobs
+    setup_finished_jobs(&manager, &worker).await;
+    let aggregated: i64 = janitor
+        .delete_completed_and_failed_jobs()
+        .await
+        .unwrap()
+        .iter()
+        .map(|d| d.count)
+        .sum();
+    assert_eq!(purged, 5);
+    assert_eq!(purged, aggregated as u64);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_preview_delete_reports_what_a_real_run_deletes(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+    let janitor = Janitor::from_pool(db.clone());
+
+    setup_finished_jobs(&manager, &worker).await;
+
+    let sorted = |mut deletes: Vec<AggregatedDelete>| {
+        deletes.sort_by_key(|d| (d.hour, d.team_id, d.function_id.clone(), d.state.clone()));
+        deletes
+    };
+
+    // A preview leaves every job where it was
+    let preview = sorted(janitor.preview_delete().await.unwrap());
+    assert_eq!(
+        manager
+            .query_jobs(&JobQuery::default())
+            .await
+            .unwrap()
+            .len(),
+        7
+    );
+    // And running it again reports the same thing, since nothing changed
+    assert_eq!(sorted(janitor.preview_delete().await.unwrap()), preview);
+
+    // It takes no row locks, so it isn't held up by something else holding the jobs, as a delete pass would be
+    let mut holder = db.begin().await.unwrap();
+    sqlx::query("SELECT id FROM cyclotron_jobs FOR UPDATE")
+        .execute(&mut *holder)
+        .await
+        .unwrap();
+    let held = tokio::time::timeout(std::time::Duration::from_secs(5), janitor.preview_delete())
+        .await
+        .expect("preview blocked on row locks")
+        .unwrap();
+    assert_eq!(sorted(held), preview);
+    holder.rollback().await.unwrap();
+
+    let deleted = sorted(janitor.delete_completed_and_failed_jobs().await.unwrap());
+    assert_eq!(deleted, preview);
+    let deletes = DeleteSet(deleted);
+    assert_eq!(deletes.total_for(JobState::Completed), 3);
+    assert_eq!(deletes.total_for(JobState::Failed), 2);
+
+    // Once they're gone, there's nothing left for a preview to report
+    assert!(janitor.preview_delete().await.unwrap().is_empty());
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_purge_only_deletes_jobs_older_than_age(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+    let janitor = Janitor::from_pool(db.clone());
+
+    setup_finished_jobs(&manager, &worker).await;
+
+    // Backdate two of the finished jobs
+    sqlx::query(
+        "UPDATE cyclotron_jobs SET last_transition = NOW() - interval '2 hours'
+        WHERE id IN (SELECT id FROM cyclotron_jobs WHERE state IN ('completed', 'failed') LIMIT 2)",
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let purged = janitor
+        .purge_completed_older_than(Duration::hours(1), 100)
+        .await
+        .unwrap();
+    assert_eq!(purged, 2);
+
+    // The rest are still there, until they're old enough
+    let remaining = manager.query_jobs(&JobQuery::default()).await.unwrap();
+    assert_eq!(remaining.len(), 5);
+    let purged = janitor
+        .purge_completed_older_than(Duration::zero(), 100)
+        .await
+        .unwrap();
+    assert_eq!(purged, 3);
+}
@@ -0,0 +1,74 @@
+use common::create_new_job;
+use cyclotron_core::{JobError, JobState, PayloadCipher, QueueError, QueueManager, Worker};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+mod common;
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_lazy_payloads_are_fetched_on_first_access(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    let mut init = create_new_job();
+    init.blob = Some(b"original".to_vec());
+    init.parameters = Some(b"params".to_vec());
+    manager.create_job(init).await.unwrap();
+
+    let jobs = worker.dequeue_lazy("test", 1).await.unwrap();
+    let job = &jobs[0];
+    assert_eq!(job.header.state, JobState::Available);
+    assert!(!job.blob.is_loaded());
+    assert!(!job.parameters.is_loaded());
+
+    // Changing the blob behind the worker's back shows it wasn't fetched with the rest of the job
+    sqlx::query("UPDATE cyclotron_jobs SET blob = $1 WHERE id = $2")
+        .bind(b"changed".to_vec())
+        .bind(job.header.id)
+        .execute(&db)
+        .await
+        .unwrap();
+    assert_eq!(job.blob.get().await.unwrap(), Some(&b"changed"[..]));
+    assert!(job.blob.is_loaded());
+    // Fetching one payload doesn't fetch the others
+    assert!(!job.parameters.is_loaded());
+    assert_eq!(job.vm_state.get().await.unwrap(), None);
+
+    // Once fetched, it's kept
+    sqlx::query("UPDATE cyclotron_jobs SET blob = NULL WHERE id = $1")
+        .bind(job.header.id)
+        .execute(&db)
+        .await
+        .unwrap();
+    assert_eq!(job.blob.get().await.unwrap(), Some(&b"changed"[..]));
+
+    // A payload can't be fetched once the lock is lost
+    sqlx::query("UPDATE cyclotron_jobs SET lock_id = $1 WHERE id = $2")
+        .bind(Uuid::now_v7())
+        .bind(job.header.id)
+        .execute(&db)
+        .await
+        .unwrap();
+    let res = job.parameters.get().await;
+    assert!(matches!(
+        res,
+        Err(QueueError::JobError(JobError::InvalidLock(..)))
+    ));
+    assert!(!job.parameters.is_loaded());
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_lazy_payloads_are_decrypted(db: PgPool) {
+    let key = [7; 32];
+    let mut manager = QueueManager::from_pool(db.clone());
+    manager.payload_cipher = Some(PayloadCipher::new(1, &key));
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.payload_cipher = Some(PayloadCipher::new(1, &key));
+
+    let mut init = create_new_job();
+    init.blob = Some(b"secret".to_vec());
+    manager.create_job(init).await.unwrap();
+
+    let jobs = worker.dequeue_lazy("test", 1).await.unwrap();
+    assert_eq!(jobs[0].blob.get().await.unwrap(), Some(&b"secret"[..]));
+}
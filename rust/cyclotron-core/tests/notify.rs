@@ -0,0 +1,91 @@
+use std::time::Duration;
+
+use common::create_new_job;
+use cyclotron_core::{EnqueueNotification, QueueManager, ENQUEUE_CHANNEL};
+use sqlx::{postgres::PgListener, PgPool};
+use uuid::Uuid;
+
+mod common;
+
+async fn listen(db: &PgPool) -> PgListener {
+    let mut listener = PgListener::connect_with(db).await.unwrap();
+    listener.listen(ENQUEUE_CHANNEL).await.unwrap();
+    listener
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_enqueue_and_notify(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut listener = listen(&db).await;
+
+    let job = manager.enqueue_and_notify(create_new_job()).await.unwrap();
+
+    let notification = tokio::time::timeout(Duration::from_secs(5), listener.recv())
+        .await
+        .expect("timed out waiting for the notification")
+        .unwrap();
+    let payload: EnqueueNotification = serde_json::from_str(notification.payload()).unwrap();
+    assert_eq!(
+        payload,
+        EnqueueNotification {
+            id: job.id,
+            queue_name: "test".to_string(),
+        }
+    );
+
+    // By the time anyone hears about the job, it's there to be found
+    assert!(manager.get_job(payload.id).await.unwrap().is_some());
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_aborted_enqueue_leaves_no_job_or_notification(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut listener = listen(&db).await;
+
+    // The enqueue gets as far as the insert and the notify, but its commit fails - a deferred trigger makes
+    // every commit that inserted a job fail, after everything else in the transaction has run
+    sqlx::query(
+        r#"
+CREATE FUNCTION fail_commit() RETURNS trigger AS $$
+BEGIN
+    RAISE EXCEPTION 'commit failed';
+END
+$$ LANGUAGE plpgsql"#,
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    sqlx::query(
+        r#"
+CREATE CONSTRAINT TRIGGER fail_commit AFTER INSERT ON cyclotron_jobs
+DEFERRABLE INITIALLY DEFERRED FOR EACH ROW EXECUTE FUNCTION fail_commit()"#,
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+
+    let mut init = create_new_job();
+    let id = Uuid::now_v7();
+    init.id = Some(id);
+    assert!(manager.enqueue_and_notify(init).await.is_err());
+
+    sqlx::query("DROP TRIGGER fail_commit ON cyclotron_jobs")
+        .execute(&db)
+        .await
+        .unwrap();
+    assert!(manager.get_job(id).await.unwrap().is_none());
+    let received = tokio::time::timeout(Duration::from_millis(500), listener.recv()).await;
+    assert!(
+        received.is_err(),
+        "got a notification for a rolled back job"
+    );
+
+    // The listener is still good, and hears about the next job that is committed
+    let committed = manager.enqueue_and_notify(create_new_job()).await.unwrap();
+    let notification = tokio::time::timeout(Duration::from_secs(5), listener.recv())
+        .await
+        .expect("timed out waiting for the notification")
+        .unwrap();
+    let payload: EnqueueNotification = serde_json::from_str(notification.payload()).unwrap();
+    assert_eq!(payload.id, committed.id);
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_trait::async_trait;
+use chrono::Duration;
+use common::create_new_job;
+use cyclotron_core::{
+    Bytes, Enqueued, JobState, ObjectStore, QueueError, QueueManager, VmStateOffload, Worker,
+    WorkerConfig,
+};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+mod common;
+
+#[derive(Default)]
+struct MockObjectStore {
+    objects: Mutex<HashMap<String, Bytes>>,
+    failing: AtomicBool, // Fails every put while set
+}
+
+#[async_trait]
+impl ObjectStore for MockObjectStore {
+    async fn put(&self, key: &str, bytes: Bytes) -> Result<(), QueueError> {
+        if self.failing.load(Ordering::SeqCst) {
+            return Err(QueueError::ObjectStoreError("unavailable".to_string()));
+        }
+        self.objects.lock().unwrap().insert(key.to_string(), bytes);
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, QueueError> {
+        Ok(self.objects.lock().unwrap().get(key).cloned())
+    }
+}
+
+async fn stored_vm_state(db: &PgPool, id: Uuid) -> Option<Bytes> {
+    sqlx::query_scalar("SELECT vm_state FROM cyclotron_jobs WHERE id = $1")
+        .bind(id)
+        .fetch_one(db)
+        .await
+        .unwrap()
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_large_vm_states_are_offloaded(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+    let store = Arc::new(MockObjectStore::default());
+    worker.set_vm_state_offload(Some(VmStateOffload::new(store.clone(), 1024)));
+
+    let large = manager.create_job(create_new_job()).await.unwrap();
+    let small = manager.create_job(create_new_job()).await.unwrap();
+    let large_state = vec![7u8; 64 * 1024];
+    let small_state = b"small".to_vec();
+
+    assert_eq!(worker.dequeue_jobs("test", 2).await.unwrap().len(), 2);
+    for (id, state) in [(large.id, &large_state), (small.id, &small_state)] {
+        worker.set_vm_state(id, Some(state.clone())).unwrap();
+        worker.set_state(id, JobState::Available).unwrap();
+        worker.release_job(id, None).await.unwrap();
+    }
+
+    // The large state went to the object store, leaving a reference much smaller than it in the table
+    let objects = store.objects.lock().unwrap().clone();
+    assert_eq!(objects.len(), 1);
+    let (key, object) = objects.into_iter().next().unwrap();
+    assert!(key.starts_with(&format!("vm_state/{}/", large.id)));
+    assert_eq!(object, large_state);
+    let reference = stored_vm_state(&db, large.id).await.unwrap();
+    assert!(reference.len() < 1024);
+    assert_ne!(reference, large_state);
+
+    // The small one stayed inline
+    assert_eq!(
+        stored_vm_state(&db, small.id).await,
+        Some(small_state.clone())
+    );
+
+    // And both are read back as they were written
+    let jobs = worker.dequeue_with_vm_state("test", 2).await.unwrap();
+    assert_eq!(jobs.len(), 2);
+    let by_id: HashMap<_, _> = jobs.into_iter().map(|j| (j.id, j.vm_state)).collect();
+    assert_eq!(by_id[&large.id], Some(large_state.clone()));
+    assert_eq!(by_id[&small.id], Some(small_state));
+    assert_eq!(
+        worker.get_vm_state(large.id).await.unwrap(),
+        Some(large_state)
+    );
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_offloaded_vm_states_need_a_store_to_read(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut offloading = Worker::from_pool(db.clone(), Default::default());
+    offloading.max_buffered = 0;
+    let store = Arc::new(MockObjectStore::default());
+    offloading.set_vm_state_offload(Some(VmStateOffload::new(store, 16)));
+
+    let job = manager.create_job(create_new_job()).await.unwrap();
+    offloading.dequeue_jobs("test", 1).await.unwrap();
+    offloading
+        .set_vm_state(job.id, Some(vec![1u8; 1024]))
+        .unwrap();
+    offloading.set_state(job.id, JobState::Available).unwrap();
+    offloading.release_job(job.id, None).await.unwrap();
+
+    // A worker without offloading gets an error, rather than the reference as if it were the state
+    let plain = Worker::from_pool(db, Default::default());
+    let res = plain.dequeue_with_vm_state("test", 1).await;
+    assert!(matches!(res, Err(QueueError::ObjectStoreError(_))));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_failed_uploads_are_retried_with_the_whole_state(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    // Lingering long enough that only the force flushes below flush the update
+    let config = WorkerConfig {
+        linger_time_ms: Some(60_000),
+        ..Default::default()
+    };
+    let mut worker = Worker::from_pool(db.clone(), config);
+    let store = Arc::new(MockObjectStore::default());
+    store.failing.store(true, Ordering::SeqCst);
+    worker.set_vm_state_offload(Some(VmStateOffload::new(store.clone(), 16)));
+
+    let job = manager.create_job(create_new_job()).await.unwrap();
+    worker.dequeue_jobs("test", 1).await.unwrap();
+    let state = vec![3u8; 1024];
+    worker.set_vm_state(job.id, Some(state.clone())).unwrap();
+    worker.set_state(job.id, JobState::Available).unwrap();
+    let handle = worker.release_job(job.id, None);
+
+    // The upload fails, so the flush does too, leaving the update to be retried
+    let res = worker.force_flush().await;
+    assert!(matches!(res, Err(QueueError::ObjectStoreError(_))));
+    assert!(store.objects.lock().unwrap().is_empty());
+
+    // And once the store's back, the retry uploads the state as it was set, not what the failed attempt left
+    store.failing.store(false, Ordering::SeqCst);
+    worker.force_flush().await.unwrap();
+    handle.await.unwrap();
+    let objects: Vec<_> = store.objects.lock().unwrap().values().cloned().collect();
+    assert_eq!(objects.len(), 1);
+    assert_eq!(objects[0], state);
+    let jobs = worker.dequeue_with_vm_state("test", 1).await.unwrap();
+    assert_eq!(jobs[0].vm_state, Some(state));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_large_vm_states_are_offloaded_on_creation(db: PgPool) {
+    let store = Arc::new(MockObjectStore::default());
+    let offload = VmStateOffload::new(store.clone(), 1024);
+    let mut manager = QueueManager::from_pool(db.clone());
+    manager.vm_state_offload = Some(offload.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.set_vm_state_offload(Some(offload));
+
+    let large_state = vec![5u8; 64 * 1024];
+    let mut init = create_new_job();
+    init.vm_state = Some(large_state.clone());
+    let single = manager.create_job(init.clone()).await.unwrap();
+    let mut small = create_new_job();
+    small.vm_state = Some(b"small".to_vec());
+    let bulk = manager
+        .bulk_create_jobs(vec![init, small.clone()])
+        .await
+        .unwrap();
+
+    // Callers get back the states they created the jobs with
+    assert_eq!(single.vm_state, Some(large_state.clone()));
+    assert_eq!(bulk[0].vm_state, Some(large_state.clone()));
+    assert_eq!(bulk[1].vm_state, small.vm_state);
+
+    // While only the small state is in the table
+    assert_eq!(store.objects.lock().unwrap().len(), 2);
+    for job in [&single, &bulk[0]] {
+        assert!(stored_vm_state(&db, job.id).await.unwrap().len() < 1024);
+        assert!(store
+            .objects
+            .lock()
+            .unwrap()
+            .keys()
+            .any(|k| k.starts_with(&format!("vm_state/{}/", job.id))));
+    }
+    assert_eq!(stored_vm_state(&db, bulk[1].id).await, small.vm_state);
+
+    // And workers read them back as they were created
+    let jobs = worker.dequeue_with_vm_state("test", 3).await.unwrap();
+    let by_id: HashMap<_, _> = jobs.into_iter().map(|j| (j.id, j.vm_state)).collect();
+    assert_eq!(by_id[&single.id], Some(large_state.clone()));
+    assert_eq!(by_id[&bulk[0].id], Some(large_state));
+    assert_eq!(by_id[&bulk[1].id], small.vm_state);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_deduplicated_jobs_upload_nothing(db: PgPool) {
+    let store = Arc::new(MockObjectStore::default());
+    let mut manager = QueueManager::from_pool(db.clone());
+    manager.vm_state_offload = Some(VmStateOffload::new(store.clone(), 1024));
+
+    let mut init = create_new_job();
+    init.parameters = Some(b"same".to_vec());
+    init.vm_state = Some(vec![5u8; 64 * 1024]);
+    let window = Duration::minutes(5);
+    let Enqueued::Created(job) = manager
+        .create_job_deduplicated(init.clone(), window)
+        .await
+        .unwrap()
+    else {
+        panic!("the first job should be created");
+    };
+    assert_eq!(store.objects.lock().unwrap().len(), 1);
+
+    // The duplicate is caught before its state is uploaded
+    let duplicate = manager.create_job_deduplicated(init, window).await.unwrap();
+    assert_eq!(duplicate.id(), job.id);
+    assert!(matches!(duplicate, Enqueued::Deduplicated(_)));
+    assert_eq!(store.objects.lock().unwrap().len(), 1);
+}
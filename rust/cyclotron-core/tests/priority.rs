@@ -0,0 +1,53 @@
+use common::{create_new_job, CapturedLogs};
+use cyclotron_core::QueueManager;
+use sqlx::PgPool;
+
+mod common;
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_priority_clamped_into_band(db: PgPool) {
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(logs.clone())
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let mut manager = QueueManager::from_pool(db);
+    manager.priority_band = -30000..=30000;
+
+    // Below the band, e.g. trying to jump into the system band
+    let mut init = create_new_job();
+    init.priority = -32000;
+    let job = manager.create_job(init).await.unwrap();
+    assert_eq!(job.priority, -30000);
+    assert!(logs.contents().contains("clamping"));
+
+    // Above it
+    let mut init = create_new_job();
+    init.priority = i16::MAX;
+    let jobs = manager.bulk_create_jobs(vec![init]).await.unwrap();
+    assert_eq!(jobs[0].priority, 30000);
+    assert_eq!(logs.contents().matches("clamping").count(), 2);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_priority_in_band_unchanged(db: PgPool) {
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(logs.clone())
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let mut manager = QueueManager::from_pool(db);
+    manager.priority_band = -30000..=30000;
+
+    for priority in [-30000, 0, 5, 30000] {
+        let mut init = create_new_job();
+        init.priority = priority;
+        let job = manager.create_job(init).await.unwrap();
+        assert_eq!(job.priority, priority);
+    }
+    assert!(!logs.contents().contains("clamping"));
+}
@@ -0,0 +1,1026 @@
+use chrono::{Duration, Utc};
+use common::create_new_job;
+use cyclotron_core::{
+    Filter, Job, JobError, JobQuery, JobState, QueueConfig, QueueConfigFilter, QueueManager,
+    Worker, DEFAULT_QUERY_LIMIT,
+};
+use sqlx::{postgres::PgPoolOptions, Executor, PgPool};
+use uuid::Uuid;
+
+mod common;
+
+// Puts one job in each of the available, running, completed and failed states
+async fn setup_jobs(manager: &QueueManager, worker: &Worker) {
+    let job = create_new_job();
+    for _ in 0..4 {
+        manager.create_job(job.clone()).await.unwrap();
+    }
+
+    let jobs = worker.dequeue_jobs(&job.queue_name, 3).await.unwrap();
+    assert_eq!(jobs.len(), 3);
+
+    worker.set_state(jobs[0].id, JobState::Completed).unwrap();
+    worker.set_state(jobs[1].id, JobState::Failed).unwrap();
+    let handles = [
+        worker.release_job(jobs[0].id, None),
+        worker.release_job(jobs[1].id, None),
+    ];
+    worker.force_flush().await.unwrap();
+    for handle in handles {
+        handle.await.unwrap();
+    }
+}
+
+// The ids of the jobs matching a query, sorted. Queries return jobs oldest first, but jobs created together -
+// e.g. in one bulk insert - can come back in any order, so tests compare ids as sets
+async fn sorted_ids(manager: &QueueManager, query: &JobQuery) -> Vec<Uuid> {
+    sorted(
+        manager
+            .query_jobs(query)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|j| j.id)
+            .collect(),
+    )
+}
+
+fn sorted(mut ids: Vec<Uuid>) -> Vec<Uuid> {
+    ids.sort();
+    ids
+}
+
+fn states(jobs: &[cyclotron_core::Job]) -> Vec<JobState> {
+    let mut states: Vec<_> = jobs.iter().map(|j| j.state).collect();
+    states.sort_by_key(|s| *s as u8);
+    states
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_query_excluding_terminal_states(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db, Default::default());
+    setup_jobs(&manager, &worker).await;
+
+    let query = JobQuery {
+        exclude_states: vec![JobState::Completed, JobState::Failed],
+        ..Default::default()
+    };
+    let jobs = manager.query_jobs(&query).await.unwrap();
+    assert_eq!(states(&jobs), vec![JobState::Available, JobState::Running]);
+
+    // With no filters, everything comes back
+    let jobs = manager.query_jobs(&JobQuery::default()).await.unwrap();
+    assert_eq!(jobs.len(), 4);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_query_include_and_exclude_states(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db, Default::default());
+    setup_jobs(&manager, &worker).await;
+
+    // A state that's both included and excluded is excluded
+    let query = JobQuery {
+        states: vec![JobState::Running, JobState::Completed],
+        exclude_states: vec![JobState::Completed],
+        ..Default::default()
+    };
+    let jobs = manager.query_jobs(&query).await.unwrap();
+    assert_eq!(states(&jobs), vec![JobState::Running]);
+
+    // Excluding states that weren't included changes nothing
+    let query = JobQuery {
+        states: vec![JobState::Available, JobState::Failed],
+        exclude_states: vec![JobState::Running],
+        ..Default::default()
+    };
+    let jobs = manager.query_jobs(&query).await.unwrap();
+    assert_eq!(states(&jobs), vec![JobState::Available, JobState::Failed]);
+
+    // And the limit applies after the state filters
+    let query = JobQuery {
+        exclude_states: vec![JobState::Available],
+        limit: Some(2),
+        ..Default::default()
+    };
+    let jobs = manager.query_jobs(&query).await.unwrap();
+    assert_eq!(jobs.len(), 2);
+    assert!(jobs.iter().all(|j| j.state != JobState::Available));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_query_search(db: PgPool) {
+    let mut manager = QueueManager::from_pool(db.clone());
+
+    // The customer id is buried at different depths, in differently shaped parameters
+    let parameters = [
+        serde_json::json!({"customer": {"id": "cus_123"}, "amount": 10}),
+        serde_json::json!({"request": {"body": {"customer_id": "cus_456"}}}),
+        serde_json::json!(["cus_789", {"retry": true}]),
+    ];
+    let mut ids = vec![];
+    for parameters in parameters {
+        let mut init = create_new_job();
+        init.parameters = Some(serde_json::to_vec(&parameters).unwrap());
+        ids.push(manager.create_job(init).await.unwrap().id);
+    }
+    // And one in metadata, rather than parameters
+    let mut init = create_new_job();
+    init.metadata = Some(br#"{"customer_id": "cus_000"}"#.to_vec());
+    ids.push(manager.create_job(init).await.unwrap().id);
+
+    let search = |needle: &str| JobQuery {
+        search: Some(needle.to_string()),
+        ..Default::default()
+    };
+
+    // Search is a table scan, so it has to be opted into
+    let res = manager.query_jobs(&search("cus_456")).await;
+    assert!(matches!(
+        res,
+        Err(cyclotron_core::QueueError::SearchNotEnabled)
+    ));
+
+    manager.search_enabled = true;
+    let found = manager.query_jobs(&search("cus_456")).await.unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id, ids[1]);
+
+    let found = manager.query_jobs(&search("cus_000")).await.unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id, ids[3]);
+
+    let found = manager.query_jobs(&search("cus_")).await.unwrap();
+    assert_eq!(found.len(), 4);
+
+    assert!(manager
+        .query_jobs(&search("cus_999"))
+        .await
+        .unwrap()
+        .is_empty());
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_require_function_id_and_null_filter(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let with_function = create_new_job();
+    let function_id = with_function.function_id.unwrap();
+    let with_function = manager.create_job(with_function).await.unwrap();
+    assert_eq!(with_function.require_function_id().unwrap(), function_id);
+
+    let mut without_function = create_new_job();
+    without_function.function_id = None;
+    let without_function = manager.create_job(without_function).await.unwrap();
+    assert!(matches!(
+        without_function.require_function_id(),
+        Err(JobError::MissingFunctionId(id)) if id == without_function.id
+    ));
+
+    let by_null = |is_null| JobQuery {
+        function_id_is_null: Some(is_null),
+        ..Default::default()
+    };
+
+    let found = manager.query_jobs(&by_null(true)).await.unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id, without_function.id);
+
+    let found = manager.query_jobs(&by_null(false)).await.unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id, with_function.id);
+
+    // Unset, it doesn't filter at all
+    let found = manager.query_jobs(&JobQuery::default()).await.unwrap();
+    assert_eq!(found.len(), 2);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_function_ids_filter(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    // Every job from create_new_job has its own function id
+    let mut jobs = vec![];
+    for _ in 0..3 {
+        jobs.push(manager.create_job(create_new_job()).await.unwrap());
+    }
+    let mut without_function = create_new_job();
+    without_function.function_id = None;
+    let without_function = manager.create_job(without_function).await.unwrap();
+    assert_eq!(without_function.function_id, None);
+
+    let by_functions = |function_ids| JobQuery {
+        function_ids,
+        ..Default::default()
+    };
+
+    // A single function, either through the set or on its own
+    let found = sorted_ids(&manager, &by_functions(vec![jobs[0].function_id])).await;
+    assert_eq!(found, [jobs[0].id]);
+    let single = JobQuery {
+        function_id: jobs[0].function_id,
+        ..Default::default()
+    };
+    assert_eq!(sorted_ids(&manager, &single).await, [jobs[0].id]);
+
+    // Several at once
+    let query = by_functions(vec![jobs[0].function_id, jobs[2].function_id]);
+    assert_eq!(
+        sorted_ids(&manager, &query).await,
+        sorted(vec![jobs[0].id, jobs[2].id])
+    );
+
+    // And jobs with no function, alongside or instead of the others
+    let query = by_functions(vec![jobs[0].function_id, jobs[2].function_id, None]);
+    assert_eq!(
+        sorted_ids(&manager, &query).await,
+        sorted(vec![jobs[0].id, jobs[2].id, without_function.id])
+    );
+    let only_null = sorted_ids(&manager, &by_functions(vec![None])).await;
+    assert_eq!(only_null, [without_function.id]);
+
+    // An empty set doesn't filter at all
+    assert_eq!(sorted_ids(&manager, &by_functions(vec![])).await.len(), 4);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_find_orphans(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    // Two live functions, one with two jobs, and two deleted ones
+    let mut jobs = vec![];
+    for _ in 0..4 {
+        jobs.push(manager.create_job(create_new_job()).await.unwrap());
+    }
+    let mut second = create_new_job();
+    second.function_id = jobs[0].function_id;
+    jobs.push(manager.create_job(second).await.unwrap());
+    let mut without_function = create_new_job();
+    without_function.function_id = None;
+    manager.create_job(without_function).await.unwrap();
+
+    let valid = [jobs[0].function_id.unwrap(), jobs[1].function_id.unwrap()];
+    let orphans: Vec<_> = manager
+        .find_orphans(&valid)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|j| j.id)
+        .collect();
+    assert_eq!(orphans, [jobs[2].id, jobs[3].id]);
+
+    // With nothing valid, every job with a function is orphaned, but jobs without one never are
+    let orphans = manager.find_orphans(&[]).await.unwrap();
+    assert_eq!(orphans.len(), 5);
+    assert!(orphans.iter().all(|j| j.function_id.is_some()));
+
+    // With everything valid, nothing is
+    let all: Vec<_> = jobs.iter().filter_map(|j| j.function_id).collect();
+    assert!(manager.find_orphans(&all).await.unwrap().is_empty());
+
+    // The exclusion on its own leaves jobs without a function in
+    let query = JobQuery {
+        exclude_function_ids: all,
+        ..Default::default()
+    };
+    let found = manager.query_jobs(&query).await.unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].function_id, None);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_get_job_and_get_jobs(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let first = manager.create_job(create_new_job()).await.unwrap();
+    let second = manager.create_job(create_new_job()).await.unwrap();
+    let missing = uuid::Uuid::now_v7();
+
+    let found = manager.get_job(first.id).await.unwrap().unwrap();
+    assert_eq!(found.id, first.id);
+    assert_eq!(found.function_id, first.function_id);
+
+    assert!(manager.get_job(missing).await.unwrap().is_none());
+
+    // Missing ids in a batch are skipped, rather than being an error
+    let mut found = manager
+        .get_jobs(&[second.id, missing, first.id])
+        .await
+        .unwrap();
+    found.sort_by_key(|j| j.id);
+    let ids: Vec<_> = found.iter().map(|j| j.id).collect();
+    assert_eq!(ids, vec![first.id, second.id]);
+
+    assert!(manager.get_jobs(&[]).await.unwrap().is_empty());
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_state_counts_split_delayed(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db, Default::default());
+    // One each of available, running, completed and failed, all due
+    setup_jobs(&manager, &worker).await;
+
+    // Plus two available jobs that aren't due yet
+    for _ in 0..2 {
+        let mut init = create_new_job();
+        init.scheduled = chrono::Utc::now() + chrono::Duration::hours(1);
+        manager.create_job(init).await.unwrap();
+    }
+
+    let counts = |counts: Vec<cyclotron_core::StateCount>| -> Vec<(String, i64)> {
+        counts.into_iter().map(|c| (c.state, c.count)).collect()
+    };
+    let pairs = |expected: &[(&str, i64)]| -> Vec<(String, i64)> {
+        expected.iter().map(|(s, c)| (s.to_string(), *c)).collect()
+    };
+
+    // Without the split, every available job is just available
+    let unsplit = manager
+        .state_counts(&JobQuery::default(), false)
+        .await
+        .unwrap();
+    assert_eq!(
+        counts(unsplit),
+        pairs(&[
+            ("available", 3),
+            ("completed", 1),
+            ("failed", 1),
+            ("running", 1)
+        ])
+    );
+
+    // With it, the ones scheduled in the future are counted separately
+    let split = manager
+        .state_counts(&JobQuery::default(), true)
+        .await
+        .unwrap();
+    assert_eq!(
+        counts(split),
+        pairs(&[
+            ("available", 1),
+            ("completed", 1),
+            ("delayed", 2),
+            ("failed", 1),
+            ("running", 1)
+        ])
+    );
+
+    // The query's filters still apply
+    let query = JobQuery {
+        states: vec![JobState::Available],
+        ..Default::default()
+    };
+    let split = manager.state_counts(&query, true).await.unwrap();
+    assert_eq!(counts(split), pairs(&[("available", 1), ("delayed", 2)]));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_min_janitor_touches_filter(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let mut ids = Vec::new();
+    for touches in [0i16, 1, 2, 5] {
+        let job = manager.create_job(create_new_job()).await.unwrap();
+        sqlx::query("UPDATE cyclotron_jobs SET janitor_touch_count = $1 WHERE id = $2")
+            .bind(touches)
+            .bind(job.id)
+            .execute(&db)
+            .await
+            .unwrap();
+        ids.push(job.id);
+    }
+
+    let by_touches = |min| JobQuery {
+        min_janitor_touches: Some(min),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        sorted_ids(&manager, &by_touches(2)).await,
+        sorted(vec![ids[2], ids[3]])
+    );
+
+    // The threshold is inclusive, and 0 matches everything
+    let found = manager.query_jobs(&by_touches(5)).await.unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].janitor_touch_count, 5);
+    assert_eq!(manager.query_jobs(&by_touches(0)).await.unwrap().len(), 4);
+    assert!(manager.query_jobs(&by_touches(6)).await.unwrap().is_empty());
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_lock_id_filter(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let first = Worker::from_pool(db.clone(), Default::default());
+    let second = Worker::from_pool(db, Default::default());
+
+    for _ in 0..5 {
+        manager.create_job(create_new_job()).await.unwrap();
+    }
+    let held_by_first = first.dequeue_jobs("test", 2).await.unwrap();
+    let held_by_second = second.dequeue_jobs("test", 1).await.unwrap();
+
+    let held = |jobs: &[Job]| sorted(jobs.iter().map(|j| j.id).collect());
+    let by_lock = |lock_id| JobQuery {
+        lock_id,
+        ..Default::default()
+    };
+
+    assert_eq!(
+        sorted_ids(&manager, &by_lock(held_by_first[0].lock_id)).await,
+        held(&held_by_first)
+    );
+    assert_eq!(
+        sorted_ids(&manager, &by_lock(held_by_second[0].lock_id)).await,
+        held(&held_by_second)
+    );
+
+    // A lock nobody holds matches nothing
+    assert!(sorted_ids(&manager, &by_lock(Some(Uuid::now_v7())))
+        .await
+        .is_empty());
+
+    // Once released, a job isn't held under its old lock
+    let released = held_by_first[0].id;
+    first.set_state(released, JobState::Completed).unwrap();
+    first.release_job_returning(released).await.unwrap();
+    assert_eq!(
+        sorted_ids(&manager, &by_lock(held_by_first[0].lock_id)).await,
+        [held_by_first[1].id]
+    );
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_locked_filter(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db, Default::default());
+
+    for _ in 0..5 {
+        manager.create_job(create_new_job()).await.unwrap();
+    }
+    let dequeued = worker.dequeue_jobs("test", 3).await.unwrap();
+
+    let by_locked = |locked| JobQuery {
+        locked: Some(locked),
+        ..Default::default()
+    };
+
+    let locked = manager.query_jobs(&by_locked(true)).await.unwrap();
+    assert_eq!(locked.len(), 3);
+    assert!(locked.iter().all(|j| j.lock_id == dequeued[0].lock_id));
+
+    let unlocked = manager.query_jobs(&by_locked(false)).await.unwrap();
+    assert_eq!(unlocked.len(), 2);
+    assert!(unlocked
+        .iter()
+        .all(|j| j.lock_id.is_none() && j.state == JobState::Available));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_payload_presence_filters(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let bare = manager.create_job(create_new_job()).await.unwrap();
+    let mut init = create_new_job();
+    init.vm_state = Some(b"state".to_vec());
+    let with_vm_state = manager.create_job(init).await.unwrap();
+    let mut init = create_new_job();
+    init.blob = Some(b"blob".to_vec());
+    let with_blob = manager.create_job(init).await.unwrap();
+    let mut init = create_new_job();
+    init.parameters = Some(b"params".to_vec());
+    let with_parameters = manager.create_job(init).await.unwrap();
+
+    let all = [bare.id, with_vm_state.id, with_blob.id, with_parameters.id];
+    let others = |id: Uuid| sorted(all.iter().copied().filter(|o| *o != id).collect());
+
+    type PresenceFilter = fn(bool) -> JobQuery;
+    let filters: [(PresenceFilter, Uuid); 3] = [
+        (
+            |present| JobQuery {
+                has_vm_state: Some(present),
+                ..Default::default()
+            },
+            with_vm_state.id,
+        ),
+        (
+            |present| JobQuery {
+                has_blob: Some(present),
+                ..Default::default()
+            },
+            with_blob.id,
+        ),
+        (
+            |present| JobQuery {
+                has_parameters: Some(present),
+                ..Default::default()
+            },
+            with_parameters.id,
+        ),
+    ];
+    for (query, expected) in filters {
+        assert_eq!(sorted_ids(&manager, &query(true)).await, vec![expected]);
+        assert_eq!(sorted_ids(&manager, &query(false)).await, others(expected));
+    }
+
+    // Combined, they're ANDed like any other filters
+    let none = JobQuery {
+        has_vm_state: Some(false),
+        has_blob: Some(false),
+        has_parameters: Some(false),
+        ..Default::default()
+    };
+    assert_eq!(sorted_ids(&manager, &none).await, vec![bare.id]);
+}
+
+// Puts one of the team's jobs in each of the available, running and failed states, returning their ids
+// in that order
+async fn setup_team_jobs(manager: &QueueManager, worker: &Worker, team_id: i32) -> [Uuid; 3] {
+    let queue_name = format!("team_{}", team_id);
+    let mut ids = vec![];
+    for _ in 0..3 {
+        let mut init = create_new_job();
+        init.team_id = team_id;
+        init.queue_name = queue_name.clone();
+        ids.push(manager.create_job(init).await.unwrap().id);
+    }
+    // Jobs are dequeued oldest first, so it's the last one that's left available
+    let dequeued = worker.dequeue_jobs(&queue_name, 2).await.unwrap();
+    assert_eq!(dequeued[1].id, ids[1]);
+    worker.set_state(ids[1], JobState::Failed).unwrap();
+    worker.release_job(ids[1], None).await.unwrap();
+    [ids[2], ids[0], ids[1]]
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_or_of_and_filters(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db, Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+
+    let [_, _, team_1_failed] = setup_team_jobs(&manager, &worker, 1).await;
+    let [_, team_2_running, _] = setup_team_jobs(&manager, &worker, 2).await;
+    setup_team_jobs(&manager, &worker, 3).await;
+
+    let team_in = |team_id, state| {
+        Filter::matches(JobQuery {
+            team_id: Some(team_id),
+            states: vec![state],
+            ..Default::default()
+        })
+    };
+    // (team 1 and failed) or (team 2 and running)
+    let filter = team_in(1, JobState::Failed).or(team_in(2, JobState::Running));
+    let query = JobQuery {
+        filter: Some(filter.clone()),
+        ..Default::default()
+    };
+    assert_eq!(
+        sorted_ids(&manager, &query).await,
+        sorted(vec![team_1_failed, team_2_running])
+    );
+
+    // The query's own fields are ANDed with the filter
+    let query = JobQuery {
+        team_id: Some(2),
+        filter: Some(filter),
+        ..Default::default()
+    };
+    assert_eq!(sorted_ids(&manager, &query).await, vec![team_2_running]);
+
+    // Filters nest, and an empty Or matches nothing, however it's combined with And
+    let nothing = JobQuery {
+        filter: Some(Filter::And(vec![
+            team_in(1, JobState::Failed),
+            Filter::Or(vec![]),
+        ])),
+        ..Default::default()
+    };
+    assert!(sorted_ids(&manager, &nothing).await.is_empty());
+    let everything = JobQuery {
+        filter: Some(Filter::And(vec![])),
+        ..Default::default()
+    };
+    assert_eq!(sorted_ids(&manager, &everything).await.len(), 9);
+
+    // Searches nested in a filter still need search enabled
+    let nested_search = JobQuery {
+        filter: Some(Filter::matches(JobQuery {
+            search: Some("needle".to_string()),
+            ..Default::default()
+        })),
+        ..Default::default()
+    };
+    assert!(matches!(
+        manager.query_jobs(&nested_search).await,
+        Err(cyclotron_core::QueueError::SearchNotEnabled)
+    ));
+}
+
+#[test]
+pub fn test_filter_combinators_flatten() {
+    let leaf = || Filter::matches(JobQuery::default());
+    let Filter::Or(filters) = leaf().or(leaf()).or(leaf()) else {
+        panic!("expected an Or");
+    };
+    assert_eq!(filters.len(), 3);
+
+    // Mixing them nests, rather than flattening
+    let Filter::And(filters) = leaf().or(leaf()).and(leaf()) else {
+        panic!("expected an And");
+    };
+    assert_eq!(filters.len(), 2);
+    assert!(matches!(filters[0], Filter::Or(_)));
+
+    // Filters round trip through JSON, for building them from dashboard requests
+    let json = serde_json::to_value(leaf().or(leaf())).unwrap();
+    assert!(json["or"].is_array());
+    let parsed: Filter = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(serde_json::to_value(parsed).unwrap(), json);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_queue_names_are_bound_not_interpolated(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+
+    let nasty = "it's'; DROP TABLE cyclotron_jobs; --";
+    let mut init = create_new_job();
+    init.queue_name = nasty.to_string();
+    let created = manager.create_job(init).await.unwrap();
+    assert_eq!(created.queue_name, nasty);
+
+    // Moving a job between queues goes through the update builder, rather than a macro query
+    let other = manager.create_job(create_new_job()).await.unwrap();
+    let dequeued = worker.dequeue_jobs("test", 1).await.unwrap();
+    assert_eq!(dequeued[0].id, other.id);
+    worker
+        .set_queue(other.id, &format!("{} again", nasty))
+        .unwrap();
+    worker.set_state(other.id, JobState::Available).unwrap();
+    worker.release_job(other.id, None).await.unwrap();
+
+    let by_queue = |name: &str| JobQuery {
+        queue_name: Some(name.to_string()),
+        ..Default::default()
+    };
+
+    let found = manager.query_jobs(&by_queue(nasty)).await.unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id, created.id);
+
+    let found = manager
+        .query_jobs(&by_queue(&format!("{} again", nasty)))
+        .await
+        .unwrap();
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].id, other.id);
+
+    // And everything's still there
+    assert_eq!(
+        manager
+            .query_jobs(&JobQuery::default())
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_scheduled_range_filters(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    // One job an hour, from two hours ago to two hours from now
+    let now = Utc::now();
+    let mut ids = Vec::new();
+    let mut times = Vec::new();
+    for hours in -2..=2 {
+        let mut init = create_new_job();
+        init.scheduled = now + Duration::hours(hours);
+        let job = manager.create_job(init).await.unwrap();
+        ids.push(job.id);
+        times.push(job.scheduled);
+    }
+
+    // The lower bound is inclusive
+    let after = JobQuery {
+        scheduled_after: Some(times[2]),
+        ..Default::default()
+    };
+    assert_eq!(
+        sorted_ids(&manager, &after).await,
+        sorted(ids[2..].to_vec())
+    );
+
+    // The upper bound is exclusive
+    let before = JobQuery {
+        scheduled_before: Some(times[2]),
+        ..Default::default()
+    };
+    assert_eq!(
+        sorted_ids(&manager, &before).await,
+        sorted(ids[..2].to_vec())
+    );
+
+    // Together, they select a window - including jobs that aren't due yet
+    let between = JobQuery {
+        scheduled_after: Some(times[1]),
+        scheduled_before: Some(times[4]),
+        ..Default::default()
+    };
+    assert_eq!(
+        sorted_ids(&manager, &between).await,
+        sorted(ids[1..4].to_vec())
+    );
+
+    let empty = JobQuery {
+        scheduled_after: Some(times[3]),
+        scheduled_before: Some(times[3]),
+        ..Default::default()
+    };
+    assert!(sorted_ids(&manager, &empty).await.is_empty());
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_correlation_id_filter(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db, Default::default());
+
+    // Three jobs from one request, one from another, and one enqueued without a correlation id
+    let request = Uuid::now_v7();
+    let mut init = create_new_job();
+    init.correlation_id = Some(request);
+    let mut ids = vec![manager.create_job(init.clone()).await.unwrap().id];
+    let created = manager
+        .bulk_create_jobs(vec![init.clone(), init])
+        .await
+        .unwrap();
+    assert!(created.iter().all(|j| j.correlation_id == Some(request)));
+    ids.extend(created.iter().map(|j| j.id));
+
+    let mut other = create_new_job();
+    other.correlation_id = Some(Uuid::now_v7());
+    manager.create_job(other).await.unwrap();
+    manager.create_job(create_new_job()).await.unwrap();
+
+    let query = JobQuery {
+        correlation_id: Some(request),
+        ..Default::default()
+    };
+    assert_eq!(sorted_ids(&manager, &query).await, sorted(ids));
+
+    // It's carried through to the worker
+    let dequeued = worker.dequeue_jobs("test", 5).await.unwrap();
+    let carried = dequeued
+        .iter()
+        .filter(|j| j.correlation_id == Some(request))
+        .count();
+    assert_eq!(carried, 3);
+    assert_eq!(
+        dequeued
+            .iter()
+            .filter(|j| j.correlation_id.is_none())
+            .count(),
+        1
+    );
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_parent_job_id_filter(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db, Default::default());
+
+    // A root fanning out to two children, one of which fans out to a grandchild
+    let root = manager.create_job(create_new_job()).await.unwrap();
+    let mut child = create_new_job();
+    child.parent_job_id = Some(root.id);
+    let children = manager
+        .bulk_create_jobs(vec![child.clone(), child])
+        .await
+        .unwrap();
+    assert!(children.iter().all(|j| j.parent_job_id == Some(root.id)));
+
+    let mut grandchild = create_new_job();
+    grandchild.parent_job_id = Some(children[0].id);
+    let grandchild = manager.create_job(grandchild).await.unwrap();
+
+    let children_of = |parent| JobQuery {
+        parent_job_id: Some(parent),
+        ..Default::default()
+    };
+    // Only direct children are returned, not the whole subtree
+    assert_eq!(
+        sorted_ids(&manager, &children_of(root.id)).await,
+        sorted(children.iter().map(|j| j.id).collect())
+    );
+    assert_eq!(
+        sorted_ids(&manager, &children_of(children[0].id)).await,
+        [grandchild.id]
+    );
+    assert!(sorted_ids(&manager, &children_of(grandchild.id))
+        .await
+        .is_empty());
+
+    // It doesn't hold anything up - the root and its descendants can all be dequeued at once
+    assert_eq!(worker.dequeue_jobs("test", 10).await.unwrap().len(), 4);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_queue_config_filter(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    // One urgent queue, one that delays its jobs, and one that's never configured
+    let urgent = QueueConfig {
+        default_priority: Some(0),
+        default_delay: None,
+    };
+    let delayed = QueueConfig {
+        default_priority: Some(5),
+        default_delay: Some(Duration::minutes(1)),
+    };
+    manager.set_queue_config("urgent", &urgent).await.unwrap();
+    manager.set_queue_config("delayed", &delayed).await.unwrap();
+    let mut ids = std::collections::HashMap::new();
+    for queue in ["urgent", "delayed", "unconfigured"] {
+        for _ in 0..2 {
+            let mut init = create_new_job();
+            init.queue_name = queue.to_string();
+            let job = manager.create_job(init).await.unwrap();
+            ids.entry(queue).or_insert_with(Vec::new).push(job.id);
+        }
+    }
+    let jobs_in = |queues: &[&str]| sorted(queues.iter().flat_map(|q| ids[q].clone()).collect());
+    let matching = |config: QueueConfigFilter| JobQuery {
+        queue_config: Some(config),
+        ..Default::default()
+    };
+
+    let high_priority = QueueConfigFilter {
+        default_priority: Some(0),
+        ..Default::default()
+    };
+    assert_eq!(
+        sorted_ids(&manager, &matching(high_priority)).await,
+        jobs_in(&["urgent"])
+    );
+    let undelayed = QueueConfigFilter {
+        has_default_delay: Some(false),
+        ..Default::default()
+    };
+    assert_eq!(
+        sorted_ids(&manager, &matching(undelayed)).await,
+        jobs_in(&["urgent"])
+    );
+    let both = QueueConfigFilter {
+        default_priority: Some(5),
+        has_default_delay: Some(true),
+    };
+    assert_eq!(
+        sorted_ids(&manager, &matching(both)).await,
+        jobs_in(&["delayed"])
+    );
+    // An empty filter matches every configured queue, and none that aren't
+    assert_eq!(
+        sorted_ids(&manager, &matching(QueueConfigFilter::default())).await,
+        jobs_in(&["urgent", "delayed"])
+    );
+
+    // It combines with the other filters, and works anywhere they do
+    let query = JobQuery {
+        queue_name: Some("urgent".to_string()),
+        queue_config: Some(QueueConfigFilter {
+            default_priority: Some(5),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    assert!(manager.query_jobs(&query).await.unwrap().is_empty());
+    let counts = manager
+        .state_counts(
+            &JobQuery {
+                queue_config: Some(QueueConfigFilter::default()),
+                ..Default::default()
+            },
+            false,
+        )
+        .await
+        .unwrap();
+    assert_eq!(counts.len(), 1);
+    assert_eq!(counts[0].count, 4);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_query_limits(db: PgPool) {
+    let manager = QueueManager::from_pool(db);
+    let total = DEFAULT_QUERY_LIMIT as usize + 5;
+    manager
+        .bulk_create_jobs(vec![create_new_job(); total])
+        .await
+        .unwrap();
+
+    // No limit means the default one, zero means zero, and anything else is taken as it is
+    assert_eq!(
+        sorted_ids(&manager, &JobQuery::default()).await.len(),
+        DEFAULT_QUERY_LIMIT as usize
+    );
+    let limited = |limit| JobQuery {
+        limit: Some(limit),
+        ..Default::default()
+    };
+    assert_eq!(sorted_ids(&manager, &limited(0)).await.len(), 0);
+    assert_eq!(sorted_ids(&manager, &limited(10)).await.len(), 10);
+    assert_eq!(sorted_ids(&manager, &limited(1000)).await.len(), total);
+
+    // Everything has to be asked for explicitly
+    let unlimited = JobQuery::default().unlimited();
+    assert_eq!(sorted_ids(&manager, &unlimited).await.len(), total);
+    // And can't be asked for in a serialized query
+    let query: JobQuery = serde_json::from_value(serde_json::json!({ "unlimited": true })).unwrap();
+    assert!(!query.unlimited);
+    assert_eq!(
+        sorted_ids(&manager, &query).await.len(),
+        DEFAULT_QUERY_LIMIT as usize
+    );
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_job_summaries(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let mut init = create_new_job();
+    init.vm_state = Some(b"vm".to_vec());
+    init.parameters = Some(b"params".to_vec());
+    init.blob = Some(b"blob".to_vec());
+    let job = manager.create_job(init).await.unwrap();
+    manager.create_job(create_new_job()).await.unwrap();
+
+    let query = JobQuery {
+        team_id: Some(job.team_id),
+        states: vec![JobState::Available],
+        limit: Some(1),
+        ..Default::default()
+    };
+    let summaries = manager.query_job_summaries(&query).await.unwrap();
+    assert_eq!(summaries.len(), 1);
+    let summary = &summaries[0];
+    assert_eq!(summary.id, job.id);
+    assert_eq!(summary.queue_name, job.queue_name);
+    assert_eq!(summary.state, JobState::Available);
+    assert_eq!(summary.scheduled, job.scheduled);
+    assert_eq!(summary.transition_count, 0);
+
+    // The payloads are never selected - a manager whose connections can read every column but those still
+    // gets the same summaries
+    sqlx::query(
+        r#"
+DO $$
+BEGIN
+    CREATE ROLE cyclotron_summaries_reader;
+EXCEPTION WHEN duplicate_object THEN NULL;
+END
+$$"#,
+    )
+    .execute(&db)
+    .await
+    .unwrap();
+    let columns: String = sqlx::query_scalar(
+        r#"
+SELECT string_agg(column_name, ', ')
+FROM information_schema.columns
+WHERE table_name = 'cyclotron_jobs'
+    AND column_name NOT IN ('vm_state', 'metadata', 'parameters', 'blob')"#,
+    )
+    .fetch_one(&db)
+    .await
+    .unwrap();
+    sqlx::query(&format!(
+        "GRANT SELECT ({}) ON cyclotron_jobs TO cyclotron_summaries_reader",
+        columns
+    ))
+    .execute(&db)
+    .await
+    .unwrap();
+    let reader = PgPoolOptions::new()
+        .after_connect(|conn, _| {
+            Box::pin(async move {
+                conn.execute("SET ROLE cyclotron_summaries_reader").await?;
+                Ok(())
+            })
+        })
+        .connect_with(db.connect_options().as_ref().clone())
+        .await
+        .unwrap();
+    let restricted = QueueManager::from_pool(reader);
+    for query in [JobQuery::default(), query] {
+        assert_eq!(
+            restricted.query_job_summaries(&query).await.unwrap(),
+            manager.query_job_summaries(&query).await.unwrap()
+        );
+    }
+    // Which wouldn't prove much if the role could read the payloads anyway
+    assert!(restricted.get_job(job.id).await.is_err());
+}
@@ -0,0 +1,321 @@
+use chrono::{Duration, Utc};
+use common::create_new_job;
+use cyclotron_core::{
+    BatchRejected, JobQuery, JobState, QueueConfig, QueueError, QueueManager, StateCount, Worker,
+};
+use sqlx::PgPool;
+
+mod common;
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_draining_queue_rejects_new_jobs(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+
+    let job = create_new_job();
+    let queue_name = job.queue_name.clone();
+
+    // Put a couple of jobs in the queue, and pick one of them up, before we start draining
+    manager.create_job(job.clone()).await.unwrap();
+    manager.create_job(job.clone()).await.unwrap();
+    let running = worker.dequeue_jobs(&queue_name, 1).await.unwrap();
+    assert_eq!(running.len(), 1);
+
+    assert!(!manager.queue_is_draining(&queue_name).await.unwrap());
+    manager.drain_queue(&queue_name).await.unwrap();
+    assert!(manager.queue_is_draining(&queue_name).await.unwrap());
+
+    // Draining twice is fine
+    manager.drain_queue(&queue_name).await.unwrap();
+
+    // New jobs are rejected, both individually and in bulk
+    let res = manager.create_job(job.clone()).await;
+    assert!(matches!(res, Err(QueueError::QueueDraining(q)) if q == queue_name));
+
+    let mut other_queue = create_new_job();
+    other_queue.queue_name = "not_draining".to_string();
+    let res = manager
+        .bulk_create_jobs(vec![other_queue.clone(), job.clone()])
+        .await;
+    assert!(matches!(res, Err(QueueError::QueueDraining(q)) if q == queue_name));
+    // And a rejected batch inserts nothing, even for queues that aren't draining
+    assert!(worker
+        .dequeue_jobs(&other_queue.queue_name, 10)
+        .await
+        .unwrap()
+        .is_empty());
+
+    // Other queues are unaffected
+    manager.create_job(other_queue.clone()).await.unwrap();
+    manager.bulk_create_jobs(vec![other_queue]).await.unwrap();
+
+    // But the work already in the queue can still be completed, and dequeued
+    worker
+        .set_state(running[0].id, JobState::Completed)
+        .unwrap();
+    worker.release_job(running[0].id, None).await.unwrap();
+
+    let remaining = worker.dequeue_jobs(&queue_name, 10).await.unwrap();
+    assert_eq!(remaining.len(), 1);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_max_queue_depth_rejects_over_limit(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+
+    let job = create_new_job();
+    let queue_name = job.queue_name.clone();
+    let max_depth = Some(3);
+
+    // Up to the limit, enqueues go through
+    for _ in 0..3 {
+        manager
+            .create_job_with_max_depth(job.clone(), max_depth)
+            .await
+            .unwrap();
+    }
+
+    // The next one is rejected, and not inserted
+    let res = manager
+        .create_job_with_max_depth(job.clone(), max_depth)
+        .await;
+    assert!(matches!(res, Err(QueueError::QueueFull(q, 3)) if q == queue_name));
+
+    // Other queues aren't affected
+    let mut other_queue = create_new_job();
+    other_queue.queue_name = "other".to_string();
+    manager
+        .create_job_with_max_depth(other_queue, max_depth)
+        .await
+        .unwrap();
+
+    // Only available jobs count towards the depth, so once one is picked up there's room again
+    let running = worker.dequeue_jobs(&queue_name, 1).await.unwrap();
+    assert_eq!(running.len(), 1);
+    manager
+        .create_job_with_max_depth(job.clone(), max_depth)
+        .await
+        .unwrap();
+    let res = manager
+        .create_job_with_max_depth(job.clone(), max_depth)
+        .await;
+    assert!(matches!(res, Err(QueueError::QueueFull(_, _))));
+
+    // And without a max depth, there's no limit
+    manager.create_job_with_max_depth(job, None).await.unwrap();
+    let all = worker.dequeue_jobs(&queue_name, 10).await.unwrap();
+    assert_eq!(all.len(), 4);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_transactional_batch_is_all_or_nothing(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let job = create_new_job();
+    let mut blocked = create_new_job();
+    blocked.queue_name = "blocked".to_string();
+    manager.drain_queue(&blocked.queue_name).await.unwrap();
+
+    // The last job in the batch conflicts with its queue, after the first two have been inserted
+    // within the transaction, so all of them are rolled back
+    let res = manager
+        .bulk_create_jobs_transactional(vec![job.clone(), job.clone(), blocked])
+        .await;
+    assert!(matches!(
+        res,
+        Err(BatchRejected {
+            index: Some(2),
+            error: QueueError::QueueDraining(q),
+        }) if q == "blocked"
+    ));
+
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM cyclotron_jobs")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(count, 0);
+
+    // A batch with no conflicts is inserted in full
+    let jobs = manager
+        .bulk_create_jobs_transactional(vec![job.clone(), job])
+        .await
+        .unwrap();
+    assert_eq!(jobs.len(), 2);
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM cyclotron_jobs")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(count, 2);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_queue_defaults_apply_to_unset_fields(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let init = create_new_job();
+    let config = QueueConfig {
+        default_priority: Some(5),
+        default_delay: Some(Duration::minutes(10)),
+    };
+    manager
+        .set_queue_config(&init.queue_name, &config)
+        .await
+        .unwrap();
+
+    // Left unset, both come from the queue, rather than the init
+    let mut init = init;
+    init.priority = 3;
+    init.scheduled = Utc::now() - Duration::minutes(5);
+    let before = Utc::now();
+    let job = manager
+        .create_job_with_queue_defaults(init.clone(), None, None)
+        .await
+        .unwrap();
+    assert_eq!(job.priority, 5);
+    assert!(job.scheduled >= before + Duration::minutes(10));
+    assert!(job.scheduled <= Utc::now() + Duration::minutes(10));
+
+    // Explicit values win
+    let scheduled = Utc::now() - Duration::minutes(1);
+    let job = manager
+        .create_job_with_queue_defaults(init.clone(), Some(-1), Some(scheduled))
+        .await
+        .unwrap();
+    assert_eq!(job.priority, -1);
+    assert!((job.scheduled - scheduled).num_milliseconds().abs() < 1);
+
+    // And a queue with no config falls back to the init's own fields
+    let mut other = init.clone();
+    other.queue_name = "unconfigured".to_string();
+    let job = manager
+        .create_job_with_queue_defaults(other, None, None)
+        .await
+        .unwrap();
+    assert_eq!(job.priority, 3);
+    assert!((job.scheduled - init.scheduled).num_milliseconds().abs() < 1);
+
+    // Reconfiguring a queue replaces its old defaults entirely - with no default delay, the init's schedule is kept
+    manager
+        .set_queue_config(
+            &init.queue_name,
+            &QueueConfig {
+                default_priority: Some(1),
+                default_delay: None,
+            },
+        )
+        .await
+        .unwrap();
+    let job = manager
+        .create_job_with_queue_defaults(init.clone(), None, None)
+        .await
+        .unwrap();
+    assert_eq!(job.priority, 1);
+    assert!((job.scheduled - init.scheduled).num_milliseconds().abs() < 1);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_remap_queue_moves_matching_pending_jobs(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    // Team 1 has three jobs in the queue, one of which we start running
+    let init = create_new_job();
+    for _ in 0..3 {
+        manager.create_job(init.clone()).await.unwrap();
+    }
+    let running = worker.dequeue_jobs(&init.queue_name, 1).await.unwrap();
+    assert_eq!(running[0].team_id, 1);
+
+    // Team 2 has two, and team 1 has another, paused behind one of team 2's
+    let mut other_team = create_new_job();
+    other_team.team_id = 2;
+    let dependency = manager.create_job(other_team.clone()).await.unwrap();
+    manager.create_job(other_team.clone()).await.unwrap();
+    let paused = manager
+        .enqueue_after(create_new_job(), dependency.id)
+        .await
+        .unwrap();
+    assert_eq!(paused.state, JobState::Paused);
+
+    let filter = JobQuery {
+        team_id: Some(1),
+        ..Default::default()
+    };
+    let moved = manager
+        .remap_queue(&init.queue_name, "split", Some(&filter))
+        .await
+        .unwrap();
+    assert_eq!(moved, 3);
+
+    let in_queue = |queue_name: &str| JobQuery {
+        queue_name: Some(queue_name.to_string()),
+        ..Default::default()
+    };
+    let split = manager.query_jobs(&in_queue("split")).await.unwrap();
+    assert_eq!(split.len(), 3);
+    assert!(split.iter().all(|j| j.team_id == 1));
+    assert!(split.iter().any(|j| j.id == paused.id));
+
+    // The running job, and team 2's jobs, stayed put
+    let left = manager
+        .query_jobs(&in_queue(&init.queue_name))
+        .await
+        .unwrap();
+    assert_eq!(left.len(), 3);
+    assert!(left
+        .iter()
+        .any(|j| j.id == running[0].id && j.state == JobState::Running));
+    assert_eq!(left.iter().filter(|j| j.team_id == 2).count(), 2);
+
+    // With no filter, everything pending in the queue moves - which is now just team 2's jobs
+    let moved = manager
+        .remap_queue(&init.queue_name, "split", None)
+        .await
+        .unwrap();
+    assert_eq!(moved, 2);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_list_queues(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    assert!(manager.list_queues().await.unwrap().is_empty());
+
+    for (queue, jobs) in [("alpha", 3), ("beta", 2), ("gamma", 1)] {
+        for _ in 0..jobs {
+            let mut init = create_new_job();
+            init.queue_name = queue.to_string();
+            manager.create_job(init).await.unwrap();
+        }
+    }
+    // Start one of alpha's jobs, so it has jobs in two states
+    worker.dequeue_jobs("alpha", 1).await.unwrap();
+
+    let count = |state: &str, count| StateCount {
+        state: state.to_string(),
+        count,
+    };
+    let queues: Vec<_> = manager
+        .list_queues()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|q| (q.queue_name, q.total, q.states))
+        .collect();
+    assert_eq!(
+        queues,
+        [
+            (
+                "alpha".to_string(),
+                3,
+                vec![count("available", 2), count("running", 1)]
+            ),
+            ("beta".to_string(), 2, vec![count("available", 2)]),
+            ("gamma".to_string(), 1, vec![count("available", 1)]),
+        ]
+    );
+}
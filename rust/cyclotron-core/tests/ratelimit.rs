@@ -0,0 +1,112 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use common::create_new_job;
+use cyclotron_core::{QueueManager, RateLimit, TeamRateLimiter, TeamRateLimits, Worker};
+use sqlx::PgPool;
+
+mod common;
+
+// Jobs held back by the rate limiter are rescheduled for when their team will next have capacity, so this
+// waits out those (short) delays before dequeuing everything available, returning how many of the dequeued
+// jobs were for team 1, and how many for anyone else
+async fn dequeue_available(worker: &Worker) -> (usize, usize) {
+    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+    let (mut limited, mut unlimited) = (0, 0);
+    for _ in 0..5 {
+        for job in worker.dequeue_jobs("test", 10).await.unwrap() {
+            if job.team_id == 1 {
+                limited += 1;
+            } else {
+                unlimited += 1;
+            }
+        }
+    }
+    (limited, unlimited)
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_team_rate_limit_on_dequeue(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, so deferred jobs are back in the queue straight away
+
+    // The limiter's clock only moves when we move it
+    let start = Instant::now();
+    let elapsed = Arc::new(Mutex::new(Duration::ZERO));
+    let clock = elapsed.clone();
+    worker.team_rate_limiter = Some(TeamRateLimiter::with_clock(
+        TeamRateLimits {
+            default: None,
+            teams: HashMap::from([(
+                1,
+                RateLimit {
+                    per_second: 5.0,
+                    burst: None,
+                },
+            )]),
+        },
+        move || start + *clock.lock().unwrap(),
+    ));
+    let advance = |by: Duration| *elapsed.lock().unwrap() += by;
+
+    // Plenty of work for the limited team, and some for a team without a limit
+    for team_id in [1; 20].into_iter().chain([2; 5]) {
+        let mut init = create_new_job();
+        init.team_id = team_id;
+        manager.create_job(init).await.unwrap();
+    }
+
+    // The limited team's jobs are all eligible, but only its burst of one is handed out, while the other
+    // team's aren't held back at all
+    assert_eq!(dequeue_available(&worker).await, (1, 5));
+
+    // Half the time it takes to earn another isn't enough
+    advance(Duration::from_millis(100));
+    assert_eq!(dequeue_available(&worker).await, (0, 0));
+    advance(Duration::from_millis(100));
+    assert_eq!(dequeue_available(&worker).await, (1, 0));
+
+    // And however long it's been, it can't save up more than its burst
+    advance(Duration::from_secs(10));
+    assert_eq!(dequeue_available(&worker).await, (1, 0));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_served_counts_reflect_team_rate_limits(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    // Team 1 gets a burst of 3, and nothing after that
+    worker.team_rate_limiter = Some(TeamRateLimiter::new(TeamRateLimits {
+        default: None,
+        teams: HashMap::from([(
+            1,
+            RateLimit {
+                per_second: 0.0,
+                burst: Some(3.0),
+            },
+        )]),
+    }));
+
+    // A skewed workload, most of it for the limited team
+    for team_id in [1; 20].into_iter().chain([2; 5]) {
+        let mut init = create_new_job();
+        init.team_id = team_id;
+        manager.create_job(init).await.unwrap();
+    }
+    for _ in 0..3 {
+        worker.dequeue_jobs("test", 10).await.unwrap();
+    }
+
+    // Only what the rate limiter let through was served, however much more of the queue it was
+    let snapshot = worker.metrics_snapshot();
+    assert_eq!(snapshot.served_by_team, HashMap::from([(1, 3), (2, 5)]));
+
+    // Once the window is over, the counts start again from zero
+    worker.served_window = chrono::Duration::zero();
+    let snapshot = worker.metrics_snapshot();
+    assert!(snapshot.served_by_team.is_empty());
+}
@@ -0,0 +1,62 @@
+use common::create_new_job;
+use cyclotron_core::{JobQuery, JobState, QueueManager, Worker};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+mod common;
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_complete_job_with_reply(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db, Default::default());
+
+    let mut init = create_new_job();
+    init.reply_to = Some("replies".to_string());
+    init.correlation_id = Some(Uuid::now_v7());
+    let request = manager.create_job(init.clone()).await.unwrap();
+    assert_eq!(request.reply_to.as_deref(), Some("replies"));
+
+    let job = worker.dequeue_jobs("test", 1).await.unwrap().pop().unwrap();
+    let reply = worker
+        .complete_job_with_reply(job.id, Some(b"the result".to_vec()))
+        .await
+        .unwrap()
+        .expect("job had a reply_to, so should have been replied to");
+
+    assert_eq!(reply.queue_name, "replies");
+    assert_eq!(reply.state, JobState::Available);
+    assert_eq!(reply.team_id, init.team_id);
+    assert_eq!(reply.parent_job_id, Some(request.id));
+    assert_eq!(reply.correlation_id, init.correlation_id);
+    assert_eq!(reply.parameters.as_deref(), Some(&b"the result"[..]));
+    assert_eq!(reply.reply_to, None);
+
+    let request = manager.get_job(request.id).await.unwrap().unwrap();
+    assert_eq!(request.state, JobState::Completed);
+
+    // The requester finds it waiting in the reply-to queue
+    let replies = worker.dequeue_jobs("replies", 10).await.unwrap();
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].id, reply.id);
+    assert_eq!(replies[0].parent_job_id, Some(request.id));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_complete_job_without_reply_to(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db, Default::default());
+
+    let created = manager.create_job(create_new_job()).await.unwrap();
+    let job = worker.dequeue_jobs("test", 1).await.unwrap().pop().unwrap();
+    let reply = worker
+        .complete_job_with_reply(job.id, Some(b"the result".to_vec()))
+        .await
+        .unwrap();
+    assert!(reply.is_none());
+
+    // The job was still completed, and nothing else was enqueued
+    let jobs = manager.query_jobs(&JobQuery::default()).await.unwrap();
+    assert_eq!(jobs.len(), 1);
+    assert_eq!(jobs[0].id, created.id);
+    assert_eq!(jobs[0].state, JobState::Completed);
+}
@@ -0,0 +1,108 @@
+use chrono::{DateTime, Duration, Utc};
+use common::create_new_job;
+use cyclotron_core::{Janitor, JobState, QueueManager, Worker};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+mod common;
+
+// Creates a job for the function, then rewrites its bookkeeping so it looks like it waited `waited` before being
+// run, and has since been through `transition_count` transitions, the last of them a minute ago
+async fn job_that_waited(
+    db: &PgPool,
+    manager: &QueueManager,
+    function_id: Uuid,
+    waited: Duration,
+    transition_count: i16,
+) {
+    let mut init = create_new_job();
+    init.function_id = Some(function_id);
+    let job = manager.create_job(init).await.unwrap();
+
+    let created = Utc::now() - Duration::hours(1);
+    sqlx::query(
+        "UPDATE cyclotron_jobs SET created = $1, first_dequeued = $2, last_transition = $3, transition_count = $4 WHERE id = $5",
+    )
+    .bind(created)
+    .bind(created + waited)
+    .bind(Utc::now() - Duration::minutes(1))
+    .bind(transition_count)
+    .bind(job.id)
+    .execute(db)
+    .await
+    .unwrap();
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_time_in_queue_percentiles(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let janitor = Janitor::from_pool(db.clone());
+
+    // Jobs that have been retried or released since are counted by their first run, however long ago that was
+    let first = Uuid::now_v7();
+    for secs in 1..=10 {
+        job_that_waited(&db, &manager, first, Duration::seconds(secs), secs as i16).await;
+    }
+
+    let second = Uuid::now_v7();
+    job_that_waited(&db, &manager, second, Duration::zero(), 1).await;
+    job_that_waited(&db, &manager, second, Duration::seconds(100), 1).await;
+
+    // Never been run, so also left out
+    manager.create_job(create_new_job()).await.unwrap();
+
+    let now = Utc::now();
+    let mut latencies = janitor
+        .time_in_queue(now - Duration::days(1), now)
+        .await
+        .unwrap();
+    latencies.sort_by_key(|l| l.function_id);
+
+    assert_eq!(latencies.len(), 2);
+    let close = |a: f64, b: f64| (a - b).abs() < 0.001;
+
+    assert_eq!(latencies[0].function_id, Some(first));
+    assert_eq!(latencies[0].jobs, 10);
+    assert!(close(latencies[0].p50_seconds, 5.5));
+    assert!(close(latencies[0].p95_seconds, 9.55));
+
+    assert_eq!(latencies[1].function_id, Some(second));
+    assert_eq!(latencies[1].jobs, 2);
+    assert!(close(latencies[1].p50_seconds, 50.0));
+    assert!(close(latencies[1].p95_seconds, 95.0));
+
+    // Jobs created outside the window aren't counted
+    let latencies = janitor
+        .time_in_queue(now - Duration::minutes(30), now)
+        .await
+        .unwrap();
+    assert!(latencies.is_empty());
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_first_dequeue_is_kept(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+    let first_dequeued = |id: Uuid| {
+        sqlx::query_scalar::<_, Option<DateTime<Utc>>>(
+            "SELECT first_dequeued FROM cyclotron_jobs WHERE id = $1",
+        )
+        .bind(id)
+        .fetch_one(&db)
+    };
+
+    let job = manager.create_job(create_new_job()).await.unwrap();
+    assert_eq!(first_dequeued(job.id).await.unwrap(), None);
+
+    worker.dequeue_jobs("test", 1).await.unwrap();
+    let first = first_dequeued(job.id).await.unwrap();
+    assert!(first.is_some());
+
+    // Returning the job to the queue and running it again doesn't move it
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    worker.set_state(job.id, JobState::Available).unwrap();
+    worker.release_job(job.id, None).await.unwrap();
+    worker.dequeue_jobs("test", 1).await.unwrap();
+    assert_eq!(first_dequeued(job.id).await.unwrap(), first);
+}
@@ -0,0 +1,549 @@
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+use chrono::Duration;
+use common::{create_new_job, CapturedLogs};
+use cyclotron_core::{
+    CancellationToken, Janitor, JobQuery, JobState, QueueManager, RunConfig, Worker,
+    PROCESSING_TIME_BUCKETS_MS,
+};
+use sqlx::PgPool;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+mod common;
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_run_processes_jobs(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+
+    // Half the jobs are marked to fail, via their parameters
+    let inits: Vec<_> = (0..20)
+        .map(|i| {
+            let mut init = create_new_job();
+            init.parameters = Some(if i % 2 == 0 {
+                b"ok".to_vec()
+            } else {
+                b"fail".to_vec()
+            });
+            init
+        })
+        .collect();
+    let queue_name = inits[0].queue_name.clone();
+    manager.bulk_create_jobs(inits).await.unwrap();
+
+    // Each failing job is tried twice, so we expect 30 handler calls in total
+    let config = RunConfig {
+        concurrency: 4,
+        poll_interval: Duration::milliseconds(10),
+        max_attempts: 2,
+        base_backoff: Duration::zero(),
+        ..Default::default()
+    };
+    let calls = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(Notify::new());
+
+    let handler = |job: cyclotron_core::Job, _| {
+        let calls = calls.clone();
+        let done = done.clone();
+        async move {
+            if calls.fetch_add(1, Ordering::SeqCst) + 1 == 30 {
+                done.notify_one();
+            }
+            match job.parameters.as_deref() {
+                Some(b"ok") => Ok(Some(job.id.as_bytes().to_vec())),
+                _ => Err("handler failed"),
+            }
+        }
+    };
+
+    tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        worker.run(&queue_name, config, done.notified(), handler),
+    )
+    .await
+    .expect("run loop timed out")
+    .expect("run loop failed");
+
+    assert_eq!(calls.load(Ordering::SeqCst), 30);
+
+    let jobs = manager.query_jobs(&JobQuery::default()).await.unwrap();
+    assert_eq!(jobs.len(), 20);
+    for job in jobs {
+        if job.parameters.as_deref() == Some(&b"ok"[..]) {
+            assert_eq!(job.state, JobState::Completed);
+            assert_eq!(job.failed_attempts, 0);
+            // With the handler's output in its blob
+            assert_eq!(job.blob.as_deref(), Some(&job.id.as_bytes()[..]));
+        } else {
+            assert_eq!(job.state, JobState::Failed);
+            assert_eq!(job.failed_attempts, 2);
+            assert_eq!(job.retry_history().len(), 2);
+            assert_eq!(job.retry_history()[1].error, "handler failed");
+        }
+    }
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_run_dead_letters_panicking_jobs(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+
+    let mut ids = vec![];
+    for _ in 0..5 {
+        ids.push(manager.create_job(create_new_job()).await.unwrap().id);
+    }
+    let poison = ids[2];
+
+    let config = RunConfig {
+        concurrency: 2,
+        poll_interval: Duration::milliseconds(10),
+        base_backoff: Duration::zero(),
+        max_panics: 3,
+        ..Default::default()
+    };
+    let completed = Arc::new(AtomicUsize::new(0));
+    let panics = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(Notify::new());
+
+    let handler = |job: cyclotron_core::Job, _| {
+        let completed = completed.clone();
+        let panics = panics.clone();
+        let done = done.clone();
+        async move {
+            if job.id == poison {
+                // The third panic is the last, since the job is dead-lettered after it
+                if panics.fetch_add(1, Ordering::SeqCst) + 1 == 3
+                    && completed.load(Ordering::SeqCst) == 4
+                {
+                    done.notify_one();
+                }
+                panic!("poison job");
+            }
+            if completed.fetch_add(1, Ordering::SeqCst) + 1 == 4
+                && panics.load(Ordering::SeqCst) == 3
+            {
+                done.notify_one();
+            }
+            Ok::<_, String>(None)
+        }
+    };
+
+    tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        worker.run("test", config, done.notified(), handler),
+    )
+    .await
+    .expect("run loop timed out")
+    .expect("run loop failed");
+
+    // Every other job ran fine, despite the panics
+    assert_eq!(completed.load(Ordering::SeqCst), 4);
+    assert_eq!(panics.load(Ordering::SeqCst), 3);
+
+    let jobs = manager.query_jobs(&JobQuery::default()).await.unwrap();
+    for job in jobs {
+        if job.id == poison {
+            assert_eq!(job.queue_name, "_cyclotron_dead_letter");
+            // The count reflects the panics before the one that got it dead-lettered
+            let metadata: serde_json::Value =
+                serde_json::from_slice(job.metadata.as_deref().unwrap()).unwrap();
+            assert_eq!(metadata[cyclotron_core::PANIC_COUNT_KEY], 2);
+        } else {
+            assert_eq!(job.state, JobState::Completed);
+        }
+    }
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_run_cancels_handler_when_lock_is_lost(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let janitor = Janitor::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+    worker.heartbeat_window = Duration::zero(); // Every heartbeat goes to the DB
+
+    let job = manager.create_job(create_new_job()).await.unwrap();
+
+    // With one job at a time, the reclaimed job can't be dequeued again until its first run is cancelled
+    let config = RunConfig {
+        concurrency: 1,
+        poll_interval: Duration::milliseconds(10),
+        heartbeat_interval: Duration::milliseconds(20),
+        ..Default::default()
+    };
+    let calls = Arc::new(AtomicUsize::new(0));
+    let started = Arc::new(Notify::new());
+    let finished = Arc::new(Notify::new());
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let cancelled_notify = Arc::new(Notify::new());
+
+    // The first run only finishes once it's cancelled, the second completes straight away
+    let handler = |_job: cyclotron_core::Job, cancel: CancellationToken| {
+        let calls = calls.clone();
+        let started = started.clone();
+        let finished = finished.clone();
+        let cancelled = cancelled.clone();
+        let cancelled_notify = cancelled_notify.clone();
+        async move {
+            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                started.notify_one();
+                cancel.cancelled().await;
+                cancelled.store(true, Ordering::SeqCst);
+                cancelled_notify.notify_one();
+                // What a cancelled handler returns is ignored - it's not what the job's released with
+                return Err("cancelled");
+            }
+            finished.notify_one();
+            Ok::<_, &str>(None)
+        }
+    };
+
+    // Once the handler's running, the janitor reclaims the job out from under it. We expect the handler to be
+    // cancelled, and the job to be picked up and run again, before we shut down
+    let shutdown = async {
+        started.notified().await;
+        assert_eq!(
+            janitor.reset_stalled_jobs(Duration::zero()).await.unwrap(),
+            1
+        );
+        cancelled_notify.notified().await;
+        finished.notified().await;
+    };
+
+    tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        worker.run(&job.queue_name, config, shutdown, handler),
+    )
+    .await
+    .expect("run loop timed out")
+    .expect("run loop failed");
+
+    assert!(cancelled.load(Ordering::SeqCst));
+    // The second run, of the job the janitor returned to the queue, completed it
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    let job = manager.get_job(job.id).await.unwrap().unwrap();
+    assert_eq!(job.state, JobState::Completed);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_run_force_releases_jobs_when_shutdown_times_out(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+
+    let mut quick = create_new_job();
+    quick.parameters = Some(b"quick".to_vec());
+    let mut stuck = create_new_job();
+    stuck.parameters = Some(b"stuck".to_vec());
+    let quick = manager.create_job(quick).await.unwrap();
+    let stuck = manager.create_job(stuck).await.unwrap();
+
+    let timeout = Duration::milliseconds(200);
+    let config = RunConfig {
+        concurrency: 2,
+        poll_interval: Duration::milliseconds(10),
+        shutdown_timeout: Some(timeout),
+        ..Default::default()
+    };
+    let started = Arc::new(AtomicUsize::new(0));
+    let all_started = Arc::new(Notify::new());
+
+    // One job finishes once shutdown has begun, the other only once it's cancelled
+    let handler = |job: cyclotron_core::Job, cancel: CancellationToken| {
+        let started = started.clone();
+        let all_started = all_started.clone();
+        async move {
+            if started.fetch_add(1, Ordering::SeqCst) + 1 == 2 {
+                all_started.notify_one();
+            }
+            if job.parameters.as_deref() == Some(b"stuck") {
+                cancel.cancelled().await;
+                return Ok(None);
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            Ok::<_, &str>(None)
+        }
+    };
+
+    let report = tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        worker.run(&quick.queue_name, config, all_started.notified(), handler),
+    )
+    .await
+    .expect("run loop timed out")
+    .expect("run loop failed");
+
+    assert_eq!(report.completed, 1);
+    assert_eq!(report.force_released, 1);
+    assert_eq!(report.timed_out_after, Some(timeout));
+
+    let quick = manager.get_job(quick.id).await.unwrap().unwrap();
+    assert_eq!(quick.state, JobState::Completed);
+    // The stuck job is back in the queue, unlocked, for the next worker to pick up
+    let stuck = manager.get_job(stuck.id).await.unwrap().unwrap();
+    assert_eq!(stuck.state, JobState::Available);
+    assert_eq!(stuck.lock_id, None);
+    assert_eq!(stuck.last_heartbeat, None);
+    let dequeued = worker.dequeue_jobs(&stuck.queue_name, 1).await.unwrap();
+    assert_eq!(dequeued[0].id, stuck.id);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_run_counts_releases_flushed_at_shutdown_timeout_as_completed(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+
+    let mut quick = create_new_job();
+    quick.parameters = Some(b"quick".to_vec());
+    let mut stuck = create_new_job();
+    stuck.parameters = Some(b"stuck".to_vec());
+    let quick = manager.create_job(quick).await.unwrap();
+    let stuck = manager.create_job(stuck).await.unwrap();
+
+    let timeout = Duration::milliseconds(200);
+    let config = RunConfig {
+        concurrency: 2,
+        poll_interval: Duration::milliseconds(10),
+        shutdown_timeout: Some(timeout),
+        ..Default::default()
+    };
+    let started = Arc::new(AtomicUsize::new(0));
+    let all_started = Arc::new(Notify::new());
+
+    // The quick job is released straight away, but something else holds its row until after the shutdown has
+    // timed out, so the release is still being flushed when the stuck job is force-released
+    let handler = |job: cyclotron_core::Job, cancel: CancellationToken| {
+        let started = started.clone();
+        let all_started = all_started.clone();
+        let db = db.clone();
+        async move {
+            if job.parameters.as_deref() == Some(b"quick") {
+                let mut holder = db.begin().await.unwrap();
+                sqlx::query("SELECT id FROM cyclotron_jobs WHERE id = $1 FOR UPDATE")
+                    .bind(job.id)
+                    .execute(&mut *holder)
+                    .await
+                    .unwrap();
+                tokio::spawn(async move {
+                    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+                    holder.rollback().await.unwrap();
+                });
+            }
+            if started.fetch_add(1, Ordering::SeqCst) + 1 == 2 {
+                all_started.notify_one();
+            }
+            if job.parameters.as_deref() == Some(b"stuck") {
+                cancel.cancelled().await;
+            }
+            Ok::<_, &str>(None)
+        }
+    };
+
+    let report = tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        worker.run(&quick.queue_name, config, all_started.notified(), handler),
+    )
+    .await
+    .expect("run loop timed out")
+    .expect("run loop failed");
+
+    // Every job is accounted for, once
+    assert_eq!(report.completed, 1);
+    assert_eq!(report.force_released, 1);
+    let quick = manager.get_job(quick.id).await.unwrap().unwrap();
+    assert_eq!(quick.state, JobState::Completed);
+    let stuck = manager.get_job(stuck.id).await.unwrap().unwrap();
+    assert_eq!(stuck.state, JobState::Available);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_run_records_processing_time_by_function(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+
+    // Three jobs for a quick function, and two for a slow one
+    let quick = Uuid::now_v7();
+    let slow = Uuid::now_v7();
+    let inits: Vec<_> = [quick, quick, quick, slow, slow]
+        .into_iter()
+        .map(|function_id| {
+            let mut init = create_new_job();
+            init.function_id = Some(function_id);
+            init
+        })
+        .collect();
+    let queue_name = inits[0].queue_name.clone();
+    manager.bulk_create_jobs(inits).await.unwrap();
+
+    let config = RunConfig {
+        concurrency: 5,
+        poll_interval: Duration::milliseconds(10),
+        ..Default::default()
+    };
+    let calls = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(Notify::new());
+
+    let handler = |job: cyclotron_core::Job, _| {
+        let calls = calls.clone();
+        let done = done.clone();
+        async move {
+            if job.function_id == Some(slow) {
+                tokio::time::sleep(std::time::Duration::from_millis(60)).await;
+            }
+            if calls.fetch_add(1, Ordering::SeqCst) + 1 == 5 {
+                done.notify_one();
+            }
+            Ok::<_, &str>(None)
+        }
+    };
+
+    tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        worker.run(&queue_name, config, done.notified(), handler),
+    )
+    .await
+    .expect("run loop timed out")
+    .expect("run loop failed");
+
+    let processing = worker.metrics_snapshot().processing_by_function;
+    assert_eq!(processing.len(), 2);
+
+    // Buckets are "no longer than", so 60ms sleeps land at or past the 100ms bucket, and instant handlers before it
+    let hundred_ms = PROCESSING_TIME_BUCKETS_MS
+        .iter()
+        .position(|b| *b == 100)
+        .unwrap();
+
+    let quick = &processing[&quick];
+    assert_eq!(quick.count, 3);
+    assert_eq!(quick.buckets.iter().sum::<u64>(), 3);
+    assert_eq!(quick.buckets[..hundred_ms].iter().sum::<u64>(), 3);
+
+    let slow = &processing[&slow];
+    assert_eq!(slow.count, 2);
+    assert!(slow.sum_ms >= 120);
+    assert_eq!(slow.buckets[hundred_ms..].iter().sum::<u64>(), 2);
+
+    // Neither has run enough jobs for a median
+    assert_eq!(quick.median_ms, None);
+    assert_eq!(slow.median_ms, None);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_run_warns_on_slow_jobs(db: PgPool) {
+    let logs = CapturedLogs::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(logs.clone())
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+
+    // Enough jobs of one function to give it a median, and then one that takes far longer than it
+    let function_id = Uuid::now_v7();
+    let inits: Vec<_> = (0..11)
+        .map(|_| {
+            let mut init = create_new_job();
+            init.function_id = Some(function_id);
+            init
+        })
+        .collect();
+    let queue_name = inits[0].queue_name.clone();
+    let jobs = manager.bulk_create_jobs(inits).await.unwrap();
+    let slow = jobs[10].id;
+
+    // One at a time, so the slow job runs last
+    let config = RunConfig {
+        concurrency: 1,
+        poll_interval: Duration::milliseconds(10),
+        slow_job_factor: Some(5.0),
+        ..Default::default()
+    };
+    let calls = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(Notify::new());
+
+    let handler = |job: cyclotron_core::Job, _| {
+        let calls = calls.clone();
+        let done = done.clone();
+        async move {
+            let ms = if job.id == slow { 300 } else { 10 };
+            tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
+            if calls.fetch_add(1, Ordering::SeqCst) + 1 == 11 {
+                done.notify_one();
+            }
+            Ok::<_, &str>(None)
+        }
+    };
+
+    tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        worker.run(&queue_name, config, done.notified(), handler),
+    )
+    .await
+    .expect("run loop timed out")
+    .expect("run loop failed");
+
+    // Only the slow job is called out
+    let logs = logs.contents();
+    assert_eq!(logs.matches("the function's median").count(), 1);
+    assert!(logs.contains(&format!("Job {} of function {}", slow, function_id)));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_run_keeps_timings_for_recent_functions(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+    worker.max_timed_functions = 2;
+
+    // Three jobs, each of a different function, run one at a time
+    let inits: Vec<_> = (0..3).map(|_| create_new_job()).collect();
+    let functions: Vec<_> = inits.iter().map(|i| i.function_id.unwrap()).collect();
+    let queue_name = inits[0].queue_name.clone();
+    manager.bulk_create_jobs(inits).await.unwrap();
+
+    let config = RunConfig {
+        concurrency: 1,
+        poll_interval: Duration::milliseconds(10),
+        ..Default::default()
+    };
+    let calls = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(Notify::new());
+
+    let handler = |_, _| {
+        let calls = calls.clone();
+        let done = done.clone();
+        async move {
+            if calls.fetch_add(1, Ordering::SeqCst) + 1 == 3 {
+                done.notify_one();
+            }
+            Ok::<_, &str>(None)
+        }
+    };
+
+    tokio::time::timeout(
+        std::time::Duration::from_secs(30),
+        worker.run(&queue_name, config, done.notified(), handler),
+    )
+    .await
+    .expect("run loop timed out")
+    .expect("run loop failed");
+
+    // The first function was dropped to make room for the third
+    let processing = worker.metrics_snapshot().processing_by_function;
+    assert_eq!(processing.len(), 2);
+    assert!(!processing.contains_key(&functions[0]));
+    assert_eq!(processing[&functions[1]].count, 1);
+    assert_eq!(processing[&functions[2]].count, 1);
+}
@@ -0,0 +1,117 @@
+use chrono::{Duration, Utc};
+use common::create_new_job;
+use cyclotron_core::{JobQuery, QueueError, QueueManager, ScheduleAheadPolicy, Worker};
+use sqlx::PgPool;
+
+mod common;
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_jobs_scheduled_too_far_ahead_are_rejected(db: PgPool) {
+    let mut manager = QueueManager::from_pool(db.clone());
+    manager.max_schedule_ahead = Some(Duration::days(30));
+
+    let mut init = create_new_job();
+    let requested = Utc::now() + Duration::days(365 * 1000);
+    init.scheduled = requested;
+    let res = manager.create_job(init.clone()).await;
+    let Err(QueueError::ScheduledTooFarAhead(scheduled, latest)) = res else {
+        panic!("expected ScheduledTooFarAhead, got {:?}", res);
+    };
+    assert_eq!(scheduled, requested);
+    assert!(latest <= Utc::now() + Duration::days(30));
+
+    // Batches are rejected as a whole
+    let res = manager.bulk_create_jobs(vec![create_new_job(), init]).await;
+    assert!(matches!(res, Err(QueueError::ScheduledTooFarAhead(..))));
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM cyclotron_jobs")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(count, 0);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_jobs_scheduled_too_far_ahead_can_be_clamped(db: PgPool) {
+    let mut manager = QueueManager::from_pool(db);
+    manager.max_schedule_ahead = Some(Duration::days(30));
+    manager.schedule_ahead_policy = ScheduleAheadPolicy::Clamp;
+
+    let mut init = create_new_job();
+    init.scheduled = Utc::now() + Duration::days(365 * 1000);
+    let before = Utc::now();
+    let job = manager.create_job(init).await.unwrap();
+    let after = Utc::now();
+
+    // Postgres truncates to microseconds, so allow for that on the lower bound
+    assert!(job.scheduled >= before + Duration::days(30) - Duration::microseconds(1));
+    assert!(job.scheduled <= after + Duration::days(30));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_jobs_scheduled_within_the_window_are_unchanged(db: PgPool) {
+    let mut manager = QueueManager::from_pool(db);
+    manager.max_schedule_ahead = Some(Duration::days(30));
+
+    for policy in [ScheduleAheadPolicy::Reject, ScheduleAheadPolicy::Clamp] {
+        manager.schedule_ahead_policy = policy;
+        for ahead in [Duration::zero(), Duration::days(29), -Duration::days(1)] {
+            let mut init = create_new_job();
+            init.scheduled = Utc::now() + ahead;
+            let expected = init.scheduled;
+            let job = manager.create_job(init).await.unwrap();
+            assert!((job.scheduled - expected).abs() < Duration::milliseconds(1));
+        }
+    }
+
+    // And with no limit set, anything goes
+    manager.max_schedule_ahead = None;
+    let mut init = create_new_job();
+    init.scheduled = Utc::now() + Duration::days(365 * 1000);
+    manager.create_job(init).await.unwrap();
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_jitter_schedule_spreads_due_jobs(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let worker = Worker::from_pool(db.clone(), Default::default());
+
+    let due = Utc::now() - Duration::minutes(5);
+    let mut init = create_new_job();
+    init.scheduled = due;
+    let jobs: Vec<_> = (0..1000).map(|_| init.clone()).collect();
+    manager.bulk_create_jobs(jobs).await.unwrap();
+    // A running job isn't rescheduled
+    let running = worker.dequeue_jobs(&init.queue_name, 1).await.unwrap();
+
+    let before = Utc::now();
+    let spread = Duration::minutes(10);
+    let query = JobQuery {
+        queue_name: Some(init.queue_name.clone()),
+        ..Default::default()
+    };
+    let rescheduled = manager.jitter_schedule(&query, spread).await.unwrap();
+    let after = Utc::now();
+    assert_eq!(rescheduled, 999);
+
+    let jobs = manager.query_jobs(&query.unlimited()).await.unwrap();
+    let (mut available, held): (Vec<_>, Vec<_>) =
+        jobs.into_iter().partition(|j| j.id != running[0].id);
+    assert!(held[0].scheduled < before);
+    available.sort_by_key(|j| j.scheduled);
+    // Microsecond truncation can put a job a hair before the start of the call
+    let earliest = before - Duration::milliseconds(1);
+    assert!(available.iter().all(|j| j.scheduled >= earliest));
+    assert!(available.iter().all(|j| j.scheduled <= after + spread));
+
+    // Spread across the window, rather than bunched up - every tenth of it has some of the jobs in it
+    let bucket = spread / 10;
+    for i in 0..10 {
+        let start = before + bucket * i;
+        let end = after + bucket * (i + 1);
+        let in_bucket = available
+            .iter()
+            .filter(|j| j.scheduled >= start && j.scheduled < end)
+            .count();
+        assert!(in_bucket > 50, "only {} jobs in bucket {}", in_bucket, i);
+    }
+}
@@ -0,0 +1,32 @@
+use cyclotron_core::{check_compatibility, QueueError, SCHEMA_VERSION};
+use sqlx::PgPool;
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_check_compatibility(db: PgPool) {
+    // Freshly migrated, the schema is exactly what the code expects
+    check_compatibility(&db).await.unwrap();
+
+    for version in [SCHEMA_VERSION + 1, SCHEMA_VERSION - 1] {
+        sqlx::query("UPDATE cyclotron_meta SET schema_version = $1")
+            .bind(version)
+            .execute(&db)
+            .await
+            .unwrap();
+        let res = check_compatibility(&db).await;
+        assert!(matches!(
+            res,
+            Err(QueueError::IncompatibleSchema(found, expected))
+                if found == version && expected == SCHEMA_VERSION
+        ));
+    }
+
+    // A database from before the schema was versioned is version 0
+    sqlx::query("DROP TABLE cyclotron_meta")
+        .execute(&db)
+        .await
+        .unwrap();
+    assert!(matches!(
+        check_compatibility(&db).await,
+        Err(QueueError::IncompatibleSchema(0, SCHEMA_VERSION))
+    ));
+}
@@ -1,7 +1,7 @@
 use chrono::{Duration, Utc};
 use common::create_new_job;
-use cyclotron_core::test_support::Shard;
-use sqlx::PgPool;
+use cyclotron_core::{test_support::Shard, AcquireRetry, QueueError, QueueManager, Worker};
+use sqlx::{postgres::PgPoolOptions, PgPool};
 use tokio::sync::RwLock;
 
 mod common;
@@ -12,9 +12,20 @@ pub fn get_shard(db: PgPool) -> Shard {
         last_healthy: RwLock::new(Utc::now()),
         check_interval: Duration::milliseconds(0), // We always want to check the limit, for these tests
         depth_limit: 10,
+        acquire_retry: AcquireRetry::default(),
     }
 }
 
+// A single connection pool that gives up quickly when asked for a connection, for testing retries on acquisition
+async fn single_connection_pool(db: &PgPool) -> PgPool {
+    PgPoolOptions::new()
+        .max_connections(1)
+        .acquire_timeout(std::time::Duration::from_millis(200))
+        .connect_with((*db.connect_options()).clone())
+        .await
+        .unwrap()
+}
+
 #[sqlx::test(migrations = "./migrations")]
 pub async fn test_shard_limiting(db: PgPool) {
     let shard = get_shard(db.clone());
@@ -66,3 +77,73 @@ pub async fn test_shard_allows_bulk_inserts_beyond_capacity(db: PgPool) {
     let result = shard.create_job(create_new_job()).await;
     assert!(result.is_err());
 }
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_shard_retries_acquiring_a_connection(db: PgPool) {
+    let pool = single_connection_pool(&db).await;
+    let shard = get_shard(pool.clone());
+
+    // Someone else holds the only connection for longer than the pool will wait for it
+    let held = pool.acquire().await.unwrap();
+    let holder = tokio::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        drop(held);
+    });
+
+    // The first attempt times out, but a retry gets the connection once it's given back
+    shard.create_job(create_new_job()).await.unwrap();
+    holder.await.unwrap();
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_shard_gives_up_acquiring_without_retries(db: PgPool) {
+    let pool = single_connection_pool(&db).await;
+    let mut shard = get_shard(pool.clone());
+    shard.acquire_retry.attempts = 1;
+
+    let _held = pool.acquire().await.unwrap();
+
+    let result = shard.create_job(create_new_job()).await;
+    assert!(matches!(
+        result,
+        Err(QueueError::SqlxError(sqlx::Error::PoolTimedOut))
+    ));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_shard_stops_retrying_after_max_wait(db: PgPool) {
+    let pool = single_connection_pool(&db).await;
+    let mut shard = get_shard(pool.clone());
+    shard.acquire_retry.attempts = 100;
+    shard.acquire_retry.max_wait = Duration::milliseconds(500);
+
+    let _held = pool.acquire().await.unwrap();
+
+    // Every attempt waits out the pool's timeout, which counts towards the cap, so this gives up after a few
+    let started = std::time::Instant::now();
+    let result = shard.create_job(create_new_job()).await;
+    assert!(matches!(
+        result,
+        Err(QueueError::SqlxError(sqlx::Error::PoolTimedOut))
+    ));
+    assert!(started.elapsed() < std::time::Duration::from_secs(2));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_worker_retries_acquiring_a_connection(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    manager.create_job(create_new_job()).await.unwrap();
+    let pool = single_connection_pool(&db).await;
+    let worker = Worker::from_pool(pool.clone(), Default::default());
+
+    let held = pool.acquire().await.unwrap();
+    let holder = tokio::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        drop(held);
+    });
+
+    // As with the shard, the dequeue rides out the pool timing out
+    let jobs = worker.dequeue_jobs("test", 1).await.unwrap();
+    assert_eq!(jobs.len(), 1);
+    holder.await.unwrap();
+}
@@ -0,0 +1,222 @@
+use chrono::{Duration, Utc};
+use common::create_new_job;
+use cyclotron_core::{
+    Job, JobError, JobInit, JobState, JobStore, JobUpdate, MemoryStore, QueueError, QueueManager,
+};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+mod common;
+
+// Every scenario here runs against both postgres and the in-memory store, recording what it sees in a form
+// that doesn't depend on ids or exact timestamps. The two recordings have to match.
+
+fn labelled(label: &str) -> JobInit {
+    let mut init = create_new_job();
+    init.parameters = Some(label.as_bytes().to_vec());
+    init
+}
+
+fn label(job: &Job) -> String {
+    String::from_utf8(job.parameters.clone().unwrap_or_default()).unwrap()
+}
+
+fn observe(job: &Job) -> String {
+    // Postgres and serde format JSON differently, so metadata is compared parsed
+    let metadata = job
+        .metadata
+        .as_deref()
+        .map(|m| serde_json::from_slice::<Value>(m).unwrap());
+    format!(
+        "{} {:?} queue={} priority={} transitions={} touches={} locked={} vm_state={:?} metadata={:?}",
+        label(job),
+        job.state,
+        job.queue_name,
+        job.priority,
+        job.transition_count,
+        job.janitor_touch_count,
+        job.lock_id.is_some(),
+        job.vm_state,
+        metadata
+    )
+}
+
+fn outcome<T>(res: &Result<T, QueueError>) -> String {
+    match res {
+        Ok(_) => "ok".to_string(),
+        Err(QueueError::JobError(JobError::InvalidLock(..))) => "invalid lock".to_string(),
+        Err(QueueError::DuplicateJobId(_)) => "duplicate id".to_string(),
+        Err(QueueError::ShardFull(limit)) => format!("shard full at {}", limit),
+        Err(e) => panic!("unexpected error: {}", e),
+    }
+}
+
+async fn get(store: &dyn JobStore, id: Uuid) -> Job {
+    store.get_job(id).await.unwrap().expect("job exists")
+}
+
+async fn dequeue_ordering(store: &dyn JobStore) -> Vec<String> {
+    let mut seen = vec![];
+
+    let mut low = labelled("low");
+    low.priority = 1;
+    let mut early = labelled("early");
+    early.priority = 1;
+    early.scheduled = Utc::now() - Duration::minutes(2);
+    let high = labelled("high");
+    let mut later = labelled("later");
+    later.scheduled = Utc::now() + Duration::hours(1);
+    let mut elsewhere = labelled("elsewhere");
+    elsewhere.queue_name = "other".to_string();
+    elsewhere.vm_state = Some(b"vm".to_vec());
+
+    for init in [low, early, high, later, elsewhere] {
+        let job = store.create_job(init).await.unwrap();
+        seen.push(observe(&job));
+    }
+
+    // Priority first, then schedule, and nothing that isn't due yet or is in another queue
+    let dequeued = store.dequeue_jobs("test", 10).await.unwrap();
+    let order: Vec<_> = dequeued.iter().map(label).collect();
+    assert_eq!(order, ["high", "early", "low"]);
+    // The whole batch is held under one lock
+    assert!(dequeued.iter().all(|j| j.lock_id == dequeued[0].lock_id));
+    seen.extend(dequeued.iter().map(observe));
+
+    // Locked jobs aren't handed out again
+    seen.push(format!(
+        "{}",
+        store.dequeue_jobs("test", 10).await.unwrap().len()
+    ));
+
+    // Dequeues never carry the VM state
+    let other = store.dequeue_jobs("other", 10).await.unwrap();
+    assert_eq!(other[0].vm_state, None);
+    seen.extend(other.iter().map(observe));
+
+    seen
+}
+
+async fn locks_and_updates(store: &dyn JobStore) -> Vec<String> {
+    let mut seen = vec![];
+
+    let mut init = labelled("job");
+    init.id = Some(Uuid::now_v7());
+    init.metadata = Some(serde_json::to_vec(&json!({"kept": 1})).unwrap());
+    let created = store.create_job(init.clone()).await;
+    seen.push(outcome(&created));
+    let duplicate = store.create_job(init).await;
+    seen.push(outcome(&duplicate));
+    let id = created.unwrap().id;
+
+    let job = store.dequeue_jobs("test", 1).await.unwrap().pop().unwrap();
+    let lock_id = job.lock_id.unwrap();
+
+    // Only the lock holder can update the job
+    let mut wrong = JobUpdate::new(Uuid::now_v7());
+    wrong.state = Some(JobState::Completed);
+    seen.push(outcome(&store.flush_job(id, wrong).await));
+
+    // Keeping it running keeps the lock, and isn't a transition
+    let mut update = JobUpdate::new(lock_id);
+    update.state = Some(JobState::Running);
+    update.priority = Some(5);
+    update.metadata_patch = Some(json!({"added": 2}));
+    seen.push(outcome(&store.flush_job(id, update).await));
+    seen.push(observe(&get(store, id).await));
+    seen.push(outcome(&store.heartbeat(id, lock_id).await));
+
+    // As does a touch-only update
+    let touch = JobUpdate::touch_only(lock_id);
+    seen.push(outcome(&store.flush_job(id, touch).await));
+    seen.push(observe(&get(store, id).await));
+
+    // Anything else releases it
+    let mut update = JobUpdate::new(lock_id);
+    update.state = Some(JobState::Available);
+    update.queue_name = Some("moved".to_string());
+    seen.push(outcome(&store.flush_job(id, update).await));
+    seen.push(observe(&get(store, id).await));
+    seen.push(outcome(&store.flush_job(id, JobUpdate::new(lock_id)).await));
+    seen.push(outcome(&store.heartbeat(id, lock_id).await));
+
+    // Dequeued again, it's under a new lock
+    let job = store.dequeue_jobs("moved", 1).await.unwrap().pop().unwrap();
+    assert_ne!(job.lock_id, Some(lock_id));
+    let mut update = JobUpdate::new(job.lock_id.unwrap());
+    update.state = Some(JobState::Completed);
+    update.parameters = Some(Some(b"done".to_vec()));
+    seen.push(outcome(&store.flush_job(id, update).await));
+    seen.push(observe(&get(store, id).await));
+
+    seen
+}
+
+async fn janitor_cleanup(store: &dyn JobStore) -> Vec<String> {
+    let mut seen = vec![];
+
+    let retried = store.create_job(labelled("retried")).await.unwrap().id;
+    let mut once = labelled("once");
+    once.at_most_once = true;
+    let once = store.create_job(once).await.unwrap().id;
+    let done = store.create_job(labelled("done")).await.unwrap().id;
+    let waiting = store.create_job(labelled("waiting")).await.unwrap().id;
+
+    let dequeued = store.dequeue_jobs("test", 3).await.unwrap();
+    let done_job = dequeued.iter().find(|j| j.id == done).unwrap();
+    let mut update = JobUpdate::new(done_job.lock_id.unwrap());
+    update.state = Some(JobState::Completed);
+    store.flush_job(done, update).await.unwrap();
+
+    // With a long timeout, nothing has stalled yet - with no timeout at all, every running job has
+    let reset = store.reset_stalled_jobs(Duration::hours(1)).await.unwrap();
+    seen.push(format!("reset {}", reset));
+    let reset = store.reset_stalled_jobs(Duration::zero()).await.unwrap();
+    seen.push(format!("reset {}", reset));
+    for id in [retried, once, done, waiting] {
+        seen.push(observe(&get(store, id).await));
+    }
+
+    let deleted = store.delete_completed_and_failed_jobs().await.unwrap();
+    seen.push(format!("deleted {}", deleted));
+    for id in [retried, once, done, waiting] {
+        seen.push(format!("{}", store.get_job(id).await.unwrap().is_some()));
+    }
+
+    seen
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_stores_agree_on_dequeue_ordering(db: PgPool) {
+    let postgres = dequeue_ordering(&QueueManager::from_pool(db)).await;
+    let memory = dequeue_ordering(&MemoryStore::default()).await;
+    assert_eq!(postgres, memory);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_stores_agree_on_locks_and_updates(db: PgPool) {
+    let postgres = locks_and_updates(&QueueManager::from_pool(db)).await;
+    let memory = locks_and_updates(&MemoryStore::default()).await;
+    assert_eq!(postgres, memory);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_stores_agree_on_janitor_cleanup(db: PgPool) {
+    let postgres = janitor_cleanup(&QueueManager::from_pool(db)).await;
+    let memory = janitor_cleanup(&MemoryStore::default()).await;
+    assert_eq!(postgres, memory);
+}
+
+#[tokio::test]
+pub async fn test_memory_store_is_bounded() {
+    let store = MemoryStore::new(2);
+    store.create_job(create_new_job()).await.unwrap();
+    store.create_job(create_new_job()).await.unwrap();
+    let res = store.create_job(create_new_job()).await;
+    assert_eq!(outcome(&res), "shard full at 2");
+
+    // Running jobs don't count towards the limit
+    store.dequeue_jobs("test", 1).await.unwrap();
+    store.create_job(create_new_job()).await.unwrap();
+}
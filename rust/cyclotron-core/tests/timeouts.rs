@@ -0,0 +1,69 @@
+use std::time::{Duration, Instant};
+
+use common::create_new_job;
+use cyclotron_core::{QueueError, QueueManager, Worker};
+use sqlx::{postgres::PgPoolOptions, PgPool};
+
+mod common;
+
+// A pool onto the test database, with the statement timeout a `PoolConfig` with `statement_timeout_ms` set would give
+async fn pool_with_timeout(db: &PgPool, timeout_ms: u64) -> PgPool {
+    let options = db
+        .connect_options()
+        .as_ref()
+        .clone()
+        .options([("statement_timeout", timeout_ms.to_string())]);
+    PgPoolOptions::new().connect_with(options).await.unwrap()
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_slow_statements_are_aborted(db: PgPool) {
+    let pool = pool_with_timeout(&db, 200).await;
+
+    let start = Instant::now();
+    let err = sqlx::query("SELECT pg_sleep(10)")
+        .execute(&pool)
+        .await
+        .unwrap_err();
+    let elapsed = start.elapsed();
+    assert!(matches!(
+        QueueError::from(err),
+        QueueError::StatementTimeout
+    ));
+    assert!(elapsed >= Duration::from_millis(200));
+    assert!(elapsed < Duration::from_secs(5));
+
+    // Other errors aren't mistaken for timeouts
+    let err = sqlx::query("SELECT * FROM no_such_table")
+        .execute(&pool)
+        .await
+        .unwrap_err();
+    assert!(matches!(QueueError::from(err), QueueError::SqlxError(_)));
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_stuck_operations_fail_with_a_timeout(db: PgPool) {
+    let pool = pool_with_timeout(&db, 200).await;
+    let manager = QueueManager::from_pool(pool.clone());
+    let worker = Worker::from_pool(pool.clone(), Default::default());
+    manager.create_job(create_new_job()).await.unwrap();
+
+    // Something else holding the table leaves every operation on it waiting
+    let mut blocker = db.begin().await.unwrap();
+    sqlx::query("LOCK TABLE cyclotron_jobs IN ACCESS EXCLUSIVE MODE")
+        .execute(&mut *blocker)
+        .await
+        .unwrap();
+
+    let start = Instant::now();
+    let res = worker.dequeue_jobs("test", 1).await;
+    assert!(matches!(res, Err(QueueError::StatementTimeout)));
+    let res = manager.create_job(create_new_job()).await;
+    assert!(matches!(res, Err(QueueError::StatementTimeout)));
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    // Once it lets go, the same pool works again
+    blocker.rollback().await.unwrap();
+    let jobs = worker.dequeue_jobs("test", 1).await.unwrap();
+    assert_eq!(jobs.len(), 1);
+}
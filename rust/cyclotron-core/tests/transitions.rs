@@ -0,0 +1,92 @@
+use chrono::{DateTime, Duration, Utc};
+use common::create_new_job;
+use cyclotron_core::{Janitor, JobState, QueueManager, Worker};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+mod common;
+
+async fn transitions(manager: &QueueManager, id: Uuid) -> (i16, DateTime<Utc>) {
+    let job = manager.get_job(id).await.unwrap().unwrap();
+    (job.transition_count, job.last_transition)
+}
+
+// Asserts the op bumped the job's transition count by exactly one, and stamped its last transition time
+async fn assert_one_transition(
+    manager: &QueueManager,
+    id: Uuid,
+    before: (i16, DateTime<Utc>),
+    op: &str,
+) -> (i16, DateTime<Utc>) {
+    let after = transitions(manager, id).await;
+    assert_eq!(after.0, before.0 + 1, "{} should be one transition", op);
+    assert!(after.1 > before.1, "{} should stamp last_transition", op);
+    after
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_every_state_change_is_one_transition(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+    let janitor = Janitor::from_pool(db.clone());
+
+    let id = manager.create_job(create_new_job()).await.unwrap().id;
+    let mut state = transitions(&manager, id).await;
+    assert_eq!(state.0, 0);
+
+    // Returning a job to the queue, then pausing it, then completing it, each via a release
+    for (op, next) in [
+        ("requeue", JobState::Available),
+        ("pause", JobState::Paused),
+        ("complete", JobState::Completed),
+    ] {
+        if op == "complete" {
+            // Paused jobs aren't dequeued, so make it available again behind the worker's back
+            sqlx::query("UPDATE cyclotron_jobs SET state = 'available' WHERE id = $1")
+                .bind(id)
+                .execute(&db)
+                .await
+                .unwrap();
+        }
+        worker.dequeue_jobs("test", 1).await.unwrap();
+        state = assert_one_transition(&manager, id, state, "dequeue").await;
+
+        // Heartbeating isn't a transition
+        worker.heartbeat(id).await.unwrap();
+        assert_eq!(transitions(&manager, id).await.0, state.0);
+
+        worker.set_state(id, next).unwrap();
+        worker.release_job(id, None).await.unwrap();
+        state = assert_one_transition(&manager, id, state, op).await;
+    }
+
+    // Failing is the same
+    let id = manager.create_job(create_new_job()).await.unwrap().id;
+    worker.dequeue_jobs("test", 1).await.unwrap();
+    let state = transitions(&manager, id).await;
+    worker.fail_job(id, "failed").await.unwrap();
+    worker.release_job(id, None).await.unwrap();
+    assert_one_transition(&manager, id, state, "fail").await;
+
+    // As is the janitor resetting a stalled job
+    let id = manager.create_job(create_new_job()).await.unwrap().id;
+    worker.dequeue_jobs("test", 1).await.unwrap();
+    let state = transitions(&manager, id).await;
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    janitor
+        .reset_stalled_jobs(Duration::milliseconds(1))
+        .await
+        .unwrap();
+    assert_one_transition(&manager, id, state, "reset").await;
+
+    // And dead lettering. The reset job is available again, so this one goes in a queue of its own
+    let mut init = create_new_job();
+    init.queue_name = "dead_letter_test".to_string();
+    let id = manager.create_job(init).await.unwrap().id;
+    let jobs = worker.dequeue_jobs("dead_letter_test", 1).await.unwrap();
+    assert_eq!(jobs[0].id, id);
+    let state = transitions(&manager, id).await;
+    worker.dead_letter(id, "dead").await.unwrap();
+    assert_one_transition(&manager, id, state, "dead letter").await;
+}
@@ -0,0 +1,1002 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Utc};
+use common::create_new_job;
+use cyclotron_core::{
+    AggregatedDelete, CodecError, DeleteSet, Enqueued, EpochMillis, Job, JobError, JobInit,
+    JobQuery, JobState, JobStateCode, JobUpdate, QueueError, QueueManager, RetryMode, RetryPolicy,
+    RunConfig, StateSeverity, UniqueBatch, UnknownJobState,
+};
+use serde_json::json;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+mod common;
+
+const ALL_STATES: [JobState; 5] = [
+    JobState::Available,
+    JobState::Running,
+    JobState::Completed,
+    JobState::Failed,
+    JobState::Paused,
+];
+
+// Never called, just fails to compile if a variant is added to JobState without being added to ALL_STATES
+#[allow(dead_code)]
+fn all_states_is_exhaustive(state: JobState) {
+    match state {
+        JobState::Available
+        | JobState::Running
+        | JobState::Completed
+        | JobState::Failed
+        | JobState::Paused => {}
+    }
+}
+
+#[test]
+pub fn test_job_init_scheduled_is_kept_when_present() {
+    let scheduled: DateTime<Utc> = "2024-08-01T12:00:00Z".parse().unwrap();
+    let init: JobInit = serde_json::from_value(json!({
+        "team_id": 1,
+        "queue_name": "test",
+        "priority": 0,
+        "scheduled": scheduled,
+        "function_id": null,
+        "vm_state": null,
+        "parameters": null,
+        "blob": null,
+        "metadata": null,
+    }))
+    .expect("failed to deserialize job init");
+
+    assert_eq!(init.scheduled, scheduled);
+}
+
+#[test]
+pub fn test_job_init_scheduled_defaults_to_now_when_absent() {
+    let before = Utc::now();
+    let init: JobInit = serde_json::from_value(json!({
+        "team_id": 1,
+        "queue_name": "test",
+        "priority": 0,
+        "function_id": null,
+        "vm_state": null,
+        "parameters": null,
+        "blob": null,
+        "metadata": null,
+    }))
+    .expect("failed to deserialize job init");
+    let after = Utc::now();
+
+    assert!(init.scheduled >= before && init.scheduled <= after);
+}
+
+#[test]
+pub fn test_job_init_accepts_snake_and_camel_case() {
+    let scheduled: DateTime<Utc> = "2024-08-01T12:00:00Z".parse().unwrap();
+    let function_id = "0191a5c4-7e5b-7d6e-8d5c-1d9f2f2b5a01";
+
+    let snake: JobInit = serde_json::from_value(json!({
+        "team_id": 1,
+        "queue_name": "test",
+        "priority": 2,
+        "scheduled": scheduled,
+        "function_id": function_id,
+        "vm_state": [1, 2, 3],
+        "parameters": [4],
+        "blob": null,
+        "metadata": [5],
+        "at_most_once": true,
+    }))
+    .expect("failed to deserialize snake_case job init");
+
+    let camel: JobInit = serde_json::from_value(json!({
+        "teamId": 1,
+        "queueName": "test",
+        "priority": 2,
+        "scheduled": scheduled,
+        "functionId": function_id,
+        "vmState": [1, 2, 3],
+        "parameters": [4],
+        "blob": null,
+        "metadata": [5],
+        "atMostOnce": true,
+    }))
+    .expect("failed to deserialize camelCase job init");
+
+    assert_eq!(snake, camel);
+    assert_eq!(camel.queue_name, "test");
+    assert_eq!(camel.function_id, Some(function_id.parse().unwrap()));
+    assert_eq!(camel.vm_state, Some(vec![1, 2, 3]));
+    assert!(camel.at_most_once);
+
+    // Serializing always uses snake_case
+    let serialized = serde_json::to_value(&camel).unwrap();
+    assert_eq!(serialized["queue_name"], "test");
+    assert!(serialized.get("queueName").is_none());
+}
+
+#[test]
+pub fn test_job_init_default() {
+    let before = Utc::now();
+    let init = JobInit {
+        team_id: 2,
+        queue_name: "test".to_string(),
+        ..Default::default()
+    };
+    let after = Utc::now();
+
+    assert_eq!(init.team_id, 2);
+    assert_eq!(init.queue_name, "test");
+    assert_eq!(init.priority, 0);
+    assert!(init.scheduled >= before && init.scheduled <= after);
+    assert_eq!(init.id, None);
+    assert_eq!(init.function_id, None);
+    assert_eq!(init.vm_state, None);
+    assert_eq!(init.parameters, None);
+    assert_eq!(init.blob, None);
+    assert_eq!(init.metadata, None);
+    assert!(!init.at_most_once);
+    assert_eq!(init.correlation_id, None);
+    assert_eq!(init.parent_job_id, None);
+    assert_eq!(init.reply_to, None);
+}
+
+#[cfg(feature = "schema")]
+#[test]
+pub fn test_job_init_json_schema() {
+    let schema = serde_json::to_value(JobInit::json_schema()).unwrap();
+    let properties = schema["properties"].as_object().unwrap();
+
+    // Every field is described, and no others
+    let mut fields: Vec<_> = properties.keys().map(String::as_str).collect();
+    fields.sort_unstable();
+    assert_eq!(
+        fields,
+        [
+            "at_most_once",
+            "blob",
+            "correlation_id",
+            "function_id",
+            "id",
+            "metadata",
+            "parameters",
+            "parent_job_id",
+            "priority",
+            "queue_name",
+            "reply_to",
+            "scheduled",
+            "team_id",
+            "vm_state",
+        ]
+    );
+
+    // Types can be a single name, or a list of them for nullable fields
+    let types = |field: &str| -> Vec<String> {
+        match &properties[field]["type"] {
+            serde_json::Value::String(t) => vec![t.clone()],
+            serde_json::Value::Array(ts) => {
+                ts.iter().map(|t| t.as_str().unwrap().to_string()).collect()
+            }
+            other => panic!("unexpected type for {}: {}", field, other),
+        }
+    };
+
+    assert_eq!(types("team_id"), ["integer"]);
+    assert_eq!(types("queue_name"), ["string"]);
+    assert_eq!(types("reply_to"), ["string", "null"]);
+    assert_eq!(types("priority"), ["integer"]);
+    assert_eq!(types("at_most_once"), ["boolean"]);
+    assert_eq!(properties["scheduled"]["format"], "date-time");
+    for field in ["id", "function_id", "correlation_id", "parent_job_id"] {
+        assert_eq!(types(field), ["string", "null"]);
+        assert_eq!(properties[field]["format"], "uuid");
+    }
+    for field in ["vm_state", "parameters", "blob", "metadata"] {
+        assert_eq!(types(field), ["array", "null"]);
+        assert_eq!(properties[field]["items"]["type"], "integer");
+        assert!(properties[field]["description"]
+            .as_str()
+            .unwrap()
+            .contains("array of integers"));
+    }
+
+    // Only the fields without a default are required
+    let mut required: Vec<_> = schema["required"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|f| f.as_str().unwrap())
+        .collect();
+    required.sort_unstable();
+    assert_eq!(required, ["priority", "queue_name", "team_id"]);
+}
+
+#[test]
+pub fn test_compact_codec_round_trip() {
+    let mut init = create_new_job();
+    init.vm_state = Some(vec![1, 2, 3]);
+    init.parameters = Some(vec![0; 64]);
+    init.at_most_once = true;
+
+    let bytes = init.to_bytes().unwrap();
+    assert_eq!(JobInit::from_bytes(&bytes).unwrap(), init);
+    // The whole point - binary payloads don't get inflated the way they do in JSON
+    assert!(bytes.len() < serde_json::to_vec(&init).unwrap().len());
+
+    let job = running_job();
+    let decoded = Job::from_bytes(&job.to_bytes().unwrap()).unwrap();
+    assert_eq!(decoded.id, job.id);
+    assert_eq!(decoded.lock_id, job.lock_id);
+    assert_eq!(decoded.state, job.state);
+    assert_eq!(decoded.scheduled, job.scheduled);
+}
+
+#[test]
+pub fn test_compact_codec_rejects_unknown_versions() {
+    let mut bytes = create_new_job().to_bytes().unwrap();
+    bytes[3] = 99; // The version byte, right after the magic
+    assert!(matches!(
+        JobInit::from_bytes(&bytes),
+        Err(CodecError::UnsupportedVersion(99))
+    ));
+
+    // And things that aren't compact encoded jobs at all are rejected up front
+    let json = serde_json::to_vec(&create_new_job()).unwrap();
+    assert!(matches!(
+        JobInit::from_bytes(&json),
+        Err(CodecError::MissingMagic)
+    ));
+    assert!(matches!(
+        JobInit::from_bytes(b"cyc"),
+        Err(CodecError::MissingMagic)
+    ));
+}
+
+#[test]
+pub fn test_job_state_string_round_trip() {
+    for state in ALL_STATES {
+        let serialized = serde_json::to_value(state).unwrap();
+        let name = serialized.as_str().expect("states serialize as strings");
+        assert_eq!(JobState::from_str(name), Ok(state));
+        assert_eq!(
+            serde_json::from_value::<JobState>(serialized).unwrap(),
+            state
+        );
+    }
+}
+
+#[test]
+pub fn test_job_state_code_round_trip() {
+    // The codes are frozen - if this fails, a state's code has changed, which breaks anyone storing them
+    let frozen = [
+        (JobState::Available, 0),
+        (JobState::Running, 1),
+        (JobState::Completed, 2),
+        (JobState::Failed, 3),
+        (JobState::Paused, 4),
+    ];
+    assert_eq!(frozen.len(), ALL_STATES.len());
+
+    for (state, code) in frozen {
+        assert_eq!(state.code(), code);
+        assert_eq!(JobState::from_code(code), Some(state));
+
+        // Through the integer representation
+        let serialized = serde_json::to_value(JobStateCode(state)).unwrap();
+        assert_eq!(serialized, json!(code));
+        let decoded: JobStateCode = serde_json::from_value(serialized).unwrap();
+        assert_eq!(JobState::from(decoded), state);
+
+        // The default is still the name, and the two convert into each other
+        let serialized = serde_json::to_value(state).unwrap();
+        assert_eq!(serialized, json!(state.as_str()));
+        let by_name: JobState = serde_json::from_value(serialized).unwrap();
+        assert_eq!(JobStateCode::from(by_name), JobStateCode(state));
+    }
+
+    assert_eq!(JobState::from_code(5), None);
+    assert!(serde_json::from_value::<JobStateCode>(json!(5)).is_err());
+    assert!(serde_json::from_value::<JobStateCode>(json!("available")).is_err());
+
+    // Usable anywhere a state is, e.g. in a consumer's own types
+    let codes: Vec<JobStateCode> = serde_json::from_value(json!([4, 0, 2])).unwrap();
+    assert_eq!(
+        codes,
+        [
+            JobStateCode(JobState::Paused),
+            JobStateCode(JobState::Available),
+            JobStateCode(JobState::Completed),
+        ]
+    );
+}
+
+#[test]
+pub fn test_job_state_metadata() {
+    let all: Vec<_> = JobState::all().collect();
+    assert_eq!(all, ALL_STATES);
+
+    for state in JobState::all() {
+        let metadata = state.metadata();
+        assert_eq!(metadata.state, state);
+        assert!(!metadata.display_name.is_empty());
+        // Nothing is both finished and still going
+        assert!(!(metadata.is_terminal && metadata.is_active));
+        // Only failures are errors
+        assert_eq!(
+            metadata.severity == StateSeverity::Error,
+            state == JobState::Failed
+        );
+    }
+
+    let terminal: Vec<_> = JobState::all()
+        .filter(|s| s.metadata().is_terminal)
+        .collect();
+    assert_eq!(terminal, [JobState::Completed, JobState::Failed]);
+    let active: Vec<_> = JobState::all().filter(|s| s.metadata().is_active).collect();
+    assert_eq!(active, [JobState::Available, JobState::Running]);
+
+    let serialized = serde_json::to_value(JobState::Paused.metadata()).unwrap();
+    assert_eq!(
+        serialized,
+        json!({
+            "state": "paused",
+            "display_name": "Paused",
+            "is_terminal": false,
+            "is_active": false,
+            "severity": "warning",
+        })
+    );
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_job_state_db_round_trip(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    for state in ALL_STATES {
+        let job = manager.create_job(create_new_job()).await.unwrap();
+        sqlx::query("UPDATE cyclotron_jobs SET state = $1 WHERE id = $2")
+            .bind(state)
+            .bind(job.id)
+            .execute(&db)
+            .await
+            .unwrap_or_else(|e| panic!("failed to write state {:?}: {}", state, e));
+
+        let read: JobState = sqlx::query_scalar("SELECT state FROM cyclotron_jobs WHERE id = $1")
+            .bind(job.id)
+            .fetch_one(&db)
+            .await
+            .unwrap_or_else(|e| panic!("failed to read state {:?}: {}", state, e));
+        assert_eq!(read, state);
+    }
+
+    // And filtering on every state works, too
+    let query = JobQuery {
+        states: ALL_STATES.to_vec(),
+        ..Default::default()
+    };
+    assert_eq!(
+        manager.query_jobs(&query).await.unwrap().len(),
+        ALL_STATES.len()
+    );
+}
+
+#[test]
+pub fn test_delete_set_ndjson_round_trip() {
+    let hour: DateTime<Utc> = "2024-08-01T12:00:00Z".parse().unwrap();
+    let set = DeleteSet(vec![
+        AggregatedDelete {
+            hour,
+            team_id: 1,
+            function_id: Some("0191a5c4-7e5b-7d6e-8d5c-1d9f2f2b5a01".to_string()),
+            state: "completed".to_string(),
+            count: 10,
+        },
+        AggregatedDelete {
+            hour,
+            team_id: 2,
+            function_id: None,
+            state: "failed".to_string(),
+            count: 1,
+        },
+    ]);
+
+    let ndjson = set.to_ndjson();
+    let lines: Vec<_> = ndjson.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(ndjson.ends_with('\n'));
+
+    // Each line is a standalone record, with an RFC3339 timestamp
+    let first: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+    assert_eq!(
+        first,
+        json!({
+            "hour": "2024-08-01T12:00:00Z",
+            "team_id": 1,
+            "function_id": "0191a5c4-7e5b-7d6e-8d5c-1d9f2f2b5a01",
+            "state": "completed",
+            "count": 10,
+        })
+    );
+
+    assert_eq!(DeleteSet::from_ndjson(&ndjson).unwrap(), set);
+    assert_eq!(DeleteSet::from_ndjson("").unwrap(), DeleteSet::default());
+}
+
+#[test]
+pub fn test_deadline_priority() {
+    let now: DateTime<Utc> = "2024-08-01T12:00:00Z".parse().unwrap();
+    let priority = |ahead| JobInit::deadline_priority(now, now + ahead);
+
+    // Sooner deadlines take precedence, which means a lower number
+    assert!(priority(Duration::minutes(5)) < priority(Duration::hours(1)));
+    assert!(priority(Duration::hours(1)) < priority(Duration::days(1)));
+    assert_eq!(priority(Duration::minutes(5)), 5);
+    assert_eq!(priority(Duration::seconds(61)), 2);
+
+    // Clamped at both ends
+    assert_eq!(priority(Duration::zero()), 0);
+    assert_eq!(priority(-Duration::hours(1)), 0);
+    assert_eq!(priority(Duration::days(365)), i16::MAX);
+
+    let soon = create_new_job().with_deadline(Utc::now() + Duration::minutes(10));
+    let later = create_new_job().with_deadline(Utc::now() + Duration::hours(10));
+    assert!(soon.priority < later.priority);
+}
+
+#[test]
+pub fn test_delete_set_totals() {
+    let hour: DateTime<Utc> = "2024-08-01T12:00:00Z".parse().unwrap();
+    let delete = |team_id, state: &str, count| AggregatedDelete {
+        hour,
+        team_id,
+        function_id: None,
+        state: state.to_string(),
+        count,
+    };
+    let set = DeleteSet(vec![
+        delete(1, "completed", 10),
+        delete(2, "completed", 5),
+        delete(1, "failed", 3),
+        delete(2, "paused", 2),
+        delete(3, "available", 1),
+    ]);
+
+    assert_eq!(set.total_for(JobState::Completed), 15);
+    assert_eq!(set.total_for(JobState::Failed), 3);
+    assert_eq!(set.total_for(JobState::Running), 0);
+    let per_state: u64 = ALL_STATES.iter().map(|s| set.total_for(*s)).sum();
+    assert_eq!(set.total(), per_state);
+    assert_eq!(set.total(), 21);
+    assert_eq!(DeleteSet::default().total(), 0);
+}
+
+#[test]
+pub fn test_delete_set_rollup_daily() {
+    let delete =
+        |hour: &str, team_id, function_id: Option<&str>, state: &str, count| AggregatedDelete {
+            hour: hour.parse().unwrap(),
+            team_id,
+            function_id: function_id.map(str::to_string),
+            state: state.to_string(),
+            count,
+        };
+    let set = DeleteSet(vec![
+        delete("2024-08-01T23:00:00Z", 1, None, "completed", 4),
+        delete("2024-08-01T09:00:00Z", 1, None, "completed", 10),
+        delete("2024-08-01T09:00:00Z", 1, None, "failed", 2),
+        delete("2024-08-01T10:00:00Z", 1, Some("f"), "completed", 3),
+        delete("2024-08-01T10:00:00Z", 2, None, "completed", 1),
+        delete("2024-08-02T00:00:00Z", 1, None, "completed", 7),
+        delete("2024-08-02T05:00:00Z", 1, None, "completed", 8),
+    ]);
+
+    let daily = set.rollup_daily();
+    assert_eq!(
+        daily,
+        DeleteSet(vec![
+            delete("2024-08-01T00:00:00Z", 1, None, "completed", 14),
+            delete("2024-08-01T00:00:00Z", 1, None, "failed", 2),
+            delete("2024-08-01T00:00:00Z", 1, Some("f"), "completed", 3),
+            delete("2024-08-01T00:00:00Z", 2, None, "completed", 1),
+            delete("2024-08-02T00:00:00Z", 1, None, "completed", 15),
+        ])
+    );
+    // Nothing is lost, and rolling up again changes nothing
+    assert_eq!(daily.total(), set.total());
+    assert_eq!(daily.rollup_daily(), daily);
+    assert_eq!(DeleteSet::default().rollup_daily(), DeleteSet::default());
+}
+
+// A valid running job, for tests to break
+fn running_job() -> Job {
+    let now = Utc::now();
+    Job {
+        id: Uuid::now_v7(),
+        team_id: 1,
+        function_id: None,
+        created: now,
+        lock_id: Some(Uuid::now_v7()),
+        last_heartbeat: Some(now),
+        lock_expires_at: None,
+        janitor_touch_count: 0,
+        transition_count: 1,
+        failed_attempts: 0,
+        last_transition: now,
+        queue_name: "test".to_string(),
+        state: JobState::Running,
+        priority: 0,
+        scheduled: now - Duration::minutes(1),
+        vm_state: None,
+        metadata: None,
+        parameters: None,
+        blob: None,
+        at_most_once: false,
+        correlation_id: None,
+        parent_job_id: None,
+        reply_to: None,
+    }
+}
+
+#[test]
+pub fn test_job_debug_redacts_payloads() {
+    let mut job = running_job();
+    let parameters = b"secret-token".to_vec();
+    let blob = vec![0xAB; 100];
+    job.parameters = Some(parameters.clone());
+    job.blob = Some(blob.clone());
+    job.vm_state = Some(vec![]);
+
+    let redacted = format!("{:?}", job);
+    assert!(redacted.contains("parameters: Some(<12 bytes>)"));
+    assert!(redacted.contains("blob: Some(<100 bytes>)"));
+    assert!(redacted.contains("vm_state: Some(<0 bytes>)"));
+    assert!(redacted.contains("metadata: None"));
+    assert!(!redacted.contains(&format!("{:?}", parameters)));
+    assert!(!redacted.contains("171"));
+    // Everything else is still there
+    assert!(redacted.contains(&job.id.to_string()));
+    assert!(redacted.contains("queue_name: \"test\""));
+
+    // As it is when the job is split up
+    let (_, payloads) = job.clone().into_parts();
+    let redacted = format!("{:?}", payloads);
+    assert!(redacted.contains("parameters: Some(<12 bytes>)"));
+    assert!(!redacted.contains(&format!("{:?}", parameters)));
+
+    // Unless the contents are asked for explicitly
+    let full = format!("{:?}", job.debug_unredacted());
+    assert!(full.contains(&format!("parameters: Some({:?})", parameters)));
+    assert!(full.contains(&format!("blob: Some({:?})", blob)));
+
+    // The types that carry payloads on their way in, or jobs on their way out, redact them the same way
+    let init = JobInit {
+        parameters: Some(parameters.clone()),
+        blob: Some(blob.clone()),
+        ..Default::default()
+    };
+    let redacted = format!("{:?}", init);
+    assert!(redacted.contains("parameters: Some(<12 bytes>)"));
+    assert!(redacted.contains("blob: Some(<100 bytes>)"));
+    assert!(redacted.contains("vm_state: None"));
+    assert!(!redacted.contains(&format!("{:?}", parameters)));
+
+    let mut update = JobUpdate::new(Uuid::now_v7());
+    update.parameters = Some(Some(parameters.clone()));
+    update.blob = Some(None);
+    let redacted = format!("{:?}", update);
+    assert!(redacted.contains("parameters: Some(Some(<12 bytes>))"));
+    assert!(redacted.contains("blob: Some(None)"));
+    assert!(redacted.contains("vm_state: None"));
+    assert!(!redacted.contains(&format!("{:?}", parameters)));
+
+    let batch = UniqueBatch {
+        jobs: vec![job.clone()],
+        collapsed: 0,
+    };
+    let redacted = format!("{:?}", batch);
+    assert!(redacted.contains("parameters: Some(<12 bytes>)"));
+    assert!(!redacted.contains(&format!("{:?}", parameters)));
+
+    let redacted = format!("{:?}", Enqueued::Created(job));
+    assert!(redacted.contains("parameters: Some(<12 bytes>)"));
+    assert!(!redacted.contains(&format!("{:?}", parameters)));
+}
+
+// A running job with all its timestamps set, at whole milliseconds so they survive being written as epoch millis
+fn running_job_at_millis() -> Job {
+    let now = DateTime::from_timestamp_millis(Utc::now().timestamp_millis()).unwrap();
+    Job {
+        created: now,
+        last_heartbeat: Some(now),
+        lock_expires_at: Some(now + Duration::minutes(5)),
+        last_transition: now,
+        scheduled: now - Duration::minutes(1),
+        ..running_job()
+    }
+}
+
+const TIMESTAMP_FIELDS: [&str; 5] = [
+    "created",
+    "last_heartbeat",
+    "lock_expires_at",
+    "last_transition",
+    "scheduled",
+];
+
+fn timestamps(job: &Job) -> [Option<DateTime<Utc>>; 5] {
+    [
+        Some(job.created),
+        job.last_heartbeat,
+        job.lock_expires_at,
+        Some(job.last_transition),
+        Some(job.scheduled),
+    ]
+}
+
+#[test]
+pub fn test_job_timestamps_serialize_as_rfc3339() {
+    let job = running_job_at_millis();
+    let serialized = serde_json::to_value(&job).unwrap();
+    for (field, timestamp) in TIMESTAMP_FIELDS.into_iter().zip(timestamps(&job)) {
+        let written: DateTime<Utc> = serialized[field].as_str().unwrap().parse().unwrap();
+        assert_eq!(Some(written), timestamp, "{}", field);
+    }
+    let deserialized: Job = serde_json::from_value(serialized).unwrap();
+    assert_eq!(timestamps(&deserialized), timestamps(&job));
+
+    let init = JobInit {
+        scheduled: job.scheduled,
+        ..create_new_job()
+    };
+    let serialized = serde_json::to_value(&init).unwrap();
+    assert!(serialized["scheduled"].is_string());
+    assert_eq!(serde_json::from_value::<JobInit>(serialized).unwrap(), init);
+}
+
+#[test]
+pub fn test_job_timestamps_serialize_as_epoch_millis() {
+    let job = running_job_at_millis();
+    let serialized = serde_json::to_value(EpochMillis(&job)).unwrap();
+    for (field, timestamp) in TIMESTAMP_FIELDS.into_iter().zip(timestamps(&job)) {
+        assert_eq!(
+            serialized[field],
+            timestamp.unwrap().timestamp_millis(),
+            "{}",
+            field
+        );
+    }
+    let deserialized: Job = serde_json::from_value(serialized).unwrap();
+    assert_eq!(timestamps(&deserialized), timestamps(&job));
+
+    let init = JobInit {
+        scheduled: job.scheduled,
+        ..create_new_job()
+    };
+    let serialized = serde_json::to_value(EpochMillis(vec![&init])).unwrap();
+    assert_eq!(serialized[0]["scheduled"], job.scheduled.timestamp_millis());
+    assert_eq!(
+        serde_json::from_value::<JobInit>(serialized[0].clone()).unwrap(),
+        init
+    );
+
+    // Only what's serialized through the wrapper is affected
+    assert!(serde_json::to_value(&job).unwrap()["created"].is_string());
+    let decoded = Job::from_bytes(&job.to_bytes().unwrap()).unwrap();
+    assert_eq!(timestamps(&decoded), timestamps(&job));
+}
+
+#[test]
+pub fn test_job_timestamps_deserialize_from_either_format() {
+    let job = running_job_at_millis();
+    let mut rfc3339 = serde_json::to_value(&job).unwrap();
+    let mut millis = rfc3339.clone();
+    for (field, timestamp) in TIMESTAMP_FIELDS.into_iter().zip(timestamps(&job)) {
+        let timestamp = timestamp.unwrap();
+        rfc3339[field] = json!(timestamp.to_rfc3339());
+        millis[field] = json!(timestamp.timestamp_millis());
+    }
+    for value in [rfc3339, millis.clone()] {
+        let deserialized: Job = serde_json::from_value(value).unwrap();
+        assert_eq!(timestamps(&deserialized), timestamps(&job));
+    }
+
+    // Optional timestamps can still be null, or left out entirely
+    millis["last_heartbeat"] = json!(null);
+    millis.as_object_mut().unwrap().remove("lock_expires_at");
+    let deserialized: Job = serde_json::from_value(millis).unwrap();
+    assert_eq!(deserialized.last_heartbeat, None);
+    assert_eq!(deserialized.lock_expires_at, None);
+
+    for scheduled in [
+        json!(job.scheduled.to_rfc3339()),
+        json!(job.scheduled.timestamp_millis()),
+    ] {
+        let init: JobInit = serde_json::from_value(json!({
+            "team_id": 1,
+            "queue_name": "test",
+            "priority": 0,
+            "scheduled": scheduled,
+        }))
+        .unwrap();
+        assert_eq!(init.scheduled, job.scheduled);
+    }
+
+    // Anything else is still rejected
+    let err = serde_json::from_value::<JobInit>(json!({
+        "team_id": 1,
+        "queue_name": "test",
+        "priority": 0,
+        "scheduled": true,
+    }))
+    .unwrap_err();
+    assert!(err.to_string().contains("epoch"));
+}
+
+#[test]
+pub fn test_check_invariants_passes_valid_jobs() {
+    let job = running_job();
+    job.check_invariants().unwrap();
+
+    // Available jobs can be scheduled in the future, and finished ones can have dropped their lock
+    let mut job = running_job();
+    job.state = JobState::Available;
+    job.lock_id = None;
+    job.scheduled = Utc::now() + Duration::hours(1);
+    job.check_invariants().unwrap();
+
+    let mut job = running_job();
+    job.state = JobState::Completed;
+    job.lock_id = None;
+    job.check_invariants().unwrap();
+}
+
+#[test]
+pub fn test_check_invariants_running_without_lock() {
+    let mut job = running_job();
+    job.lock_id = None;
+    assert!(matches!(
+        job.check_invariants(),
+        Err(JobError::InvariantViolated(id, _)) if id == job.id
+    ));
+}
+
+#[test]
+pub fn test_check_invariants_finished_but_scheduled_in_future() {
+    for state in [JobState::Completed, JobState::Failed] {
+        let mut job = running_job();
+        job.state = state;
+        job.scheduled = Utc::now() + Duration::hours(1);
+        assert!(matches!(
+            job.check_invariants(),
+            Err(JobError::InvariantViolated(id, _)) if id == job.id
+        ));
+    }
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_matches_init(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+
+    let mut init = create_new_job();
+    init.priority = 3;
+    init.vm_state = Some(b"vm".to_vec());
+    init.parameters = Some(b"params".to_vec());
+    init.blob = Some(vec![0; 32]);
+    init.metadata = Some(b"{}".to_vec());
+
+    // Creating a job returns it with all its payloads, and everything the init asked for
+    let job = manager.create_job(init.clone()).await.unwrap();
+    assert!(job.matches_init(&init));
+
+    let mut different = init.clone();
+    different.blob = Some(vec![1; 32]);
+    assert!(!job.matches_init(&different));
+
+    let mut different = init.clone();
+    different.parameters = None;
+    assert!(!job.matches_init(&different));
+
+    let mut different = init.clone();
+    different.team_id = 2;
+    assert!(!job.matches_init(&different));
+
+    // The schedule isn't something the job has to preserve
+    let mut rescheduled = init.clone();
+    rescheduled.scheduled = Utc::now() + Duration::hours(1);
+    assert!(job.matches_init(&rescheduled));
+}
+
+#[test]
+pub fn test_into_parts() {
+    let mut job = running_job();
+    job.vm_state = Some(vec![1; 1024]);
+    job.metadata = Some(b"{}".to_vec());
+    job.parameters = Some(vec![2; 16]);
+    job.blob = Some(vec![3; 4096]);
+    let blob_ptr = job.blob.as_ref().unwrap().as_ptr();
+
+    let (id, lock_id, state, queue_name) = (job.id, job.lock_id, job.state, job.queue_name.clone());
+    let (header, payloads) = job.into_parts();
+
+    assert_eq!(header.id, id);
+    assert_eq!(header.lock_id, lock_id);
+    assert_eq!(header.state, state);
+    assert_eq!(header.queue_name, queue_name);
+
+    assert_eq!(payloads.vm_state, Some(vec![1; 1024]));
+    assert_eq!(payloads.metadata, Some(b"{}".to_vec()));
+    assert_eq!(payloads.parameters, Some(vec![2; 16]));
+    assert_eq!(payloads.blob, Some(vec![3; 4096]));
+    // The buffers were moved, not copied
+    assert_eq!(payloads.blob.as_ref().unwrap().as_ptr(), blob_ptr);
+}
+
+#[sqlx::test(migrations = "./migrations")]
+pub async fn test_unknown_state_is_a_typed_error(db: PgPool) {
+    let manager = QueueManager::from_pool(db.clone());
+    let job = manager.create_job(create_new_job()).await.unwrap();
+
+    // As if a newer migration had added a state, and moved the job into it
+    sqlx::query("ALTER TYPE JobState ADD VALUE 'archived'")
+        .execute(&db)
+        .await
+        .unwrap();
+    sqlx::query("UPDATE cyclotron_jobs SET state = 'archived' WHERE id = $1")
+        .bind(job.id)
+        .execute(&db)
+        .await
+        .unwrap();
+
+    let res = manager.get_job(job.id).await;
+    let Err(QueueError::SqlxError(sqlx::Error::ColumnDecode { source, .. })) = res else {
+        panic!("expected a column decode error, got {:?}", res);
+    };
+    assert_eq!(
+        source.downcast_ref::<UnknownJobState>(),
+        Some(&UnknownJobState("archived".to_string()))
+    );
+    assert!(source.to_string().contains("\"archived\""));
+}
+
+#[cfg(feature = "table")]
+#[test]
+pub fn test_format_jobs_table() {
+    let running = Job {
+        queue_name: "a-rather-long-queue-name".to_string(),
+        priority: 3,
+        created: Utc::now() - Duration::minutes(90),
+        ..running_job()
+    };
+    let failed = Job {
+        state: JobState::Failed,
+        lock_id: None,
+        ..running_job()
+    };
+    let table = cyclotron_core::format_jobs_table(&[running.clone(), failed.clone()]);
+
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(lines.len(), 3, "{}", table);
+    let header: Vec<&str> = lines[0].split_whitespace().collect();
+    assert_eq!(
+        header,
+        ["ID", "QUEUE", "STATE", "PRIORITY", "SCHEDULED", "AGE"]
+    );
+
+    for (line, job) in lines[1..].iter().zip([&running, &failed]) {
+        assert!(line.starts_with(&job.id.to_string()), "{}", line);
+        assert!(line.contains(&job.queue_name), "{}", line);
+        assert!(line.contains(job.state.metadata().display_name), "{}", line);
+        assert!(
+            line.contains(&job.scheduled.format("%Y-%m-%d %H:%M:%S").to_string()),
+            "{}",
+            line
+        );
+    }
+    assert!(lines[1].ends_with("1h30m"), "{}", lines[1]);
+
+    // Columns line up, with the long queue name widening its column for every row
+    let queue_at = lines[0].find("QUEUE").unwrap();
+    assert_eq!(lines[1].find(&running.queue_name), Some(queue_at));
+    assert!(lines[2][queue_at..].starts_with(&format!("{:<24}", "test")));
+}
+
+#[test]
+pub fn test_retry_policy_backoff() {
+    let policy = RetryPolicy {
+        base: Duration::seconds(1),
+        factor: 2.0,
+        max: Duration::minutes(1),
+        jitter: 0.0,
+        mode: RetryMode::Resume,
+    };
+    for (attempt, expected) in [(1, 1), (2, 2), (3, 4), (4, 8), (6, 32)] {
+        assert_eq!(
+            policy.backoff(attempt),
+            Duration::seconds(expected),
+            "{}",
+            attempt
+        );
+    }
+    // 64 seconds is past the cap, as is everything after it, however far
+    for attempt in [7, 8, 100, u32::MAX] {
+        assert_eq!(policy.backoff(attempt), Duration::minutes(1), "{}", attempt);
+    }
+
+    let gentle = RetryPolicy {
+        factor: 1.5,
+        ..policy
+    };
+    assert_eq!(gentle.backoff(3), Duration::milliseconds(2250));
+}
+
+#[test]
+pub fn test_job_next_retry_at() {
+    let policy = RetryPolicy {
+        base: Duration::seconds(10),
+        factor: 3.0,
+        max: Duration::minutes(10),
+        jitter: 0.0,
+        mode: RetryMode::Resume,
+    };
+    let now = Utc::now();
+
+    // Running its first attempt, then its third, and waiting for its second
+    let first = running_job();
+    assert_eq!(first.attempt(), 1);
+    assert_eq!(
+        first.next_retry_at(&policy, now),
+        now + Duration::seconds(10)
+    );
+    let third = Job {
+        failed_attempts: 2,
+        ..running_job()
+    };
+    assert_eq!(third.attempt(), 3);
+    assert_eq!(
+        third.next_retry_at(&policy, now),
+        now + Duration::seconds(90)
+    );
+    let waiting = Job {
+        state: JobState::Available,
+        lock_id: None,
+        failed_attempts: 1,
+        ..running_job()
+    };
+    assert_eq!(waiting.attempt(), 2);
+    assert_eq!(
+        waiting.next_retry_at(&policy, now),
+        now + Duration::seconds(30)
+    );
+
+    // Jitter shortens the delay by up to that fraction, the same way every time for a given job and attempt,
+    // but differently across jobs
+    let jittered = RetryPolicy {
+        jitter: 0.5,
+        ..policy
+    };
+    let delays: Vec<Duration> = (0..20)
+        .map(|_| {
+            let job = running_job();
+            let at = job.next_retry_at(&jittered, now);
+            assert_eq!(at, job.next_retry_at(&jittered, now));
+            at - now
+        })
+        .collect();
+    for delay in &delays {
+        assert!(*delay >= Duration::seconds(5) && *delay <= Duration::seconds(10));
+    }
+    assert!(delays.iter().any(|d| *d != delays[0]));
+}
+
+#[test]
+pub fn test_run_config_default_retry_policy() {
+    let config = RunConfig::default();
+    let policy = config.default_retry_policy();
+    // The config's own doubling backoff, resuming
+    assert_eq!(policy.base, config.base_backoff);
+    assert_eq!(policy.max, config.max_backoff);
+    assert_eq!(policy.mode, RetryMode::Resume);
+    assert_eq!(policy.backoff(3), config.backoff(3));
+    assert_eq!(config.backoff(3), Duration::seconds(4));
+}
@@ -31,6 +31,9 @@ pub struct Config {
     #[envconfig(default = "60")]
     pub pg_idle_timeout_seconds: u64,
 
+    // Statements running longer than this are aborted. Defaults to cyclotron's DEFAULT_WORKER_STATEMENT_TIMEOUT_MS, zero disables it
+    pub pg_statement_timeout_ms: Option<u64>,
+
     #[envconfig(default = "false")]
     pub allow_internal_ips: bool,
 
@@ -82,6 +85,9 @@ pub struct Config {
 
     #[envconfig(default = "10")]
     pub flush_loop_interval_ms: u64,
+
+    #[envconfig(default = "60")]
+    pub served_window_seconds: u64,
 }
 
 #[allow(dead_code)]
@@ -131,6 +137,7 @@ impl Config {
             acquire_timeout_seconds: Some(self.pg_acquire_timeout_seconds),
             max_lifetime_seconds: Some(self.pg_max_lifetime_seconds),
             idle_timeout_seconds: Some(self.pg_idle_timeout_seconds),
+            statement_timeout_ms: self.pg_statement_timeout_ms,
         };
 
         let worker_config = WorkerConfig {
@@ -139,6 +146,8 @@ impl Config {
             max_updates_buffered: Some(self.max_updates_buffered),
             max_bytes_buffered: Some(self.max_bytes_buffered),
             flush_loop_interval_ms: Some(self.flush_loop_interval_ms),
+            served_window_seconds: Some(self.served_window_seconds),
+            ..Default::default()
         };
 
         (app_config, pool_config, self.kafka, worker_config)
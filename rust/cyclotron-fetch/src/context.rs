@@ -1,4 +1,5 @@
-use std::sync::{Arc, RwLock};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock};
 
 use common_kafka::config::KafkaConfig;
 use common_kafka::kafka_producer::create_kafka_producer;
@@ -19,6 +20,9 @@ pub struct AppContext {
     pub liveness: HealthHandle,
     pub config: AppConfig,
     pub metric_labels: RwLock<Vec<(String, String)>>,
+    // The team labels the served-by-team gauge was last reported under, so ones that stop being reported can be
+    // zeroed, rather than left at their last value
+    pub reported_teams: Mutex<HashSet<String>>,
 }
 
 impl AppContext {
@@ -72,6 +76,7 @@ impl AppContext {
             liveness,
             config,
             metric_labels: RwLock::new(labels),
+            reported_teams: Default::default(),
         })
     }
 
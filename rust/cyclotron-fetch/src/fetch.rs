@@ -1,4 +1,9 @@
-use std::{cmp::min, collections::HashMap, fmt::Display, sync::Arc};
+use std::{
+    cmp::{min, Reverse},
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    sync::Arc,
+};
 
 use chrono::{DateTime, Duration, Utc};
 use cyclotron_core::{Bytes, Job, JobError, JobState, QueueError, Worker};
@@ -17,6 +22,10 @@ use crate::{context::AppContext, metrics_constants::*};
 pub const DEFAULT_RETRIES: u32 = 3;
 pub const DEFAULT_ON_FINISH: OnFinish = OnFinish::Return;
 pub const HEARTBEAT_INTERVAL_MS: i64 = 5000;
+// The most teams the served-by-team gauge reports individually, to bound its cardinality. The rest are summed
+// under OTHER_TEAMS_LABEL
+pub const SERVED_BY_TEAM_REPORTED_TEAMS: usize = 20;
+pub const OTHER_TEAMS_LABEL: &str = "other";
 
 // Exclusively for errors in the worker - these will
 // never be serialised into the job queue, and indicate
@@ -250,6 +259,26 @@ pub async fn tick(context: Arc<AppContext>) -> Result<usize, FetchError> {
 
     common_metrics::inc(WORKER_DEQUEUED, &labels, num_jobs as u64);
 
+    // Jobs served per team this served window, to check the fairness policies are doing their job. Teams that
+    // aren't reported this time, because the window rolled over or they dropped out of the top teams, are zeroed
+    let served = top_served_teams(
+        context.worker.metrics_snapshot().served_by_team,
+        SERVED_BY_TEAM_REPORTED_TEAMS,
+    );
+    {
+        let mut reported_teams = context.reported_teams.lock().unwrap();
+        let reported: HashSet<String> = served.iter().map(|(team, _)| team.clone()).collect();
+        let dropped = reported_teams
+            .difference(&reported)
+            .map(|team| (team.clone(), 0));
+        for (team, served) in served.iter().cloned().chain(dropped) {
+            let mut team_labels = labels.as_ref().clone();
+            team_labels.push((TEAM_ID_LABEL.to_string(), team));
+            common_metrics::gauge(WORKER_SERVED_BY_TEAM, &team_labels, served as f64);
+        }
+        *reported_teams = reported;
+    }
+
     let _time = common_metrics::timing_guard(SPAWN_TIME, &labels);
     for job in jobs {
         let context = context.clone();
@@ -280,6 +309,25 @@ pub async fn tick(context: Arc<AppContext>) -> Result<usize, FetchError> {
     Ok(num_jobs)
 }
 
+// The `limit` teams served the most, as team label values, with the rest summed under OTHER_TEAMS_LABEL, if
+// there are any
+pub fn top_served_teams(served_by_team: HashMap<i32, u64>, limit: usize) -> Vec<(String, u64)> {
+    let mut served: Vec<_> = served_by_team.into_iter().collect();
+    // Ties broken by team id, so which teams make the cut doesn't flap between ticks
+    served.sort_unstable_by_key(|(team_id, served)| (Reverse(*served), *team_id));
+
+    let others: u64 = served.iter().skip(limit).map(|(_, served)| served).sum();
+    let mut top: Vec<_> = served
+        .into_iter()
+        .take(limit)
+        .map(|(team_id, served)| (team_id.to_string(), served))
+        .collect();
+    if others > 0 {
+        top.push((OTHER_TEAMS_LABEL.to_string(), others));
+    }
+    top
+}
+
 impl From<&Job> for FetchMetadata {
     fn from(job: &Job) -> Self {
         let Some(m) = &job.metadata else {
@@ -89,6 +89,11 @@ async fn main() {
     .expect("failed to create app context");
 
     context.worker.run_migrations().await;
+    context
+        .worker
+        .check_compatibility()
+        .await
+        .expect("database schema is incompatible");
 
     let http_server = tokio::spawn(listen(app, bind));
 
@@ -1,6 +1,7 @@
 // Metric names
 pub const WORKER_SAT: &str = "cyclotron_fetch_worker_available_permits";
 pub const WORKER_DEQUEUED: &str = "cyclotron_fetch_worker_dequeued_jobs";
+pub const WORKER_SERVED_BY_TEAM: &str = "cyclotron_fetch_worker_served_jobs_by_team";
 pub const DEQUEUE_TIME: &str = "cyclotron_fetch_dequeue_ms";
 pub const SPAWN_TIME: &str = "cyclotron_fetch_spawn_tasks_ms";
 pub const JOB_TOTAL_TIME: &str = "cyclotron_fetch_job_total_run_ms";
@@ -16,3 +17,4 @@ pub const BODY_FETCH_SUCCEEDED: &str = "cyclotron_fetch_body_fetch_succeeded";
 // Label keys
 pub const OUTCOME_LABEL: &str = "outcome";
 pub const RESPONSE_STATUS_LABEL: &str = "response_status";
+pub const TEAM_ID_LABEL: &str = "team_id";
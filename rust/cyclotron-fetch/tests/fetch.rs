@@ -2,7 +2,7 @@ use std::{collections::HashMap, sync::Arc};
 
 use chrono::Duration;
 use cyclotron_core::{QueueManager, Worker};
-use cyclotron_fetch::fetch::{tick, FetchResult, HttpMethod};
+use cyclotron_fetch::fetch::{tick, top_served_teams, FetchResult, HttpMethod, OTHER_TEAMS_LABEL};
 use httpmock::{Method, MockServer};
 use serde_json::json;
 use sqlx::PgPool;
@@ -310,3 +310,24 @@ pub async fn test_completes_fetch_with_vm_state(db: PgPool) {
 
     mock.assert_hits(1);
 }
+
+#[test]
+pub fn test_top_served_teams_caps_reported_teams() {
+    let served = HashMap::from([(1, 5), (2, 10), (3, 1), (4, 5)]);
+
+    // The busiest teams are reported individually, ties broken by team id, and the rest summed
+    let top = top_served_teams(served.clone(), 2);
+    assert_eq!(
+        top,
+        [
+            ("2".to_string(), 10),
+            ("1".to_string(), 5),
+            (OTHER_TEAMS_LABEL.to_string(), 6)
+        ]
+    );
+
+    // When every team fits, there's nothing to sum
+    let top = top_served_teams(served, 4);
+    assert_eq!(top.len(), 4);
+    assert!(top.iter().all(|(team, _)| team != OTHER_TEAMS_LABEL));
+}
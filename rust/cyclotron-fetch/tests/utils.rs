@@ -50,6 +50,7 @@ pub async fn get_app_test_context(db: PgPool) -> AppContext {
         liveness,
         config,
         metric_labels: Default::default(),
+        reported_teams: Default::default(),
     }
 }
 
@@ -66,6 +67,7 @@ pub fn construct_params(url: String, method: HttpMethod) -> FetchParameters {
 
 pub fn construct_job(parameters: FetchParameters, body: Option<Bytes>) -> JobInit {
     JobInit {
+        id: None,
         team_id: 1,
         queue_name: FETCH_QUEUE.to_string(),
         priority: 0,
@@ -75,6 +77,10 @@ pub fn construct_job(parameters: FetchParameters, body: Option<Bytes>) -> JobIni
         parameters: Some(serde_json::to_vec(&parameters).unwrap()),
         blob: body,
         metadata: None,
+        at_most_once: false,
+        correlation_id: None,
+        parent_job_id: None,
+        reply_to: None,
     }
 }
 
@@ -1,4 +1,7 @@
-use chrono::Duration;
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+use chrono_tz::Tz;
 
 use cyclotron_core::PoolConfig;
 use envconfig::Envconfig;
@@ -20,6 +23,11 @@ pub struct Config {
     #[envconfig(default = "30")]
     pub cleanup_interval_secs: u64,
 
+    // Each pass waits cleanup_interval_secs, plus or minus up to this fraction of it, so janitors started at the
+    // same time don't all hit the database at once
+    #[envconfig(default = "0.1")]
+    pub cleanup_interval_jitter: f64,
+
     #[envconfig(default = "10")]
     pub pg_max_connections: u32,
 
@@ -35,6 +43,9 @@ pub struct Config {
     #[envconfig(default = "60")]
     pub pg_idle_timeout_seconds: u64,
 
+    // Statements running longer than this are aborted. Defaults to cyclotron's DEFAULT_JANITOR_STATEMENT_TIMEOUT_MS, zero disables it
+    pub pg_statement_timeout_ms: Option<u64>,
+
     // Generally, this should be equivalent to a "shard id", as only one janitor should be running
     // per shard
     #[envconfig(default = "default_janitor_id")]
@@ -49,6 +60,18 @@ pub struct Config {
     #[envconfig(default = "60")]
     pub janitor_stall_timeout_seconds: u16,
 
+    // If set, delete passes only run inside this daily window, e.g. "02:00-05:00". Stalled job resets and the
+    // rest of the janitor's work run regardless.
+    pub janitor_delete_window: Option<DeleteWindow>,
+
+    // The IANA time zone the delete window is in, e.g. "America/New_York". The window follows its daylight saving
+    // changes, so it stays at the same local hours all year.
+    #[envconfig(default = "UTC")]
+    pub janitor_delete_window_tz: Tz,
+
+    // If set, recorded job transitions older than this are pruned, during delete passes. Unset keeps them forever
+    pub janitor_transition_retention_hours: Option<u64>,
+
     #[envconfig(nested = true)]
     pub kafka: KafkaConfig,
 }
@@ -67,6 +90,7 @@ impl Config {
             acquire_timeout_seconds: Some(self.pg_acquire_timeout_seconds),
             max_lifetime_seconds: Some(self.pg_max_lifetime_seconds),
             idle_timeout_seconds: Some(self.pg_idle_timeout_seconds),
+            statement_timeout_ms: self.pg_statement_timeout_ms,
         };
 
         let settings = JanitorSettings {
@@ -74,6 +98,12 @@ impl Config {
             max_touches: self.janitor_max_touches,
             id: self.janitor_id.clone(),
             shard_id: self.shard_id.clone(),
+            delete_window: self
+                .janitor_delete_window
+                .map(|w| w.with_tz(self.janitor_delete_window_tz)),
+            transition_retention: self
+                .janitor_transition_retention_hours
+                .map(|h| Duration::hours(h as i64)),
         };
 
         JanitorConfig {
@@ -95,4 +125,58 @@ pub struct JanitorSettings {
     pub max_touches: i16,
     pub id: String,
     pub shard_id: String,
+    pub delete_window: Option<DeleteWindow>, // If set, delete passes are skipped outside of it
+    pub transition_retention: Option<Duration>, // If set, delete passes also prune recorded transitions older than this
+}
+
+// A daily window of local time, in some time zone, e.g. for limiting expensive work to off-peak hours. Windows whose end
+// is before their start wrap around midnight, so "22:00-02:00" covers the two hours either side of it. The start is
+// inclusive, and the end exclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeleteWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub tz: Tz,
+}
+
+impl DeleteWindow {
+    pub fn new(start: NaiveTime, end: NaiveTime, tz: Tz) -> Self {
+        Self { start, end, tz }
+    }
+
+    pub fn with_tz(self, tz: Tz) -> Self {
+        Self { tz, ..self }
+    }
+
+    // Compares against the wall clock time in the window's zone, so on the days clocks change the window covers
+    // whatever local hours it spans - an hour less or more of UTC time.
+    pub fn contains(&self, at: DateTime<Utc>) -> bool {
+        let time = at.with_timezone(&self.tz).time();
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseDeleteWindowError;
+
+// Parses "HH:MM-HH:MM", in UTC - use `with_tz` to move it. Empty windows, that start and end at the same
+// time, are rejected, since they'd silently mean deletes never run.
+impl FromStr for DeleteWindow {
+    type Err = ParseDeleteWindowError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').ok_or(ParseDeleteWindowError)?;
+        let parse = |t: &str| {
+            NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|_| ParseDeleteWindowError)
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start == end {
+            return Err(ParseDeleteWindowError);
+        }
+        Ok(Self::new(start, end, Tz::UTC))
+    }
 }
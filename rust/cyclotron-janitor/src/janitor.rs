@@ -1,11 +1,13 @@
+use chrono::{DateTime, Utc};
 use common_kafka::kafka_messages::app_metrics2::{
     AppMetric2, Kind as AppMetric2Kind, Source as AppMetric2Source,
 };
 use common_kafka::kafka_producer::create_kafka_producer;
 use common_kafka::kafka_producer::{send_iter_to_kafka, KafkaContext, KafkaProduceError};
 use common_kafka::APP_METRICS2_TOPIC;
-use cyclotron_core::{AggregatedDelete, QueueError, SHARD_ID_KEY};
+use cyclotron_core::{AggregatedDelete, DeleteSet, JobState, QueueError, SHARD_ID_KEY};
 use health::HealthRegistry;
+use rand::Rng;
 use tracing::{error, info, warn};
 
 use rdkafka::producer::FutureProducer;
@@ -15,6 +17,9 @@ use crate::{
     metrics_constants::*,
 };
 
+// How many recorded transitions each pruning transaction deletes
+const TRANSITION_PRUNE_BATCH_SIZE: u64 = 10_000;
+
 // The janitor reports it's own metrics, this is mostly for testing purposes
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct CleanupResult {
@@ -29,6 +34,7 @@ pub struct Janitor {
     pub kafka_producer: FutureProducer<KafkaContext>,
     pub settings: JanitorSettings,
     pub metrics_labels: Vec<(String, String)>,
+    pub clock: fn() -> DateTime<Utc>, // What runs check the delete window against. Defaults to Utc::now
 }
 
 impl Janitor {
@@ -57,6 +63,7 @@ impl Janitor {
             kafka_producer,
             settings,
             metrics_labels,
+            clock: Utc::now,
         })
     }
 
@@ -64,25 +71,36 @@ impl Janitor {
         self.inner.run_migrations().await;
     }
 
+    pub async fn check_compatibility(&self) -> Result<(), QueueError> {
+        cyclotron_core::check_compatibility(&self.inner.pool).await
+    }
+
     pub async fn run_once(&self) -> Result<CleanupResult, QueueError> {
         info!("Running janitor loop");
         let _loop_start = common_metrics::timing_guard(RUN_TIME, &self.metrics_labels);
         common_metrics::inc(RUN_STARTS, &self.metrics_labels, 1);
 
-        let aggregated_deletes = {
+        // Deletes are the expensive part of a run, so they can be limited to off-peak hours
+        let in_window = self
+            .settings
+            .delete_window
+            .is_none_or(|w| w.contains((self.clock)()));
+
+        // Only one janitor runs the delete pass against a given database at a time - if another is mid-pass, we skip it
+        let aggregated_deletes = if in_window {
             let _time = common_metrics::timing_guard(CLEANUP_TIME, &self.metrics_labels);
-            self.inner.delete_completed_and_failed_jobs().await?
+            self.inner.try_delete_completed_and_failed_jobs().await?
+        } else {
+            info!("Outside the delete window, skipping delete pass");
+            Some(vec![])
         };
+        let aggregated_deletes = DeleteSet(aggregated_deletes.unwrap_or_else(|| {
+            info!("Another janitor is running a delete pass, skipping");
+            vec![]
+        }));
 
-        let mut completed_count = 0u64;
-        let mut failed_count = 0u64;
-        for delete in &aggregated_deletes {
-            if delete.state == "completed" {
-                completed_count += delete.count as u64;
-            } else if delete.state == "failed" {
-                failed_count += delete.count as u64;
-            }
-        }
+        let completed_count = aggregated_deletes.total_for(JobState::Completed);
+        let failed_count = aggregated_deletes.total_for(JobState::Failed);
         common_metrics::inc(COMPLETED_COUNT, &self.metrics_labels, completed_count);
         common_metrics::inc(FAILED_COUNT, &self.metrics_labels, failed_count);
 
@@ -90,6 +108,7 @@ impl Janitor {
             &self.kafka_producer,
             APP_METRICS2_TOPIC,
             aggregated_deletes
+                .0
                 .into_iter()
                 .map(aggregated_delete_to_app_metric2),
         )
@@ -107,6 +126,16 @@ impl Janitor {
             }
         }
 
+        // Pruning the transition record is a delete pass too, so it's limited to the same window
+        if let Some(retention) = self.settings.transition_retention.filter(|_| in_window) {
+            let _time = common_metrics::timing_guard(PRUNED_TRANSITIONS_TIME, &self.metrics_labels);
+            let pruned = self
+                .inner
+                .prune_transitions_older_than(retention, TRANSITION_PRUNE_BATCH_SIZE)
+                .await?;
+            common_metrics::inc(PRUNED_TRANSITIONS_COUNT, &self.metrics_labels, pruned);
+        }
+
         let poisoned = {
             let _time = common_metrics::timing_guard(POISONED_TIME, &self.metrics_labels);
             self.inner
@@ -160,6 +189,16 @@ impl Janitor {
     }
 }
 
+// Returns `interval`, plus or minus a random amount up to `jitter` (a fraction, clamped to [0, 1]) of it
+pub fn jittered(interval: std::time::Duration, jitter: f64) -> std::time::Duration {
+    let jitter = jitter.clamp(0.0, 1.0);
+    if jitter == 0.0 {
+        return interval;
+    }
+    let factor = rand::thread_rng().gen_range(1.0 - jitter..=1.0 + jitter);
+    interval.mul_f64(factor)
+}
+
 fn aggregated_delete_to_app_metric2(delete: AggregatedDelete) -> AppMetric2 {
     let kind = match delete.state.as_str() {
         "completed" => AppMetric2Kind::Success,
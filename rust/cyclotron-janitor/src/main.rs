@@ -1,6 +1,9 @@
 use axum::{extract::State, routing::get, Router};
 use common_metrics::setup_metrics_routes;
-use cyclotron_janitor::{config::Config, janitor::Janitor};
+use cyclotron_janitor::{
+    config::Config,
+    janitor::{jittered, Janitor},
+};
 use envconfig::Envconfig;
 use eyre::Result;
 use health::{HealthHandle, HealthRegistry};
@@ -9,18 +12,24 @@ use tracing::{error, info};
 
 common_alloc::used!();
 
-async fn cleanup_loop(janitor: Janitor, livenes: HealthHandle, interval_secs: u64) -> Result<()> {
-    let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+async fn cleanup_loop(
+    janitor: Janitor,
+    livenes: HealthHandle,
+    interval_secs: u64,
+    jitter: f64,
+) -> Result<()> {
+    let interval = Duration::from_secs(interval_secs);
 
+    // The first pass runs straight away, and each one after waits out a jittered interval
     loop {
-        interval.tick().await;
-
         if let Err(e) = janitor.run_once().await {
             // don't bother reporting unhealthy - a few times around this loop will put us in a stalled state
             error!("janitor failed cleanup with: {}", e);
         } else {
             livenes.report_healthy().await;
         }
+
+        tokio::time::sleep(jittered(interval, jitter)).await;
     }
 }
 
@@ -70,6 +79,11 @@ async fn main() {
         .expect("failed to create janitor");
 
     janitor.run_migrations().await;
+    // Another service may have migrated the schema past what this build understands
+    janitor
+        .check_compatibility()
+        .await
+        .expect("database schema is incompatible");
 
     let janitor_liveness = liveness
         .register(
@@ -82,6 +96,7 @@ async fn main() {
         janitor,
         janitor_liveness,
         config.cleanup_interval_secs,
+        config.cleanup_interval_jitter,
     ));
 
     let app = setup_metrics_routes(app(liveness, janitor_id));
@@ -9,6 +9,9 @@ pub const CLEANUP_TIME: &str = "cyclotron_janitor_completed_failed_jobs_cleanup_
 pub const POISONED_COUNT: &str = "cyclotron_janitor_poison_pills";
 pub const POISONED_TIME: &str = "cyclotron_janitor_poison_pills_cleanup_ms";
 
+pub const PRUNED_TRANSITIONS_COUNT: &str = "cyclotron_janitor_pruned_transitions";
+pub const PRUNED_TRANSITIONS_TIME: &str = "cyclotron_janitor_pruned_transitions_ms";
+
 pub const STALLED_COUNT: &str = "cyclotron_janitor_stalled_jobs_reset";
 pub const STALLED_TIME: &str = "cyclotron_janitor_stalled_jobs_reset_ms";
 
@@ -1,9 +1,13 @@
-use chrono::{Duration, Timelike, Utc};
+use chrono::{DateTime, Duration, NaiveTime, Timelike, Utc};
+use chrono_tz::Tz;
 use common_kafka::kafka_messages::app_metrics2::{
     AppMetric2, Kind as AppMetric2Kind, Source as AppMetric2Source,
 };
 use cyclotron_core::{JobInit, JobState, QueueManager, Worker};
-use cyclotron_janitor::{config::JanitorSettings, janitor::Janitor};
+use cyclotron_janitor::{
+    config::{DeleteWindow, JanitorSettings},
+    janitor::Janitor,
+};
 use rdkafka::consumer::{Consumer, StreamConsumer};
 use rdkafka::{ClientConfig, Message};
 use sqlx::PgPool;
@@ -47,12 +51,15 @@ async fn janitor_test(db: PgPool) {
         max_touches,
         id: "test_janitor".to_string(),
         shard_id: "test_shard".to_string(),
+        delete_window: None,
+        transition_retention: None,
     };
     let janitor = Janitor {
         inner: cyclotron_core::Janitor::from_pool(db.clone()),
         kafka_producer: mock_producer,
         settings,
         metrics_labels: vec![],
+        clock: Utc::now,
     };
 
     let now = Utc::now() - Duration::seconds(10);
@@ -60,6 +67,7 @@ async fn janitor_test(db: PgPool) {
 
     let uuid = Uuid::now_v7();
     let job_init = JobInit {
+        id: None,
         team_id: 1,
         queue_name: queue_name.clone(),
         priority: 0,
@@ -69,6 +77,10 @@ async fn janitor_test(db: PgPool) {
         parameters: None,
         blob: None,
         metadata: None,
+        at_most_once: false,
+        correlation_id: None,
+        parent_job_id: None,
+        reply_to: None,
     };
 
     // First test - if we mark a job as completed, the janitor will clean it up
@@ -311,3 +323,150 @@ async fn janitor_test(db: PgPool) {
     assert_eq!(result.poisoned, 0);
     assert_eq!(result.stalled, 0);
 }
+
+fn at(time: &str) -> DateTime<Utc> {
+    on("2024-09-01", time)
+}
+
+fn on(day: &str, time: &str) -> DateTime<Utc> {
+    format!("{}T{}:00Z", day, time).parse().unwrap()
+}
+
+#[test]
+fn delete_window_in_and_out() {
+    let window: DeleteWindow = "02:00-05:00".parse().unwrap();
+    assert!(window.contains(at("02:00")));
+    assert!(window.contains(at("03:30")));
+    assert!(window.contains(at("04:59")));
+    // The end is exclusive
+    assert!(!window.contains(at("05:00")));
+    assert!(!window.contains(at("01:59")));
+    assert!(!window.contains(at("14:00")));
+}
+
+#[test]
+fn delete_window_wraps_around_midnight() {
+    let window: DeleteWindow = "22:00-02:00".parse().unwrap();
+    assert!(window.contains(at("22:00")));
+    assert!(window.contains(at("23:59")));
+    assert!(window.contains(at("00:00")));
+    assert!(window.contains(at("01:59")));
+    assert!(!window.contains(at("02:00")));
+    assert!(!window.contains(at("12:00")));
+    assert!(!window.contains(at("21:59")));
+}
+
+#[test]
+fn delete_window_respects_tz() {
+    // New York is on EDT, UTC-4, in September, so 02:00-05:00 there is 06:00-09:00 UTC
+    let window = "02:00-05:00"
+        .parse::<DeleteWindow>()
+        .unwrap()
+        .with_tz(Tz::America__New_York);
+    assert!(!window.contains(at("03:00")));
+    assert!(window.contains(at("06:00")));
+    assert!(window.contains(at("08:59")));
+    assert!(!window.contains(at("09:00")));
+
+    // And a zone can push the window over midnight UTC - 22:00-02:00 in Moscow, at UTC+3, is 19:00-23:00 UTC
+    let window = "22:00-02:00"
+        .parse::<DeleteWindow>()
+        .unwrap()
+        .with_tz(Tz::Europe__Moscow);
+    assert!(window.contains(at("19:00")));
+    assert!(window.contains(at("22:30")));
+    assert!(!window.contains(at("23:00")));
+    assert!(!window.contains(at("00:30")));
+}
+
+#[test]
+fn delete_window_follows_dst() {
+    // New York's clocks went forward from 02:00 EST to 03:00 EDT on 2024-03-10, so 02:00-05:00 local moves
+    // from 07:00-10:00 UTC to 06:00-09:00 UTC
+    let window = "02:00-05:00"
+        .parse::<DeleteWindow>()
+        .unwrap()
+        .with_tz(Tz::America__New_York);
+    assert!(!window.contains(on("2024-03-09", "06:30")));
+    assert!(window.contains(on("2024-03-09", "07:00")));
+    assert!(window.contains(on("2024-03-09", "09:30")));
+    assert!(!window.contains(on("2024-03-09", "10:00")));
+
+    // On the day itself 02:00-03:00 local never happens, so the window opens at 03:00 EDT, 07:00 UTC
+    assert!(!window.contains(on("2024-03-10", "06:30")));
+    assert!(window.contains(on("2024-03-10", "07:00")));
+    assert!(window.contains(on("2024-03-10", "08:59")));
+    assert!(!window.contains(on("2024-03-10", "09:00")));
+
+    assert!(window.contains(on("2024-03-11", "06:30")));
+    assert!(!window.contains(on("2024-03-11", "09:30")));
+}
+
+#[test]
+fn delete_window_parsing() {
+    let window: DeleteWindow = " 02:00 - 05:30 ".parse().unwrap();
+    assert_eq!(window.start, NaiveTime::from_hms_opt(2, 0, 0).unwrap());
+    assert_eq!(window.end, NaiveTime::from_hms_opt(5, 30, 0).unwrap());
+
+    for bad in [
+        "",
+        "02:00",
+        "02:00-",
+        "2am-5am",
+        "25:00-05:00",
+        "03:00-03:00",
+    ] {
+        assert!(
+            bad.parse::<DeleteWindow>().is_err(),
+            "{:?} should not parse",
+            bad
+        );
+    }
+}
+
+#[sqlx::test(migrations = "../cyclotron-core/migrations")]
+async fn janitor_skips_deletes_outside_window(db: PgPool) {
+    let mut worker = Worker::from_pool(db.clone(), Default::default());
+    worker.max_buffered = 0; // No buffering for testing, flush immediately
+    let manager = QueueManager::from_pool(db.clone());
+    let (_mock_cluster, mock_producer) = create_mock_kafka().await;
+
+    // Runs at 01:00 UTC, outside the window
+    let window: DeleteWindow = "02:00-05:00".parse().unwrap();
+    let mut janitor = Janitor {
+        inner: cyclotron_core::Janitor::from_pool(db.clone()),
+        kafka_producer: mock_producer,
+        settings: JanitorSettings {
+            stall_timeout: Duration::seconds(60),
+            max_touches: 3,
+            id: "test_janitor".to_string(),
+            shard_id: "test_shard".to_string(),
+            delete_window: Some(window),
+            transition_retention: None,
+        },
+        metrics_labels: vec![],
+        clock: || at("01:00"),
+    };
+
+    let now = Utc::now();
+    let job = manager
+        .create_job(JobInit {
+            team_id: 1,
+            queue_name: "default".to_string(),
+            scheduled: now - Duration::seconds(10),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    worker.dequeue_jobs("default", 1).await.unwrap();
+    worker.set_state(job.id, JobState::Completed).unwrap();
+    worker.release_job(job.id, None).await.unwrap();
+
+    let result = janitor.run_once().await.unwrap();
+    assert_eq!(result.completed, 0);
+
+    // Inside the window, the same run deletes it
+    janitor.clock = || at("03:00");
+    let result = janitor.run_once().await.unwrap();
+    assert_eq!(result.completed, 1);
+}
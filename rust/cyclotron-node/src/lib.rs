@@ -164,6 +164,11 @@ pub struct JsJob {
     pub vm_state: Option<String>,
     pub parameters: Option<String>,
     pub metadata: Option<String>,
+    #[serde(default)]
+    pub at_most_once: bool,
+    pub correlation_id: Option<Uuid>,
+    pub parent_job_id: Option<Uuid>,
+    pub reply_to: Option<String>,
 }
 
 fn create_job(mut cx: FunctionContext) -> JsResult<JsPromise> {
@@ -200,8 +205,8 @@ fn create_job(mut cx: FunctionContext) -> JsResult<JsPromise> {
         };
         let res = manager.create_job(job).await;
         deferred.settle_with(&channel, move |mut cx| {
-            let id = res.or_else(|e| cx.throw_error(format!("{}", e)))?;
-            Ok(cx.string(id.to_string()))
+            let job = res.or_else(|e| cx.throw_error(format!("{}", e)))?;
+            Ok(cx.string(job.id.to_string()))
         });
     };
 
@@ -273,10 +278,10 @@ fn bulk_create_jobs(mut cx: FunctionContext) -> JsResult<JsPromise> {
 
         let res = manager.bulk_create_jobs(jobs).await;
         deferred.settle_with(&channel, move |mut cx| {
-            let ids = res.or_else(|e| cx.throw_error(format!("{}", e)))?;
-            let returned = JsArray::new(&mut cx, ids.len());
-            for (i, id) in ids.iter().enumerate() {
-                let id = cx.string(id.to_string());
+            let jobs = res.or_else(|e| cx.throw_error(format!("{}", e)))?;
+            let returned = JsArray::new(&mut cx, jobs.len());
+            for (i, job) in jobs.iter().enumerate() {
+                let id = cx.string(job.id.to_string());
                 returned.set(&mut cx, i as u32, id)?;
             }
             Ok(returned)
@@ -649,6 +654,8 @@ fn jobs_to_js_array<'a>(cx: &mut TaskContext<'a>, jobs: Vec<Job>) -> JsResult<'a
         js_obj.set(cx, "janitorTouchCount", janitor_touch_count)?;
         let transition_count = cx.number(job.transition_count as f64);
         js_obj.set(cx, "transitionCount", transition_count)?;
+        let failed_attempts = cx.number(job.failed_attempts as f64);
+        js_obj.set(cx, "failedAttempts", failed_attempts)?;
 
         let js_last_transition = cx.string(job.last_transition.to_rfc3339());
         js_obj.set(cx, "lastTransition", js_last_transition)?;
@@ -716,6 +723,7 @@ fn jobs_to_js_array<'a>(cx: &mut TaskContext<'a>, jobs: Vec<Job>) -> JsResult<'a
 impl JsJob {
     fn to_job_init(&self, blob: Option<Vec<u8>>) -> JobInit {
         JobInit {
+            id: None,
             team_id: self.team_id,
             queue_name: self.queue_name.clone(),
             priority: self.priority,
@@ -725,6 +733,10 @@ impl JsJob {
             parameters: self.parameters.as_ref().map(|s| s.as_bytes().to_vec()),
             metadata: self.metadata.as_ref().map(|s| s.as_bytes().to_vec()),
             blob,
+            at_most_once: self.at_most_once,
+            correlation_id: self.correlation_id,
+            parent_job_id: self.parent_job_id,
+            reply_to: self.reply_to.clone(),
         }
     }
 }
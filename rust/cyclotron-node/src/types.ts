@@ -44,6 +44,7 @@ export type CyclotronJob = {
     lastHeartbeat: Date | null
     janitorTouchCount: number
     transitionCount: number
+    failedAttempts: number
     lastTransition: Date
     queueName: string
     state: CyclotronJobState